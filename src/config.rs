@@ -0,0 +1,346 @@
+//! Settings for the emulator, from the command line and a TOML config file
+//!
+//! Anything that can go on the command line can also be kept in the config
+//! file. Most options use the long option's name as the key, with a few
+//! exceptions:
+//!
+//! - `--windowed` and `--headless` are `mode = "windowed"` or `"headless"`
+//! - The headless options go in a `[headless]` table, where `--ppm` and
+//!   `--png` are `format = "ppm"` or `"png"`, and `--vram` and `--no-vram` are
+//!   `vram = true` or `false`
+//! - A disc image is `disc = "game.cue"`
+//!
+//! ```toml
+//! bios = "bios/SCPH1001.bin"
+//! region = "usa"
+//! log = "cdrom=debug"
+//! memcard = ["cards/slot1.mcr", "cards/slot2.mcr"]
+//! mode = "headless"
+//! frames = 600
+//!
+//! [headless]
+//! out = "frames"
+//! format = "ppm"
+//! vram = true
+//! wav = "audio.wav"
+//! ```
+//!
+//! Options given on the command line take precedence over the file.
+
+use crate::devices::region::Region;
+use crate::devices::sio::PORT_COUNT;
+use crate::utils::frame_dump::ImageFormat;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "\
+Usage: psx [DISC] [OPTIONS]
+       psx memcard ...
+
+Options:
+    --config FILE     Read settings from FILE, instead of ./psx.toml
+    --bios FILE       The BIOS image to boot (default: ./bios/SCPH1001.bin)
+    --exe FILE        Run a PS-X EXE once the BIOS has started up
+    --region REGION   japan, usa or europe (default: the BIOS's region)
+    --memcard FILE    Put a memory card in the next free slot
+    --log FILTERS     Log targets and levels, like RUST_LOG (e.g. cdrom=debug)
+    --frames N        Stop after N frames
+    --cycles N        Stop after N CPU cycles
+    --windowed        Show the output in a window (the default without a limit)
+    --headless        Dump frames to disk instead (the default with a limit)
+
+Headless options:
+    --out DIR         Where frames are written (default: ./frames)
+    --ppm             Write frames as PPM
+    --png             Write frames as PNG (the default)
+    --vram            Also write a raw dump of VRAM each frame
+    --no-vram         Don't dump VRAM (the default)
+    --wav FILE        Record the audio output to FILE";
+
+/// The config file that's read if --config isn't given, and only if it exists
+const DEFAULT_CONFIG_PATH: &str = "./psx.toml";
+const DEFAULT_BIOS_PATH: &str = "./bios/SCPH1001.bin";
+const DEFAULT_OUT_DIR: &str = "./frames";
+
+/// Options for running the emulator, after merging the config file in
+pub struct Options {
+    pub bios: PathBuf,
+    /// A disc image to put in the drive
+    pub disc: Option<PathBuf>,
+    /// An EXE to run once the BIOS has started up
+    pub exe: Option<PathBuf>,
+    /// The console's region, or None to accept discs from any region
+    pub region: Option<Region>,
+    /// Memory card images for each slot, in order
+    pub memory_cards: Vec<PathBuf>,
+    /// Log filters, in the same format as RUST_LOG
+    pub log: Option<String>,
+    pub limits: Limits,
+    pub mode: Mode,
+}
+
+/// When to stop emulating. With no limits, emulation runs until the window is
+/// closed.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct Limits {
+    pub frames: Option<u32>,
+    pub cycles: Option<u64>,
+}
+
+impl Limits {
+    pub fn is_reached(&self, frames: u32, cycles: u64) -> bool {
+        return self.frames.is_some_and(|limit| frames >= limit)
+            || self.cycles.is_some_and(|limit| cycles >= limit);
+    }
+}
+
+pub enum Mode {
+    Windowed,
+    /// Run without a display, dumping frames to disk instead
+    Headless(HeadlessOptions),
+}
+
+pub struct HeadlessOptions {
+    /// The directory frames are written to
+    pub out_dir: PathBuf,
+    pub format: ImageFormat,
+    /// Whether to also write a raw dump of all of VRAM each frame
+    pub dump_vram: bool,
+    /// A WAV file to record the audio output to
+    pub wav: Option<PathBuf>,
+}
+
+/// Settings as they're written, in the config file or on the command line
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Settings {
+    bios: Option<PathBuf>,
+    disc: Option<PathBuf>,
+    exe: Option<PathBuf>,
+    region: Option<String>,
+    memcard: Vec<PathBuf>,
+    log: Option<String>,
+    frames: Option<u32>,
+    cycles: Option<u64>,
+    mode: Option<String>,
+    headless: HeadlessSettings,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HeadlessSettings {
+    out: Option<PathBuf>,
+    format: Option<String>,
+    vram: Option<bool>,
+    wav: Option<PathBuf>,
+}
+
+impl Settings {
+    /// Fill in anything that isn't set from another set of settings
+    fn or(self, other: Settings) -> Settings {
+        return Settings {
+            bios: self.bios.or(other.bios),
+            disc: self.disc.or(other.disc),
+            exe: self.exe.or(other.exe),
+            region: self.region.or(other.region),
+            memcard: if self.memcard.is_empty() {
+                other.memcard
+            } else {
+                self.memcard
+            },
+            log: self.log.or(other.log),
+            frames: self.frames.or(other.frames),
+            cycles: self.cycles.or(other.cycles),
+            mode: self.mode.or(other.mode),
+            headless: HeadlessSettings {
+                out: self.headless.out.or(other.headless.out),
+                format: self.headless.format.or(other.headless.format),
+                vram: self.headless.vram.or(other.headless.vram),
+                wav: self.headless.wav.or(other.headless.wav),
+            },
+        };
+    }
+
+    fn load(path: &Path) -> Result<Settings, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read config file {}: {}", path.display(), err))?;
+        return toml::from_str(&text)
+            .map_err(|err| format!("Invalid config file {}: {}", path.display(), err));
+    }
+
+    fn into_options(self) -> Result<Options, String> {
+        let region = self.region.as_deref().map(str::parse).transpose()?;
+        if self.memcard.len() > PORT_COUNT {
+            return Err(format!("At most {} memory cards fit", PORT_COUNT));
+        }
+        let limits = Limits {
+            frames: self.frames,
+            cycles: self.cycles,
+        };
+        let is_limited = limits != Limits::default();
+        let mode = match self.mode.as_deref() {
+            Some("windowed") => Mode::Windowed,
+            Some("headless") if !is_limited => {
+                return Err("Headless mode needs a --frames or --cycles limit".to_string());
+            }
+            Some("headless") => Mode::Headless(self.headless.into_options()?),
+            Some(mode) => {
+                return Err(format!(
+                    "Not a mode: {} (expected windowed or headless)",
+                    mode
+                ))
+            }
+            None if is_limited => Mode::Headless(self.headless.into_options()?),
+            None => Mode::Windowed,
+        };
+        return Ok(Options {
+            bios: self
+                .bios
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_BIOS_PATH)),
+            disc: self.disc,
+            exe: self.exe,
+            region,
+            memory_cards: self.memcard,
+            log: self.log,
+            limits,
+            mode,
+        });
+    }
+}
+
+impl HeadlessSettings {
+    fn into_options(self) -> Result<HeadlessOptions, String> {
+        let format = match self.format.as_deref() {
+            None | Some("png") => ImageFormat::Png,
+            Some("ppm") => ImageFormat::Ppm,
+            Some(format) => {
+                return Err(format!(
+                    "Not an image format: {} (expected png or ppm)",
+                    format
+                ))
+            }
+        };
+        return Ok(HeadlessOptions {
+            out_dir: self.out.unwrap_or_else(|| PathBuf::from(DEFAULT_OUT_DIR)),
+            format,
+            dump_vram: self.vram.unwrap_or(false),
+            wav: self.wav,
+        });
+    }
+}
+
+/// Parse the command line, and merge in the config file
+pub fn parse_args<I: Iterator<Item = String>>(args: I) -> Result<Options, String> {
+    let (config_path, settings) = parse_command_line(args)?;
+    let config = match config_path {
+        Some(path) => Settings::load(&path)?,
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+            Settings::load(Path::new(DEFAULT_CONFIG_PATH))?
+        }
+        None => Settings::default(),
+    };
+    return settings.or(config).into_options();
+}
+
+/// Parse the command line into settings, and the config file to read if one
+/// was given
+fn parse_command_line<I: Iterator<Item = String>>(
+    mut args: I,
+) -> Result<(Option<PathBuf>, Settings), String> {
+    let mut config_path = None;
+    let mut settings = Settings::default();
+    while let Some(arg) = args.next() {
+        let mut value = |what: &str| {
+            return args.next().ok_or_else(|| format!("{} needs {}", arg, what));
+        };
+        match arg.as_str() {
+            "--config" => config_path = Some(PathBuf::from(value("a file")?)),
+            "--bios" => settings.bios = Some(PathBuf::from(value("a file")?)),
+            "--exe" => settings.exe = Some(PathBuf::from(value("a file")?)),
+            "--region" => settings.region = Some(value("a region")?),
+            "--memcard" => settings.memcard.push(PathBuf::from(value("a file")?)),
+            "--log" => settings.log = Some(value("a filter")?),
+            "--frames" => {
+                let count = value("a count")?;
+                let count = count
+                    .parse()
+                    .map_err(|_| format!("Not a frame count: {}", count))?;
+                settings.frames = Some(count);
+            }
+            "--cycles" => {
+                let count = value("a count")?;
+                let count = count
+                    .parse()
+                    .map_err(|_| format!("Not a cycle count: {}", count))?;
+                settings.cycles = Some(count);
+            }
+            "--windowed" => settings.mode = Some("windowed".to_string()),
+            "--headless" => settings.mode = Some("headless".to_string()),
+            "--out" => settings.headless.out = Some(PathBuf::from(value("a directory")?)),
+            "--ppm" => settings.headless.format = Some("ppm".to_string()),
+            "--png" => settings.headless.format = Some("png".to_string()),
+            "--vram" => settings.headless.vram = Some(true),
+            "--no-vram" => settings.headless.vram = Some(false),
+            "--wav" => settings.headless.wav = Some(PathBuf::from(value("a file")?)),
+            _ if !arg.starts_with("--") && settings.disc.is_none() => {
+                settings.disc = Some(PathBuf::from(arg))
+            }
+            _ => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+    return Ok((config_path, settings));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str], config: &str) -> Result<Options, String> {
+        let (_, settings) = parse_command_line(args.iter().map(|arg| arg.to_string()))?;
+        let config: Settings = toml::from_str(config).map_err(|err| err.to_string())?;
+        return settings.or(config).into_options();
+    }
+
+    #[test]
+    fn merges_config_file() {
+        let config = "bios = \"scph5501.bin\"\nregion = \"usa\"\nframes = 10\n\
+                      [headless]\nformat = \"ppm\"\n";
+        let opts = parse(&["game.cue", "--frames", "20"], config).unwrap();
+        assert_eq!(opts.bios, PathBuf::from("scph5501.bin"));
+        assert_eq!(opts.disc, Some(PathBuf::from("game.cue")));
+        assert_eq!(opts.region, Some(Region::NorthAmerica));
+        assert_eq!(opts.limits.frames, Some(20));
+        match opts.mode {
+            Mode::Headless(headless) => assert_eq!(headless.format, ImageFormat::Ppm),
+            Mode::Windowed => panic!("A limit should default to headless mode"),
+        }
+
+        let opts = parse(&["--windowed"], config).unwrap();
+        assert!(matches!(opts.mode, Mode::Windowed));
+        let opts = parse(&[], "").unwrap();
+        assert_eq!(opts.bios, PathBuf::from(DEFAULT_BIOS_PATH));
+        assert!(matches!(opts.mode, Mode::Windowed));
+    }
+
+    #[test]
+    fn overrides_headless_config() {
+        let config = "frames = 10\n[headless]\nformat = \"ppm\"\nvram = true\n";
+        match parse(&["--png", "--no-vram"], config).unwrap().mode {
+            Mode::Headless(headless) => {
+                assert_eq!(headless.format, ImageFormat::Png);
+                assert!(!headless.dump_vram);
+            }
+            Mode::Windowed => panic!("A limit should default to headless mode"),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert!(parse(&["--region", "mars"], "").is_err());
+        assert!(parse(&["--headless"], "").is_err());
+        assert!(parse(&["--frames", "ten"], "").is_err());
+        assert!(parse(&["--bios"], "").is_err());
+        assert!(parse(&[], "bois = \"typo.bin\"").is_err());
+        assert!(parse(&["--frames", "1"], "[headless]\nformat = \"gif\"").is_err());
+    }
+}
@@ -108,6 +108,6 @@ impl SizedData for u32 {
     }
 
     fn to_u32(&self) -> u32 {
-        *self as u32
+        *self
     }
 }
@@ -0,0 +1,1136 @@
+//! The CD-ROM controller
+//!
+//! The controller exposes four byte-wide ports at 0x1F801800, which are
+//! banked by the index in the low 2 bits of the first port. Commands are
+//! written with their parameters, and some time later the controller
+//! acknowledges them with an INT3 and a response. Commands that take a while
+//! (seeking, reading, pausing) follow up with a second interrupt once done.
+//!
+//! Audio from CD-DA tracks and XA-ADPCM sectors is decoded to 44.1kHz and
+//! passed through a volume matrix on its way to the SPU's CD input.
+
+use super::disc::{DiscImage, Track, TrackType};
+use super::structs::*;
+use super::xa::{self, XaDecoder};
+use crate::devices::bus::{BusDevice, SizedData};
+use crate::devices::intctrl::{InterruptController, Irq};
+use crate::devices::region::Region;
+use log::debug;
+use std::collections::VecDeque;
+
+/// The CPU clock, used to derive drive timings
+const CPU_CLOCK: u32 = 33_868_800;
+
+//#region Timings, in CPU cycles
+/// The delay before most commands are acknowledged
+const FIRST_RESPONSE_DELAY: u32 = 0xC4E1;
+/// Init takes a bit longer to be acknowledged
+const INIT_RESPONSE_DELAY: u32 = 0x13CCE;
+const INIT_COMPLETE_DELAY: u32 = 120_000;
+const GET_ID_COMPLETE_DELAY: u32 = 0x4A00;
+/// Pausing while already paused is nearly instant
+const PAUSE_IDLE_DELAY: u32 = 0x1DF2;
+/// Pausing while reading has to wait for the current sector to finish
+const PAUSE_SINGLE_SPEED_DELAY: u32 = 0x21_181C;
+const PAUSE_DOUBLE_SPEED_DELAY: u32 = 0x10_BD93;
+const READ_TOC_DELAY: u32 = CPU_CLOCK / 2;
+/// The fixed part of a seek, on top of the time to move the sled
+const SEEK_BASE_DELAY: u32 = 20_000;
+/// The longest a seek can take, from one end of the disc to the other
+const SEEK_MAX_DELAY: u32 = CPU_CLOCK;
+//#endregion
+
+//#region Interrupt types
+/// A sector (or report) is ready
+const INT1_DATA_READY: u8 = 1;
+/// A command has completed (the second response)
+const INT2_COMPLETE: u8 = 2;
+/// A command was acknowledged (the first response)
+const INT3_ACKNOWLEDGE: u8 = 3;
+/// The end of the disc was reached
+pub(super) const INT4_DATA_END: u8 = 4;
+/// A command failed
+const INT5_ERROR: u8 = 5;
+//#endregion
+
+//#region Error codes, sent after the status byte with INT5
+const ERROR_WRONG_PARAM_COUNT: u8 = 0x20;
+const ERROR_INVALID_COMMAND: u8 = 0x40;
+const ERROR_DOOR_OPEN: u8 = 0x80;
+//#endregion
+
+//#region Status register bits
+const STATUS_PARAM_FIFO_EMPTY: u8 = 0x08;
+const STATUS_PARAM_FIFO_NOT_FULL: u8 = 0x10;
+const STATUS_RESPONSE_FIFO_NOT_EMPTY: u8 = 0x20;
+const STATUS_DATA_FIFO_NOT_EMPTY: u8 = 0x40;
+const STATUS_BUSY: u8 = 0x80;
+//#endregion
+
+const FIFO_SIZE: usize = 16;
+
+/// How much decoded audio is kept for the SPU before the oldest is dropped
+const AUDIO_BUFFER_SIZE: usize = 44100 / 2;
+
+/// Offsets into a raw sector of the data returned by reads
+const SECTOR_USER_DATA: std::ops::Range<usize> = 24..(24 + 0x800);
+const SECTOR_WHOLE_DATA: std::ops::Range<usize> = 12..(12 + 0x924);
+
+/// An interrupt waiting to be delivered, with its response bytes
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct Response {
+    int: u8,
+    bytes: Vec<u8>,
+}
+
+/// Something the drive will do once a delay has passed
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum DriveEvent {
+    InitComplete,
+    PauseComplete,
+    GetIdComplete,
+    ReadTocComplete,
+    /// The seek finished, and the drive should carry on as asked
+    SeekComplete(AfterSeek),
+    /// The next sector is under the head
+    SectorReady,
+}
+
+/// What the drive does once a seek finishes
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum AfterSeek {
+    /// Report that the seek completed
+    Stop,
+    Read,
+    /// Play CD-DA audio
+    Play,
+}
+
+/// The volume of each CD audio channel going to each SPU input, where 0x80
+/// is full volume
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+struct VolumeMatrix {
+    left_to_left: u8,
+    left_to_right: u8,
+    right_to_right: u8,
+    right_to_left: u8,
+}
+
+impl VolumeMatrix {
+    fn new() -> VolumeMatrix {
+        return VolumeMatrix {
+            left_to_left: 0x80,
+            left_to_right: 0,
+            right_to_right: 0x80,
+            right_to_left: 0,
+        };
+    }
+
+    fn apply(&self, sample: [i16; 2]) -> [i16; 2] {
+        let mix = |from_left: u8, from_right: u8| {
+            let level =
+                (sample[0] as i32 * from_left as i32 + sample[1] as i32 * from_right as i32) >> 7;
+            level.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+        };
+        return [
+            mix(self.left_to_left, self.right_to_left),
+            mix(self.left_to_right, self.right_to_right),
+        ];
+    }
+}
+
+pub struct CdRom {
+    /// The bank selected for ports 1-3
+    index: u8,
+    params: VecDeque<u8>,
+    response: VecDeque<u8>,
+    data: VecDeque<u8>,
+    irq_enable: u8,
+    irq_flags: u8,
+    /// Interrupts waiting for the current one to be acknowledged
+    queued: VecDeque<Response>,
+    /// A command that's been sent, its parameters, and the cycles until its
+    /// first response
+    pending_command: Option<(u8, Vec<u8>, u32)>,
+    /// A drive event and the cycles until it happens
+    event: Option<(DriveEvent, u32)>,
+    stat: u8,
+    mode: DriveMode,
+    /// The target of the last Setloc, if it hasn't been seeked to yet
+    seek_target: Option<Msf>,
+    /// The sector under the drive head
+    position: u32,
+    /// The most recently read raw sector
+    sector_buffer: Vec<u8>,
+    disc: Option<Box<dyn DiscImage>>,
+    /// The console's region, or None to accept licensed discs from anywhere
+    region: Option<Region>,
+    /// The XA file and channel played when filtering is enabled
+    filter: (u8, u8),
+    xa_decoder: XaDecoder,
+    /// Decoded audio waiting for the SPU
+    audio: VecDeque<[i16; 2]>,
+    /// Whether all audio output is muted, with Mute and Demute
+    is_muted: bool,
+    /// Whether XA-ADPCM output is muted
+    is_adpcm_muted: bool,
+    volume: VolumeMatrix,
+    /// Volume changes that haven't been applied yet
+    pending_volume: VolumeMatrix,
+}
+
+impl CdRom {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> CdRom {
+        CdRom {
+            index: 0,
+            params: VecDeque::with_capacity(FIFO_SIZE),
+            response: VecDeque::with_capacity(FIFO_SIZE),
+            data: VecDeque::with_capacity(0x924),
+            irq_enable: 0,
+            irq_flags: 0,
+            queued: VecDeque::new(),
+            pending_command: None,
+            event: None,
+            stat: STAT_SHELL_OPEN,
+            mode: DriveMode::from(0),
+            seek_target: None,
+            position: LEAD_IN_SECTORS,
+            sector_buffer: vec![],
+            disc: None,
+            region: None,
+            filter: (0, 0),
+            xa_decoder: XaDecoder::new(),
+            audio: VecDeque::new(),
+            is_muted: false,
+            is_adpcm_muted: false,
+            volume: VolumeMatrix::new(),
+            pending_volume: VolumeMatrix::new(),
+        }
+    }
+
+    /// Put a disc in the drive, as if it had been there since power-on
+    pub fn insert_disc(&mut self, disc: Box<dyn DiscImage>) {
+        self.disc = Some(disc);
+        self.stat = STAT_MOTOR_ON;
+    }
+
+    /// Lock the drive to discs from one region, as a retail console's is
+    pub fn set_region(&mut self, region: Option<Region>) {
+        self.region = region;
+    }
+
+    /// Advance the drive by the given number of CPU cycles
+    pub fn tick(&mut self, cycles: u32, intctrl: &mut InterruptController) {
+        if let Some((command, params, delay)) = self.pending_command.take() {
+            if delay > cycles {
+                self.pending_command = Some((command, params, delay - cycles));
+            } else {
+                self.execute(command, &params);
+            }
+        }
+        if let Some((event, delay)) = self.event {
+            if delay > cycles {
+                self.event = Some((event, delay - cycles));
+            } else {
+                self.event = None;
+                self.handle_event(event);
+            }
+        }
+        // the next interrupt isn't delivered until the last is acknowledged
+        if (self.irq_flags & 0x1F) == 0 {
+            if let Some(response) = self.queued.pop_front() {
+                self.irq_flags = response.int;
+                self.response = response.bytes.into_iter().collect();
+                if (self.irq_flags & self.irq_enable & 0x1F) != 0 {
+                    intctrl.request(Irq::CdRom);
+                }
+            }
+        }
+    }
+
+    /// Read a word from the data FIFO, for DMA
+    pub fn read_data_word(&mut self) -> u32 {
+        let mut word = 0u32;
+        for i in 0..4 {
+            word |= (self.read_data_byte() as u32) << (i * 8);
+        }
+        return word;
+    }
+
+    /// Take the audio decoded since the last call, after the volume matrix,
+    /// oldest first
+    pub fn drain_audio(&mut self) -> impl Iterator<Item = [i16; 2]> + '_ {
+        let volume = self.volume;
+        let is_muted = self.is_muted;
+        return self.audio.drain(..).map(move |sample| {
+            if is_muted {
+                [0, 0]
+            } else {
+                volume.apply(sample)
+            }
+        });
+    }
+
+    fn read_data_byte(&mut self) -> u8 {
+        return self.data.pop_front().unwrap_or(0);
+    }
+
+    //#region Disc access
+    // Without a disc, the drive behaves as if the lid were open.
+
+    fn has_disc(&self) -> bool {
+        return self.disc.is_some();
+    }
+
+    /// Read the raw 2352-byte sector at the given absolute sector number
+    fn read_sector(&mut self, sector: u32) -> Option<Vec<u8>> {
+        let disc = self.disc.as_mut()?;
+        return match disc.read_sector(Msf::from_sector(sector)) {
+            Ok(data) => Some(data.to_vec()),
+            Err(err) => {
+                debug!(target: "cdrom", "Could not read sector {}: {}", sector, err);
+                None
+            }
+        };
+    }
+
+    /// Return the region letter from the license string on the disc, or None
+    /// if the disc isn't licensed
+    fn get_region(&mut self) -> Option<u8> {
+        // the license string is in the user data of LBA 4
+        let sector = self.read_sector(LEAD_IN_SECTORS + 4)?;
+        let license = &sector[SECTOR_USER_DATA];
+        let contains = |text: &[u8]| license.windows(text.len()).any(|w| w == text);
+        if !contains(b"Sony Computer Entertainment") {
+            return None;
+        }
+        return Some(if contains(b"Amer") {
+            b'A'
+        } else if contains(b"Europe") {
+            b'E'
+        } else {
+            b'I'
+        });
+    }
+    //#endregion
+
+    fn queue_response(&mut self, int: u8, bytes: &[u8]) {
+        self.queued.push_back(Response {
+            int,
+            bytes: bytes.to_vec(),
+        });
+    }
+
+    fn queue_error(&mut self, code: u8) {
+        let bytes = [self.stat | STAT_ERROR, code];
+        self.queue_response(INT5_ERROR, &bytes);
+    }
+
+    fn schedule(&mut self, event: DriveEvent, delay: u32) {
+        self.event = Some((event, delay));
+    }
+
+    fn sector_period(&self) -> u32 {
+        let sectors_per_second = if self.mode.is_double_speed() {
+            SECTORS_PER_SECOND * 2
+        } else {
+            SECTORS_PER_SECOND
+        };
+        return CPU_CLOCK / sectors_per_second;
+    }
+
+    /// Estimate how long moving the head to the given sector will take
+    fn seek_delay(&self, target: u32) -> u32 {
+        let distance = (target as i64 - self.position as i64).unsigned_abs() as u32;
+        // a full disc is about 80 minutes of sectors
+        let full_disc = 80 * 60 * SECTORS_PER_SECOND;
+        let travel =
+            (distance.min(full_disc) as u64 * SEEK_MAX_DELAY as u64 / full_disc as u64) as u32;
+        return SEEK_BASE_DELAY + travel;
+    }
+
+    //#region Commands
+    fn write_command(&mut self, command: u8) {
+        let params: Vec<u8> = self.params.drain(..).collect();
+        debug!(target: "cdrom", "Command 0x{:02X} {:02X?}", command, params);
+        let delay = if command == 0x0A {
+            INIT_RESPONSE_DELAY
+        } else {
+            FIRST_RESPONSE_DELAY
+        };
+        self.pending_command = Some((command, params, delay));
+    }
+
+    fn execute(&mut self, command: u8, params: &[u8]) {
+        let expected_params = match command {
+            0x02 => 3,
+            0x0D => 2,
+            0x0E | 0x14 => 1,
+            // Play takes an optional track number
+            0x03 => params.len().min(1),
+            // Test has a subfunction, and sometimes more
+            0x19 => params.len().max(1),
+            _ => 0,
+        };
+        if params.len() != expected_params {
+            self.queue_error(ERROR_WRONG_PARAM_COUNT);
+            return;
+        }
+
+        match command {
+            // GetStat
+            0x01 => {
+                self.queue_response(INT3_ACKNOWLEDGE, &[self.stat]);
+                // the shell open flag stays set until it has been read, and
+                // the lid is closed
+                if self.has_disc() {
+                    self.stat &= !STAT_SHELL_OPEN;
+                }
+            }
+            // Setloc
+            0x02 => match Msf::from_bcd(params[0], params[1], params[2]) {
+                Some(msf) => {
+                    self.seek_target = Some(msf);
+                    self.queue_response(INT3_ACKNOWLEDGE, &[self.stat]);
+                }
+                None => self.queue_error(ERROR_INVALID_COMMAND),
+            },
+            // Play
+            0x03 => {
+                if !self.has_disc() {
+                    self.queue_error(ERROR_DOOR_OPEN);
+                    return;
+                }
+                // a track number of 0 (or none) plays from the Setloc target
+                match params.first().map(|&track| from_bcd(track)) {
+                    Some(Some(0)) | None => (),
+                    Some(Some(track)) => match self.get_track_start(track) {
+                        Some(msf) => self.seek_target = Some(msf),
+                        None => return self.queue_error(ERROR_INVALID_COMMAND),
+                    },
+                    Some(None) => return self.queue_error(ERROR_INVALID_COMMAND),
+                }
+                self.queue_response(INT3_ACKNOWLEDGE, &[self.stat]);
+                self.start_seek(AfterSeek::Play);
+            }
+            // ReadN, ReadS
+            0x06 | 0x1B => {
+                if !self.has_disc() {
+                    self.queue_error(ERROR_DOOR_OPEN);
+                    return;
+                }
+                self.queue_response(INT3_ACKNOWLEDGE, &[self.stat]);
+                self.start_seek(AfterSeek::Read);
+            }
+            // Pause
+            0x09 => {
+                self.queue_response(INT3_ACKNOWLEDGE, &[self.stat]);
+                let delay = if (self.stat & (STAT_READING | STAT_PLAYING)) == 0 {
+                    PAUSE_IDLE_DELAY
+                } else if self.mode.is_double_speed() {
+                    PAUSE_DOUBLE_SPEED_DELAY
+                } else {
+                    PAUSE_SINGLE_SPEED_DELAY
+                };
+                self.schedule(DriveEvent::PauseComplete, delay);
+            }
+            // Init
+            0x0A => {
+                self.mode = DriveMode::from(0);
+                self.stat |= STAT_MOTOR_ON;
+                self.stat &= !(STAT_READING | STAT_SEEKING | STAT_PLAYING);
+                self.event = None;
+                self.queue_response(INT3_ACKNOWLEDGE, &[self.stat]);
+                self.schedule(DriveEvent::InitComplete, INIT_COMPLETE_DELAY);
+            }
+            // Mute, Demute
+            0x0B | 0x0C => {
+                self.is_muted = command == 0x0B;
+                self.queue_response(INT3_ACKNOWLEDGE, &[self.stat]);
+            }
+            // Setfilter
+            0x0D => {
+                self.filter = (params[0], params[1]);
+                self.queue_response(INT3_ACKNOWLEDGE, &[self.stat]);
+            }
+            // Setmode
+            0x0E => {
+                self.mode = DriveMode::from(params[0]);
+                self.queue_response(INT3_ACKNOWLEDGE, &[self.stat]);
+            }
+            // GetTN
+            0x13 => match self.get_track_range() {
+                Some((first, last)) => {
+                    let bytes = [self.stat, to_bcd(first), to_bcd(last)];
+                    self.queue_response(INT3_ACKNOWLEDGE, &bytes);
+                }
+                None => self.queue_error(ERROR_DOOR_OPEN),
+            },
+            // GetTD
+            0x14 => {
+                if !self.has_disc() {
+                    self.queue_error(ERROR_DOOR_OPEN);
+                    return;
+                }
+                let track = match from_bcd(params[0]) {
+                    Some(track) => track,
+                    None => return self.queue_error(ERROR_INVALID_COMMAND),
+                };
+                match self.get_track_start(track) {
+                    Some(msf) => {
+                        let bcd = msf.to_bcd();
+                        self.queue_response(INT3_ACKNOWLEDGE, &[self.stat, bcd[0], bcd[1]]);
+                    }
+                    None => self.queue_error(ERROR_INVALID_COMMAND),
+                }
+            }
+            // SeekL, SeekP
+            0x15 | 0x16 => {
+                if !self.has_disc() {
+                    self.queue_error(ERROR_DOOR_OPEN);
+                    return;
+                }
+                self.queue_response(INT3_ACKNOWLEDGE, &[self.stat]);
+                self.start_seek(AfterSeek::Stop);
+            }
+            // Test
+            0x19 => match params[0] {
+                // the BIOS asks for the controller's version, and these are
+                // the values for the SCPH-1001's controller
+                0x20 => self.queue_response(INT3_ACKNOWLEDGE, &[0x94, 0x09, 0x19, 0xC0]),
+                _ => self.queue_error(ERROR_INVALID_COMMAND),
+            },
+            // GetID
+            0x1A => {
+                if !self.has_disc() {
+                    self.queue_error(ERROR_DOOR_OPEN);
+                    return;
+                }
+                self.queue_response(INT3_ACKNOWLEDGE, &[self.stat]);
+                self.schedule(DriveEvent::GetIdComplete, GET_ID_COMPLETE_DELAY);
+            }
+            // ReadTOC
+            0x1E => {
+                if !self.has_disc() {
+                    self.queue_error(ERROR_DOOR_OPEN);
+                    return;
+                }
+                self.queue_response(INT3_ACKNOWLEDGE, &[self.stat]);
+                self.schedule(DriveEvent::ReadTocComplete, READ_TOC_DELAY);
+            }
+            _ => {
+                debug!(target: "cdrom", "Unimplemented command 0x{:02X}", command);
+                self.queue_error(ERROR_INVALID_COMMAND);
+            }
+        }
+    }
+
+    /// Move to the last Setloc target (if any), then carry on as asked
+    fn start_seek(&mut self, then: AfterSeek) {
+        self.stat &= !(STAT_READING | STAT_PLAYING);
+        match (self.seek_target.take(), then) {
+            (Some(target), _) => {
+                let delay = self.seek_delay(target.to_sector());
+                self.position = target.to_sector();
+                self.stat |= STAT_SEEKING;
+                self.schedule(DriveEvent::SeekComplete(then), delay);
+            }
+            // reading or playing without a Setloc continues from the current
+            // position
+            (None, AfterSeek::Read) | (None, AfterSeek::Play) => self.start(then),
+            (None, AfterSeek::Stop) => {
+                self.schedule(DriveEvent::SeekComplete(then), SEEK_BASE_DELAY)
+            }
+        }
+    }
+
+    /// Start reading or playing from the current position
+    fn start(&mut self, then: AfterSeek) {
+        match then {
+            AfterSeek::Read => self.stat |= STAT_READING,
+            AfterSeek::Play => self.stat |= STAT_PLAYING,
+            AfterSeek::Stop => return,
+        }
+        self.xa_decoder.reset();
+        self.schedule(DriveEvent::SectorReady, self.sector_period());
+    }
+
+    /// Return the track containing the given absolute sector
+    fn get_track_at(&self, sector: u32) -> Option<Track> {
+        let lba = sector.checked_sub(LEAD_IN_SECTORS)?;
+        return self.disc.as_ref()?.find_track(lba).copied();
+    }
+
+    /// Return the first and last track numbers on the disc
+    fn get_track_range(&self) -> Option<(u8, u8)> {
+        let tracks = self.disc.as_ref()?.tracks();
+        return Some((tracks.first()?.number, tracks.last()?.number));
+    }
+
+    /// Return the start of the given track, or the end of the disc for
+    /// track 0
+    fn get_track_start(&self, track: u8) -> Option<Msf> {
+        let disc = self.disc.as_ref()?;
+        if track == 0 {
+            return Some(disc.lead_out());
+        }
+        let track = disc.tracks().iter().find(|t| t.number == track)?;
+        return Some(Msf::from_lba(track.start));
+    }
+    //#endregion
+
+    fn handle_event(&mut self, event: DriveEvent) {
+        match event {
+            DriveEvent::InitComplete | DriveEvent::ReadTocComplete => {
+                self.queue_response(INT2_COMPLETE, &[self.stat]);
+            }
+            DriveEvent::PauseComplete => {
+                self.stat &= !(STAT_READING | STAT_PLAYING | STAT_SEEKING);
+                self.queue_response(INT2_COMPLETE, &[self.stat]);
+            }
+            DriveEvent::GetIdComplete => {
+                let is_audio = self
+                    .disc
+                    .as_ref()
+                    .and_then(|disc| disc.tracks().first())
+                    .map(|track| track.track_type == TrackType::Audio)
+                    .unwrap_or(false);
+                let stat = self.stat;
+                let console_region = self.region.map(|region| region.get_license_letter());
+                match (is_audio, self.get_region()) {
+                    (true, _) => {
+                        let bytes = [stat | STAT_ID_ERROR, 0x90, 0, 0, 0, 0, 0, 0];
+                        self.queue_response(INT5_ERROR, &bytes);
+                    }
+                    (false, Some(region)) if console_region.unwrap_or(region) == region => {
+                        let bytes = [stat, 0x00, 0x20, 0x00, b'S', b'C', b'E', region];
+                        self.queue_response(INT2_COMPLETE, &bytes);
+                    }
+                    // unlicensed discs and discs from other regions are
+                    // both denied
+                    (false, _) => {
+                        let bytes = [stat | STAT_ID_ERROR, 0x80, 0x20, 0, 0, 0, 0, 0];
+                        self.queue_response(INT5_ERROR, &bytes);
+                    }
+                }
+            }
+            DriveEvent::SeekComplete(then) => {
+                self.stat &= !STAT_SEEKING;
+                match then {
+                    AfterSeek::Stop => self.queue_response(INT2_COMPLETE, &[self.stat]),
+                    _ => self.start(then),
+                }
+            }
+            DriveEvent::SectorReady if (self.stat & STAT_PLAYING) != 0 => self.play_sector(),
+            DriveEvent::SectorReady => self.read_next_sector(),
+        }
+    }
+
+    fn read_next_sector(&mut self) {
+        let sector = match self.read_sector(self.position) {
+            Some(sector) => sector,
+            None => {
+                self.stat &= !STAT_READING;
+                self.queue_response(INT4_DATA_END, &[self.stat]);
+                return;
+            }
+        };
+        self.position += 1;
+        self.schedule(DriveEvent::SectorReady, self.sector_period());
+
+        // XA-ADPCM sectors go to the SPU instead of the CPU, and are skipped
+        // entirely if they don't match the filter
+        let stream = xa::get_audio_stream(&sector).filter(|_| sector[15] == 2);
+        if let (true, Some(stream)) = (self.mode.is_xa_adpcm_enabled(), stream) {
+            if !self.mode.is_xa_filter_enabled() || stream == self.filter {
+                if self.is_adpcm_muted {
+                    self.xa_decoder.decode_sector(&sector, &mut VecDeque::new());
+                } else {
+                    self.xa_decoder.decode_sector(&sector, &mut self.audio);
+                }
+                self.trim_audio();
+            }
+            return;
+        }
+
+        self.sector_buffer = sector;
+        // software that falls behind loses sectors, rather than building up a
+        // backlog
+        self.queued.retain(|r| r.int != INT1_DATA_READY);
+        self.queue_response(INT1_DATA_READY, &[self.stat]);
+    }
+
+    /// Play a sector of CD-DA audio
+    fn play_sector(&mut self) {
+        let sector = match self.read_sector(self.position) {
+            Some(sector) => sector,
+            None => {
+                self.stat &= !STAT_PLAYING;
+                self.queue_response(INT4_DATA_END, &[self.stat]);
+                return;
+            }
+        };
+        self.audio.extend(sector.chunks_exact(4).map(|sample| {
+            [
+                i16::from_le_bytes([sample[0], sample[1]]),
+                i16::from_le_bytes([sample[2], sample[3]]),
+            ]
+        }));
+        self.trim_audio();
+        if self.mode.is_report_enabled() {
+            self.queue_report(&sector);
+        }
+
+        let track = self.get_track_at(self.position).map(|track| track.number);
+        self.position += 1;
+        // auto-pause stops at the end of the track
+        let next_track = self.get_track_at(self.position).map(|track| track.number);
+        if self.mode.is_auto_pause() && next_track != track {
+            self.stat &= !STAT_PLAYING;
+            self.queue_response(INT4_DATA_END, &[self.stat]);
+            return;
+        }
+        self.schedule(DriveEvent::SectorReady, self.sector_period());
+    }
+
+    /// Send a report of the play position, which happens every 10 sectors
+    ///
+    /// Reports alternate between the absolute position on the disc, and the
+    /// position relative to the track (marked by bit 7 of the seconds).
+    fn queue_report(&mut self, sector: &[u8]) {
+        let position = Msf::from_sector(self.position);
+        if !position.frame.is_multiple_of(10) {
+            return;
+        }
+        let track = self.get_track_at(self.position);
+        let (number, start) = track.map(|t| (t.number, t.start)).unwrap_or((0, 0));
+        let lba = self.position - LEAD_IN_SECTORS;
+        let index = if lba < start { 0 } else { 1 };
+        let time = if (position.frame / 10).is_multiple_of(2) {
+            position.to_bcd()
+        } else {
+            let relative = Msf::from_sector(lba.abs_diff(start)).to_bcd();
+            [relative[0], relative[1] | 0x80, relative[2]]
+        };
+        let peak = sector
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]).unsigned_abs())
+            .max()
+            .unwrap_or(0)
+            .min(0x7FFF);
+        let bytes = [
+            self.stat,
+            to_bcd(number),
+            to_bcd(index),
+            time[0],
+            time[1],
+            time[2],
+            peak as u8,
+            (peak >> 8) as u8,
+        ];
+        self.queued.retain(|r| r.int != INT1_DATA_READY);
+        self.queue_response(INT1_DATA_READY, &bytes);
+    }
+
+    /// Drop the oldest audio if nothing has been taking it
+    fn trim_audio(&mut self) {
+        let excess = self.audio.len().saturating_sub(AUDIO_BUFFER_SIZE);
+        self.audio.drain(..excess);
+    }
+
+    /// Handle a write to the request register
+    fn write_request(&mut self, data: u8) {
+        if (data & 0x80) == 0 {
+            self.data.clear();
+            return;
+        }
+        if self.sector_buffer.is_empty() {
+            return;
+        }
+        let range = if self.mode.is_whole_sector() {
+            SECTOR_WHOLE_DATA
+        } else {
+            SECTOR_USER_DATA
+        };
+        self.data = self.sector_buffer[range].iter().copied().collect();
+    }
+
+    fn get_status(&self) -> u8 {
+        let mut status = self.index;
+        if self.params.is_empty() {
+            status |= STATUS_PARAM_FIFO_EMPTY;
+        }
+        if self.params.len() < FIFO_SIZE {
+            status |= STATUS_PARAM_FIFO_NOT_FULL;
+        }
+        if !self.response.is_empty() {
+            status |= STATUS_RESPONSE_FIFO_NOT_EMPTY;
+        }
+        if !self.data.is_empty() {
+            status |= STATUS_DATA_FIFO_NOT_EMPTY;
+        }
+        if self.pending_command.is_some() {
+            status |= STATUS_BUSY;
+        }
+        return status;
+    }
+
+    fn read_reg(&mut self, addr: u32) -> u8 {
+        return match (addr, self.index) {
+            (0, _) => self.get_status(),
+            (1, _) => self.response.pop_front().unwrap_or(0),
+            (2, _) => self.read_data_byte(),
+            (3, 0) | (3, 2) => self.irq_enable | 0xE0,
+            (3, _) => self.irq_flags | 0xE0,
+            _ => unreachable!(),
+        };
+    }
+
+    fn write_reg(&mut self, addr: u32, data: u8) {
+        match (addr, self.index) {
+            (0, _) => self.index = data & 0x3,
+            (1, 0) => self.write_command(data),
+            (2, 0) => {
+                if self.params.len() < FIFO_SIZE {
+                    self.params.push_back(data);
+                }
+            }
+            (2, 1) => self.irq_enable = data & 0x1F,
+            (2, 2) => self.pending_volume.left_to_left = data,
+            (3, 2) => self.pending_volume.left_to_right = data,
+            (1, 3) => self.pending_volume.right_to_right = data,
+            (2, 3) => self.pending_volume.right_to_left = data,
+            (3, 3) => {
+                self.is_adpcm_muted = (data & 0x01) != 0;
+                if (data & 0x20) != 0 {
+                    self.volume = self.pending_volume;
+                }
+            }
+            (3, 0) => self.write_request(data),
+            (3, 1) => {
+                self.irq_flags &= !(data & 0x1F);
+                if (data & 0x40) != 0 {
+                    self.params.clear();
+                }
+            }
+            _ => {
+                debug!(target: "cdrom", "Unimplemented write to 1F80180{}.{} = 0x{:02X}", addr, self.index, data);
+            }
+        }
+    }
+}
+
+impl BusDevice for CdRom {
+    fn read<T: SizedData>(&mut self, addr: u32) -> T {
+        // wider reads of the data FIFO pop several bytes at once
+        let mut data = 0u32;
+        for i in 0..T::width() as u32 {
+            data |= (self.read_reg(addr & 0x3) as u32) << (i * 8);
+        }
+        T::from_u32(data)
+    }
+
+    fn peek<T: SizedData>(&self, addr: u32) -> Option<T> {
+        let data = match (addr & 0x3, self.index) {
+            (0, _) => self.get_status(),
+            (1, _) => *self.response.front()?,
+            (2, _) => *self.data.front()?,
+            (3, 0) | (3, 2) => self.irq_enable | 0xE0,
+            (3, _) => self.irq_flags | 0xE0,
+            _ => unreachable!(),
+        };
+        Some(T::from_u32(data as u32))
+    }
+
+    fn write<T: SizedData>(&mut self, addr: u32, data: T) {
+        self.write_reg(addr & 0x3, data.to_u32() as u8);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::cdrom::disc::{Track, SECTOR_SIZE};
+
+    /// Send a command and run the drive until it responds
+    fn send_command(cdrom: &mut CdRom, command: u8, params: &[u8]) -> (u8, Vec<u8>) {
+        let mut intctrl = InterruptController::new();
+        cdrom.write(0, 0u8);
+        for &param in params {
+            cdrom.write(2, param);
+        }
+        cdrom.write(1, command);
+        wait_for_irq(cdrom, &mut intctrl)
+    }
+
+    /// Run the drive until it raises an interrupt, then acknowledge it
+    fn wait_for_irq(cdrom: &mut CdRom, intctrl: &mut InterruptController) -> (u8, Vec<u8>) {
+        cdrom.write(0, 1u8);
+        cdrom.write(2, 0x1Fu8);
+        for _ in 0..(CPU_CLOCK / 100) {
+            cdrom.tick(100, intctrl);
+            if (cdrom.read::<u8>(3) & 0x1F) != 0 {
+                break;
+            }
+        }
+        let int = cdrom.read::<u8>(3) & 0x1F;
+        let mut bytes = vec![];
+        while (cdrom.read::<u8>(0) & STATUS_RESPONSE_FIFO_NOT_EMPTY) != 0 {
+            bytes.push(cdrom.read::<u8>(1));
+        }
+        cdrom.write(3, 0x1Fu8);
+        (int, bytes)
+    }
+
+    #[test]
+    fn reports_status() {
+        let mut cdrom = CdRom::new();
+        assert_eq!(cdrom.read::<u8>(0), 0x18, "FIFOs should start empty");
+        assert_eq!(
+            send_command(&mut cdrom, 0x01, &[]),
+            (3, vec![STAT_SHELL_OPEN])
+        );
+    }
+
+    #[test]
+    fn reports_busy_until_acknowledged() {
+        let mut cdrom = CdRom::new();
+        let mut intctrl = InterruptController::new();
+        cdrom.write(1, 0x01u8);
+        assert_ne!(cdrom.read::<u8>(0) & STATUS_BUSY, 0);
+        cdrom.tick(FIRST_RESPONSE_DELAY - 1, &mut intctrl);
+        assert_ne!(cdrom.read::<u8>(0) & STATUS_BUSY, 0);
+        cdrom.tick(1, &mut intctrl);
+        assert_eq!(cdrom.read::<u8>(0) & STATUS_BUSY, 0);
+    }
+
+    #[test]
+    fn sends_second_response_after_first_is_acknowledged() {
+        let mut cdrom = CdRom::new();
+        let mut intctrl = InterruptController::new();
+        assert_eq!(send_command(&mut cdrom, 0x0A, &[]), (3, vec![0x12]));
+        assert_eq!(wait_for_irq(&mut cdrom, &mut intctrl), (2, vec![0x12]));
+    }
+
+    #[test]
+    fn raises_irq_when_enabled() {
+        let mut cdrom = CdRom::new();
+        let mut intctrl = InterruptController::new();
+        intctrl.write(4, 0xFFFFu32);
+        cdrom.write(1, 0x01u8);
+        cdrom.tick(FIRST_RESPONSE_DELAY, &mut intctrl);
+        assert!(!intctrl.is_pending(), "IRQ should be masked by the CD-ROM");
+        // enable and acknowledge all interrupts
+        cdrom.write(0, 1u8);
+        cdrom.write(2, 0x1Fu8);
+        cdrom.write(3, 0x1Fu8);
+        cdrom.write(0, 0u8);
+        cdrom.write(1, 0x01u8);
+        cdrom.tick(FIRST_RESPONSE_DELAY, &mut intctrl);
+        assert!(intctrl.is_pending());
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        let mut cdrom = CdRom::new();
+        assert_eq!(
+            send_command(&mut cdrom, 0x02, &[0x00]),
+            (5, vec![0x11, 0x20])
+        );
+        assert_eq!(send_command(&mut cdrom, 0x55, &[]), (5, vec![0x11, 0x40]));
+    }
+
+    #[test]
+    fn reports_controller_version() {
+        let mut cdrom = CdRom::new();
+        let (int, bytes) = send_command(&mut cdrom, 0x19, &[0x20]);
+        assert_eq!((int, bytes), (3, vec![0x94, 0x09, 0x19, 0xC0]));
+    }
+
+    #[test]
+    fn reports_missing_disc() {
+        let mut cdrom = CdRom::new();
+        assert_eq!(send_command(&mut cdrom, 0x1A, &[]), (5, vec![0x11, 0x80]));
+        assert_eq!(send_command(&mut cdrom, 0x06, &[]), (5, vec![0x11, 0x80]));
+    }
+
+    /// A disc where every byte of a sector is its LBA
+    struct TestDisc {
+        tracks: Vec<Track>,
+    }
+
+    impl DiscImage for TestDisc {
+        fn tracks(&self) -> &[Track] {
+            &self.tracks
+        }
+
+        fn read_sector_lba(&mut self, lba: u32) -> std::io::Result<[u8; SECTOR_SIZE]> {
+            let mut sector = [lba as u8; SECTOR_SIZE];
+            if lba == 4 {
+                let license = b"Licensed by Sony Computer Entertainment Europe";
+                sector[24..24 + license.len()].copy_from_slice(license);
+            }
+            Ok(sector)
+        }
+    }
+
+    fn insert_test_disc(cdrom: &mut CdRom) {
+        cdrom.insert_disc(Box::new(TestDisc {
+            tracks: vec![Track {
+                number: 1,
+                track_type: TrackType::Mode2,
+                pregap_start: 0,
+                start: 0,
+                length: 100,
+            }],
+        }));
+    }
+
+    /// Insert a disc with two 20-sector audio tracks
+    fn insert_audio_disc(cdrom: &mut CdRom) {
+        let track = |number: u8| Track {
+            number,
+            track_type: TrackType::Audio,
+            pregap_start: (number as u32 - 1) * 20,
+            start: (number as u32 - 1) * 20,
+            length: 20,
+        };
+        cdrom.insert_disc(Box::new(TestDisc {
+            tracks: vec![track(1), track(2)],
+        }));
+    }
+
+    /// A data disc of XA-ADPCM sectors, interleaving channels 0 and 1 of
+    /// file 1
+    struct XaDisc {
+        tracks: [Track; 1],
+    }
+
+    impl DiscImage for XaDisc {
+        fn tracks(&self) -> &[Track] {
+            &self.tracks
+        }
+
+        fn read_sector_lba(&mut self, lba: u32) -> std::io::Result<[u8; SECTOR_SIZE]> {
+            let mut sector = [0u8; SECTOR_SIZE];
+            sector[15] = 2;
+            sector[16..20].copy_from_slice(&[1, (lba % 2) as u8, 0x64, 0x00]);
+            Ok(sector)
+        }
+    }
+
+    #[test]
+    fn identifies_disc() {
+        let mut cdrom = CdRom::new();
+        let mut intctrl = InterruptController::new();
+        insert_test_disc(&mut cdrom);
+        assert_eq!(send_command(&mut cdrom, 0x1A, &[]), (3, vec![0x02]));
+        let (int, bytes) = wait_for_irq(&mut cdrom, &mut intctrl);
+        assert_eq!((int, bytes), (2, b"\x02\x00\x20\x00SCEE".to_vec()));
+        // an American console won't take a European disc
+        cdrom.set_region(Some(Region::NorthAmerica));
+        send_command(&mut cdrom, 0x1A, &[]);
+        let (int, bytes) = wait_for_irq(&mut cdrom, &mut intctrl);
+        assert_eq!((int, bytes[1]), (5, 0x80));
+        cdrom.set_region(None);
+        // the lead-out is at 00:03:25, and track 1 at 00:02:00
+        assert_eq!(
+            send_command(&mut cdrom, 0x14, &[0x00]),
+            (3, vec![0x02, 0x00, 0x03])
+        );
+        assert_eq!(
+            send_command(&mut cdrom, 0x14, &[0x01]),
+            (3, vec![0x02, 0x00, 0x02])
+        );
+    }
+
+    #[test]
+    fn reads_sectors() {
+        let mut cdrom = CdRom::new();
+        let mut intctrl = InterruptController::new();
+        insert_test_disc(&mut cdrom);
+        send_command(&mut cdrom, 0x02, &[0x00, 0x02, 0x10]);
+        assert_eq!(send_command(&mut cdrom, 0x06, &[]), (3, vec![0x02]));
+        // 00:02:10 is LBA 10
+        for lba in 10..12 {
+            assert_eq!(wait_for_irq(&mut cdrom, &mut intctrl), (1, vec![0x22]));
+            cdrom.write(0, 0u8);
+            cdrom.write(3, 0x80u8);
+            assert_eq!(cdrom.read_data_word(), u32::from_le_bytes([lba; 4]));
+        }
+    }
+
+    #[test]
+    fn plays_audio_tracks() {
+        let mut cdrom = CdRom::new();
+        let mut intctrl = InterruptController::new();
+        insert_audio_disc(&mut cdrom);
+        // auto-pause and reports
+        send_command(&mut cdrom, 0x0E, &[0x06]);
+        assert_eq!(send_command(&mut cdrom, 0x03, &[0x01]), (3, vec![0x02]));
+
+        // an absolute report at 00:02:00, then a relative one 10 sectors in
+        let (int, bytes) = wait_for_irq(&mut cdrom, &mut intctrl);
+        assert_eq!(
+            (int, bytes),
+            (1, vec![0x82, 0x01, 0x01, 0x00, 0x02, 0x00, 0, 0])
+        );
+        let (int, bytes) = wait_for_irq(&mut cdrom, &mut intctrl);
+        let report = vec![0x82, 0x01, 0x01, 0x00, 0x80, 0x10, 0x0A, 0x0A];
+        assert_eq!((int, bytes), (1, report));
+        // then a pause at the end of the track
+        assert_eq!(wait_for_irq(&mut cdrom, &mut intctrl), (4, vec![0x02]));
+
+        let audio: Vec<_> = cdrom.drain_audio().collect();
+        assert_eq!(audio.len(), 20 * 588);
+        assert_eq!(audio[588 * 10], [0x0A0A, 0x0A0A]);
+    }
+
+    #[test]
+    fn applies_volume_matrix() {
+        let mut cdrom = CdRom::new();
+        // swap the channels at half volume
+        cdrom.write(0, 2u8);
+        cdrom.write(2, 0x00u8);
+        cdrom.write(3, 0x40u8);
+        cdrom.write(0, 3u8);
+        cdrom.write(1, 0x00u8);
+        cdrom.write(2, 0x40u8);
+        cdrom.audio.push_back([0x1000, 0x2000]);
+        assert_eq!(cdrom.drain_audio().next(), Some([0x1000, 0x2000]));
+        cdrom.write(3, 0x20u8);
+        cdrom.audio.push_back([0x1000, 0x2000]);
+        assert_eq!(cdrom.drain_audio().next(), Some([0x1000, 0x0800]));
+    }
+
+    #[test]
+    fn filters_xa_audio() {
+        let mut cdrom = CdRom::new();
+        let mut intctrl = InterruptController::new();
+        cdrom.insert_disc(Box::new(XaDisc {
+            tracks: [Track {
+                number: 1,
+                track_type: TrackType::Mode2,
+                pregap_start: 0,
+                start: 0,
+                length: 100,
+            }],
+        }));
+        send_command(&mut cdrom, 0x0E, &[0x48]);
+        send_command(&mut cdrom, 0x0D, &[0x01, 0x01]);
+        send_command(&mut cdrom, 0x02, &[0x00, 0x02, 0x10]);
+        send_command(&mut cdrom, 0x06, &[]);
+
+        // run for four sectors, none of which reach the CPU
+        let period = cdrom.sector_period();
+        let cycles = SEEK_BASE_DELAY + 2000 + 4 * period + period / 2;
+        for _ in 0..cycles / 100 {
+            cdrom.tick(100, &mut intctrl);
+        }
+        assert_eq!(cdrom.read::<u8>(3) & 0x1F, 0);
+        // only the two sectors on channel 1 are decoded, each 4032 samples
+        // at 37.8kHz
+        assert_eq!(cdrom.drain_audio().count(), 2 * 4032 * 7 / 6);
+    }
+}
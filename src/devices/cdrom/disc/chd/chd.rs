@@ -0,0 +1,955 @@
+//! CHD (MAME's "Compressed Hunks of Data") images
+//!
+//! A CHD stores a disc as a sequence of fixed-size hunks, each compressed
+//! independently with one of up to four codecs named in the header. A map
+//! gives the codec, size and location of each hunk. For CDs, every hunk holds
+//! a whole number of 2448-byte frames (a raw sector and its subcode), and the
+//! track layout is stored as text metadata. Only version 5 is supported.
+
+use super::super::{
+    out_of_range, synthesize_form1_sector, synthesize_sector, DiscImage, Track, TrackType,
+    SECTOR_SIZE,
+};
+use super::codec::{Codec, FRAME_SIZE};
+use super::huffman::{BitReader, HuffmanDecoder};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const CHD_TAG: &[u8; 8] = b"MComprHD";
+const HEADER_SIZE: usize = 124;
+const CD_TRACK_METADATA_TAG: u32 = u32::from_be_bytes(*b"CHTR");
+const CD_TRACK_METADATA2_TAG: u32 = u32::from_be_bytes(*b"CHT2");
+/// Tracks are padded to a multiple of this many frames in the image
+const CD_TRACK_PADDING: u32 = 4;
+/// The number of decompressed hunks to keep around
+const HUNK_CACHE_SIZE: usize = 16;
+
+//#region Map entry compression types
+const COMPRESSION_TYPE_3: u8 = 3;
+const COMPRESSION_NONE: u8 = 4;
+const COMPRESSION_SELF: u8 = 5;
+const COMPRESSION_PARENT: u8 = 6;
+const COMPRESSION_RLE_SMALL: u8 = 7;
+const COMPRESSION_RLE_LARGE: u8 = 8;
+const COMPRESSION_SELF_0: u8 = 9;
+const COMPRESSION_SELF_1: u8 = 10;
+const COMPRESSION_PARENT_SELF: u8 = 11;
+const COMPRESSION_PARENT_0: u8 = 12;
+const COMPRESSION_PARENT_1: u8 = 13;
+//#endregion
+
+/// Where a hunk is stored, and how
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum HunkEntry {
+    /// Compressed with the given codec from the header
+    Compressed {
+        codec: u8,
+        offset: u64,
+        length: u32,
+        crc: u16,
+    },
+    /// Stored as-is, with a checksum unless the whole image is uncompressed
+    Uncompressed { offset: u64, crc: Option<u16> },
+    /// A copy of another hunk
+    SelfRef(u32),
+    /// A copy of a hunk in a parent image
+    Parent,
+    /// All zeros, which only uncompressed images use
+    Zero,
+}
+
+/// How a track's sectors are stored in its frames
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum SectorFormat {
+    Raw,
+    Mode1Data,
+    Mode2Data,
+    Mode2Form1Data,
+}
+
+impl SectorFormat {
+    fn size(&self) -> usize {
+        return match self {
+            SectorFormat::Raw => 2352,
+            SectorFormat::Mode1Data | SectorFormat::Mode2Form1Data => 2048,
+            SectorFormat::Mode2Data => 2336,
+        };
+    }
+}
+
+/// A run of consecutive sectors on the disc, and the frame they start at in
+/// the image (if they're stored at all)
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+struct Extent {
+    start: u32,
+    length: u32,
+    track_type: TrackType,
+    format: SectorFormat,
+    frame: Option<u32>,
+}
+
+pub struct ChdImage {
+    file: File,
+    hunk_bytes: u32,
+    codecs: [Option<Codec>; 4],
+    map: Vec<HunkEntry>,
+    tracks: Vec<Track>,
+    extents: Vec<Extent>,
+    /// Recently used hunks, most recent first
+    cache: VecDeque<(u32, Vec<u8>)>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+}
+
+fn read_be(bytes: &[u8]) -> u64 {
+    return bytes
+        .iter()
+        .fold(0, |value, &byte| (value << 8) | byte as u64);
+}
+
+/// The CRC-16/CCITT checksum CHD uses for hunks and maps
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if (crc & 0x8000) != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    return crc;
+}
+
+impl ChdImage {
+    pub fn open(path: &Path) -> io::Result<ChdImage> {
+        let mut file = File::open(path)?;
+        let mut header = [0u8; HEADER_SIZE];
+        file.read_exact(&mut header)?;
+        if &header[..8] != CHD_TAG {
+            return Err(invalid("Not a CHD file"));
+        }
+        let version = read_be(&header[12..16]);
+        if version != 5 {
+            return Err(invalid(&format!("Unsupported CHD version {}", version)));
+        }
+        let mut codecs = [None; 4];
+        let mut compressed = false;
+        for (i, codec) in codecs.iter_mut().enumerate() {
+            let tag = read_be(&header[16 + i * 4..20 + i * 4]) as u32;
+            if tag == 0 {
+                continue;
+            }
+            compressed = true;
+            *codec = Some(Codec::from_tag(tag).ok_or_else(|| {
+                let name = String::from_utf8_lossy(&tag.to_be_bytes()).into_owned();
+                invalid(&format!("Unsupported CHD codec {}", name))
+            })?);
+        }
+        let logical_bytes = read_be(&header[32..40]);
+        let map_offset = read_be(&header[40..48]);
+        let meta_offset = read_be(&header[48..56]);
+        let hunk_bytes = read_be(&header[56..60]) as u32;
+        let unit_bytes = read_be(&header[60..64]) as u32;
+        if unit_bytes as usize != FRAME_SIZE
+            || hunk_bytes == 0
+            || !hunk_bytes.is_multiple_of(unit_bytes)
+        {
+            return Err(invalid("Not a CD CHD"));
+        }
+        let hunk_count = logical_bytes.div_ceil(hunk_bytes as u64) as u32;
+
+        let map = if compressed {
+            read_compressed_map(&mut file, map_offset, hunk_count, hunk_bytes, unit_bytes)?
+        } else {
+            read_uncompressed_map(&mut file, map_offset, hunk_count, hunk_bytes)?
+        };
+        let metadata = read_track_metadata(&mut file, meta_offset)?;
+        let (tracks, extents) = layout(&metadata)?;
+        return Ok(ChdImage {
+            file,
+            hunk_bytes,
+            codecs,
+            map,
+            tracks,
+            extents,
+            cache: VecDeque::with_capacity(HUNK_CACHE_SIZE),
+        });
+    }
+
+    /// Return a hunk, decompressing it if it isn't in the cache
+    fn read_hunk(&mut self, hunk: u32) -> io::Result<&[u8]> {
+        match self.cache.iter().position(|(index, _)| *index == hunk) {
+            Some(pos) => {
+                let entry = self.cache.remove(pos).unwrap();
+                self.cache.push_front(entry);
+            }
+            None => {
+                let data = self.decompress_hunk(hunk, 0)?;
+                if self.cache.len() == HUNK_CACHE_SIZE {
+                    self.cache.pop_back();
+                }
+                self.cache.push_front((hunk, data));
+            }
+        }
+        return Ok(&self.cache[0].1);
+    }
+
+    fn decompress_hunk(&mut self, hunk: u32, depth: u32) -> io::Result<Vec<u8>> {
+        let entry = *self
+            .map
+            .get(hunk as usize)
+            .ok_or_else(|| invalid("Hunk is past the end of the CHD"))?;
+        let mut data = vec![0u8; self.hunk_bytes as usize];
+        let expected_crc = match entry {
+            HunkEntry::Compressed {
+                codec,
+                offset,
+                length,
+                crc,
+            } => {
+                let codec = self.codecs[codec as usize].ok_or_else(|| invalid("Missing codec"))?;
+                let mut src = vec![0u8; length as usize];
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.file.read_exact(&mut src)?;
+                codec.decompress(&src, &mut data)?;
+                Some(crc)
+            }
+            HunkEntry::Uncompressed { offset, crc } => {
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.file.read_exact(&mut data)?;
+                crc
+            }
+            HunkEntry::SelfRef(other) => {
+                // a reference chain can't be longer than the map
+                if other == hunk || depth as usize > self.map.len() {
+                    return Err(invalid("Circular hunk reference"));
+                }
+                return self.decompress_hunk(other, depth + 1);
+            }
+            HunkEntry::Parent => {
+                return Err(invalid("CHDs with parents aren't supported"));
+            }
+            HunkEntry::Zero => None,
+        };
+        if let Some(crc) = expected_crc {
+            if crc16(&data) != crc {
+                return Err(invalid(&format!("Hunk {} failed its checksum", hunk)));
+            }
+        }
+        return Ok(data);
+    }
+
+    /// Read the stored part of the frame at the given index
+    fn read_frame(&mut self, frame: u32, size: usize) -> io::Result<Vec<u8>> {
+        let frames_per_hunk = self.hunk_bytes / FRAME_SIZE as u32;
+        let offset = (frame % frames_per_hunk) as usize * FRAME_SIZE;
+        let hunk = self.read_hunk(frame / frames_per_hunk)?;
+        return Ok(hunk[offset..offset + size].to_vec());
+    }
+}
+
+impl DiscImage for ChdImage {
+    fn tracks(&self) -> &[Track] {
+        return &self.tracks;
+    }
+
+    fn read_sector_lba(&mut self, lba: u32) -> io::Result<[u8; SECTOR_SIZE]> {
+        let extent = *self
+            .extents
+            .iter()
+            .find(|extent| lba >= extent.start && lba < extent.start + extent.length)
+            .ok_or_else(|| out_of_range(lba))?;
+        let frame = match extent.frame {
+            Some(frame) => frame + (lba - extent.start),
+            None => return Ok(synthesize_sector(lba, extent.track_type, &[])),
+        };
+        let data = self.read_frame(frame, extent.format.size())?;
+        return Ok(match extent.format {
+            SectorFormat::Raw if extent.track_type == TrackType::Audio => {
+                // audio is stored with big-endian samples
+                let mut sector = [0u8; SECTOR_SIZE];
+                for (out, sample) in sector.chunks_exact_mut(2).zip(data.chunks_exact(2)) {
+                    out.copy_from_slice(&[sample[1], sample[0]]);
+                }
+                sector
+            }
+            SectorFormat::Raw => {
+                let mut sector = [0u8; SECTOR_SIZE];
+                sector.copy_from_slice(&data);
+                sector
+            }
+            SectorFormat::Mode1Data => synthesize_sector(lba, TrackType::Mode1, &data),
+            SectorFormat::Mode2Data => synthesize_sector(lba, TrackType::Mode2, &data),
+            SectorFormat::Mode2Form1Data => synthesize_form1_sector(lba, &data),
+        });
+    }
+}
+
+/// Read the map of an uncompressed image, which is just hunk numbers
+fn read_uncompressed_map(
+    file: &mut File,
+    map_offset: u64,
+    hunk_count: u32,
+    hunk_bytes: u32,
+) -> io::Result<Vec<HunkEntry>> {
+    let mut raw = vec![0u8; hunk_count as usize * 4];
+    file.seek(SeekFrom::Start(map_offset))?;
+    file.read_exact(&mut raw)?;
+    return Ok(raw
+        .chunks_exact(4)
+        .map(|entry| match read_be(entry) {
+            0 => HunkEntry::Zero,
+            block => HunkEntry::Uncompressed {
+                offset: block * hunk_bytes as u64,
+                crc: None,
+            },
+        })
+        .collect());
+}
+
+/// Read and decode the Huffman-compressed map of a compressed image
+fn read_compressed_map(
+    file: &mut File,
+    map_offset: u64,
+    hunk_count: u32,
+    hunk_bytes: u32,
+    unit_bytes: u32,
+) -> io::Result<Vec<HunkEntry>> {
+    let mut header = [0u8; 16];
+    file.seek(SeekFrom::Start(map_offset))?;
+    file.read_exact(&mut header)?;
+    let map_bytes = read_be(&header[0..4]) as usize;
+    let mut offset = read_be(&header[4..10]);
+    let map_crc = read_be(&header[10..12]) as u16;
+    let (length_bits, self_bits, parent_bits) =
+        (header[12] as u32, header[13] as u32, header[14] as u32);
+    let mut compressed = vec![0u8; map_bytes];
+    file.read_exact(&mut compressed)?;
+    let mut reader = BitReader::new(&compressed);
+
+    // first come the compression types, Huffman coded with runs
+    let decoder = HuffmanDecoder::import_tree_rle(&mut reader, 16, 8)
+        .ok_or_else(|| invalid("Bad CHD map"))?;
+    let mut types = Vec::with_capacity(hunk_count as usize);
+    let mut last_type = 0;
+    while types.len() < hunk_count as usize {
+        let count = match decoder.decode_one(&mut reader) as u8 {
+            // runs repeat the last type for the token's own hunk, and then
+            // at least 2 more
+            COMPRESSION_RLE_SMALL => 3 + decoder.decode_one(&mut reader),
+            COMPRESSION_RLE_LARGE => {
+                let high = decoder.decode_one(&mut reader) << 4;
+                3 + 16 + high + decoder.decode_one(&mut reader)
+            }
+            compression => {
+                last_type = compression;
+                1
+            }
+        };
+        for _ in 0..count {
+            types.push(last_type);
+        }
+    }
+    types.truncate(hunk_count as usize);
+
+    // then the details of each hunk, with references relative to the last
+    let mut map = Vec::with_capacity(hunk_count as usize);
+    let mut raw = Vec::with_capacity(hunk_count as usize * 12);
+    let (mut last_self, mut last_parent) = (0u64, 0u64);
+    for (hunk, &compression) in types.iter().enumerate() {
+        let (entry, stored_type, length, entry_offset, crc) = match compression {
+            0..=COMPRESSION_TYPE_3 => {
+                let length = reader.read(length_bits);
+                let crc = reader.read(16) as u16;
+                let entry = HunkEntry::Compressed {
+                    codec: compression,
+                    offset,
+                    length,
+                    crc,
+                };
+                offset += length as u64;
+                (entry, compression, length, offset - length as u64, crc)
+            }
+            COMPRESSION_NONE => {
+                let crc = reader.read(16) as u16;
+                let entry = HunkEntry::Uncompressed {
+                    offset,
+                    crc: Some(crc),
+                };
+                offset += hunk_bytes as u64;
+                (
+                    entry,
+                    compression,
+                    hunk_bytes,
+                    offset - hunk_bytes as u64,
+                    crc,
+                )
+            }
+            COMPRESSION_SELF | COMPRESSION_SELF_0 | COMPRESSION_SELF_1 => {
+                last_self = match compression {
+                    COMPRESSION_SELF => reader.read(self_bits) as u64,
+                    COMPRESSION_SELF_1 => last_self + 1,
+                    _ => last_self,
+                };
+                let entry = HunkEntry::SelfRef(last_self as u32);
+                (entry, COMPRESSION_SELF, 0, last_self, 0)
+            }
+            COMPRESSION_PARENT
+            | COMPRESSION_PARENT_SELF
+            | COMPRESSION_PARENT_0
+            | COMPRESSION_PARENT_1 => {
+                last_parent = match compression {
+                    COMPRESSION_PARENT => reader.read(parent_bits) as u64,
+                    COMPRESSION_PARENT_SELF => {
+                        (hunk as u64 * hunk_bytes as u64) / unit_bytes as u64
+                    }
+                    COMPRESSION_PARENT_1 => last_parent + (hunk_bytes / unit_bytes) as u64,
+                    _ => last_parent,
+                };
+                (HunkEntry::Parent, COMPRESSION_PARENT, 0, last_parent, 0)
+            }
+            _ => return Err(invalid("Bad CHD map")),
+        };
+        map.push(entry);
+        // the checksum covers the map as it would be stored uncompressed
+        raw.push(stored_type);
+        raw.extend_from_slice(&length.to_be_bytes()[1..]);
+        raw.extend_from_slice(&entry_offset.to_be_bytes()[2..]);
+        raw.extend_from_slice(&crc.to_be_bytes());
+    }
+    if reader.is_overflowed() || crc16(&raw) != map_crc {
+        return Err(invalid("CHD map failed its checksum"));
+    }
+    return Ok(map);
+}
+
+/// A track as described by the image's metadata
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct TrackMetadata {
+    number: u8,
+    track_type: TrackType,
+    format: SectorFormat,
+    /// Frames stored in the image, including any stored pregap
+    frames: u32,
+    pregap: u32,
+    /// Whether the pregap is stored in the image, or is silence
+    is_pregap_stored: bool,
+    postgap: u32,
+}
+
+/// Walk the metadata list, collecting the CD track descriptions
+fn read_track_metadata(file: &mut File, mut offset: u64) -> io::Result<Vec<TrackMetadata>> {
+    let mut tracks = vec![];
+    while offset != 0 {
+        let mut header = [0u8; 16];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header)?;
+        let tag = read_be(&header[0..4]) as u32;
+        let length = read_be(&header[5..8]) as usize;
+        offset = read_be(&header[8..16]);
+        if tag != CD_TRACK_METADATA_TAG && tag != CD_TRACK_METADATA2_TAG {
+            continue;
+        }
+        let mut data = vec![0u8; length];
+        file.read_exact(&mut data)?;
+        let text = String::from_utf8_lossy(&data);
+        tracks.push(parse_track_metadata(text.trim_end_matches('\0'))?);
+    }
+    if tracks.is_empty() {
+        return Err(invalid("CHD has no CD track metadata"));
+    }
+    tracks.sort_by_key(|track| track.number);
+    return Ok(tracks);
+}
+
+/// Parse a track description, such as
+/// `TRACK:1 TYPE:MODE2_RAW SUBTYPE:NONE FRAMES:1234 PREGAP:0 PGTYPE:MODE2_RAW
+/// PGSUB:NONE POSTGAP:0`
+fn parse_track_metadata(text: &str) -> io::Result<TrackMetadata> {
+    let bad = || invalid(&format!("Bad CD track metadata: {}", text));
+    let field = |name: &str| {
+        text.split_whitespace()
+            .filter_map(|pair| pair.split_once(':'))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    };
+    let number_field = |name: &str| -> io::Result<u32> {
+        return match field(name) {
+            Some(value) => value.parse().map_err(|_| bad()),
+            // older metadata has no gaps
+            None => Ok(0),
+        };
+    };
+    let (track_type, format) = match field("TYPE").ok_or_else(bad)? {
+        "AUDIO" => (TrackType::Audio, SectorFormat::Raw),
+        "MODE1" | "MODE1/2048" => (TrackType::Mode1, SectorFormat::Mode1Data),
+        "MODE1_RAW" | "MODE1/2352" => (TrackType::Mode1, SectorFormat::Raw),
+        "MODE2" | "MODE2/2336" | "MODE2_FORM_MIX" => (TrackType::Mode2, SectorFormat::Mode2Data),
+        "MODE2_FORM1" | "MODE2/2048" => (TrackType::Mode2, SectorFormat::Mode2Form1Data),
+        "MODE2_RAW" | "MODE2/2352" => (TrackType::Mode2, SectorFormat::Raw),
+        other => {
+            return Err(invalid(&format!("Unsupported CD track type {}", other)));
+        }
+    };
+    return Ok(TrackMetadata {
+        number: number_field("TRACK")? as u8,
+        track_type,
+        format,
+        frames: number_field("FRAMES")?,
+        pregap: number_field("PREGAP")?,
+        // a V prefix means the pregap's data is in the image
+        is_pregap_stored: field("PGTYPE").is_some_and(|pgtype| pgtype.starts_with('V')),
+        postgap: number_field("POSTGAP")?,
+    });
+}
+
+/// Work out where each track lies on the disc, and where its frames are
+fn layout(metadata: &[TrackMetadata]) -> io::Result<(Vec<Track>, Vec<Extent>)> {
+    let mut tracks = vec![];
+    let mut extents = vec![];
+    let mut lba = 0;
+    let mut frame = 0;
+    for track in metadata {
+        let silence = |start, length| Extent {
+            start,
+            length,
+            track_type: track.track_type,
+            format: track.format,
+            frame: None,
+        };
+        let pregap_start = lba;
+        let stored_pregap = if track.is_pregap_stored {
+            track.pregap
+        } else {
+            extents.push(silence(lba, track.pregap));
+            lba += track.pregap;
+            0
+        };
+        if track.frames < stored_pregap {
+            return Err(invalid("CD track is shorter than its pregap"));
+        }
+        extents.push(Extent {
+            start: lba,
+            length: track.frames,
+            track_type: track.track_type,
+            format: track.format,
+            frame: Some(frame),
+        });
+        let start = lba + stored_pregap;
+        lba += track.frames;
+        extents.push(silence(lba, track.postgap));
+        lba += track.postgap;
+        frame += track.frames.div_ceil(CD_TRACK_PADDING) * CD_TRACK_PADDING;
+        tracks.push(Track {
+            number: track.number,
+            track_type: track.track_type,
+            pregap_start,
+            start,
+            length: lba - start,
+        });
+    }
+    extents.retain(|extent| extent.length > 0);
+    return Ok((tracks, extents));
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::codec::generate_ecc;
+    use super::*;
+    use crate::devices::cdrom::disc::CueImage;
+    use flate2::write::DeflateEncoder;
+    use std::io::Write;
+
+    const HUNK_FRAMES: usize = 8;
+    const HUNK_BYTES: usize = HUNK_FRAMES * FRAME_SIZE;
+    const LENGTH_BITS: u32 = 20;
+    const SELF_BITS: u32 = 16;
+
+    /// Writes bits most significant first
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn write(&mut self, value: u32, bits: u32) {
+            for bit in (0..bits).rev() {
+                if self.bits.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                let byte = self.bytes.last_mut().unwrap();
+                *byte |= (((value >> bit) & 1) as u8) << (7 - (self.bits % 8));
+                self.bits += 1;
+            }
+        }
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn lzma(data: &[u8]) -> Vec<u8> {
+        let options = lzma_rs::compress::Options {
+            unpacked_size: lzma_rs::compress::UnpackedSize::SkipWritingToHeader,
+        };
+        let mut out = vec![];
+        lzma_rs::lzma_compress_with_options(&mut &data[..], &mut out, &options).unwrap();
+        // CHD streams have no properties header
+        out.split_off(5)
+    }
+
+    fn flac_crc(data: &[u8], poly: u16, width: u32) -> u16 {
+        let top = 1u16 << (width - 1);
+        let mask = if width == 16 {
+            0xFFFF
+        } else {
+            (1 << width) - 1
+        };
+        let mut crc = 0u16;
+        for &byte in data {
+            crc ^= (byte as u16) << (width - 8);
+            for _ in 0..8 {
+                crc = if (crc & top) != 0 {
+                    (crc << 1) ^ poly
+                } else {
+                    crc << 1
+                } & mask;
+            }
+        }
+        crc
+    }
+
+    /// Encode stereo samples as FLAC frames with verbatim subframes
+    fn flac(data: &[u8]) -> Vec<u8> {
+        let samples: Vec<i16> = data
+            .chunks_exact(2)
+            .map(|s| i16::from_be_bytes([s[0], s[1]]))
+            .collect();
+        let mut out = vec![];
+        for (number, block) in samples.chunks(2352 * 2).enumerate() {
+            let start = out.len();
+            // fixed blocking, 16-bit block size, 44.1kHz, independent stereo,
+            // 16 bits per sample
+            out.extend_from_slice(&[0xFF, 0xF8, 0x79, 0x18, number as u8]);
+            out.extend_from_slice(&((block.len() / 2 - 1) as u16).to_be_bytes());
+            out.push(flac_crc(&out[start..], 0x07, 8) as u8);
+            for channel in 0..2 {
+                out.push(0x02);
+                for sample in block.iter().skip(channel).step_by(2) {
+                    out.extend_from_slice(&sample.to_be_bytes());
+                }
+            }
+            let crc = flac_crc(&out[start..], 0x8005, 16);
+            out.extend_from_slice(&crc.to_be_bytes());
+        }
+        out
+    }
+
+    /// Compress a hunk with one of the CD codecs, stripping the ECC of any
+    /// data sectors where it can be rebuilt
+    fn compress_hunk(codec: Codec, hunk: &[u8]) -> Vec<u8> {
+        let mut ecc_flags = [0u8; 1];
+        let mut sectors = vec![];
+        let mut subcode = vec![];
+        for (i, frame) in hunk.chunks_exact(FRAME_SIZE).enumerate() {
+            let mut sector = frame[..2352].to_vec();
+            let mut rebuilt = sector.clone();
+            generate_ecc(&mut rebuilt);
+            if codec != Codec::Flac
+                && sector[..12] == super::super::super::SECTOR_SYNC
+                && rebuilt == sector
+            {
+                ecc_flags[0] |= 1 << i;
+                sector[..12].fill(0);
+                sector[0x81C..].fill(0);
+            }
+            sectors.extend_from_slice(&sector);
+            subcode.extend_from_slice(&frame[2352..]);
+        }
+        let mut out = vec![];
+        match codec {
+            Codec::Flac => out.extend(flac(&sectors)),
+            _ => {
+                let base = match codec {
+                    Codec::Lzma => lzma(&sectors),
+                    _ => deflate(&sectors),
+                };
+                out.extend_from_slice(&ecc_flags);
+                out.extend_from_slice(&(base.len() as u16).to_be_bytes());
+                out.extend(base);
+            }
+        }
+        out.extend(deflate(&subcode));
+        out
+    }
+
+    /// Write the compression type of each hunk into the map, with runs of the
+    /// same type shortened to RLE tokens
+    fn write_compression_types(map: &mut BitWriter, types: &[u8]) {
+        let mut index = 0;
+        while index < types.len() {
+            let compression = types[index];
+            let run = types[index..]
+                .iter()
+                .take_while(|&&other| other == compression)
+                .count();
+            index += run;
+            map.write(compression as u32, 4);
+            let mut repeats = run - 1;
+            while repeats >= 3 {
+                if repeats >= 19 {
+                    let extra = (repeats - 19).min(0xFF);
+                    map.write(COMPRESSION_RLE_LARGE as u32, 4);
+                    map.write(extra as u32 >> 4, 4);
+                    map.write(extra as u32 & 0xF, 4);
+                    repeats -= 19 + extra;
+                } else {
+                    map.write(COMPRESSION_RLE_SMALL as u32, 4);
+                    map.write(repeats as u32 - 3, 4);
+                    repeats = 0;
+                }
+            }
+            for _ in 0..repeats {
+                map.write(compression as u32, 4);
+            }
+        }
+    }
+
+    /// Write a disc out as a CHD, the way chdman would
+    fn write_chd(path: &Path, disc: &mut dyn DiscImage) {
+        let tracks = disc.tracks().to_vec();
+        let mut frames: Vec<u8> = vec![];
+        let mut frame_types = vec![];
+        let mut metadata = vec![];
+        for track in &tracks {
+            let type_name = match track.track_type {
+                TrackType::Audio => "AUDIO",
+                TrackType::Mode1 => "MODE1_RAW",
+                TrackType::Mode2 => "MODE2_RAW",
+            };
+            let count = track.end() - track.pregap_start;
+            metadata.push(format!(
+                "TRACK:{} TYPE:{} SUBTYPE:NONE FRAMES:{} PREGAP:{} PGTYPE:V{} PGSUB:NONE \
+                 POSTGAP:0",
+                track.number,
+                type_name,
+                count,
+                track.start - track.pregap_start,
+                type_name
+            ));
+            for lba in track.pregap_start..track.end() {
+                let mut sector = disc.read_sector_lba(lba).unwrap();
+                if track.track_type == TrackType::Audio {
+                    for sample in sector.chunks_exact_mut(2) {
+                        sample.swap(0, 1);
+                    }
+                }
+                frames.extend_from_slice(&sector);
+                frames.extend_from_slice(&[0u8; 96]);
+            }
+            let padding = count.next_multiple_of(CD_TRACK_PADDING) - count;
+            frames.resize(frames.len() + padding as usize * FRAME_SIZE, 0);
+            frame_types.resize(frames.len() / FRAME_SIZE, track.track_type);
+        }
+        frames.resize(frames.len().next_multiple_of(HUNK_BYTES), 0);
+        frame_types.resize(frames.len() / FRAME_SIZE, TrackType::Audio);
+
+        let mut out = vec![0u8; HEADER_SIZE];
+        let meta_offset = out.len() as u64;
+        for (i, text) in metadata.iter().enumerate() {
+            let next = if i + 1 == metadata.len() {
+                0
+            } else {
+                out.len() as u64 + 16 + text.len() as u64 + 1
+            };
+            out.extend_from_slice(&CD_TRACK_METADATA2_TAG.to_be_bytes());
+            out.push(0x01);
+            out.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes()[1..]);
+            out.extend_from_slice(&next.to_be_bytes());
+            out.extend_from_slice(text.as_bytes());
+            out.push(0);
+        }
+
+        let first_offset = out.len() as u64;
+        let mut offset = first_offset;
+        let mut map = BitWriter::default();
+        // every compression type gets a 4-bit code equal to its value
+        for _ in 0..16 {
+            map.write(4, 4);
+        }
+        let mut entries = BitWriter::default();
+        let mut types = vec![];
+        let mut raw_map = vec![];
+        let hunks: Vec<&[u8]> = frames.chunks_exact(HUNK_BYTES).collect();
+        for (index, hunk) in hunks.iter().enumerate() {
+            let crc = crc16(hunk);
+            let (compression, length, entry_offset, crc) =
+                if let Some(original) = hunks[..index].iter().position(|h| h == hunk) {
+                    entries.write(original as u32, SELF_BITS);
+                    (COMPRESSION_SELF, 0, original as u64, 0)
+                } else {
+                    let data = match index % 3 {
+                        _ if frame_types[index * HUNK_FRAMES] == TrackType::Audio => {
+                            Some((2, compress_hunk(Codec::Flac, hunk)))
+                        }
+                        0 => Some((0, compress_hunk(Codec::Lzma, hunk))),
+                        1 => Some((1, compress_hunk(Codec::Deflate, hunk))),
+                        _ => None,
+                    };
+                    let (compression, data) = match data {
+                        Some((codec, data)) => (codec, data),
+                        None => (COMPRESSION_NONE, hunk.to_vec()),
+                    };
+                    if compression != COMPRESSION_NONE {
+                        entries.write(data.len() as u32, LENGTH_BITS);
+                    }
+                    entries.write(crc as u32, 16);
+                    let entry_offset = offset;
+                    offset += data.len() as u64;
+                    out.extend(data);
+                    (compression, offset - entry_offset, entry_offset, crc)
+                };
+            types.push(compression);
+            raw_map.push(compression);
+            raw_map.extend_from_slice(&(length as u32).to_be_bytes()[1..]);
+            raw_map.extend_from_slice(&entry_offset.to_be_bytes()[2..]);
+            raw_map.extend_from_slice(&crc.to_be_bytes());
+        }
+        write_compression_types(&mut map, &types);
+        for (i, &byte) in entries.bytes.iter().enumerate() {
+            let bits = (entries.bits - i * 8).min(8) as u32;
+            map.write(byte as u32 >> (8 - bits), bits);
+        }
+
+        let map_offset = out.len() as u64;
+        out.extend_from_slice(&(map.bytes.len() as u32).to_be_bytes());
+        out.extend_from_slice(&first_offset.to_be_bytes()[2..]);
+        out.extend_from_slice(&crc16(&raw_map).to_be_bytes());
+        out.extend_from_slice(&[LENGTH_BITS as u8, SELF_BITS as u8, 0, 0]);
+        out.extend(map.bytes);
+
+        let header = &mut out[..HEADER_SIZE];
+        header[..8].copy_from_slice(CHD_TAG);
+        header[8..12].copy_from_slice(&(HEADER_SIZE as u32).to_be_bytes());
+        header[12..16].copy_from_slice(&5u32.to_be_bytes());
+        header[16..28].copy_from_slice(b"cdlzcdzlcdfl");
+        header[32..40].copy_from_slice(&(frames.len() as u64).to_be_bytes());
+        header[40..48].copy_from_slice(&map_offset.to_be_bytes());
+        header[48..56].copy_from_slice(&meta_offset.to_be_bytes());
+        header[56..60].copy_from_slice(&(HUNK_BYTES as u32).to_be_bytes());
+        header[60..64].copy_from_slice(&(FRAME_SIZE as u32).to_be_bytes());
+        std::fs::write(path, out).unwrap();
+    }
+
+    /// Write a BIN/CUE with a data track, and audio tracks with a stored and a
+    /// silent pregap
+    fn write_bin_cue(dir: &Path) {
+        let mut bin = vec![];
+        for lba in 0..30u32 {
+            let mut sector = [0u8; 2352];
+            sector[..12].copy_from_slice(&super::super::super::SECTOR_SYNC);
+            let msf = crate::devices::cdrom::Msf::from_lba(lba).to_bcd();
+            sector[12..15].copy_from_slice(&msf);
+            sector[15] = 2;
+            for (i, byte) in sector.iter_mut().enumerate().skip(16).take(2060) {
+                *byte = (i as u32 * 13 + lba * 7) as u8;
+            }
+            // the last sector's ECC is left bad, so it can't be stripped
+            if lba != 29 {
+                generate_ecc(&mut sector);
+            }
+            bin.extend_from_slice(&sector);
+        }
+        for i in 0..(40 * 2352u32) {
+            bin.push((i * 31 / 7) as u8);
+        }
+        // long stretches of silence make runs of self-referencing hunks, which
+        // the map stores as RLE tokens, split up by some noise
+        let mut noise = 1u32;
+        for sector in 0..280 {
+            for _ in 0..2352 {
+                noise = noise.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let is_noise = (200..216).contains(&sector);
+                bin.push(if is_noise { (noise >> 16) as u8 } else { 0 });
+            }
+        }
+        std::fs::write(dir.join("disc.bin"), &bin).unwrap();
+        std::fs::write(
+            dir.join("disc.cue"),
+            "FILE \"disc.bin\" BINARY\n  TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\n  \
+             TRACK 02 AUDIO\n    INDEX 00 00:00:30\n    INDEX 01 00:00:33\n  \
+             TRACK 03 AUDIO\n    PREGAP 00:00:05\n    INDEX 01 00:00:50\n",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn reads_generated_chd() {
+        let dir = std::env::temp_dir().join(format!("psx-chd-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write_bin_cue(&dir);
+        let mut cue = CueImage::open(&dir.join("disc.cue")).unwrap();
+        write_chd(&dir.join("disc.chd"), &mut cue);
+        let chd = ChdImage::open(&dir.join("disc.chd"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut chd = chd.unwrap();
+
+        assert_eq!(chd.tracks(), cue.tracks());
+        let codecs = chd.map.iter().map(|entry| match entry {
+            HunkEntry::Compressed { codec, .. } => *codec,
+            HunkEntry::Uncompressed { .. } => COMPRESSION_NONE,
+            _ => COMPRESSION_SELF,
+        });
+        assert!(codecs.clone().any(|c| c == 0), "No cdlz hunks");
+        assert!(codecs.clone().any(|c| c == 1), "No cdzl hunks");
+        assert!(codecs.clone().any(|c| c == 2), "No cdfl hunks");
+        assert!(codecs.clone().any(|c| c == COMPRESSION_NONE));
+        let codecs: Vec<u8> = codecs.collect();
+        let runs: Vec<usize> = codecs
+            .chunk_by(|a, b| a == b)
+            .filter(|run| run[0] == COMPRESSION_SELF)
+            .map(|run| run.len())
+            .collect();
+        assert!(
+            runs.iter().any(|&run| run >= 20),
+            "No long runs: {:?}",
+            runs
+        );
+        assert!(
+            runs.iter().any(|&run| (4..20).contains(&run)),
+            "No short runs: {:?}",
+            runs
+        );
+        for lba in 0..chd.lead_out().to_lba().unwrap() {
+            let expected = cue.read_sector_lba(lba).unwrap();
+            assert!(
+                chd.read_sector_lba(lba).unwrap()[..] == expected[..],
+                "LBA {}",
+                lba
+            );
+        }
+    }
+
+    #[test]
+    fn parses_track_metadata() {
+        let track = parse_track_metadata(
+            "TRACK:2 TYPE:AUDIO SUBTYPE:NONE FRAMES:1000 PREGAP:150 PGTYPE:VAUDIO PGSUB:NONE \
+             POSTGAP:0",
+        )
+        .unwrap();
+        assert_eq!(track.number, 2);
+        assert_eq!(track.track_type, TrackType::Audio);
+        assert_eq!((track.frames, track.pregap), (1000, 150));
+        assert!(track.is_pregap_stored);
+    }
+}
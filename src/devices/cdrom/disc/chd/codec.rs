@@ -0,0 +1,254 @@
+//! The CD-specific CHD codecs
+//!
+//! Each CD codec compresses the 2352-byte sector data of every frame in a hunk
+//! with one algorithm (LZMA, DEFLATE or FLAC), and the 96-byte subcode data
+//! with DEFLATE. Data sectors whose error correction codes could be rebuilt
+//! have their sync pattern and ECC stripped, with a bit per frame recording
+//! which ones need rebuilding.
+
+use super::super::SECTOR_SYNC;
+use flate2::read::DeflateDecoder;
+use std::io::{self, Cursor, Read};
+
+/// A frame of a CD hunk: one raw sector followed by its subcode
+pub const FRAME_SIZE: usize = 2448;
+const SECTOR_DATA_SIZE: usize = 2352;
+const SUBCODE_SIZE: usize = 96;
+
+/// A codec a CHD's hunks can be compressed with
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Codec {
+    /// LZMA sector data (cdlz)
+    Lzma,
+    /// DEFLATE sector data (cdzl)
+    Deflate,
+    /// FLAC sector data, as 16-bit stereo samples (cdfl)
+    Flac,
+}
+
+impl Codec {
+    /// Find the codec for a tag from the CHD header
+    pub fn from_tag(tag: u32) -> Option<Codec> {
+        return match &tag.to_be_bytes() {
+            b"cdlz" => Some(Codec::Lzma),
+            b"cdzl" => Some(Codec::Deflate),
+            b"cdfl" => Some(Codec::Flac),
+            _ => None,
+        };
+    }
+
+    /// Decompress a whole hunk, which must be a multiple of the frame size
+    pub fn decompress(&self, src: &[u8], dest: &mut [u8]) -> io::Result<()> {
+        let frames = dest.len() / FRAME_SIZE;
+        let mut sectors = vec![0u8; frames * SECTOR_DATA_SIZE];
+        let mut subcode = vec![0u8; frames * SUBCODE_SIZE];
+        let ecc_flags = match self {
+            Codec::Lzma | Codec::Deflate => {
+                let length_bytes = if dest.len() < 65536 { 2 } else { 3 };
+                let ecc_bytes = frames.div_ceil(8);
+                let header_bytes = ecc_bytes + length_bytes;
+                let header = src.get(..header_bytes).ok_or_else(truncated)?;
+                let base_length = header[ecc_bytes..]
+                    .iter()
+                    .fold(0usize, |length, &byte| (length << 8) | byte as usize);
+                let base = src
+                    .get(header_bytes..header_bytes + base_length)
+                    .ok_or_else(truncated)?;
+                if *self == Codec::Lzma {
+                    decompress_lzma(base, &mut sectors)?;
+                } else {
+                    inflate(base, &mut sectors)?;
+                }
+                inflate(&src[header_bytes + base_length..], &mut subcode)?;
+                &header[..ecc_bytes]
+            }
+            Codec::Flac => {
+                let used = decompress_flac(src, &mut sectors)?;
+                inflate(&src[used..], &mut subcode)?;
+                &[]
+            }
+        };
+
+        for (frame_idx, frame) in dest.chunks_exact_mut(FRAME_SIZE).enumerate() {
+            let sector = &mut frame[..SECTOR_DATA_SIZE];
+            sector.copy_from_slice(&sectors[frame_idx * SECTOR_DATA_SIZE..][..SECTOR_DATA_SIZE]);
+            frame[SECTOR_DATA_SIZE..]
+                .copy_from_slice(&subcode[frame_idx * SUBCODE_SIZE..][..SUBCODE_SIZE]);
+            let needs_ecc = ecc_flags
+                .get(frame_idx / 8)
+                .map(|flags| (flags & (1 << (frame_idx % 8))) != 0)
+                .unwrap_or(false);
+            if needs_ecc {
+                frame[..12].copy_from_slice(&SECTOR_SYNC);
+                generate_ecc(&mut frame[..SECTOR_DATA_SIZE]);
+            }
+        }
+        return Ok(());
+    }
+}
+
+fn truncated() -> io::Error {
+    return io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated CHD hunk");
+}
+
+fn invalid(msg: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg);
+}
+
+/// Inflate raw DEFLATE data (with no zlib header) to fill `dest`
+fn inflate(src: &[u8], dest: &mut [u8]) -> io::Result<()> {
+    return DeflateDecoder::new(src).read_exact(dest);
+}
+
+/// Decompress a raw LZMA stream, which has no header of its own
+fn decompress_lzma(src: &[u8], dest: &mut [u8]) -> io::Result<()> {
+    // CHD always uses the default literal/position bits, and a dictionary no
+    // bigger than a hunk
+    const PROPERTIES: u8 = 0x5D;
+    let dict_size = (dest.len() as u32).max(4096);
+    let mut stream = Vec::with_capacity(5 + src.len());
+    stream.push(PROPERTIES);
+    stream.extend_from_slice(&dict_size.to_le_bytes());
+    stream.extend_from_slice(src);
+
+    let options = lzma_rs::decompress::Options {
+        unpacked_size: lzma_rs::decompress::UnpackedSize::UseProvided(Some(dest.len() as u64)),
+        ..Default::default()
+    };
+    let mut output = Vec::with_capacity(dest.len());
+    lzma_rs::lzma_decompress_with_options(&mut &stream[..], &mut output, &options)
+        .map_err(|err| invalid(format!("Bad LZMA data: {:?}", err)))?;
+    if output.len() != dest.len() {
+        return Err(truncated());
+    }
+    dest.copy_from_slice(&output);
+    return Ok(());
+}
+
+/// Decode FLAC frames of 16-bit stereo samples, stored big-endian, returning
+/// the number of bytes of `src` used
+fn decompress_flac(src: &[u8], dest: &mut [u8]) -> io::Result<usize> {
+    let mut cursor = Cursor::new(src);
+    let mut written = 0;
+    let mut buffer = vec![];
+    while written < dest.len() {
+        let mut frames = claxon::frame::FrameReader::new(&mut cursor);
+        let block = frames
+            .read_next_or_eof(buffer)
+            .map_err(|err| invalid(format!("Bad FLAC data: {}", err)))?
+            .ok_or_else(truncated)?;
+        if block.channels() != 2 {
+            return Err(invalid("FLAC data isn't stereo".to_string()));
+        }
+        for (left, right) in block.stereo_samples() {
+            let out = dest.get_mut(written..written + 4).ok_or_else(truncated)?;
+            out[..2].copy_from_slice(&(left as i16).to_be_bytes());
+            out[2..].copy_from_slice(&(right as i16).to_be_bytes());
+            written += 4;
+        }
+        buffer = block.into_buffer();
+    }
+    return Ok(cursor.position() as usize);
+}
+
+//#region Error correction
+// Data sectors end in Reed-Solomon product codes: 172 bytes of "P" parity
+// computed down the columns of the header and data, then 104 bytes of "Q"
+// parity computed along its diagonals.
+
+const ECC_P_OFFSET: usize = 0x81C;
+const ECC_Q_OFFSET: usize = 0x8C8;
+
+/// Multiplication by 2 in the field the codes are computed over
+const fn ecc_low(value: u8) -> u8 {
+    let doubled = (value as u16) << 1;
+    return if (value & 0x80) != 0 {
+        (doubled ^ 0x11D) as u8
+    } else {
+        doubled as u8
+    };
+}
+
+/// Division by 3 in the same field
+const ECC_HIGH: [u8; 256] = {
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[(ecc_low(i as u8) ^ i as u8) as usize] = i as u8;
+        i += 1;
+    }
+    table
+};
+
+/// Compute a pair of parity bytes over the given offsets from the header
+fn ecc_compute_bytes(sector: &[u8], offsets: impl Iterator<Item = usize>) -> (u8, u8) {
+    // Mode 2 sectors compute their codes as if the header were zero, so
+    // they stay valid when copied elsewhere on the disc
+    let is_mode2 = sector[15] == 2;
+    let (mut first, mut second) = (0u8, 0u8);
+    for offset in offsets {
+        let byte = if is_mode2 && offset < 4 {
+            0
+        } else {
+            sector[12 + offset]
+        };
+        first = ecc_low(first ^ byte);
+        second ^= byte;
+    }
+    first = ECC_HIGH[(ecc_low(first) ^ second) as usize];
+    return (first, second ^ first);
+}
+
+/// Rebuild the error correction codes of a raw data sector
+pub fn generate_ecc(sector: &mut [u8]) {
+    for byte in 0..86 {
+        let (first, second) = ecc_compute_bytes(sector, (0..24).map(|i| byte + 86 * i));
+        sector[ECC_P_OFFSET + byte] = first;
+        sector[ECC_P_OFFSET + 86 + byte] = second;
+    }
+    for byte in 0..52 {
+        let offsets = (0..43).map(|i| 2 * ((43 * (byte / 2) + 44 * i) % 1118) + (byte & 1));
+        let (first, second) = ecc_compute_bytes(sector, offsets);
+        sector[ECC_Q_OFFSET + byte] = first;
+        sector[ECC_Q_OFFSET + 52 + byte] = second;
+    }
+}
+//#endregion
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Multiply in the field the codes are computed over
+    fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+        let mut product = 0;
+        while b != 0 {
+            if (b & 1) != 0 {
+                product ^= a;
+            }
+            a = ecc_low(a);
+            b >>= 1;
+        }
+        product
+    }
+
+    #[test]
+    fn generates_valid_parity() {
+        let mut sector = [0u8; SECTOR_DATA_SIZE];
+        for (i, byte) in sector.iter_mut().enumerate().skip(12) {
+            *byte = (i * 7 + i / 5) as u8;
+        }
+        sector[15] = 1;
+        generate_ecc(&mut sector);
+        // each P codeword (24 data bytes then its 2 parity bytes) should have
+        // zero syndromes for the generator's roots, 1 and 2
+        for column in 0..86 {
+            let mut codeword: Vec<u8> = (0..24).map(|i| sector[12 + column + 86 * i]).collect();
+            codeword.push(sector[ECC_P_OFFSET + column]);
+            codeword.push(sector[ECC_P_OFFSET + 86 + column]);
+            let s0 = codeword.iter().fold(0, |acc, &b| acc ^ b);
+            let s1 = codeword.iter().fold(0, |acc, &b| gf_mul(acc, 2) ^ b);
+            assert_eq!((s0, s1), (0, 0), "P column {}", column);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! The bit reader and canonical Huffman decoder used for CHD hunk maps
+
+/// Reads big-endian bit fields from a byte slice
+///
+/// Reading past the end of the data returns zero bits, and marks the reader
+/// as overflowed.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// The position of the next bit to read
+    offset: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, offset: 0 }
+    }
+
+    /// Return the next bits without consuming them
+    pub fn peek(&self, bits: u32) -> u32 {
+        let mut value = 0;
+        for i in 0..bits as usize {
+            let offset = self.offset + i;
+            let bit = match self.data.get(offset / 8) {
+                Some(byte) => (byte >> (7 - (offset % 8))) & 1,
+                None => 0,
+            };
+            value = (value << 1) | bit as u32;
+        }
+        return value;
+    }
+
+    pub fn read(&mut self, bits: u32) -> u32 {
+        let value = self.peek(bits);
+        self.offset += bits as usize;
+        return value;
+    }
+
+    /// Whether more bits have been read than there were in the data
+    pub fn is_overflowed(&self) -> bool {
+        return self.offset > self.data.len() * 8;
+    }
+}
+
+/// A canonical Huffman decoder, as used by CHD
+///
+/// Codes are assigned longest-first, so the longest codes get the lowest
+/// values. This is backwards from DEFLATE.
+pub struct HuffmanDecoder {
+    max_bits: u32,
+    /// The symbol and code length for each possible max_bits-wide prefix
+    lookup: Vec<(u16, u8)>,
+}
+
+impl HuffmanDecoder {
+    /// Read a tree whose code lengths are run-length encoded, returning None if
+    /// the tree isn't valid
+    pub fn import_tree_rle(
+        reader: &mut BitReader,
+        num_codes: usize,
+        max_bits: u32,
+    ) -> Option<HuffmanDecoder> {
+        let length_bits = if max_bits >= 16 {
+            5
+        } else if max_bits >= 8 {
+            4
+        } else {
+            3
+        };
+        let mut lengths = Vec::with_capacity(num_codes);
+        while lengths.len() < num_codes {
+            let length = reader.read(length_bits);
+            if length != 1 {
+                lengths.push(length);
+                continue;
+            }
+            // a 1 escapes either a literal 1, or a run of some other length
+            let length = reader.read(length_bits);
+            if length == 1 {
+                lengths.push(length);
+            } else {
+                let count = reader.read(length_bits) as usize + 3;
+                if lengths.len() + count > num_codes {
+                    return None;
+                }
+                lengths.extend(std::iter::repeat_n(length, count));
+            }
+        }
+        return HuffmanDecoder::from_lengths(&lengths, max_bits);
+    }
+
+    fn from_lengths(lengths: &[u32], max_bits: u32) -> Option<HuffmanDecoder> {
+        let mut histogram = [0u32; 33];
+        for &length in lengths {
+            if length > max_bits {
+                return None;
+            }
+            histogram[length as usize] += 1;
+        }
+        // work out the first code of each length, from the longest down
+        let mut start = 0;
+        for length in (1..=32).rev() {
+            let next_start = (start + histogram[length]) >> 1;
+            if length != 1 && next_start * 2 != start + histogram[length] {
+                return None;
+            }
+            histogram[length] = start;
+            start = next_start;
+        }
+
+        let mut lookup = vec![(0u16, 0u8); 1 << max_bits];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length == 0 {
+                continue;
+            }
+            let code = histogram[length as usize];
+            histogram[length as usize] += 1;
+            // every prefix starting with this code decodes to this symbol
+            let shift = max_bits - length;
+            let first = (code << shift) as usize;
+            let entries = lookup.get_mut(first..first + (1 << shift))?;
+            for entry in entries {
+                *entry = (symbol as u16, length as u8);
+            }
+        }
+        return Some(HuffmanDecoder { max_bits, lookup });
+    }
+
+    pub fn decode_one(&self, reader: &mut BitReader) -> u32 {
+        let (symbol, length) = self.lookup[reader.peek(self.max_bits) as usize];
+        reader.read(length as u32);
+        return symbol as u32;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_bits_msb_first() {
+        let mut reader = BitReader::new(&[0b1010_0000, 0xFF]);
+        assert_eq!(reader.read(3), 0b101);
+        assert_eq!(reader.read(9), 0b0_0000_1111);
+        assert_eq!(reader.read(8), 0b1111_0000);
+        assert!(reader.is_overflowed());
+    }
+
+    #[test]
+    fn assigns_canonical_codes_longest_first() {
+        // lengths 1, 2, 2: the two 2-bit codes come first
+        let decoder = HuffmanDecoder::from_lengths(&[1, 2, 2], 2).unwrap();
+        let mut reader = BitReader::new(&[0b0001_1000]);
+        let symbols: Vec<_> = (0..3).map(|_| decoder.decode_one(&mut reader)).collect();
+        assert_eq!(symbols, vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn imports_rle_trees() {
+        // 4-bit lengths: a run of 3+1 codes of length 2, escaped by a 1
+        let data = [0x12, 0x10];
+        let decoder = HuffmanDecoder::import_tree_rle(&mut BitReader::new(&data), 4, 8);
+        let decoder = decoder.unwrap();
+        assert!(decoder.lookup.iter().all(|&(_, length)| length == 2));
+    }
+}
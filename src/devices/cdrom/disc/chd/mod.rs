@@ -0,0 +1,6 @@
+#[allow(clippy::module_inception)]
+mod chd;
+mod codec;
+mod huffman;
+
+pub use self::chd::ChdImage;
@@ -0,0 +1,399 @@
+//! BIN/CUE images
+//!
+//! The cue sheet describes how the tracks are laid out across one or more
+//! binary files. Track positions in the sheet are relative to the start of
+//! their file, and PREGAP/POSTGAP commands add silence that isn't stored in
+//! any file, so the sheet has to be laid out into disc positions up front.
+
+use super::{out_of_range, synthesize_sector, DiscImage, Track, TrackType, SECTOR_SIZE};
+use crate::devices::cdrom::structs::SECTORS_PER_SECOND;
+use log::debug;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// How sectors are stored in a track's file
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum SectorFormat {
+    /// Whole 2352-byte sectors
+    Raw,
+    /// Only the 2048 bytes of Mode 1 user data
+    Mode1Data,
+    /// Everything after the header of a Mode 2 sector
+    Mode2Data,
+}
+
+impl SectorFormat {
+    fn size(&self) -> u64 {
+        return match self {
+            SectorFormat::Raw => 2352,
+            SectorFormat::Mode1Data => 2048,
+            SectorFormat::Mode2Data => 2336,
+        };
+    }
+}
+
+/// A track as written in the cue sheet
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct CueTrack {
+    number: u8,
+    track_type: TrackType,
+    format: SectorFormat,
+    /// Sectors of silence before the track that aren't stored in the file
+    pregap: u32,
+    /// Sectors of silence after the track that aren't stored in the file
+    postgap: u32,
+    /// The start of the pregap stored in the file, relative to the file start
+    index0: Option<u32>,
+    /// The start of the track, relative to the file start
+    index1: Option<u32>,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone)]
+struct CueFile {
+    path: PathBuf,
+    tracks: Vec<CueTrack>,
+}
+
+/// A run of consecutive sectors on the disc, and where they're stored
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+struct Extent {
+    start: u32,
+    length: u32,
+    track_type: TrackType,
+    /// The file index and byte offset the sectors start at, or None if they
+    /// aren't stored anywhere
+    source: Option<(usize, u64)>,
+    format: SectorFormat,
+}
+
+pub struct CueImage {
+    files: Vec<File>,
+    tracks: Vec<Track>,
+    extents: Vec<Extent>,
+}
+
+impl CueImage {
+    pub fn open(path: &Path) -> io::Result<CueImage> {
+        let sheet = std::fs::read_to_string(path)?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let cue_files = parse_cue(&sheet, base_dir)?;
+        let mut files = Vec::with_capacity(cue_files.len());
+        let mut file_sizes = Vec::with_capacity(cue_files.len());
+        for cue_file in &cue_files {
+            let file = File::open(&cue_file.path).map_err(|err| {
+                io::Error::new(err.kind(), format!("{:?}: {}", cue_file.path, err))
+            })?;
+            file_sizes.push(file.metadata()?.len());
+            files.push(file);
+        }
+        let (tracks, extents) = layout(&cue_files, &file_sizes)?;
+        return Ok(CueImage {
+            files,
+            tracks,
+            extents,
+        });
+    }
+}
+
+impl DiscImage for CueImage {
+    fn tracks(&self) -> &[Track] {
+        return &self.tracks;
+    }
+
+    fn read_sector_lba(&mut self, lba: u32) -> io::Result<[u8; SECTOR_SIZE]> {
+        let extent = *self
+            .extents
+            .iter()
+            .find(|extent| lba >= extent.start && lba < extent.start + extent.length)
+            .ok_or_else(|| out_of_range(lba))?;
+        let (file_idx, offset) = match extent.source {
+            Some(source) => source,
+            None => return Ok(synthesize_sector(lba, extent.track_type, &[])),
+        };
+        let size = extent.format.size();
+        let file = &mut self.files[file_idx];
+        file.seek(SeekFrom::Start(offset + (lba - extent.start) as u64 * size))?;
+        let mut data = [0u8; SECTOR_SIZE];
+        file.read_exact(&mut data[..size as usize])?;
+        return Ok(match extent.format {
+            SectorFormat::Raw => data,
+            _ => synthesize_sector(lba, extent.track_type, &data[..size as usize]),
+        });
+    }
+}
+
+fn parse_error(line_idx: usize, msg: &str) -> io::Error {
+    return io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Cue sheet line {}: {}", line_idx + 1, msg),
+    );
+}
+
+/// Parse a cue-sheet position (decimal mm:ss:ff) into a sector count
+fn parse_msf(text: &str) -> Option<u32> {
+    let mut fields = text.split(':').map(|field| field.parse::<u32>().ok());
+    let (minute, second, frame) = (fields.next()??, fields.next()??, fields.next()??);
+    if fields.next().is_some() || second >= 60 || frame >= SECTORS_PER_SECOND {
+        return None;
+    }
+    return Some((minute * 60 + second) * SECTORS_PER_SECOND + frame);
+}
+
+/// Split the arguments of a FILE command into the (possibly quoted) file name
+/// and the file type
+fn parse_file_args(args: &str) -> Option<(&str, &str)> {
+    let args = args.trim();
+    if let Some(quoted) = args.strip_prefix('"') {
+        let end = quoted.find('"')?;
+        return Some((&quoted[..end], quoted[end + 1..].trim()));
+    }
+    let mut parts = args.splitn(2, char::is_whitespace);
+    return Some((parts.next()?, parts.next()?.trim()));
+}
+
+fn parse_cue(sheet: &str, base_dir: &Path) -> io::Result<Vec<CueFile>> {
+    let mut files: Vec<CueFile> = vec![];
+    for (line_idx, line) in sheet.lines().enumerate() {
+        let line = line.trim();
+        let (command, args) = match line.find(char::is_whitespace) {
+            Some(pos) => (&line[..pos], line[pos..].trim()),
+            None => (line, ""),
+        };
+        let error = |msg: &str| parse_error(line_idx, msg);
+        match command.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                let (name, file_type) = parse_file_args(args).ok_or_else(|| error("Bad FILE"))?;
+                if !file_type.eq_ignore_ascii_case("BINARY") {
+                    return Err(error(&format!("Unsupported file type {}", file_type)));
+                }
+                files.push(CueFile {
+                    path: base_dir.join(name),
+                    tracks: vec![],
+                });
+            }
+            "TRACK" => {
+                let file = files.last_mut().ok_or_else(|| error("TRACK before FILE"))?;
+                let mut parts = args.split_whitespace();
+                let number = parts
+                    .next()
+                    .and_then(|number| number.parse().ok())
+                    .ok_or_else(|| error("Bad track number"))?;
+                let (track_type, format) = match parts.next().map(|t| t.to_ascii_uppercase()) {
+                    Some(ref t) if t == "AUDIO" => (TrackType::Audio, SectorFormat::Raw),
+                    Some(ref t) if t == "MODE1/2352" => (TrackType::Mode1, SectorFormat::Raw),
+                    Some(ref t) if t == "MODE1/2048" => (TrackType::Mode1, SectorFormat::Mode1Data),
+                    Some(ref t) if t == "MODE2/2352" => (TrackType::Mode2, SectorFormat::Raw),
+                    Some(ref t) if t == "MODE2/2336" => (TrackType::Mode2, SectorFormat::Mode2Data),
+                    _ => return Err(error("Unsupported track type")),
+                };
+                file.tracks.push(CueTrack {
+                    number,
+                    track_type,
+                    format,
+                    pregap: 0,
+                    postgap: 0,
+                    index0: None,
+                    index1: None,
+                });
+            }
+            "INDEX" | "PREGAP" | "POSTGAP" => {
+                let track = files
+                    .last_mut()
+                    .and_then(|file| file.tracks.last_mut())
+                    .ok_or_else(|| error(&format!("{} before TRACK", command)))?;
+                let mut parts = args.split_whitespace();
+                let index = if command.eq_ignore_ascii_case("INDEX") {
+                    parts.next().and_then(|index| index.parse::<u8>().ok())
+                } else {
+                    None
+                };
+                let position = parts
+                    .next()
+                    .and_then(parse_msf)
+                    .ok_or_else(|| error("Bad position"))?;
+                match (command.to_ascii_uppercase().as_str(), index) {
+                    ("PREGAP", _) => track.pregap = position,
+                    ("POSTGAP", _) => track.postgap = position,
+                    (_, Some(0)) => track.index0 = Some(position),
+                    (_, Some(1)) => track.index1 = Some(position),
+                    // further indices mark points within the track, which the
+                    // drive doesn't care about
+                    (_, Some(_)) => {}
+                    (_, None) => return Err(error("Bad index number")),
+                }
+            }
+            "" | "REM" | "CATALOG" | "CDTEXTFILE" | "FLAGS" | "ISRC" | "PERFORMER"
+            | "SONGWRITER" | "TITLE" => {}
+            _ => debug!(target: "cdrom", "Ignoring cue sheet command {}", command),
+        }
+    }
+    return Ok(files);
+}
+
+/// Work out where each track lies on the disc, and where its sectors come from
+fn layout(files: &[CueFile], file_sizes: &[u64]) -> io::Result<(Vec<Track>, Vec<Extent>)> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut tracks = vec![];
+    let mut extents = vec![];
+    let mut lba = 0;
+    for (file_idx, file) in files.iter().enumerate() {
+        let mut offset = 0u64;
+        let mut file_sector = 0u32;
+        for (track_idx, cue_track) in file.tracks.iter().enumerate() {
+            let index1 = cue_track
+                .index1
+                .ok_or_else(|| invalid(format!("Track {} has no INDEX 01", cue_track.number)))?;
+            let first = cue_track.index0.unwrap_or(index1);
+            if first < file_sector || index1 < first {
+                return Err(invalid(format!(
+                    "Track {} is out of order",
+                    cue_track.number
+                )));
+            }
+            let size = cue_track.format.size();
+            offset += (first - file_sector) as u64 * size;
+
+            let pregap_start = lba;
+            if cue_track.pregap > 0 {
+                extents.push(Extent {
+                    start: lba,
+                    length: cue_track.pregap,
+                    track_type: cue_track.track_type,
+                    source: None,
+                    format: cue_track.format,
+                });
+                lba += cue_track.pregap;
+            }
+
+            // the track's data runs until the next track's in the same file
+            // starts, or the end of the file
+            let data_end = match file.tracks.get(track_idx + 1) {
+                Some(next) => next.index0.or(next.index1).unwrap_or(first),
+                None => first + (file_sizes[file_idx].saturating_sub(offset) / size) as u32,
+            };
+            if data_end < index1 {
+                return Err(invalid(format!("Track {} has no data", cue_track.number)));
+            }
+            let length = data_end - first;
+            extents.push(Extent {
+                start: lba,
+                length,
+                track_type: cue_track.track_type,
+                source: Some((file_idx, offset)),
+                format: cue_track.format,
+            });
+            let start = lba + (index1 - first);
+            lba += length;
+
+            if cue_track.postgap > 0 {
+                extents.push(Extent {
+                    start: lba,
+                    length: cue_track.postgap,
+                    track_type: cue_track.track_type,
+                    source: None,
+                    format: cue_track.format,
+                });
+                lba += cue_track.postgap;
+            }
+
+            tracks.push(Track {
+                number: cue_track.number,
+                track_type: cue_track.track_type,
+                pregap_start,
+                start,
+                length: lba - start,
+            });
+            offset += length as u64 * size;
+            file_sector = data_end;
+        }
+    }
+    if tracks.is_empty() {
+        return Err(invalid("Cue sheet has no tracks".to_string()));
+    }
+    return Ok((tracks, extents));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SHEET: &str = r#"
+        FILE "game.bin" BINARY
+          TRACK 01 MODE2/2352
+            INDEX 01 00:00:00
+          TRACK 02 AUDIO
+            PREGAP 00:02:00
+            INDEX 01 00:00:10
+          TRACK 03 AUDIO
+            INDEX 00 00:00:30
+            INDEX 01 00:00:40
+        FILE "Track 4.bin" BINARY
+          TRACK 04 AUDIO
+            INDEX 00 00:00:00
+            INDEX 01 00:00:05
+    "#;
+
+    #[test]
+    fn parses_cue_sheet() {
+        let files = parse_cue(SHEET, Path::new("/discs")).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[1].path, Path::new("/discs/Track 4.bin"));
+        let track = &files[0].tracks[2];
+        assert_eq!(track.number, 3);
+        assert_eq!(track.track_type, TrackType::Audio);
+        assert_eq!((track.index0, track.index1), (Some(30), Some(40)));
+        assert_eq!(files[0].tracks[1].pregap, 150);
+    }
+
+    #[test]
+    fn lays_out_pregaps() {
+        let files = parse_cue(SHEET, Path::new(".")).unwrap();
+        let (tracks, extents) = layout(&files, &[50 * 2352, 20 * 2352]).unwrap();
+        let positions: Vec<_> = tracks
+            .iter()
+            .map(|t| (t.pregap_start, t.start, t.length))
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                (0, 0, 10),
+                // 150 sectors of silence, then the track in the file
+                (10, 160, 20),
+                (180, 190, 10),
+                (200, 205, 15),
+            ]
+        );
+        assert_eq!(extents[1].source, None);
+        assert_eq!(extents[2].source, Some((0, 10 * 2352)));
+        assert_eq!(extents[3].source, Some((0, 30 * 2352)));
+        assert_eq!(extents[4].source, Some((1, 0)));
+    }
+
+    #[test]
+    fn reads_sectors_from_bin() {
+        let dir = std::env::temp_dir().join(format!("psx-cue-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut bin = vec![0u8; 4 * SECTOR_SIZE];
+        for (i, sector) in bin.chunks_mut(SECTOR_SIZE).enumerate() {
+            sector[100] = i as u8 + 1;
+        }
+        std::fs::write(dir.join("disc.bin"), &bin).unwrap();
+        std::fs::write(
+            dir.join("disc.cue"),
+            "FILE \"disc.bin\" BINARY\n  TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\n  \
+             TRACK 02 AUDIO\n    PREGAP 00:00:02\n    INDEX 01 00:00:02\n",
+        )
+        .unwrap();
+        let mut disc = CueImage::open(&dir.join("disc.cue")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(disc.read_sector_lba(1).unwrap()[100], 2);
+        // the PREGAP is silence
+        assert_eq!(disc.read_sector_lba(2).unwrap()[100], 0);
+        assert_eq!(disc.read_sector_lba(4).unwrap()[100], 3);
+        assert_eq!(disc.read_sector_lba(5).unwrap()[100], 4);
+        assert!(disc.read_sector_lba(6).is_err());
+        assert_eq!(disc.find_track(3).map(|t| t.number), Some(2));
+    }
+}
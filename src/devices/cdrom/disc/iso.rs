@@ -0,0 +1,50 @@
+//! Single-track ISO images
+//!
+//! These only store the 2048 bytes of user data in each sector, so the rest
+//! of each sector is synthesized as Mode 2 Form 1, like PlayStation discs.
+
+use super::{out_of_range, synthesize_form1_sector, DiscImage, Track, TrackType, SECTOR_SIZE};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const ISO_SECTOR_SIZE: u64 = 2048;
+
+pub struct IsoImage {
+    file: File,
+    tracks: [Track; 1],
+}
+
+impl IsoImage {
+    pub fn open(path: &Path) -> io::Result<IsoImage> {
+        let file = File::open(path)?;
+        let length = (file.metadata()?.len() / ISO_SECTOR_SIZE) as u32;
+        return Ok(IsoImage {
+            file,
+            tracks: [Track {
+                number: 1,
+                track_type: TrackType::Mode2,
+                pregap_start: 0,
+                start: 0,
+                length,
+            }],
+        });
+    }
+}
+
+impl DiscImage for IsoImage {
+    fn tracks(&self) -> &[Track] {
+        return &self.tracks;
+    }
+
+    fn read_sector_lba(&mut self, lba: u32) -> io::Result<[u8; SECTOR_SIZE]> {
+        if lba >= self.tracks[0].end() {
+            return Err(out_of_range(lba));
+        }
+        let mut user_data = [0u8; ISO_SECTOR_SIZE as usize];
+        self.file
+            .seek(SeekFrom::Start(lba as u64 * ISO_SECTOR_SIZE))?;
+        self.file.read_exact(&mut user_data)?;
+        return Ok(synthesize_form1_sector(lba, &user_data));
+    }
+}
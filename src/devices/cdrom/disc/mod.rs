@@ -0,0 +1,171 @@
+//! Disc images
+//!
+//! Images come in a few formats, but they all boil down to a list of tracks
+//! and a way to read raw 2352-byte sectors. Formats that don't store whole
+//! sectors have the missing sync pattern and headers synthesized.
+
+mod chd;
+mod cue;
+mod iso;
+
+pub use self::chd::ChdImage;
+pub use self::cue::CueImage;
+pub use self::iso::IsoImage;
+
+use super::structs::{to_bcd, Msf, LEAD_IN_SECTORS};
+use std::io;
+use std::path::Path;
+
+/// The size of a raw sector, including sync, headers and error correction
+pub const SECTOR_SIZE: usize = 2352;
+
+/// The 12-byte sync pattern at the start of every data sector
+const SECTOR_SYNC: [u8; 12] = [
+    0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00,
+];
+
+/// The kind of data stored in a track
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TrackType {
+    /// CD-DA audio, with no headers
+    Audio,
+    /// Mode 1 data, with 2048 bytes of user data after the header
+    Mode1,
+    /// Mode 2 (XA) data, with a subheader after the header
+    Mode2,
+}
+
+/// A track on a disc
+///
+/// Positions are logical block addresses, which start after the 2-second
+/// lead-in.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Track {
+    pub number: u8,
+    pub track_type: TrackType,
+    /// The start of the pregap (INDEX 00), or the start of the track if it
+    /// doesn't have one
+    pub pregap_start: u32,
+    /// The start of the track proper (INDEX 01)
+    pub start: u32,
+    /// The number of sectors from the start of the track to the next one's
+    /// pregap (or the end of the disc)
+    pub length: u32,
+}
+
+impl Track {
+    pub fn end(&self) -> u32 {
+        return self.start + self.length;
+    }
+}
+
+/// A disc that can be put in the CD-ROM drive
+pub trait DiscImage {
+    /// The tracks on the disc, in order
+    fn tracks(&self) -> &[Track];
+
+    /// Read the raw sector at the given logical block address
+    fn read_sector_lba(&mut self, lba: u32) -> io::Result<[u8; SECTOR_SIZE]>;
+
+    /// Read the raw sector at the given position on the disc
+    fn read_sector(&mut self, msf: Msf) -> io::Result<[u8; SECTOR_SIZE]> {
+        let lba = msf.to_lba().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} lies within the lead-in", msf),
+            )
+        })?;
+        return self.read_sector_lba(lba);
+    }
+
+    /// The position of the lead-out, just after the last track
+    fn lead_out(&self) -> Msf {
+        let end = self.tracks().last().map(|track| track.end()).unwrap_or(0);
+        return Msf::from_lba(end);
+    }
+
+    /// Find the track containing the given logical block address, including
+    /// its pregap
+    fn find_track(&self, lba: u32) -> Option<&Track> {
+        return self
+            .tracks()
+            .iter()
+            .find(|track| lba >= track.pregap_start && lba < track.end());
+    }
+}
+
+/// Open a disc image, picking the format from the file extension
+pub fn open_disc(path: &Path) -> io::Result<Box<dyn DiscImage>> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    return match extension.as_deref() {
+        Some("chd") => Ok(Box::new(ChdImage::open(path)?)),
+        Some("cue") => Ok(Box::new(CueImage::open(path)?)),
+        Some("iso") => Ok(Box::new(IsoImage::open(path)?)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported disc image format: {:?}", path),
+        )),
+    };
+}
+
+/// Build a raw sector around data that's missing its sync pattern and header
+///
+/// `data` is everything after the header: 2048 bytes of user data for Mode 1,
+/// or the 2336 bytes starting at the subheader for Mode 2. Error detection and
+/// correction codes are left zeroed, since the drive doesn't check them.
+fn synthesize_sector(lba: u32, track_type: TrackType, data: &[u8]) -> [u8; SECTOR_SIZE] {
+    let mut sector = [0u8; SECTOR_SIZE];
+    if track_type == TrackType::Audio {
+        sector[..data.len()].copy_from_slice(data);
+        return sector;
+    }
+    sector[..12].copy_from_slice(&SECTOR_SYNC);
+    let msf = Msf::from_sector(lba + LEAD_IN_SECTORS);
+    sector[12] = to_bcd(msf.minute);
+    sector[13] = to_bcd(msf.second);
+    sector[14] = to_bcd(msf.frame);
+    sector[15] = match track_type {
+        TrackType::Mode1 => 1,
+        _ => 2,
+    };
+    sector[16..16 + data.len()].copy_from_slice(data);
+    return sector;
+}
+
+/// Build a Mode 2 Form 1 sector around 2048 bytes of user data
+fn synthesize_form1_sector(lba: u32, user_data: &[u8]) -> [u8; SECTOR_SIZE] {
+    // the subheader is repeated twice: file 0, channel 0, a data submode, and
+    // no coding info
+    const SUBHEADER: [u8; 8] = [0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x08, 0x00];
+    let mut data = [0u8; 8 + 2048];
+    data[..8].copy_from_slice(&SUBHEADER);
+    data[8..].copy_from_slice(user_data);
+    return synthesize_sector(lba, TrackType::Mode2, &data);
+}
+
+fn out_of_range(lba: u32) -> io::Error {
+    return io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        format!("LBA {} is past the end of the disc", lba),
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn synthesizes_form1_headers() {
+        let sector = synthesize_form1_sector(16, &[0xAB; 2048]);
+        assert_eq!(sector[..12], SECTOR_SYNC);
+        // LBA 16 is 00:02:16
+        assert_eq!(sector[12..16], [0x00, 0x02, 0x16, 0x02]);
+        assert_eq!(sector[18], 0x08);
+        assert_eq!(sector[24], 0xAB);
+        assert_eq!(sector[24 + 2047], 0xAB);
+        assert_eq!(sector[24 + 2048], 0x00);
+    }
+}
@@ -0,0 +1,8 @@
+#[allow(clippy::module_inception)]
+mod cdrom;
+pub mod disc;
+mod structs;
+mod xa;
+
+pub use self::cdrom::CdRom;
+pub use self::structs::*;
@@ -0,0 +1,179 @@
+use std::ops::Deref;
+
+/// Sectors per second of CD audio, at 1x speed
+pub const SECTORS_PER_SECOND: u32 = 75;
+/// The 2-second lead-in before LBA 0, in sectors
+pub const LEAD_IN_SECTORS: u32 = 2 * SECTORS_PER_SECOND;
+
+/// A position on a disc, in minutes, seconds and frames (sectors)
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
+pub struct Msf {
+    pub minute: u8,
+    pub second: u8,
+    pub frame: u8,
+}
+
+impl Msf {
+    pub fn new(minute: u8, second: u8, frame: u8) -> Msf {
+        Msf {
+            minute,
+            second,
+            frame,
+        }
+    }
+
+    /// Decode a position from BCD, as the CD-ROM commands send it
+    ///
+    /// Returns None if any field is not valid BCD or is out of range.
+    pub fn from_bcd(minute: u8, second: u8, frame: u8) -> Option<Msf> {
+        let msf = Msf::new(from_bcd(minute)?, from_bcd(second)?, from_bcd(frame)?);
+        if msf.second >= 60 || msf.frame >= SECTORS_PER_SECOND as u8 {
+            return None;
+        }
+        return Some(msf);
+    }
+
+    /// Convert an absolute sector number (including the lead-in) to a position
+    pub fn from_sector(sector: u32) -> Msf {
+        let frame = sector % SECTORS_PER_SECOND;
+        let seconds = sector / SECTORS_PER_SECOND;
+        return Msf::new((seconds / 60) as u8, (seconds % 60) as u8, frame as u8);
+    }
+
+    /// Convert a logical block address (which starts after the lead-in) to a
+    /// position
+    pub fn from_lba(lba: u32) -> Msf {
+        return Msf::from_sector(lba + LEAD_IN_SECTORS);
+    }
+
+    /// The absolute sector number of this position, including the lead-in
+    pub fn to_sector(&self) -> u32 {
+        return (self.minute as u32 * 60 + self.second as u32) * SECTORS_PER_SECOND
+            + self.frame as u32;
+    }
+
+    /// The logical block address of this position, or None if it lies within
+    /// the lead-in
+    pub fn to_lba(&self) -> Option<u32> {
+        return self.to_sector().checked_sub(LEAD_IN_SECTORS);
+    }
+
+    pub fn to_bcd(&self) -> [u8; 3] {
+        return [to_bcd(self.minute), to_bcd(self.second), to_bcd(self.frame)];
+    }
+}
+
+/// Decode a binary-coded decimal byte
+pub fn from_bcd(data: u8) -> Option<u8> {
+    let (hi, lo) = (data >> 4, data & 0xF);
+    if hi > 9 || lo > 9 {
+        return None;
+    }
+    return Some(hi * 10 + lo);
+}
+
+/// Encode a byte (0-99) as binary-coded decimal
+pub fn to_bcd(data: u8) -> u8 {
+    return ((data / 10) << 4) | (data % 10);
+}
+
+//#region Drive status
+/// An error occurred processing the last command
+pub const STAT_ERROR: u8 = 0x01;
+pub const STAT_MOTOR_ON: u8 = 0x02;
+pub const STAT_SEEK_ERROR: u8 = 0x04;
+pub const STAT_ID_ERROR: u8 = 0x08;
+/// The lid is (or has been) open
+pub const STAT_SHELL_OPEN: u8 = 0x10;
+pub const STAT_READING: u8 = 0x20;
+pub const STAT_SEEKING: u8 = 0x40;
+pub const STAT_PLAYING: u8 = 0x80;
+//#endregion
+
+//#region Drive mode (Setmode)
+const MODE_CDDA: u8 = 0x01;
+const MODE_AUTO_PAUSE: u8 = 0x02;
+const MODE_REPORT: u8 = 0x04;
+const MODE_XA_FILTER: u8 = 0x08;
+const MODE_WHOLE_SECTOR: u8 = 0x20;
+const MODE_XA_ADPCM: u8 = 0x40;
+const MODE_DOUBLE_SPEED: u8 = 0x80;
+
+/// The drive mode set by the Setmode command
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
+pub struct DriveMode(u8);
+
+impl DriveMode {
+    /// Whether audio tracks can be read as data
+    pub fn is_cdda_enabled(&self) -> bool {
+        return (**self & MODE_CDDA) != 0;
+    }
+
+    /// Whether playback pauses at the end of a track
+    pub fn is_auto_pause(&self) -> bool {
+        return (**self & MODE_AUTO_PAUSE) != 0;
+    }
+
+    /// Whether CD-DA playback sends position reports
+    pub fn is_report_enabled(&self) -> bool {
+        return (**self & MODE_REPORT) != 0;
+    }
+
+    /// Whether only XA sectors matching Setfilter are played
+    pub fn is_xa_filter_enabled(&self) -> bool {
+        return (**self & MODE_XA_FILTER) != 0;
+    }
+
+    /// Whether reads return 0x924 bytes (everything after the sync pattern)
+    /// rather than the 0x800 bytes of user data
+    pub fn is_whole_sector(&self) -> bool {
+        return (**self & MODE_WHOLE_SECTOR) != 0;
+    }
+
+    /// Whether XA-ADPCM sectors are sent to the SPU rather than the CPU
+    pub fn is_xa_adpcm_enabled(&self) -> bool {
+        return (**self & MODE_XA_ADPCM) != 0;
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        return (**self & MODE_DOUBLE_SPEED) != 0;
+    }
+}
+
+impl From<u8> for DriveMode {
+    fn from(data: u8) -> Self {
+        DriveMode(data)
+    }
+}
+
+impl Deref for DriveMode {
+    type Target = u8;
+
+    fn deref(&self) -> &u8 {
+        return &self.0;
+    }
+}
+//#endregion
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn converts_msf() {
+        let msf = Msf::from_bcd(0x01, 0x23, 0x45).unwrap();
+        assert_eq!(msf, Msf::new(1, 23, 45));
+        assert_eq!(msf.to_sector(), (60 + 23) * 75 + 45);
+        assert_eq!(Msf::from_sector(msf.to_sector()), msf);
+        assert_eq!(msf.to_bcd(), [0x01, 0x23, 0x45]);
+        assert_eq!(Msf::new(0, 2, 0).to_lba(), Some(0));
+        assert_eq!(Msf::from_lba(0), Msf::new(0, 2, 0));
+    }
+
+    #[test]
+    fn rejects_invalid_bcd() {
+        assert_eq!(Msf::from_bcd(0x0A, 0, 0), None);
+        assert_eq!(Msf::from_bcd(0, 0x60, 0), None);
+        assert_eq!(Msf::from_bcd(0, 0, 0x75), None);
+    }
+}
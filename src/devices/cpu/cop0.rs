@@ -28,6 +28,19 @@ pub struct Cop0 {
 /// Flag set when memory ops should only hit the cache instead of the bus
 const CACHE_ISOLATE: u32 = 0x0001_0000;
 const BOOT_EXC_VECTORS: u32 = 0x0040_0000;
+/// Current interrupt enable flag
+const INTERRUPT_ENABLE: u32 = 0x0000_0001;
+/// Interrupt mask, which lines up with the CAUSE interrupt pending bits
+const INTERRUPT_MASK: u32 = 0x0000_FF00;
+//#endregion
+
+//#region CAUSE flags
+/// Pending interrupt bits
+const CAUSE_INTERRUPT_PENDING: u32 = 0x0000_FF00;
+/// Software interrupt bits, the only bits of CAUSE that can be written to
+const CAUSE_SOFTWARE_INTERRUPTS: u32 = 0x0000_0300;
+/// Interrupt line driven by the interrupt controller
+const CAUSE_HARDWARE_INTERRUPT: u32 = 0x0000_0400;
 //#endregion
 
 //#region COP0 register addresses
@@ -61,6 +74,22 @@ impl Cop0 {
         return (self.sr & BOOT_EXC_VECTORS) > 0;
     }
 
+    /// Set the state of the hardware interrupt line from the interrupt
+    /// controller
+    pub fn set_interrupt_line(&mut self, active: bool) {
+        if active {
+            self.cause |= CAUSE_HARDWARE_INTERRUPT;
+        } else {
+            self.cause &= !CAUSE_HARDWARE_INTERRUPT;
+        }
+    }
+
+    /// Return whether an interrupt is both pending and enabled
+    pub fn should_interrupt(&self) -> bool {
+        (self.sr & INTERRUPT_ENABLE) != 0
+            && (self.sr & INTERRUPT_MASK & self.cause & CAUSE_INTERRUPT_PENDING) != 0
+    }
+
     pub fn mtc(&mut self, regidx: usize, data: u32) {
         match regidx {
             SR_IDX => self.sr = data,
@@ -77,11 +106,9 @@ impl Cop0 {
                 }
             }
             CAUSE_IDX => {
-                // same as above
-                if data != 0 {
-                    panic!("Possible attempt to trigger hardware exception in cop0");
-                }
-                self.cause = data;
+                // only the software interrupt bits are writable
+                self.cause &= !CAUSE_SOFTWARE_INTERRUPTS;
+                self.cause |= data & CAUSE_SOFTWARE_INTERRUPTS;
             }
             EPC_IDX => {
                 self.epc = data;
@@ -103,8 +130,9 @@ impl Cop0 {
 /// Setup state for an exception handler, and return the next CPU address
 pub fn handle_exception(cpu: &mut CpuR3000, exc: Exception, pc: u32, is_delay_slot: bool) -> u32 {
     let cop0 = &mut cpu.cop0;
-    // setup the cause register
-    cop0.cause = 0 | ((exc as u32) << 2);
+    // setup the cause register, leaving the pending interrupt bits untouched
+    cop0.cause &= CAUSE_INTERRUPT_PENDING;
+    cop0.cause |= (exc as u32) << 2;
 
     // advance the interrupt enable bits
     let mode = cop0.sr & 0x3F;
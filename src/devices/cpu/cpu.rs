@@ -1,4 +1,5 @@
 use super::cop0;
+use super::gte;
 use super::structs::{CpuState, Exception, Instruction, Mnemonic, CPU_POWERON_STATE};
 use crate::devices::bus::{BusDevice, SizedData};
 use crate::utils::decode::decode_instruction;
@@ -35,14 +36,17 @@ pub struct CpuR3000 {
     pub state: CpuState,
     pub cycles: u64,
     pub cop0: cop0::Cop0,
+    pub gte: gte::Gte,
 }
 
 impl CpuR3000 {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> CpuR3000 {
         return CpuR3000 {
             state: CPU_POWERON_STATE.clone(),
             cycles: 0,
             cop0: cop0::Cop0::new(),
+            gte: gte::Gte::new(),
         };
     }
 }
@@ -92,11 +96,30 @@ pub fn tick<T: WithCpu>(mb: &mut T) -> bool {
     return true;
 }
 
+/// Flush the pipeline and continue execution at a new address
+///
+/// This is for frontends that take over from the BIOS, like when sideloading
+/// an EXE, rather than for anything the CPU does itself.
+pub fn jump<T: WithCpu + BusDevice>(mb: &mut T, addr: u32) {
+    let instruction = mb.read::<u32>(addr);
+    let cpu = mb.cpu_mut();
+    let (reg_idx, val) = cpu.state.next_load;
+    write_reg(cpu, reg_idx, val);
+    cpu.state.next_load = (0, 0);
+    cpu.state.is_branch_delay = false;
+    cpu.state.next_instruction = (instruction, addr);
+    cpu.state.pc = addr.wrapping_add(4);
+}
+
 /// Unconditionally advance the state of the CPU
 pub fn exec<T: WithCpu + BusDevice>(mb: &mut T) {
     let (cur_instruction, cur_pc) = mb.cpu().state.next_instruction;
     let next_pc = mb.cpu().state.pc;
     let is_in_delay_slot = mb.cpu().state.is_branch_delay;
+    if mb.cpu().cop0.should_interrupt() {
+        take_interrupt(mb, cur_instruction, cur_pc, is_in_delay_slot);
+        return;
+    }
     // pre-execution updates
     {
         let next_instruction = mb.read::<u32>(next_pc);
@@ -140,6 +163,34 @@ pub fn exec<T: WithCpu + BusDevice>(mb: &mut T) {
     }
 }
 
+/// Abandon the current instruction and jump to the exception handler
+fn take_interrupt<T: WithCpu + BusDevice>(
+    mb: &mut T,
+    cur_instruction: u32,
+    cur_pc: u32,
+    is_in_delay_slot: bool,
+) {
+    let cpu = mb.cpu_mut();
+    // retire any load already in flight
+    let (reg_idx, val) = cpu.state.next_load;
+    write_reg(cpu, reg_idx, val);
+    cpu.state.next_load = (0, 0);
+    cpu.state.is_branch_delay = false;
+
+    // The BIOS interrupt handler assumes that a GTE command at EPC has already
+    // executed and skips over it, so we have to run it first
+    if cur_instruction & 0xFE00_0000 == 0x4A00_0000 {
+        cpu.gte.execute(cur_instruction & 0x01FF_FFFF);
+    }
+
+    let exc_addr = cop0::handle_exception(cpu, Exception::Interrupt, cur_pc, is_in_delay_slot);
+    let exc_instr = mb.read::<u32>(exc_addr);
+    let cpu = mb.cpu_mut();
+    cpu.state.next_instruction = (exc_instr, exc_addr);
+    cpu.state.pc = exc_addr.wrapping_add(4);
+    cpu.cycles += 1;
+}
+
 //#region Cpu Instructions
 #[allow(type_alias_bounds)] // leaving this in for self-documenting reasons
 type OpcodeHandler<T: WithCpu + BusDevice> = fn(&mut T, Instruction) -> Option<Exception>;
@@ -358,13 +409,17 @@ op_fn!(op_bne, (mb, instr), {
     None
 });
 
-op_fn!(op_break, (_mb, _instr), { Some(Exception::Breakpoint) });
+op_fn!(op_break, (_mb, _instr), Some(Exception::Breakpoint));
 
-op_fn!(op_cfcz, (_mb, instr), {
+op_fn!(op_cfcz, (mb, instr), {
     let coproc = instr.op() & 0b11;
     // CFC/CTC is invalid for Cop0
     match coproc {
-        2 => todo!("GTE"),
+        2 => {
+            let data = mb.cpu().gte.cfc(instr.rd() as usize);
+            mb.cpu_mut().state.next_load = (instr.rt() as usize, data);
+            None
+        }
         _ => Some(Exception::CoprocessorUnusable),
     }
 });
@@ -376,16 +431,23 @@ op_fn!(op_copz, (mb, instr), {
             cop0::handle_cop_instr(mb.cpu_mut(), instr);
             None
         }
-        2 => todo!("GTE"),
+        2 => {
+            mb.cpu_mut().gte.execute(*instr & 0x01FF_FFFF);
+            None
+        }
         _ => Some(Exception::CoprocessorUnusable),
     }
 });
 
-op_fn!(op_ctcz, (_mb, instr), {
+op_fn!(op_ctcz, (mb, instr), {
     let coproc = instr.op() & 0b11;
+    let data = get_reg(mb.cpu(), instr.rt() as usize);
     // CFC/CTC is invalid for Cop0
     match coproc {
-        2 => todo!("GTE"),
+        2 => {
+            mb.cpu_mut().gte.ctc(instr.rd() as usize, data);
+            None
+        }
         _ => Some(Exception::CoprocessorUnusable),
     }
 });
@@ -492,7 +554,7 @@ op_fn!(op_lbu, (mb, instr), {
     let base = get_reg(mb.cpu(), instr.rs() as usize);
     let addr = base.wrapping_add(sign_extend!(instr.immediate()));
     // todo: read errors
-    let data = read::<T, u8>(mb, addr) as u8;
+    let data = read::<T, u8>(mb, addr);
 
     mb.cpu_mut().state.next_load = (instr.rt() as usize, data as u32);
     None
@@ -537,11 +599,18 @@ op_fn!(op_lw, (mb, instr), {
     None
 });
 
-op_fn!(op_lwcz, (_mb, instr), {
+op_fn!(op_lwcz, (mb, instr), {
     let coproc = instr.op() & 0b11;
     // Cop0 doesn't support loads or stores
     match coproc {
-        2 => todo!("GTE"),
+        2 => {
+            let base = get_reg(mb.cpu(), instr.rs() as usize);
+            let addr = base.wrapping_add(sign_extend!(instr.immediate()));
+            // todo: read errors
+            let data = read(mb, addr);
+            mb.cpu_mut().gte.mtc(instr.rt() as usize, data);
+            None
+        }
         _ => Some(Exception::CoprocessorUnusable),
     }
 });
@@ -560,6 +629,8 @@ op_fn!(op_lwl, (mb, instr), {
     // make an aligned read
     let aligned_byte = mb.read::<u32>(addr & !0x0000_0003);
 
+    // the full-word mask is spelled out to line up with its neighbours
+    #[allow(clippy::erasing_op)]
     let new_val = match addr & 0x0000_0003 {
         0 => (current & 0x00FF_FFFF) | (aligned_byte << 24),
         1 => (current & 0x0000_FFFF) | (aligned_byte << 16),
@@ -589,6 +660,7 @@ op_fn!(op_lwr, (mb, instr), {
     // make an aligned read
     let aligned_byte = mb.read::<u32>(addr & !0x0000_0003);
 
+    #[allow(clippy::erasing_op)]
     let new_val = match addr & 0x0000_0003 {
         0 => (current & 0x0000_0000) | (aligned_byte),
         1 => (current & 0xFF00_0000) | (aligned_byte >> 8),
@@ -612,7 +684,11 @@ op_fn!(op_mfcz, (mb, instr), {
             mb.cpu_mut().state.next_load = (instr.rt() as usize, data);
             None
         }
-        2 => todo!("GTE"),
+        2 => {
+            let data = mb.cpu().gte.mfc(instr.rd() as usize);
+            mb.cpu_mut().state.next_load = (instr.rt() as usize, data);
+            None
+        }
         _ => Some(Exception::CoprocessorUnusable),
     }
 });
@@ -653,7 +729,7 @@ op_fn!(op_mult, (mb, instr), {
     let a = get_reg(mb.cpu(), source) as i32 as u64;
     let b = get_reg(mb.cpu(), target) as i32 as u64;
 
-    let v = a * b as u64;
+    let v = a * b;
 
     mb.cpu_mut().state.hi = (v >> 32) as u32;
     mb.cpu_mut().state.lo = (v & 0xFFFF_FFFF) as u32;
@@ -684,7 +760,10 @@ op_fn!(op_mtcz, (mb, instr), {
             mb.cpu_mut().cop0.mtc(instr.rd() as usize, data);
             None
         }
-        2 => todo!("GTE"),
+        2 => {
+            mb.cpu_mut().gte.mtc(instr.rd() as usize, data);
+            None
+        }
         _ => Some(Exception::CoprocessorUnusable),
     }
 });
@@ -749,7 +828,7 @@ op_fn!(op_sllv, (mb, instr), {
     let target = get_reg(mb.cpu(), instr.rt() as usize);
     let dest = instr.rd() as usize;
     let shift = get_reg(mb.cpu(), instr.rs() as usize) & 0b0001_1111;
-    write_reg(mb.cpu_mut(), dest, target.wrapping_shl(shift as u32));
+    write_reg(mb.cpu_mut(), dest, target.wrapping_shl(shift));
     None
 });
 
@@ -822,7 +901,7 @@ op_fn!(op_srav, (mb, instr), {
     let target = get_reg(mb.cpu(), instr.rt() as usize) as i32;
     let dest = instr.rd() as usize;
     let shift = get_reg(mb.cpu(), instr.rs() as usize) & 0b0001_1111;
-    write_reg(mb.cpu_mut(), dest, target.wrapping_shr(shift as u32) as u32);
+    write_reg(mb.cpu_mut(), dest, target.wrapping_shr(shift) as u32);
     None
 });
 
@@ -839,7 +918,7 @@ op_fn!(op_srlv, (mb, instr), {
     let target = get_reg(mb.cpu(), instr.rt() as usize);
     let dest = instr.rd() as usize;
     let shift = get_reg(mb.cpu(), instr.rs() as usize) & 0b0001_1111;
-    write_reg(mb.cpu_mut(), dest, target.wrapping_shr(shift as u32));
+    write_reg(mb.cpu_mut(), dest, target.wrapping_shr(shift));
     None
 });
 
@@ -885,11 +964,17 @@ op_fn!(op_sw, (mb, instr), {
     None
 });
 
-op_fn!(op_swcz, (_mb, instr), {
+op_fn!(op_swcz, (mb, instr), {
     let coproc = instr.op() & 0b11;
     // Cop0 doesn't support loads or stores
     match coproc {
-        2 => todo!("GTE"),
+        2 => {
+            let base = get_reg(mb.cpu(), instr.rs() as usize);
+            let addr = base.wrapping_add(sign_extend!(instr.immediate()));
+            let data = mb.cpu().gte.mfc(instr.rt() as usize);
+            write(mb, addr, data);
+            None
+        }
         _ => Some(Exception::CoprocessorUnusable),
     }
 });
@@ -904,6 +989,7 @@ op_fn!(op_swl, (mb, instr), {
     // make an aligned read
     let aligned_byte = get_reg(mb.cpu(), target);
 
+    #[allow(clippy::erasing_op)]
     let new_val = match addr & 0x0000_0003 {
         0 => (current & 0x00FF_FFFF) | (aligned_byte << 24),
         1 => (current & 0x0000_FFFF) | (aligned_byte << 16),
@@ -929,6 +1015,7 @@ op_fn!(op_swr, (mb, instr), {
     // make an aligned read
     let aligned_byte = get_reg(mb.cpu(), target);
 
+    #[allow(clippy::erasing_op)]
     let new_val = match addr & 0x0000_0003 {
         0 => (current & 0x0000_0000) | (aligned_byte),
         1 => (current & 0xFF00_0000) | (aligned_byte >> 8),
@@ -944,7 +1031,7 @@ op_fn!(op_swr, (mb, instr), {
     None
 });
 
-op_fn!(op_syscall, (_mb, _instr), { Some(Exception::Syscall) });
+op_fn!(op_syscall, (_mb, _instr), Some(Exception::Syscall));
 
 op_fn!(op_xor, (mb, instr), {
     let source = instr.rs() as usize;
@@ -0,0 +1,1182 @@
+//! The Geometry Transformation Engine, mounted as coprocessor 2
+//!
+//! The GTE is a fixed-point vector unit used for 3D transforms, perspective
+//! projection, and lighting. It has 32 data registers (accessed by MFC2/MTC2
+//! and LWC2/SWC2) and 32 control registers (accessed by CFC2/CTC2), and a set
+//! of commands issued with COP2.
+//!
+//! Most of the complexity here is in reproducing the hardware's overflow and
+//! saturation behavior, since games depend on the exact results (and on the
+//! FLAG register) more than you'd hope. The behavior modeled here follows the
+//! No$PSX specification, cross-checked against Mednafen and Rustation.
+
+use log::debug;
+
+//#region FLAG bits
+const FLAG_MAC0_POSITIVE: u32 = 1 << 16;
+const FLAG_MAC0_NEGATIVE: u32 = 1 << 15;
+const FLAG_SX2_SATURATED: u32 = 1 << 14;
+const FLAG_SY2_SATURATED: u32 = 1 << 13;
+const FLAG_IR0_SATURATED: u32 = 1 << 12;
+const FLAG_SZ3_OTZ_SATURATED: u32 = 1 << 18;
+const FLAG_DIVIDE_OVERFLOW: u32 = 1 << 17;
+/// Bits that, when set, also set the error bit (bit 31)
+const FLAG_ERROR_MASK: u32 = 0x7F87_E000;
+/// Bits that can actually be written to by CTC2
+const FLAG_WRITE_MASK: u32 = 0x7FFF_F000;
+//#endregion
+
+//#region Data register indices
+const VXY0_IDX: usize = 0;
+const VZ0_IDX: usize = 1;
+const VXY1_IDX: usize = 2;
+const VZ1_IDX: usize = 3;
+const VXY2_IDX: usize = 4;
+const VZ2_IDX: usize = 5;
+const RGBC_IDX: usize = 6;
+const OTZ_IDX: usize = 7;
+const IR0_IDX: usize = 8;
+const IR3_IDX: usize = 11;
+const SXY0_IDX: usize = 12;
+const SXY2_IDX: usize = 14;
+const SXYP_IDX: usize = 15;
+const SZ0_IDX: usize = 16;
+const SZ3_IDX: usize = 19;
+const RGB0_IDX: usize = 20;
+const RGB2_IDX: usize = 22;
+const RES1_IDX: usize = 23;
+const MAC0_IDX: usize = 24;
+const MAC3_IDX: usize = 27;
+const IRGB_IDX: usize = 28;
+const ORGB_IDX: usize = 29;
+const LZCS_IDX: usize = 30;
+const LZCR_IDX: usize = 31;
+//#endregion
+
+//#region Control register indices
+const RT_START_IDX: usize = 0;
+const RT_END_IDX: usize = 4;
+const TRX_IDX: usize = 5;
+const TRZ_IDX: usize = 7;
+const LLM_START_IDX: usize = 8;
+const LLM_END_IDX: usize = 12;
+const RBK_IDX: usize = 13;
+const BBK_IDX: usize = 15;
+const LCM_START_IDX: usize = 16;
+const LCM_END_IDX: usize = 20;
+const RFC_IDX: usize = 21;
+const BFC_IDX: usize = 23;
+const OFX_IDX: usize = 24;
+const OFY_IDX: usize = 25;
+const H_IDX: usize = 26;
+const DQA_IDX: usize = 27;
+const DQB_IDX: usize = 28;
+const ZSF3_IDX: usize = 29;
+const ZSF4_IDX: usize = 30;
+const FLAG_IDX: usize = 31;
+//#endregion
+
+//#region Command opcodes
+const CMD_RTPS: u32 = 0x01;
+const CMD_NCLIP: u32 = 0x06;
+const CMD_OP: u32 = 0x0C;
+const CMD_DPCS: u32 = 0x10;
+const CMD_INTPL: u32 = 0x11;
+const CMD_MVMVA: u32 = 0x12;
+const CMD_NCDS: u32 = 0x13;
+const CMD_CDP: u32 = 0x14;
+const CMD_NCDT: u32 = 0x16;
+const CMD_NCCS: u32 = 0x1B;
+const CMD_CC: u32 = 0x1C;
+const CMD_NCS: u32 = 0x1E;
+const CMD_NCT: u32 = 0x20;
+const CMD_SQR: u32 = 0x28;
+const CMD_DCPL: u32 = 0x29;
+const CMD_DPCT: u32 = 0x2A;
+const CMD_AVSZ3: u32 = 0x2D;
+const CMD_AVSZ4: u32 = 0x2E;
+const CMD_RTPT: u32 = 0x30;
+const CMD_GPF: u32 = 0x3D;
+const CMD_GPL: u32 = 0x3E;
+const CMD_NCCT: u32 = 0x3F;
+//#endregion
+
+/// The unsigned Newton-Raphson reciprocal table used by the GTE divider
+///
+/// The hardware has this burned into a ROM, but No$PSX notes it can be
+/// generated exactly with the formula below.
+const UNR_TABLE: [u8; 0x101] = generate_unr_table();
+
+const fn generate_unr_table() -> [u8; 0x101] {
+    let mut table = [0u8; 0x101];
+    let mut i = 0;
+    while i < 0x101 {
+        let val = (0x40000 / (i as i32 + 0x100) + 1) / 2 - 0x101;
+        table[i] = if val > 0 { val as u8 } else { 0 };
+        i += 1;
+    }
+    table
+}
+
+type Matrix = [[i16; 3]; 3];
+
+/// A decoded GTE command word
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub struct GteCommand(pub u32);
+
+impl GteCommand {
+    /// The real command opcode
+    pub fn opcode(&self) -> u32 {
+        self.0 & 0x3F
+    }
+
+    /// The number of bits to shift MAC results right by (either 0 or 12)
+    pub fn shift(&self) -> u32 {
+        if self.0 & (1 << 19) != 0 {
+            12
+        } else {
+            0
+        }
+    }
+
+    /// Whether IR1-3 should saturate to 0..7FFF instead of -8000..7FFF
+    pub fn lm(&self) -> bool {
+        self.0 & (1 << 10) != 0
+    }
+
+    /// MVMVA multiply matrix selector
+    pub fn mx(&self) -> u32 {
+        (self.0 >> 17) & 0b11
+    }
+
+    /// MVMVA multiply vector selector
+    pub fn v(&self) -> u32 {
+        (self.0 >> 15) & 0b11
+    }
+
+    /// MVMVA translation vector selector
+    pub fn cv(&self) -> u32 {
+        (self.0 >> 13) & 0b11
+    }
+}
+
+pub struct Gte {
+    //#region Data registers
+    /// Input vectors V0, V1, V2
+    v: [[i16; 3]; 3],
+    /// Color and GPU command code
+    rgbc: [u8; 4],
+    /// Average Z value, for ordering tables
+    otz: u16,
+    /// 16-bit intermediate accumulators (IR0-IR3)
+    ir: [i16; 4],
+    /// Screen XY coordinate FIFO
+    sxy: [(i16, i16); 3],
+    /// Screen Z FIFO
+    sz: [u16; 4],
+    /// Color FIFO
+    rgb: [[u8; 4]; 3],
+    /// Prohibited register, which nonetheless stores whatever is written to it
+    res1: u32,
+    /// 32-bit accumulators (MAC0-MAC3)
+    mac: [i32; 4],
+    /// Count leading bits source
+    lzcs: u32,
+    /// Count leading bits result
+    lzcr: u32,
+    //#endregion
+    //#region Control registers
+    /// Rotation matrix
+    rt: Matrix,
+    /// Translation vector
+    tr: [i32; 3],
+    /// Light source matrix
+    llm: Matrix,
+    /// Background color
+    bk: [i32; 3],
+    /// Light color matrix
+    lcm: Matrix,
+    /// Far color
+    fc: [i32; 3],
+    /// Screen offset X
+    ofx: i32,
+    /// Screen offset Y
+    ofy: i32,
+    /// Projection plane distance
+    h: u16,
+    /// Depth queuing parameter A (coefficient)
+    dqa: i16,
+    /// Depth queuing parameter B (offset)
+    dqb: i32,
+    /// Average Z scale factor for AVSZ3
+    zsf3: i16,
+    /// Average Z scale factor for AVSZ4
+    zsf4: i16,
+    /// Calculation error flags
+    flag: u32,
+    //#endregion
+}
+
+impl Gte {
+    pub fn new() -> Gte {
+        Gte {
+            v: [[0; 3]; 3],
+            rgbc: [0; 4],
+            otz: 0,
+            ir: [0; 4],
+            sxy: [(0, 0); 3],
+            sz: [0; 4],
+            rgb: [[0; 4]; 3],
+            res1: 0,
+            mac: [0; 4],
+            lzcs: 0,
+            lzcr: 32,
+            rt: [[0; 3]; 3],
+            tr: [0; 3],
+            llm: [[0; 3]; 3],
+            bk: [0; 3],
+            lcm: [[0; 3]; 3],
+            fc: [0; 3],
+            ofx: 0,
+            ofy: 0,
+            h: 0,
+            dqa: 0,
+            dqb: 0,
+            zsf3: 0,
+            zsf4: 0,
+            flag: 0,
+        }
+    }
+
+    //#region Register access
+    /// Read a data register (MFC2/SWC2)
+    pub fn mfc(&self, regidx: usize) -> u32 {
+        match regidx {
+            VXY0_IDX | VXY1_IDX | VXY2_IDX => {
+                let v = &self.v[regidx / 2];
+                (v[0] as u16 as u32) | ((v[1] as u16 as u32) << 16)
+            }
+            VZ0_IDX | VZ1_IDX | VZ2_IDX => self.v[regidx / 2][2] as i32 as u32,
+            RGBC_IDX => u32::from_le_bytes(self.rgbc),
+            OTZ_IDX => self.otz as u32,
+            IR0_IDX..=IR3_IDX => self.ir[regidx - IR0_IDX] as i32 as u32,
+            SXY0_IDX..=SXY2_IDX => pack_sxy(self.sxy[regidx - SXY0_IDX]),
+            // reading SXYP mirrors SXY2
+            SXYP_IDX => pack_sxy(self.sxy[2]),
+            SZ0_IDX..=SZ3_IDX => self.sz[regidx - SZ0_IDX] as u32,
+            RGB0_IDX..=RGB2_IDX => u32::from_le_bytes(self.rgb[regidx - RGB0_IDX]),
+            RES1_IDX => self.res1,
+            MAC0_IDX..=MAC3_IDX => self.mac[regidx - MAC0_IDX] as u32,
+            IRGB_IDX | ORGB_IDX => {
+                let saturate = |v: i16| (v >> 7).clamp(0, 0x1F) as u32;
+                saturate(self.ir[1]) | (saturate(self.ir[2]) << 5) | (saturate(self.ir[3]) << 10)
+            }
+            LZCS_IDX => self.lzcs,
+            LZCR_IDX => self.lzcr,
+            _ => unreachable!("Invalid GTE data register {}", regidx),
+        }
+    }
+
+    /// Write a data register (MTC2/LWC2)
+    pub fn mtc(&mut self, regidx: usize, data: u32) {
+        match regidx {
+            VXY0_IDX | VXY1_IDX | VXY2_IDX => {
+                let v = &mut self.v[regidx / 2];
+                v[0] = data as i16;
+                v[1] = (data >> 16) as i16;
+            }
+            VZ0_IDX | VZ1_IDX | VZ2_IDX => self.v[regidx / 2][2] = data as i16,
+            RGBC_IDX => self.rgbc = data.to_le_bytes(),
+            OTZ_IDX => self.otz = data as u16,
+            IR0_IDX..=IR3_IDX => self.ir[regidx - IR0_IDX] = data as i16,
+            SXY0_IDX..=SXY2_IDX => self.sxy[regidx - SXY0_IDX] = unpack_sxy(data),
+            SXYP_IDX => {
+                self.sxy[0] = self.sxy[1];
+                self.sxy[1] = self.sxy[2];
+                self.sxy[2] = unpack_sxy(data);
+            }
+            SZ0_IDX..=SZ3_IDX => self.sz[regidx - SZ0_IDX] = data as u16,
+            RGB0_IDX..=RGB2_IDX => self.rgb[regidx - RGB0_IDX] = data.to_le_bytes(),
+            RES1_IDX => self.res1 = data,
+            MAC0_IDX..=MAC3_IDX => self.mac[regidx - MAC0_IDX] = data as i32,
+            IRGB_IDX => {
+                self.ir[1] = ((data & 0x1F) << 7) as i16;
+                self.ir[2] = (((data >> 5) & 0x1F) << 7) as i16;
+                self.ir[3] = (((data >> 10) & 0x1F) << 7) as i16;
+            }
+            ORGB_IDX => {
+                debug!(target: "gte", "Write to read-only ORGB register ignored");
+            }
+            LZCS_IDX => {
+                self.lzcs = data;
+                self.lzcr = if (data as i32) < 0 {
+                    data.leading_ones()
+                } else {
+                    data.leading_zeros()
+                };
+            }
+            LZCR_IDX => {
+                debug!(target: "gte", "Write to read-only LZCR register ignored");
+            }
+            _ => unreachable!("Invalid GTE data register {}", regidx),
+        }
+    }
+
+    /// Read a control register (CFC2)
+    pub fn cfc(&self, regidx: usize) -> u32 {
+        match regidx {
+            RT_START_IDX..=RT_END_IDX => read_matrix(&self.rt, regidx - RT_START_IDX),
+            TRX_IDX..=TRZ_IDX => self.tr[regidx - TRX_IDX] as u32,
+            LLM_START_IDX..=LLM_END_IDX => read_matrix(&self.llm, regidx - LLM_START_IDX),
+            RBK_IDX..=BBK_IDX => self.bk[regidx - RBK_IDX] as u32,
+            LCM_START_IDX..=LCM_END_IDX => read_matrix(&self.lcm, regidx - LCM_START_IDX),
+            RFC_IDX..=BFC_IDX => self.fc[regidx - RFC_IDX] as u32,
+            OFX_IDX => self.ofx as u32,
+            OFY_IDX => self.ofy as u32,
+            // H is unsigned, but reads are sign-extended due to a hardware bug
+            H_IDX => self.h as i16 as u32,
+            DQA_IDX => self.dqa as i32 as u32,
+            DQB_IDX => self.dqb as u32,
+            ZSF3_IDX => self.zsf3 as i32 as u32,
+            ZSF4_IDX => self.zsf4 as i32 as u32,
+            FLAG_IDX => self.flag,
+            _ => unreachable!("Invalid GTE control register {}", regidx),
+        }
+    }
+
+    /// Write a control register (CTC2)
+    pub fn ctc(&mut self, regidx: usize, data: u32) {
+        match regidx {
+            RT_START_IDX..=RT_END_IDX => write_matrix(&mut self.rt, regidx - RT_START_IDX, data),
+            TRX_IDX..=TRZ_IDX => self.tr[regidx - TRX_IDX] = data as i32,
+            LLM_START_IDX..=LLM_END_IDX => {
+                write_matrix(&mut self.llm, regidx - LLM_START_IDX, data)
+            }
+            RBK_IDX..=BBK_IDX => self.bk[regidx - RBK_IDX] = data as i32,
+            LCM_START_IDX..=LCM_END_IDX => {
+                write_matrix(&mut self.lcm, regidx - LCM_START_IDX, data)
+            }
+            RFC_IDX..=BFC_IDX => self.fc[regidx - RFC_IDX] = data as i32,
+            OFX_IDX => self.ofx = data as i32,
+            OFY_IDX => self.ofy = data as i32,
+            H_IDX => self.h = data as u16,
+            DQA_IDX => self.dqa = data as i16,
+            DQB_IDX => self.dqb = data as i32,
+            ZSF3_IDX => self.zsf3 = data as i16,
+            ZSF4_IDX => self.zsf4 = data as i16,
+            FLAG_IDX => {
+                self.flag = data & FLAG_WRITE_MASK;
+                self.update_error_flag();
+            }
+            _ => unreachable!("Invalid GTE control register {}", regidx),
+        }
+    }
+    //#endregion
+
+    /// Execute a GTE command (COP2 imm25)
+    pub fn execute(&mut self, command: u32) {
+        let cmd = GteCommand(command);
+        self.flag = 0;
+        match cmd.opcode() {
+            CMD_RTPS => self.rtp(cmd, 0, true),
+            CMD_NCLIP => self.nclip(),
+            CMD_OP => self.op(cmd),
+            CMD_DPCS => {
+                let rgb = self.rgbc;
+                self.dpc(cmd, rgb);
+            }
+            CMD_INTPL => self.intpl(cmd),
+            CMD_MVMVA => self.mvmva(cmd),
+            CMD_NCDS => self.ncd(cmd, 0),
+            CMD_CDP => self.cdp(cmd),
+            CMD_NCDT => {
+                for idx in 0..3 {
+                    self.ncd(cmd, idx);
+                }
+            }
+            CMD_NCCS => self.ncc(cmd, 0),
+            CMD_CC => self.cc(cmd),
+            CMD_NCS => self.nc(cmd, 0),
+            CMD_NCT => {
+                for idx in 0..3 {
+                    self.nc(cmd, idx);
+                }
+            }
+            CMD_SQR => self.sqr(cmd),
+            CMD_DCPL => self.dcpl(cmd),
+            CMD_DPCT => {
+                for _ in 0..3 {
+                    // DPCT always operates on the bottom of the color FIFO,
+                    // which is shifted out by each iteration
+                    let rgb = self.rgb[0];
+                    self.dpc(cmd, rgb);
+                }
+            }
+            CMD_AVSZ3 => self.avsz3(),
+            CMD_AVSZ4 => self.avsz4(),
+            CMD_RTPT => {
+                self.rtp(cmd, 0, false);
+                self.rtp(cmd, 1, false);
+                self.rtp(cmd, 2, true);
+            }
+            CMD_GPF => self.gpf(cmd),
+            CMD_GPL => self.gpl(cmd),
+            CMD_NCCT => {
+                for idx in 0..3 {
+                    self.ncc(cmd, idx);
+                }
+            }
+            _ => debug!(target: "gte", "Unknown GTE command 0x{:07X}", command),
+        }
+        self.update_error_flag();
+    }
+
+    //#region Commands
+    /// Perspective transformation for a single vertex (RTPS/RTPT)
+    fn rtp(&mut self, cmd: GteCommand, vidx: usize, last: bool) {
+        let shift = cmd.shift();
+        let lm = cmd.lm();
+        let rt = self.rt;
+        let tr = self.tr;
+        let raw = self.multiply_matrix(&rt, self.v[vidx], tr);
+        for (i, &val) in raw.iter().enumerate() {
+            self.mac[i + 1] = (val >> shift) as i32;
+        }
+        self.ir[1] = self.saturate_ir(1, self.mac[1], lm);
+        self.ir[2] = self.saturate_ir(2, self.mac[2], lm);
+        // IR3 is saturated normally, but the flag is instead computed from the
+        // unshifted result (a well-known hardware quirk)
+        let ir3_min = if lm { 0 } else { -0x8000 };
+        let z_flag_value = raw[2] >> 12;
+        if !(-0x8000..=0x7FFF).contains(&z_flag_value) {
+            self.flag |= ir_flag(3);
+        }
+        self.ir[3] = self.mac[3].clamp(ir3_min, 0x7FFF) as i16;
+
+        self.push_sz(raw[2] >> 12);
+        let projection = self.divide() as i64;
+
+        let screen_x = self.ir[1] as i64 * projection + self.ofx as i64;
+        let screen_y = self.ir[2] as i64 * projection + self.ofy as i64;
+        self.check_mac0_overflow(screen_x);
+        self.check_mac0_overflow(screen_y);
+        self.push_sxy(screen_x >> 16, screen_y >> 16);
+
+        if last {
+            let depth = self.dqb as i64 + self.dqa as i64 * projection;
+            self.set_mac0(depth);
+            self.set_ir0(depth >> 12);
+        }
+    }
+
+    /// Normal clipping: the winding of the triangle in the SXY FIFO
+    fn nclip(&mut self) {
+        let [(x0, y0), (x1, y1), (x2, y2)] = self.sxy;
+        let (x0, y0, x1, y1, x2, y2) = (
+            x0 as i64, y0 as i64, x1 as i64, y1 as i64, x2 as i64, y2 as i64,
+        );
+        let res = x0 * y1 + x1 * y2 + x2 * y0 - x0 * y2 - x1 * y0 - x2 * y1;
+        self.set_mac0(res);
+    }
+
+    /// Outer product of IR with the diagonal of the rotation matrix
+    fn op(&mut self, cmd: GteCommand) {
+        let d = [
+            self.rt[0][0] as i64,
+            self.rt[1][1] as i64,
+            self.rt[2][2] as i64,
+        ];
+        let ir = [self.ir[1] as i64, self.ir[2] as i64, self.ir[3] as i64];
+        let raw = [
+            ir[2] * d[1] - ir[1] * d[2],
+            ir[0] * d[2] - ir[2] * d[0],
+            ir[1] * d[0] - ir[0] * d[1],
+        ];
+        self.set_mac_ir(raw, cmd.shift(), cmd.lm());
+    }
+
+    /// Depth cue a color (DPCS/DPCT)
+    fn dpc(&mut self, cmd: GteCommand, rgb: [u8; 4]) {
+        let raw = [
+            (rgb[0] as i64) << 16,
+            (rgb[1] as i64) << 16,
+            (rgb[2] as i64) << 16,
+        ];
+        self.depth_cue(raw, cmd.shift(), cmd.lm());
+    }
+
+    /// Interpolate between IR and the far color
+    fn intpl(&mut self, cmd: GteCommand) {
+        let raw = [
+            (self.ir[1] as i64) << 12,
+            (self.ir[2] as i64) << 12,
+            (self.ir[3] as i64) << 12,
+        ];
+        self.depth_cue(raw, cmd.shift(), cmd.lm());
+    }
+
+    /// Generic matrix * vector + translation
+    fn mvmva(&mut self, cmd: GteCommand) {
+        let shift = cmd.shift();
+        let lm = cmd.lm();
+        let mat = match cmd.mx() {
+            0 => self.rt,
+            1 => self.llm,
+            2 => self.lcm,
+            _ => {
+                // Selecting the reserved matrix yields a garbage matrix
+                // that's nonetheless deterministic
+                let r = (self.rgbc[0] as i16) << 4;
+                [[-r, r, self.ir[0]], [self.rt[0][2]; 3], [self.rt[1][1]; 3]]
+            }
+        };
+        let vec = match cmd.v() {
+            0 => self.v[0],
+            1 => self.v[1],
+            2 => self.v[2],
+            _ => [self.ir[1], self.ir[2], self.ir[3]],
+        };
+        match cmd.cv() {
+            0 => {
+                let tr = self.tr;
+                let raw = self.multiply_matrix(&mat, vec, tr);
+                self.set_mac_ir(raw, shift, lm);
+            }
+            1 => {
+                let bk = self.bk;
+                let raw = self.multiply_matrix(&mat, vec, bk);
+                self.set_mac_ir(raw, shift, lm);
+            }
+            2 => {
+                // The far color translation is bugged: only the first column
+                // product is added to it, and that result is discarded after
+                // setting the flags.
+                let mut raw = [0i64; 3];
+                for (row, res) in raw.iter_mut().enumerate() {
+                    let bugged = self.check_mac_overflow(
+                        row + 1,
+                        ((self.fc[row] as i64) << 12) + mat[row][0] as i64 * vec[0] as i64,
+                    );
+                    self.saturate_ir(row + 1, (bugged >> shift) as i32, false);
+                    let acc = self.check_mac_overflow(row + 1, mat[row][1] as i64 * vec[1] as i64);
+                    *res =
+                        self.check_mac_overflow(row + 1, acc + mat[row][2] as i64 * vec[2] as i64);
+                }
+                self.set_mac_ir(raw, shift, lm);
+            }
+            _ => {
+                let raw = self.multiply_matrix(&mat, vec, [0; 3]);
+                self.set_mac_ir(raw, shift, lm);
+            }
+        }
+    }
+
+    /// Normal color depth cue for a single vector (NCDS/NCDT)
+    fn ncd(&mut self, cmd: GteCommand, vidx: usize) {
+        self.light_vector(cmd, vidx);
+        let raw = self.color_product();
+        self.depth_cue(raw, cmd.shift(), cmd.lm());
+    }
+
+    /// Color depth cue
+    fn cdp(&mut self, cmd: GteCommand) {
+        self.light_color(cmd);
+        let raw = self.color_product();
+        self.depth_cue(raw, cmd.shift(), cmd.lm());
+    }
+
+    /// Normal color color for a single vector (NCCS/NCCT)
+    fn ncc(&mut self, cmd: GteCommand, vidx: usize) {
+        self.light_vector(cmd, vidx);
+        let raw = self.color_product();
+        self.set_mac_ir(raw, cmd.shift(), cmd.lm());
+        self.push_color();
+    }
+
+    /// Color color
+    fn cc(&mut self, cmd: GteCommand) {
+        self.light_color(cmd);
+        let raw = self.color_product();
+        self.set_mac_ir(raw, cmd.shift(), cmd.lm());
+        self.push_color();
+    }
+
+    /// Normal color for a single vector (NCS/NCT)
+    fn nc(&mut self, cmd: GteCommand, vidx: usize) {
+        self.light_vector(cmd, vidx);
+        self.push_color();
+    }
+
+    /// Square of IR
+    fn sqr(&mut self, cmd: GteCommand) {
+        let raw = [
+            self.ir[1] as i64 * self.ir[1] as i64,
+            self.ir[2] as i64 * self.ir[2] as i64,
+            self.ir[3] as i64 * self.ir[3] as i64,
+        ];
+        self.set_mac_ir(raw, cmd.shift(), cmd.lm());
+    }
+
+    /// Depth cue a color light
+    fn dcpl(&mut self, cmd: GteCommand) {
+        let raw = self.color_product();
+        self.depth_cue(raw, cmd.shift(), cmd.lm());
+    }
+
+    /// Average of three Z values
+    fn avsz3(&mut self) {
+        let sum = self.sz[1] as i64 + self.sz[2] as i64 + self.sz[3] as i64;
+        let res = self.zsf3 as i64 * sum;
+        self.set_mac0(res);
+        self.set_otz(res >> 12);
+    }
+
+    /// Average of four Z values
+    fn avsz4(&mut self) {
+        let sum = self.sz[0] as i64 + self.sz[1] as i64 + self.sz[2] as i64 + self.sz[3] as i64;
+        let res = self.zsf4 as i64 * sum;
+        self.set_mac0(res);
+        self.set_otz(res >> 12);
+    }
+
+    /// General purpose interpolation
+    fn gpf(&mut self, cmd: GteCommand) {
+        let ir0 = self.ir[0] as i64;
+        let raw = [
+            ir0 * self.ir[1] as i64,
+            ir0 * self.ir[2] as i64,
+            ir0 * self.ir[3] as i64,
+        ];
+        let raw = [
+            self.check_mac_overflow(1, raw[0]),
+            self.check_mac_overflow(2, raw[1]),
+            self.check_mac_overflow(3, raw[2]),
+        ];
+        self.set_mac_ir(raw, cmd.shift(), cmd.lm());
+        self.push_color();
+    }
+
+    /// General purpose interpolation with base
+    fn gpl(&mut self, cmd: GteCommand) {
+        let shift = cmd.shift();
+        let ir0 = self.ir[0] as i64;
+        let mut raw = [0i64; 3];
+        for (i, res) in raw.iter_mut().enumerate() {
+            let base = (self.mac[i + 1] as i64) << shift;
+            *res = self.check_mac_overflow(i + 1, base + ir0 * self.ir[i + 1] as i64);
+        }
+        self.set_mac_ir(raw, shift, cmd.lm());
+        self.push_color();
+    }
+    //#endregion
+
+    //#region Shared pipeline stages
+    /// Multiply a matrix by a vector, and add a translation vector
+    ///
+    /// This returns the raw (unshifted) results, truncated to 44 bits as the
+    /// hardware does after each step, with overflow flags set accordingly.
+    fn multiply_matrix(&mut self, mat: &Matrix, vec: [i16; 3], tr: [i32; 3]) -> [i64; 3] {
+        let mut res = [0i64; 3];
+        for (row, out) in res.iter_mut().enumerate() {
+            let mut acc = (tr[row] as i64) << 12;
+            for col in 0..3 {
+                acc =
+                    self.check_mac_overflow(row + 1, acc + mat[row][col] as i64 * vec[col] as i64);
+            }
+            *out = acc;
+        }
+        res
+    }
+
+    /// Light a vertex normal: IR = LCM * (LLM * V) + BK
+    fn light_vector(&mut self, cmd: GteCommand, vidx: usize) {
+        let llm = self.llm;
+        let raw = self.multiply_matrix(&llm, self.v[vidx], [0; 3]);
+        self.set_mac_ir(raw, cmd.shift(), cmd.lm());
+        self.light_color(cmd);
+    }
+
+    /// Apply the light color matrix to IR: IR = LCM * IR + BK
+    fn light_color(&mut self, cmd: GteCommand) {
+        let lcm = self.lcm;
+        let bk = self.bk;
+        let raw = self.multiply_matrix(&lcm, [self.ir[1], self.ir[2], self.ir[3]], bk);
+        self.set_mac_ir(raw, cmd.shift(), cmd.lm());
+    }
+
+    /// Multiply the RGBC color by IR: [R*IR1, G*IR2, B*IR3] << 4
+    fn color_product(&mut self) -> [i64; 3] {
+        let mut res = [0i64; 3];
+        for (i, out) in res.iter_mut().enumerate() {
+            let color = (self.rgbc[i] as i64) << 4;
+            *out = self.check_mac_overflow(i + 1, color * self.ir[i + 1] as i64);
+        }
+        res
+    }
+
+    /// Interpolate the raw vector towards the far color by IR0, then push the
+    /// result into the color FIFO
+    fn depth_cue(&mut self, raw: [i64; 3], shift: u32, lm: bool) {
+        for (i, &val) in raw.iter().enumerate() {
+            let far = self.check_mac_overflow(i + 1, ((self.fc[i] as i64) << 12) - val);
+            self.mac[i + 1] = (far >> shift) as i32;
+        }
+        for i in 1..4 {
+            self.ir[i] = self.saturate_ir(i, self.mac[i], false);
+        }
+        let ir0 = self.ir[0] as i64;
+        let mut res = [0i64; 3];
+        for (i, out) in res.iter_mut().enumerate() {
+            *out = self.check_mac_overflow(i + 1, raw[i] + self.ir[i + 1] as i64 * ir0);
+        }
+        self.set_mac_ir(res, shift, lm);
+        self.push_color();
+    }
+
+    /// Store raw results into MAC1-3, and then saturate them into IR1-3
+    fn set_mac_ir(&mut self, raw: [i64; 3], shift: u32, lm: bool) {
+        for (i, &val) in raw.iter().enumerate() {
+            let val = self.check_mac_overflow(i + 1, val);
+            self.mac[i + 1] = (val >> shift) as i32;
+            self.ir[i + 1] = self.saturate_ir(i + 1, self.mac[i + 1], lm);
+        }
+    }
+
+    /// The UNR division used for perspective projection, which computes
+    /// (H * 0x20000 / SZ3 + 1) / 2 with a saturation of 0x1FFFF
+    fn divide(&mut self) -> u32 {
+        let h = self.h as u64;
+        let sz3 = self.sz[3] as u64;
+        if h >= sz3 * 2 {
+            self.flag |= FLAG_DIVIDE_OVERFLOW;
+            return 0x1FFFF;
+        }
+        let shift = (self.sz[3]).leading_zeros();
+        let n = h << shift;
+        let d = sz3 << shift;
+        let u = UNR_TABLE[((d - 0x7FC0) >> 7) as usize] as i64 + 0x101;
+        let d = d as i64;
+        let d = (0x200_0080 - d * u) >> 8;
+        let d = (0x000_0080 + d * u) >> 8;
+        let res = ((n as i64 * d + 0x8000) >> 16) as u32;
+        res.min(0x1FFFF)
+    }
+    //#endregion
+
+    //#region Saturation and flag helpers
+    /// Check a MAC1-3 intermediate for 44-bit overflow, and return the result
+    /// sign-extended from 44 bits
+    fn check_mac_overflow(&mut self, idx: usize, val: i64) -> i64 {
+        if val > 0x7FF_FFFF_FFFF {
+            self.flag |= 1 << (31 - idx);
+        } else if val < -0x800_0000_0000 {
+            self.flag |= 1 << (28 - idx);
+        }
+        (val << 20) >> 20
+    }
+
+    fn check_mac0_overflow(&mut self, val: i64) {
+        if val > i32::MAX as i64 {
+            self.flag |= FLAG_MAC0_POSITIVE;
+        } else if val < i32::MIN as i64 {
+            self.flag |= FLAG_MAC0_NEGATIVE;
+        }
+    }
+
+    fn set_mac0(&mut self, val: i64) {
+        self.check_mac0_overflow(val);
+        self.mac[0] = val as i32;
+    }
+
+    fn saturate_ir(&mut self, idx: usize, val: i32, lm: bool) -> i16 {
+        let min = if lm { 0 } else { -0x8000 };
+        if val < min {
+            self.flag |= ir_flag(idx);
+            min as i16
+        } else if val > 0x7FFF {
+            self.flag |= ir_flag(idx);
+            0x7FFF
+        } else {
+            val as i16
+        }
+    }
+
+    fn set_ir0(&mut self, val: i64) {
+        if val < 0 {
+            self.flag |= FLAG_IR0_SATURATED;
+            self.ir[0] = 0;
+        } else if val > 0x1000 {
+            self.flag |= FLAG_IR0_SATURATED;
+            self.ir[0] = 0x1000;
+        } else {
+            self.ir[0] = val as i16;
+        }
+    }
+
+    fn set_otz(&mut self, val: i64) {
+        self.otz = self.saturate_z(val);
+    }
+
+    fn saturate_z(&mut self, val: i64) -> u16 {
+        if val < 0 {
+            self.flag |= FLAG_SZ3_OTZ_SATURATED;
+            0
+        } else if val > 0xFFFF {
+            self.flag |= FLAG_SZ3_OTZ_SATURATED;
+            0xFFFF
+        } else {
+            val as u16
+        }
+    }
+
+    fn push_sz(&mut self, val: i64) {
+        let z = self.saturate_z(val);
+        self.sz[0] = self.sz[1];
+        self.sz[1] = self.sz[2];
+        self.sz[2] = self.sz[3];
+        self.sz[3] = z;
+    }
+
+    fn push_sxy(&mut self, x: i64, y: i64) {
+        let x = if !(-0x400..=0x3FF).contains(&x) {
+            self.flag |= FLAG_SX2_SATURATED;
+            x.clamp(-0x400, 0x3FF)
+        } else {
+            x
+        };
+        let y = if !(-0x400..=0x3FF).contains(&y) {
+            self.flag |= FLAG_SY2_SATURATED;
+            y.clamp(-0x400, 0x3FF)
+        } else {
+            y
+        };
+        self.sxy[0] = self.sxy[1];
+        self.sxy[1] = self.sxy[2];
+        self.sxy[2] = (x as i16, y as i16);
+    }
+
+    /// Push MAC1-3 / 16 into the color FIFO, along with the RGBC code byte
+    fn push_color(&mut self) {
+        let mut color = [0u8; 4];
+        for (i, out) in color.iter_mut().take(3).enumerate() {
+            let val = self.mac[i + 1] >> 4;
+            *out = if !(0..=0xFF).contains(&val) {
+                self.flag |= 1 << (21 - i);
+                val.clamp(0, 0xFF) as u8
+            } else {
+                val as u8
+            };
+        }
+        color[3] = self.rgbc[3];
+        self.rgb[0] = self.rgb[1];
+        self.rgb[1] = self.rgb[2];
+        self.rgb[2] = color;
+    }
+
+    fn update_error_flag(&mut self) {
+        if self.flag & FLAG_ERROR_MASK != 0 {
+            self.flag |= 0x8000_0000;
+        } else {
+            self.flag &= !0x8000_0000;
+        }
+    }
+    //#endregion
+}
+
+/// Return the FLAG bit for an IR1-3 saturation
+fn ir_flag(idx: usize) -> u32 {
+    1 << (25 - idx)
+}
+
+fn pack_sxy((x, y): (i16, i16)) -> u32 {
+    (x as u16 as u32) | ((y as u16 as u32) << 16)
+}
+
+fn unpack_sxy(data: u32) -> (i16, i16) {
+    (data as i16, (data >> 16) as i16)
+}
+
+/// Read a matrix control register, where each register packs two elements
+/// (except the last, which holds only the 9th element sign-extended)
+fn read_matrix(mat: &Matrix, idx: usize) -> u32 {
+    let lo = mat[(idx * 2) / 3][(idx * 2) % 3];
+    if idx == 4 {
+        return lo as i32 as u32;
+    }
+    let hi = mat[(idx * 2 + 1) / 3][(idx * 2 + 1) % 3];
+    (lo as u16 as u32) | ((hi as u16 as u32) << 16)
+}
+
+fn write_matrix(mat: &mut Matrix, idx: usize, data: u32) {
+    mat[(idx * 2) / 3][(idx * 2) % 3] = data as i16;
+    if idx != 4 {
+        mat[(idx * 2 + 1) / 3][(idx * 2 + 1) % 3] = (data >> 16) as i16;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SF: u32 = 0x0008_0000;
+    const LM: u32 = 0x0000_0400;
+
+    /// Load an identity matrix (1.0 = 0x1000) into RT, LLM or LCM
+    fn load_identity(gte: &mut Gte, start_idx: usize) {
+        gte.ctc(start_idx, 0x0000_1000);
+        gte.ctc(start_idx + 2, 0x0000_1000);
+        gte.ctc(start_idx + 4, 0x0000_1000);
+    }
+
+    /// Set up an identity lighting rig with a small red ambient light, a
+    /// far color, and a halfway depth cue
+    fn setup_lighting(gte: &mut Gte) {
+        load_identity(gte, LLM_START_IDX);
+        load_identity(gte, LCM_START_IDX);
+        gte.ctc(RBK_IDX, 0x100);
+        gte.ctc(RFC_IDX, 0x10);
+        gte.ctc(RFC_IDX + 1, 0x20);
+        gte.ctc(BFC_IDX, 0x30);
+        gte.mtc(RGBC_IDX, 0x30FF_4080);
+        gte.mtc(IR0_IDX, 0x800);
+    }
+
+    fn read_mac(gte: &Gte) -> [i32; 3] {
+        [
+            gte.mfc(MAC0_IDX + 1) as i32,
+            gte.mfc(MAC0_IDX + 2) as i32,
+            gte.mfc(MAC3_IDX) as i32,
+        ]
+    }
+
+    fn read_ir(gte: &Gte) -> [i32; 3] {
+        [
+            gte.mfc(IR0_IDX + 1) as i32,
+            gte.mfc(IR0_IDX + 2) as i32,
+            gte.mfc(IR3_IDX) as i32,
+        ]
+    }
+
+    #[test]
+    fn generates_unr_table() {
+        assert_eq!(UNR_TABLE[0x00], 0xFF);
+        assert_eq!(UNR_TABLE[0x01], 0xFD);
+        assert_eq!(UNR_TABLE[0xFF], 0x00);
+        assert_eq!(UNR_TABLE[0x100], 0x00);
+    }
+
+    #[test]
+    fn sign_extends_data_registers() {
+        let mut gte = Gte::new();
+        gte.mtc(VZ0_IDX, 0x0000_8000);
+        assert_eq!(gte.mfc(VZ0_IDX), 0xFFFF_8000);
+        gte.mtc(OTZ_IDX, 0xFFFF_FFFF);
+        assert_eq!(gte.mfc(OTZ_IDX), 0x0000_FFFF);
+        gte.mtc(IR0_IDX + 1, 0x0000_FFFF);
+        assert_eq!(gte.mfc(IR0_IDX + 1), 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn counts_leading_bits() {
+        let mut gte = Gte::new();
+        gte.mtc(LZCS_IDX, 0x0000_FFFF);
+        assert_eq!(gte.mfc(LZCR_IDX), 16);
+        gte.mtc(LZCS_IDX, 0xFF00_0000);
+        assert_eq!(gte.mfc(LZCR_IDX), 8);
+    }
+
+    #[test]
+    fn packs_matrix_registers() {
+        let mut gte = Gte::new();
+        for idx in 0..5 {
+            gte.ctc(RT_START_IDX + idx, 0x0002_0001 * (idx as u32 + 1));
+        }
+        assert_eq!(gte.rt, [[1, 2, 2], [4, 3, 6], [4, 8, 5]]);
+        assert_eq!(gte.cfc(RT_START_IDX + 1), 0x0004_0002);
+    }
+
+    #[test]
+    fn pushes_sxyp_fifo() {
+        let mut gte = Gte::new();
+        gte.mtc(SXYP_IDX, 0x0001_0001);
+        gte.mtc(SXYP_IDX, 0x0002_0002);
+        gte.mtc(SXYP_IDX, 0x0003_0003);
+        assert_eq!(gte.mfc(SXY0_IDX), 0x0001_0001);
+        assert_eq!(gte.mfc(SXY0_IDX + 1), 0x0002_0002);
+        assert_eq!(gte.mfc(SXYP_IDX), 0x0003_0003);
+    }
+
+    #[test]
+    fn computes_nclip() {
+        let mut gte = Gte::new();
+        gte.mtc(SXY0_IDX, 0x0000_0000);
+        gte.mtc(SXY0_IDX + 1, 0x0000_000A); // (10, 0)
+        gte.mtc(SXY2_IDX, 0x000A_0000); // (0, 10)
+        gte.execute(CMD_NCLIP);
+        assert_eq!(gte.mfc(MAC0_IDX) as i32, 100);
+        assert_eq!(gte.cfc(FLAG_IDX), 0);
+    }
+
+    #[test]
+    fn projects_vertex_with_rtps() {
+        let mut gte = Gte::new();
+        // identity rotation (1.0 = 0x1000)
+        gte.ctc(0, 0x0000_1000);
+        gte.ctc(2, 0x0000_1000);
+        gte.ctc(4, 0x0000_1000);
+        gte.ctc(OFX_IDX, 160 << 16);
+        gte.ctc(OFY_IDX, 120 << 16);
+        gte.ctc(H_IDX, 200);
+        gte.mtc(VXY0_IDX, 0x0032_0064); // (100, 50)
+        gte.mtc(VZ0_IDX, 400);
+        // RTPS with sf=1
+        gte.execute(0x0008_0000 | CMD_RTPS);
+        assert_eq!(gte.mfc(SZ3_IDX), 400);
+        // x = 100 * 200 / 400 + 160, y = 50 * 200 / 400 + 120
+        assert_eq!(gte.mfc(SXY2_IDX), (145 << 16) | 210);
+        assert_eq!(gte.cfc(FLAG_IDX), 0);
+    }
+
+    #[test]
+    fn flags_divide_overflow() {
+        let mut gte = Gte::new();
+        gte.ctc(H_IDX, 200);
+        gte.execute(0x0008_0000 | CMD_RTPS);
+        assert_eq!(gte.cfc(FLAG_IDX), 0x8000_0000 | FLAG_DIVIDE_OVERFLOW);
+    }
+
+    #[test]
+    fn averages_z() {
+        let mut gte = Gte::new();
+        gte.ctc(ZSF3_IDX, 0x1000 / 3);
+        gte.mtc(SZ0_IDX + 1, 300);
+        gte.mtc(SZ0_IDX + 2, 300);
+        gte.mtc(SZ3_IDX, 300);
+        gte.execute(CMD_AVSZ3);
+        assert_eq!(gte.mfc(OTZ_IDX), 299);
+    }
+
+    #[test]
+    fn saturates_colors() {
+        let mut gte = Gte::new();
+        gte.mtc(RGBC_IDX, 0x40FF_FFFF);
+        gte.mtc(IR0_IDX, 0x1000);
+        gte.mtc(IR0_IDX + 1, 0x7FFF);
+        gte.mtc(IR0_IDX + 2, 0x0800);
+        gte.mtc(IR3_IDX, 0x0000);
+        // GPF with sf=1
+        gte.execute(0x0008_0000 | CMD_GPF);
+        assert_eq!(gte.mfc(RGB2_IDX), 0x4000_80FF);
+        assert_eq!(gte.cfc(FLAG_IDX) & (1 << 21), 1 << 21);
+    }
+
+    #[test]
+    fn multiplies_with_mvmva() {
+        let mut gte = Gte::new();
+        load_identity(&mut gte, RT_START_IDX);
+        gte.ctc(TRX_IDX, 10);
+        gte.ctc(TRZ_IDX, 0x1000);
+        gte.mtc(VXY0_IDX, 0xFF38_0064); // (100, -200)
+        gte.mtc(VZ0_IDX, 0x7000);
+        // MVMVA rt, v0, tr with sf=1
+        gte.execute(SF | CMD_MVMVA);
+        assert_eq!(read_mac(&gte), [110, -200, 0x8000]);
+        assert_eq!(read_ir(&gte), [110, -200, 0x7FFF]);
+        // IR3 saturation isn't an error
+        assert_eq!(gte.cfc(FLAG_IDX), ir_flag(3));
+
+        // ...but IR2 saturation is, and lm=1 clamps it to 0
+        gte.execute(SF | LM | CMD_MVMVA);
+        assert_eq!(read_ir(&gte), [110, 0, 0x7FFF]);
+        assert_eq!(gte.cfc(FLAG_IDX), 0x8000_0000 | ir_flag(2) | ir_flag(3));
+    }
+
+    #[test]
+    fn drops_far_color_translation_in_mvmva() {
+        let mut gte = Gte::new();
+        load_identity(&mut gte, RT_START_IDX);
+        gte.ctc(RFC_IDX, 0x1000);
+        gte.mtc(VXY0_IDX, 0x0005_7800);
+        gte.mtc(VZ0_IDX, 6);
+        // MVMVA rt, v0, fc with sf=1
+        gte.execute(SF | (2 << 13) | CMD_MVMVA);
+        // the far color and first column are discarded from the result...
+        assert_eq!(read_mac(&gte), [0, 5, 6]);
+        assert_eq!(read_ir(&gte), [0, 5, 6]);
+        // ...but FC + RT11 * VX0 = 0x8800 still saturated IR1
+        assert_eq!(gte.cfc(FLAG_IDX), 0x8000_0000 | ir_flag(1));
+    }
+
+    #[test]
+    fn depth_cues_normals_with_ncds() {
+        let mut gte = Gte::new();
+        setup_lighting(&mut gte);
+        gte.mtc(VXY0_IDX, 0x0400_0800);
+        gte.mtc(VZ0_IDX, 0xFE00); // -0x200
+                                  // NCDS with sf=1, lm=1
+        gte.execute(SF | LM | CMD_NCDS);
+        // lighting gives IR = (0x900, 0x400, 0), which is multiplied by the
+        // color and then interpolated halfway to the far color
+        assert_eq!(read_mac(&gte), [0x248, 0x90, 0x18]);
+        assert_eq!(read_ir(&gte), [0x248, 0x90, 0x18]);
+        assert_eq!(gte.mfc(RGB2_IDX), 0x3001_0924);
+        // the negative Z normal saturated IR3 to 0 under lm=1
+        assert_eq!(gte.cfc(FLAG_IDX), ir_flag(3));
+    }
+
+    #[test]
+    fn depth_cues_normals_with_ncdt() {
+        let mut gte = Gte::new();
+        setup_lighting(&mut gte);
+        gte.mtc(VXY0_IDX, 0x0400_0800);
+        gte.mtc(VZ0_IDX, 0xFE00);
+        gte.mtc(VXY1_IDX, 0);
+        gte.mtc(VZ1_IDX, 0);
+        gte.mtc(VXY2_IDX, 0x1000_0000);
+        gte.mtc(VZ2_IDX, 0);
+        // NCDT with sf=1, lm=1
+        gte.execute(SF | LM | CMD_NCDT);
+        assert_eq!(gte.mfc(RGB0_IDX), 0x3001_0924);
+        assert_eq!(gte.mfc(RGB0_IDX + 1), 0x3001_0104);
+        assert_eq!(gte.mfc(RGB2_IDX), 0x3001_2104);
+        assert_eq!(read_mac(&gte), [0x48, 0x210, 0x18]);
+        assert_eq!(gte.cfc(FLAG_IDX), ir_flag(3));
+    }
+
+    #[test]
+    fn colors_normals_with_nccs() {
+        let mut gte = Gte::new();
+        setup_lighting(&mut gte);
+        gte.mtc(VXY0_IDX, 0x0400_7000);
+        gte.mtc(VZ0_IDX, 0xFE00);
+        // NCCS with sf=1, lm=1
+        gte.execute(SF | LM | CMD_NCCS);
+        // lighting gives IR = (0x7100, 0x400, 0), which is multiplied by the
+        // color without any depth cueing
+        assert_eq!(read_mac(&gte), [0x3880, 0x100, 0]);
+        assert_eq!(read_ir(&gte), [0x3880, 0x100, 0]);
+        // red saturates in the color FIFO
+        assert_eq!(gte.mfc(RGB2_IDX), 0x3000_10FF);
+        assert_eq!(gte.cfc(FLAG_IDX), ir_flag(3) | (1 << 21));
+    }
+
+    #[test]
+    fn colors_normals_with_ncct() {
+        let mut gte = Gte::new();
+        setup_lighting(&mut gte);
+        gte.mtc(VXY0_IDX, 0x0000_7F00);
+        gte.mtc(VZ0_IDX, 0);
+        gte.mtc(VXY1_IDX, 0);
+        gte.mtc(VZ1_IDX, 0);
+        gte.mtc(VXY2_IDX, 0x1000_0000);
+        gte.mtc(VZ2_IDX, 0);
+        // NCCT with sf=1, lm=1
+        gte.execute(SF | LM | CMD_NCCT);
+        assert_eq!(gte.mfc(RGB0_IDX), 0x3000_00FF);
+        assert_eq!(gte.mfc(RGB0_IDX + 1), 0x3000_0008);
+        assert_eq!(gte.mfc(RGB2_IDX), 0x3000_4008);
+        assert_eq!(read_mac(&gte), [0x80, 0x400, 0]);
+        // the ambient light pushed the first normal's IR1 past 0x7FFF
+        assert_eq!(gte.cfc(FLAG_IDX), 0x8000_0000 | ir_flag(1) | (1 << 21));
+    }
+}
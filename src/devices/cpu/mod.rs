@@ -1,5 +1,7 @@
 mod cop0;
+#[allow(clippy::module_inception)]
 mod cpu;
+mod gte;
 
-pub use self::cpu::{exec, tick, CpuR3000, WithCpu};
+pub use self::cpu::{exec, jump, tick, CpuR3000, WithCpu};
 pub mod structs;
@@ -3,6 +3,7 @@ use std::ops::Deref;
 
 /// Magic addresses, or "vectors", that the CPU jumps
 #[derive(Copy, Clone, Eq, PartialEq)]
+#[repr(u32)]
 pub enum MagicAddress {
     /// KUSEG TLB miss exception (BEV only)
     TLBMiss = 0x8000_0000,
@@ -14,6 +15,9 @@ pub enum MagicAddress {
     MiscExceptionBev = 0xBFC0_0180,
     /// Reset vector
     ResetVector = 0xBFC0_0000,
+    /// Where the BIOS starts the shell, once the kernel is set up. EXEs are
+    /// sideloaded here, since a disc would be booted soon after.
+    ShellEntry = 0x8003_0000,
 }
 
 #[derive(Debug, Eq, PartialEq, Ord, PartialOrd)]
@@ -364,7 +368,7 @@ impl Instruction {
     }
 
     pub fn target(&self) -> u32 {
-        (**self & INSTR_PART_TARGET) as u32
+        **self & INSTR_PART_TARGET
     }
 }
 
@@ -373,6 +377,7 @@ mod test {
     use super::*;
 
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn constructs_instruction() {
         let _data = Instruction(0);
         assert!(true); // expect no errors
@@ -1,8 +1,55 @@
 //! The PSX DMA controller
 
-use super::structs::DmaChannel;
+use super::structs::{
+    DmaChannel, DmaChannelDirection, DmaChannelIteration, DmaChannelSync, DmaPort,
+};
 use crate::devices::bus::{BusDevice, SizedData};
-use log::debug;
+use log::{debug, warn};
+
+//#region DICR bits
+/// Bits of DICR that are simply read/write
+const DICR_READ_WRITE: u32 = 0x00FF_803F;
+/// Forces the master IRQ flag on
+const DICR_FORCE_IRQ: u32 = 0x0000_8000;
+/// Per-channel IRQ enables
+const DICR_CHANNEL_IRQ_ENABLE: u32 = 0x007F_0000;
+/// Master IRQ enable for the per-channel IRQs
+const DICR_MASTER_IRQ_ENABLE: u32 = 0x0080_0000;
+/// Per-channel IRQ flags, which are acknowledged by writing a 1
+const DICR_CHANNEL_IRQ_FLAGS: u32 = 0x7F00_0000;
+/// Read-only master IRQ flag
+const DICR_MASTER_IRQ_FLAG: u32 = 0x8000_0000;
+//#endregion
+
+/// Linked-list headers with this bit set in the next address end the list
+const LINKED_LIST_END: u32 = 0x0080_0000;
+
+/// The only CHCR bits of the OTC channel that software can change
+const OTC_CHANNEL_WRITABLE: u32 = 0x5100_0000;
+/// The OTC channel always iterates backwards
+const OTC_CHANNEL_BACKWARD: u32 = 0x0000_0002;
+/// The terminating entry of an ordering table
+const OTC_END_OF_TABLE: u32 = 0x00FF_FFFF;
+
+/// Mask for addresses in RAM. DMA transfers are always word-aligned.
+const RAM_ADDR_MASK: u32 = 0x001F_FFFC;
+
+/// The most linked-list nodes walked in one transfer, which is one for every
+/// word of RAM. A longer list must loop back on itself.
+const LINKED_LIST_MAX_NODES: u32 = (RAM_ADDR_MASK >> 2) + 1;
+
+/// A trait for devices that own a DMA controller, such as the Motherboard
+///
+/// DMA transfers read and write RAM through the owner's bus, and move data
+/// to and from the devices attached to each port through this trait.
+pub trait WithDma {
+    fn dma(&self) -> &DmaController;
+    fn dma_mut(&mut self) -> &mut DmaController;
+    /// Read a word from the device attached to a DMA port
+    fn dma_read(&mut self, port: DmaPort) -> u32;
+    /// Write a word to the device attached to a DMA port
+    fn dma_write(&mut self, port: DmaPort, data: u32);
+}
 
 pub struct DmaController {
     /// Control register
@@ -15,9 +62,16 @@ pub struct DmaController {
     unknown_2: u32,
     /// DMA channel control registers
     channels: [DmaChannel; 7],
+    /// DMA channel base address registers (MADR)
+    base_addrs: [u32; 7],
+    /// DMA channel block control registers (BCR)
+    block_ctrls: [u32; 7],
+    /// Whether the master IRQ flag has gone high since the last check
+    irq_pending: bool,
 }
 
 impl DmaController {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> DmaController {
         DmaController {
             // No$psx list this as the reset value for the control register
@@ -27,38 +81,81 @@ impl DmaController {
             unknown_1: 0,
             unknown_2: 0,
             channels: [DmaChannel::from(0); 7],
+            base_addrs: [0; 7],
+            block_ctrls: [0; 7],
+            irq_pending: false,
         }
     }
-}
 
-impl BusDevice for DmaController {
-    fn read<T: SizedData>(&mut self, addr: u32) -> T {
-        if T::width() != 4 {
-            todo!("Other bit widths");
+    pub fn get_channel(&self, port: DmaPort) -> DmaChannel {
+        self.channels[port as usize]
+    }
+
+    /// Return whether the given channel is enabled and ready to transfer
+    pub fn is_channel_active(&self, port: DmaPort) -> bool {
+        // each channel has an enable bit in DPCR, at bit 3 of its nibble
+        let is_master_enabled = (self.control >> (port as u32 * 4 + 3)) & 1 == 1;
+        is_master_enabled && self.channels[port as usize].is_ready()
+    }
+
+    /// Return, and clear, whether a DMA interrupt should be raised
+    pub fn take_irq(&mut self) -> bool {
+        let irq = self.irq_pending;
+        self.irq_pending = false;
+        irq
+    }
+
+    /// Mark a channel's transfer as finished, and raise an IRQ if enabled
+    fn complete(&mut self, port: DmaPort) {
+        self.channels[port as usize].set_completed();
+        let idx = port as u32;
+        if self.interrupt & (1 << (16 + idx)) != 0 {
+            self.interrupt |= 1 << (24 + idx);
         }
+        self.update_master_irq_flag();
+    }
+
+    fn update_master_irq_flag(&mut self) {
+        let was_set = self.interrupt & DICR_MASTER_IRQ_FLAG != 0;
+        let enabled = (self.interrupt & DICR_CHANNEL_IRQ_ENABLE) << 8;
+        let flags = self.interrupt & DICR_CHANNEL_IRQ_FLAGS;
+        let is_set = self.interrupt & DICR_FORCE_IRQ != 0
+            || (self.interrupt & DICR_MASTER_IRQ_ENABLE != 0 && (enabled & flags) != 0);
+        if is_set {
+            self.interrupt |= DICR_MASTER_IRQ_FLAG;
+        } else {
+            self.interrupt &= !DICR_MASTER_IRQ_FLAG;
+        }
+        // the interrupt controller is edge-triggered on this flag
+        if is_set && !was_set {
+            self.irq_pending = true;
+        }
+    }
+
+    fn read_reg(&self, addr: u32) -> u32 {
         let major = (addr & 0x70) >> 4;
-        let minor = addr & 0x0F;
+        let minor = addr & 0x0C;
         match major {
-            0 | 1 | 2 | 3 | 4 | 5 | 6 => {
-                let channel = &self.channels[major as usize];
-                match minor {
-                    0x0 => todo!(),
-                    0x4 => todo!(),
-                    0x8 => T::from_u32(**channel),
-                    0xC => todo!(),
-                    _ => unreachable!(),
+            0..=6 => match minor {
+                0x0 => self.base_addrs[major as usize],
+                0x4 => self.block_ctrls[major as usize],
+                0x8 => *self.channels[major as usize],
+                0xC => {
+                    debug!(target: "dma", "Attempt to read unused DMA channel register");
+                    0
                 }
-            }
+                _ => unreachable!(),
+            },
             7 => match minor {
-                0x0 => T::from_u32(self.control),
-                0x4 => T::from_u32(self.interrupt),
+                0x0 => self.control,
+                0x4 => self.interrupt,
                 0x8 => {
                     debug!(target: "dma", "Attempt to use unknown DMA register 1");
-                    T::from_u32(self.unknown_1)
+                    self.unknown_1
                 }
                 0xC => {
                     debug!(target: "dma", "Attempt to use unknown DMA register 2");
-                    T::from_u32(self.unknown_2)
+                    self.unknown_2
                 }
                 _ => unreachable!(),
             },
@@ -66,64 +163,50 @@ impl BusDevice for DmaController {
         }
     }
 
-    fn peek<T: SizedData>(&self, addr: u32) -> Option<T> {
-        if T::width() != 4 {
-            todo!("Other bit widths");
-        }
+    fn write_reg(&mut self, addr: u32, data: u32) {
         let major = (addr & 0x70) >> 4;
-        let minor = addr & 0x0F;
-        Some(match major {
-            0 | 1 | 2 | 3 | 4 | 5 | 6 => {
-                let channel = &self.channels[major as usize];
-                match minor {
-                    0x0 => todo!(),
-                    0x4 => todo!(),
-                    0x8 => T::from_u32(**channel),
-                    0xC => todo!(),
-                    _ => unreachable!(),
+        let minor = addr & 0x0C;
+        match major {
+            0..=6 => match minor {
+                // addresses are only 24 bits wide
+                0x0 => self.base_addrs[major as usize] = data & 0x00FF_FFFF,
+                0x4 => self.block_ctrls[major as usize] = data,
+                0x8 if major == DmaPort::Otc as u32 => {
+                    let data = (data & OTC_CHANNEL_WRITABLE) | OTC_CHANNEL_BACKWARD;
+                    self.channels[major as usize] = DmaChannel::from(data)
                 }
-            }
-            7 => match minor {
-                0x0 => T::from_u32(self.control),
-                0x4 => T::from_u32(self.interrupt),
                 0x8 => {
-                    debug!(target: "dma", "Attempt to use unknown DMA register 1");
-                    T::from_u32(self.unknown_1)
+                    let channel = DmaChannel::from(data);
+                    if channel.get_sync_type().is_none() {
+                        warn!(
+                            target: "dma",
+                            "{:?} set to the reserved sync mode 3, ignoring it",
+                            DmaPort::from(major as usize)
+                        );
+                    }
+                    self.channels[major as usize] = channel;
                 }
                 0xC => {
-                    debug!(target: "dma", "Attempt to use unknown DMA register 2");
-                    T::from_u32(self.unknown_2)
+                    debug!(target: "dma", "Attempt to write unused DMA channel register");
                 }
                 _ => unreachable!(),
             },
-            _ => unreachable!(),
-        })
-    }
-
-    fn write<T: SizedData>(&mut self, addr: u32, data: T) {
-        if T::width() != 4 {
-            todo!("Other bit widths");
-        }
-        let major = (addr & 0x70) >> 4;
-        let minor = addr & 0x0F;
-        match major {
-            0 | 1 | 2 | 3 | 4 | 5 | 6 => match minor {
-                0x0 => todo!(),
-                0x4 => todo!(),
-                0x8 => self.channels[major as usize] = DmaChannel::from(data.to_u32()),
-                0xC => todo!(),
-                _ => unreachable!(),
-            },
             7 => match minor {
-                0x0 => self.control = data.to_u32(),
-                0x4 => self.interrupt = data.to_u32(),
+                0x0 => self.control = data,
+                0x4 => {
+                    // writing 1 to a channel flag acknowledges it
+                    let acked = data & DICR_CHANNEL_IRQ_FLAGS;
+                    let flags = self.interrupt & DICR_CHANNEL_IRQ_FLAGS & !acked;
+                    self.interrupt = (data & DICR_READ_WRITE) | flags;
+                    self.update_master_irq_flag();
+                }
                 0x8 => {
                     debug!(target: "dma", "Attempt to use unknown DMA register 1");
-                    self.unknown_1 = data.to_u32()
+                    self.unknown_1 = data
                 }
                 0xC => {
                     debug!(target: "dma", "Attempt to use unknown DMA register 2");
-                    self.unknown_2 = data.to_u32()
+                    self.unknown_2 = data
                 }
                 _ => unreachable!(),
             },
@@ -131,3 +214,333 @@ impl BusDevice for DmaController {
         }
     }
 }
+
+impl BusDevice for DmaController {
+    fn read<T: SizedData>(&mut self, addr: u32) -> T {
+        T::from_u32(self.read_reg(addr) >> ((addr & 0x3) * 8))
+    }
+
+    fn peek<T: SizedData>(&self, addr: u32) -> Option<T> {
+        Some(T::from_u32(self.read_reg(addr) >> ((addr & 0x3) * 8)))
+    }
+
+    fn write<T: SizedData>(&mut self, addr: u32, data: T) {
+        let data = if T::width() == 4 {
+            data.to_u32()
+        } else {
+            // merge narrower writes into the existing register value
+            let shift = (addr & 0x3) * 8;
+            let mask = ((1u32 << (T::width() * 8)) - 1) << shift;
+            (self.read_reg(addr) & !mask) | (data.to_u32() << shift)
+        };
+        self.write_reg(addr, data);
+    }
+}
+
+/// Run any DMA transfers that are ready to go
+///
+/// Transfers happen instantly, and the CPU is not stalled while they run.
+pub fn exec<T: WithDma + BusDevice>(mb: &mut T) {
+    for idx in 0..7 {
+        let port = DmaPort::from(idx);
+        if !mb.dma().is_channel_active(port) {
+            continue;
+        }
+        let channel = mb.dma().get_channel(port);
+        debug!(target: "dma", "Starting DMA transfer on {:?}: {:?}", port, channel);
+        if port == DmaPort::Otc {
+            transfer_otc(mb);
+        } else {
+            match channel.get_sync_type() {
+                Some(DmaChannelSync::Manual) | Some(DmaChannelSync::Request) => {
+                    transfer_block(mb, port, channel)
+                }
+                Some(DmaChannelSync::LinkedList) => transfer_linked_list(mb, port, channel),
+                None => {
+                    warn!(target: "dma", "{:?} uses the reserved sync mode 3, ignoring", port);
+                    continue;
+                }
+            }
+        }
+        mb.dma_mut().complete(port);
+    }
+}
+
+fn transfer_block<T: WithDma + BusDevice>(mb: &mut T, port: DmaPort, channel: DmaChannel) {
+    let step = match channel.get_iter_dir() {
+        DmaChannelIteration::Forward => 4u32,
+        DmaChannelIteration::Backward => (-4i32) as u32,
+    };
+    let block_ctrl = mb.dma().block_ctrls[port as usize];
+    let block_size = match block_ctrl & 0xFFFF {
+        0 => 0x1_0000,
+        size => size,
+    };
+    let words = match channel.get_sync_type() {
+        Some(DmaChannelSync::Manual) => block_size,
+        _ => block_size * (block_ctrl >> 16),
+    };
+
+    let mut addr = mb.dma().base_addrs[port as usize];
+    for _ in 0..words {
+        let ram_addr = addr & RAM_ADDR_MASK;
+        match channel.get_direction() {
+            DmaChannelDirection::RamToDevice => {
+                let data = mb.read::<u32>(ram_addr);
+                mb.dma_write(port, data);
+            }
+            DmaChannelDirection::DeviceToRam => {
+                let data = mb.dma_read(port);
+                mb.write::<u32>(ram_addr, data);
+            }
+        }
+        addr = addr.wrapping_add(step);
+    }
+
+    // Sync mode 1 transfers update the address and block count as they go
+    if channel.get_sync_type() == Some(DmaChannelSync::Request) {
+        let dma = mb.dma_mut();
+        dma.base_addrs[port as usize] = addr & 0x00FF_FFFF;
+        dma.block_ctrls[port as usize] &= 0x0000_FFFF;
+    }
+}
+
+/// Clear an ordering table, by building a linked list of empty GPU packets
+///
+/// Each entry points to the word before it, and the last (lowest) entry
+/// terminates the list.
+fn transfer_otc<T: WithDma + BusDevice>(mb: &mut T) {
+    let port = DmaPort::Otc as usize;
+    let words = match mb.dma().block_ctrls[port] & 0xFFFF {
+        0 => 0x1_0000,
+        size => size,
+    };
+    let mut addr = mb.dma().base_addrs[port] & RAM_ADDR_MASK;
+    for i in 0..words {
+        let next = addr.wrapping_sub(4) & RAM_ADDR_MASK;
+        let data = if i == words - 1 {
+            OTC_END_OF_TABLE
+        } else {
+            next
+        };
+        mb.write::<u32>(addr, data);
+        addr = next;
+    }
+}
+
+fn transfer_linked_list<T: WithDma + BusDevice>(mb: &mut T, port: DmaPort, channel: DmaChannel) {
+    if channel.get_direction() != DmaChannelDirection::RamToDevice {
+        warn!(target: "dma", "Linked list DMA to RAM is unsupported, ignoring");
+        return;
+    }
+    let mut addr = mb.dma().base_addrs[port as usize] & RAM_ADDR_MASK;
+    let mut nodes = 0;
+    loop {
+        if nodes == LINKED_LIST_MAX_NODES {
+            warn!(
+                target: "dma",
+                "Linked list DMA on {:?} never ended, stopping at ${:06X}",
+                port,
+                addr
+            );
+            break;
+        }
+        nodes += 1;
+        let header = mb.read::<u32>(addr);
+        let words = header >> 24;
+        for i in 1..=words {
+            let data = mb.read::<u32>((addr + i * 4) & RAM_ADDR_MASK);
+            mb.dma_write(port, data);
+        }
+        if header & LINKED_LIST_END != 0 {
+            break;
+        }
+        addr = header & RAM_ADDR_MASK;
+    }
+    mb.dma_mut().base_addrs[port as usize] = 0x00FF_FFFF;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::ram::Ram;
+
+    /// A minimal bus with some RAM and a recording device on every port
+    struct TestBus {
+        dma: DmaController,
+        ram: Ram,
+        written: Vec<u32>,
+    }
+
+    impl TestBus {
+        fn new() -> TestBus {
+            let mut dma = DmaController::new();
+            // enable every channel in DPCR
+            dma.write(0x70, 0x0FFF_FFFFu32);
+            TestBus {
+                dma,
+                ram: Ram::with_size(2 * 1024 * 1024),
+                written: vec![],
+            }
+        }
+
+        /// Write a channel's registers, then run any ready transfers
+        fn start(&mut self, port: DmaPort, madr: u32, bcr: u32, chcr: u32) {
+            let base = (port as u32) * 0x10;
+            self.dma.write(base, madr);
+            self.dma.write(base + 0x4, bcr);
+            self.dma.write(base + 0x8, chcr);
+            exec(self);
+        }
+    }
+
+    impl WithDma for TestBus {
+        fn dma(&self) -> &DmaController {
+            &self.dma
+        }
+        fn dma_mut(&mut self) -> &mut DmaController {
+            &mut self.dma
+        }
+        fn dma_read(&mut self, _port: DmaPort) -> u32 {
+            0xCAFE_0000 | self.written.len() as u32
+        }
+        fn dma_write(&mut self, _port: DmaPort, data: u32) {
+            self.written.push(data);
+        }
+    }
+
+    impl BusDevice for TestBus {
+        fn read<T: SizedData>(&mut self, addr: u32) -> T {
+            self.ram.read(addr)
+        }
+        fn peek<T: SizedData>(&self, addr: u32) -> Option<T> {
+            self.ram.peek(addr)
+        }
+        fn write<T: SizedData>(&mut self, addr: u32, data: T) {
+            self.ram.write(addr, data)
+        }
+    }
+
+    #[test]
+    fn decodes_channel_control() {
+        let channel = DmaChannel::from(0x0100_0201);
+        assert_eq!(channel.get_direction(), DmaChannelDirection::RamToDevice);
+        assert_eq!(channel.get_iter_dir(), DmaChannelIteration::Forward);
+        assert_eq!(channel.get_sync_type(), Some(DmaChannelSync::Request));
+        assert!(channel.is_enabled());
+    }
+
+    #[test]
+    fn transfers_manual_block_to_device() {
+        let mut bus = TestBus::new();
+        for i in 0..4u32 {
+            bus.ram.write(0x100 + i * 4, i + 1);
+        }
+        bus.start(DmaPort::Gpu, 0x100, 4, 0x1100_0001);
+        assert_eq!(bus.written, vec![1, 2, 3, 4]);
+        assert!(!bus.dma.get_channel(DmaPort::Gpu).is_enabled());
+    }
+
+    #[test]
+    fn waits_for_manual_trigger() {
+        let mut bus = TestBus::new();
+        bus.start(DmaPort::Gpu, 0x100, 4, 0x0100_0001);
+        assert!(bus.written.is_empty());
+        assert!(bus.dma.get_channel(DmaPort::Gpu).is_enabled());
+    }
+
+    #[test]
+    fn transfers_request_blocks_to_ram() {
+        let mut bus = TestBus::new();
+        // 2 blocks of 2 words
+        bus.start(DmaPort::CdRom, 0x200, 0x0002_0002, 0x0100_0200);
+        for i in 0..4u32 {
+            assert_eq!(bus.ram.read::<u32>(0x200 + i * 4), 0xCAFE_0000);
+        }
+        assert_eq!(bus.dma.read::<u32>(0x30), 0x210, "MADR should advance");
+        assert_eq!(
+            bus.dma.read::<u32>(0x34),
+            0x0000_0002,
+            "BCR should count down"
+        );
+    }
+
+    #[test]
+    fn walks_linked_lists() {
+        let mut bus = TestBus::new();
+        // two packets: 2 words at 0x100 pointing to 0x200, and 1 word at 0x200
+        bus.ram.write(0x100, 0x0200_0200u32);
+        bus.ram.write(0x104, 0xAAAA_AAAAu32);
+        bus.ram.write(0x108, 0xBBBB_BBBBu32);
+        bus.ram.write(0x200, 0x01FF_FFFFu32);
+        bus.ram.write(0x204, 0xCCCC_CCCCu32);
+        bus.start(DmaPort::Gpu, 0x100, 0, 0x0100_0401);
+        assert_eq!(bus.written, vec![0xAAAA_AAAA, 0xBBBB_BBBB, 0xCCCC_CCCC]);
+    }
+
+    #[test]
+    fn stops_cyclic_linked_lists() {
+        let mut bus = TestBus::new();
+        // a packet at 0x100 pointing to one at 0x200, which points back
+        bus.ram.write(0x100, 0x0100_0200u32);
+        bus.ram.write(0x104, 0xAAAA_AAAAu32);
+        bus.ram.write(0x200, 0x0000_0100u32);
+        bus.start(DmaPort::Gpu, 0x100, 0, 0x0100_0401);
+        assert_eq!(bus.written.len() as u32, LINKED_LIST_MAX_NODES / 2);
+        assert!(!bus.dma.get_channel(DmaPort::Gpu).is_enabled());
+    }
+
+    #[test]
+    fn ignores_reserved_sync_mode() {
+        let mut bus = TestBus::new();
+        bus.start(DmaPort::Gpu, 0x100, 4, 0x1100_0601);
+        assert_eq!(bus.dma.get_channel(DmaPort::Gpu).get_sync_type(), None);
+        assert!(bus.written.is_empty());
+        assert!(bus.dma.get_channel(DmaPort::Gpu).is_enabled());
+    }
+
+    #[test]
+    fn clears_ordering_tables() {
+        let mut bus = TestBus::new();
+        bus.start(DmaPort::Otc, 0x1010, 4, 0x1100_0000);
+        assert_eq!(bus.ram.read::<u32>(0x1010), 0x0000_100C);
+        assert_eq!(bus.ram.read::<u32>(0x100C), 0x0000_1008);
+        assert_eq!(bus.ram.read::<u32>(0x1008), 0x0000_1004);
+        assert_eq!(bus.ram.read::<u32>(0x1004), 0x00FF_FFFF);
+        assert_eq!(bus.ram.read::<u32>(0x1000), 0, "OTC wrote past the table");
+        assert!(bus.written.is_empty(), "OTC should not talk to any device");
+        assert!(!bus.dma.get_channel(DmaPort::Otc).is_enabled());
+    }
+
+    #[test]
+    fn clears_single_entry_ordering_table() {
+        let mut bus = TestBus::new();
+        bus.ram.write(0x0FFC, 0xDEAD_BEEFu32);
+        bus.start(DmaPort::Otc, 0x1000, 1, 0x1100_0000);
+        assert_eq!(bus.ram.read::<u32>(0x1000), 0x00FF_FFFF);
+        assert_eq!(bus.ram.read::<u32>(0x0FFC), 0xDEAD_BEEF);
+    }
+
+    #[test]
+    fn forces_otc_channel_control_bits() {
+        let mut dma = DmaController::new();
+        dma.write(0x68, 0xFFFF_FFFFu32);
+        assert_eq!(dma.read::<u32>(0x68), 0x5100_0002);
+        dma.write(0x68, 0u32);
+        assert_eq!(dma.read::<u32>(0x68), 0x0000_0002);
+    }
+
+    #[test]
+    fn raises_completion_interrupts() {
+        let mut bus = TestBus::new();
+        // enable IRQs for the GPU channel, and the master enable
+        bus.dma.write(0x74, 0x0084_0000u32);
+        bus.start(DmaPort::Gpu, 0x100, 1, 0x1100_0001);
+        assert!(bus.dma.take_irq());
+        assert_eq!(bus.dma.read::<u32>(0x74), 0x8484_0000);
+        // acknowledge the GPU channel flag
+        bus.dma.write(0x74, 0x0484_0000u32);
+        assert_eq!(bus.dma.read::<u32>(0x74), 0x0084_0000);
+        assert!(!bus.dma.take_irq());
+    }
+}
@@ -1,5 +1,5 @@
 mod controller;
 mod structs;
 
-pub use self::controller::DmaController;
+pub use self::controller::{exec, DmaController, WithDma};
 pub use self::structs::*;
@@ -57,10 +57,10 @@ pub enum DmaChannelSync {
 const DMA_CHANNEL_UNUSED: u32 = 0x8E88_F8FC;
 const DMA_CHANNEL_TRANSFER: u32 = 0x0000_0001;
 const DMA_CHANNEL_INCREMENT: u32 = 0x0000_0002;
-const DMA_CHANNEL_CHOPPING: u32 = 0x0000_0004;
-const DMA_CHANNEL_SYNC_TYPE: u32 = 0x0000_0100;
-const DMA_CHANNEL_CHOP_DMA_WINDOW: u32 = 0x0000_0600;
-const DMA_CHANNEL_CHOP_CPU_WINDOW: u32 = 0x0007_0000;
+const DMA_CHANNEL_CHOPPING: u32 = 0x0000_0100;
+const DMA_CHANNEL_SYNC_TYPE: u32 = 0x0000_0600;
+const DMA_CHANNEL_CHOP_DMA_WINDOW: u32 = 0x0007_0000;
+const DMA_CHANNEL_CHOP_CPU_WINDOW: u32 = 0x0070_0000;
 const DMA_CHANNEL_ENABLE: u32 = 0x0100_0000;
 const DMA_CHANNEL_MANUAL_TRIGGER: u32 = 0x1000_0000;
 const DMA_CHANNEL_UNKNOWN: u32 = 0x6000_0000;
@@ -71,8 +71,8 @@ pub struct DmaChannel(u32);
 impl DmaChannel {
     pub fn get_direction(&self) -> DmaChannelDirection {
         return match **self & DMA_CHANNEL_TRANSFER {
-            0 => DmaChannelDirection::RamToDevice,
-            1 => DmaChannelDirection::DeviceToRam,
+            0 => DmaChannelDirection::DeviceToRam,
+            1 => DmaChannelDirection::RamToDevice,
             _ => unreachable!(),
         };
     }
@@ -86,16 +86,17 @@ impl DmaChannel {
     }
 
     pub fn is_chop_enabled(&self) -> bool {
-        return ((**self & DMA_CHANNEL_CHOPPING) >> 2) == 1;
+        return ((**self & DMA_CHANNEL_CHOPPING) >> 8) == 1;
     }
 
-    pub fn get_sync_type(&self) -> DmaChannelSync {
+    /// Return the channel's sync mode, or None for the reserved mode 3
+    pub fn get_sync_type(&self) -> Option<DmaChannelSync> {
         return match (**self & DMA_CHANNEL_SYNC_TYPE) >> 9 {
-            0 => DmaChannelSync::Manual,
-            1 => DmaChannelSync::Request,
-            2 => DmaChannelSync::LinkedList,
+            0 => Some(DmaChannelSync::Manual),
+            1 => Some(DmaChannelSync::Request),
+            2 => Some(DmaChannelSync::LinkedList),
             // I have no idea what actual hardware does in this case
-            3 => panic!("DMA controller attempting to use reserved sync mode"),
+            3 => None,
             _ => unreachable!(),
         };
     }
@@ -116,8 +117,29 @@ impl DmaChannel {
         return ((**self & DMA_CHANNEL_MANUAL_TRIGGER) >> 28) == 1;
     }
 
+    /// Return whether the channel should start transferring
+    ///
+    /// Manual sync transfers also need the trigger bit to be set, and
+    /// channels set to the reserved sync mode are ignored
+    pub fn is_ready(&self) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        return match self.get_sync_type() {
+            Some(DmaChannelSync::Manual) => self.is_manually_triggered(),
+            Some(_) => true,
+            // the controller warns about these when CHCR is written
+            None => false,
+        };
+    }
+
+    /// Clear the enable and trigger bits, signaling that the transfer is done
+    pub fn set_completed(&mut self) {
+        self.0 &= !(DMA_CHANNEL_ENABLE | DMA_CHANNEL_MANUAL_TRIGGER);
+    }
+
     pub fn get_unknown_bits(&self) -> u8 {
-        return (0xFF & ((**self * DMA_CHANNEL_UNKNOWN) >> 29)) as u8;
+        return (0xFF & ((**self & DMA_CHANNEL_UNKNOWN) >> 29)) as u8;
     }
 }
 
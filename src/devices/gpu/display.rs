@@ -0,0 +1,82 @@
+//! Reading the visible display area back out of VRAM
+
+use super::vram::Vram;
+
+/// A frame of video output, as packed 24-bit RGB
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    /// Pixel data, 3 bytes per pixel, line by line
+    pub pixels: Vec<u8>,
+}
+
+/// Copy a region of VRAM into a frame
+///
+/// In 24-bit mode, each pixel is 3 bytes packed across VRAM halfwords, so the
+/// region covers 1.5 times as many halfwords as it has pixels. The display
+/// start X is still given in halfwords.
+pub fn read_display_area(
+    vram: &Vram,
+    (start_x, start_y): (u32, u32),
+    width: u32,
+    height: u32,
+    is_24bit: bool,
+) -> Frame {
+    let mut pixels = Vec::with_capacity((width * height * 3) as usize);
+    for line in 0..height {
+        let y = start_y + line;
+        for col in 0..width {
+            if is_24bit {
+                let byte_offset = start_x * 2 + col * 3;
+                for i in 0..3 {
+                    let byte = byte_offset + i;
+                    let halfword = vram.get_pixel(byte / 2, y);
+                    pixels.push((halfword >> ((byte % 2) * 8)) as u8);
+                }
+            } else {
+                let pixel = vram.get_pixel(start_x + col, y);
+                pixels.extend_from_slice(&rgb555_to_rgb888(pixel));
+            }
+        }
+    }
+    return Frame {
+        width,
+        height,
+        pixels,
+    };
+}
+
+/// Expand a 15-bit VRAM pixel to 24-bit color
+pub fn rgb555_to_rgb888(pixel: u16) -> [u8; 3] {
+    let expand = |c: u16| -> u8 {
+        let c = (c & 0x1F) as u8;
+        // replicate the top bits into the bottom, so 0x1F maps to 0xFF
+        (c << 3) | (c >> 2)
+    };
+    return [expand(pixel), expand(pixel >> 5), expand(pixel >> 10)];
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_15bit_display_area() {
+        let mut vram = Vram::new();
+        vram.set_pixel(10, 20, 0x001F);
+        vram.set_pixel(11, 20, 0x7C00);
+        let frame = read_display_area(&vram, (10, 20), 2, 1, false);
+        assert_eq!(frame.pixels, vec![0xFF, 0, 0, 0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn reads_24bit_display_area() {
+        let mut vram = Vram::new();
+        vram.set_pixel(0, 0, 0x2211);
+        vram.set_pixel(1, 0, 0x4433);
+        vram.set_pixel(2, 0, 0x6655);
+        let frame = read_display_area(&vram, (0, 0), 2, 1, true);
+        assert_eq!(frame.pixels, vec![0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+    }
+}
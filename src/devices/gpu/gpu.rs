@@ -1,34 +1,885 @@
-use crate::devices::bus::BusDevice;
+use super::display::{self, Frame};
+use super::rasterizer::{self, sign_extend_11, DrawContext, PrimitiveAttributes, Vertex};
+use super::structs::{DisplayMode, DrawMode, GpuDmaDirection, DRAW_MODE_STATUS_BITS};
+use super::timing::{VideoSignals, VideoTiming};
+use super::vram::{Vram, VramTransfer, MASK_BIT};
+use crate::devices::bus::{BusDevice, SizedData};
 use log::debug;
 
+const GP0_PORT: u32 = 0x0;
+const GP1_PORT: u32 = 0x4;
+
+//#region GPUSTAT bits
+const GPUSTAT_TEXTURE_DISABLE: u32 = 0x0000_8000;
+const GPUSTAT_SET_MASK: u32 = 0x0000_0800;
+const GPUSTAT_CHECK_MASK: u32 = 0x0000_1000;
+const GPUSTAT_INTERLACE_FIELD: u32 = 0x0000_2000;
+const GPUSTAT_DISPLAY_DISABLE: u32 = 0x0080_0000;
+const GPUSTAT_IRQ: u32 = 0x0100_0000;
+const GPUSTAT_DMA_REQUEST: u32 = 0x0200_0000;
+const GPUSTAT_READY_FOR_COMMAND: u32 = 0x0400_0000;
+const GPUSTAT_READY_FOR_VRAM_SEND: u32 = 0x0800_0000;
+const GPUSTAT_READY_FOR_DMA_BLOCK: u32 = 0x1000_0000;
+const GPUSTAT_DRAWING_ODD_LINE: u32 = 0x8000_0000;
+//#endregion
+
+/// Polyline vertex lists are terminated by a word matching this mask
+const POLYLINE_TERMINATOR_MASK: u32 = 0xF000_F000;
+const POLYLINE_TERMINATOR: u32 = 0x5000_5000;
+
 /// A trait for devices that own a GPU, such as the Motherboard
 pub trait WithGpu {
     fn gpu(&self) -> &Gpu;
     fn gpu_mut(&mut self) -> &mut Gpu;
 }
 
+/// What the GPU is expecting to receive on GP0
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum Gp0Mode {
+    /// Collecting the words of a command
+    Command,
+    /// Collecting polyline vertices until a terminator arrives
+    Polyline,
+    /// Receiving pixel data for a CPU-to-VRAM transfer
+    ImageLoad(VramTransfer),
+}
+
 /// The 32-bit Toshiba custom GPU used on the PSX
 ///
-/// For now, this is just a mock
-pub struct Gpu {}
+/// Commands are written to GP0 (rendering and VRAM access) or GP1 (display
+/// control), and GPUREAD/GPUSTAT are read back from the same ports. GP0
+/// commands are executed as soon as their last parameter arrives, so the
+/// command FIFO never actually fills up.
+pub struct Gpu {
+    timing: VideoTiming,
+    vram: Vram,
+    //#region GP0 state
+    /// The words of the GP0 command currently being received
+    gp0_buffer: Vec<u32>,
+    /// The number of words the current GP0 command needs
+    gp0_command_length: usize,
+    gp0_mode: Gp0Mode,
+    /// GP0 E1h
+    draw_mode: DrawMode,
+    /// GP0 E2h
+    texture_window: u32,
+    /// GP0 E3h
+    draw_area_top_left: u32,
+    /// GP0 E4h
+    draw_area_bottom_right: u32,
+    /// GP0 E5h
+    draw_offset: u32,
+    /// Whether drawn pixels have their mask bit set (GP0 E6h)
+    set_mask_bit: bool,
+    /// Whether pixels with the mask bit set are protected (GP0 E6h)
+    check_mask_bit: bool,
+    //#endregion
+    //#region GP1 state
+    display_disabled: bool,
+    dma_direction: GpuDmaDirection,
+    /// The top-left corner of the display area in VRAM
+    display_start: (u32, u32),
+    /// The horizontal display range on screen, in video clock cycles
+    display_range_x: (u32, u32),
+    /// The vertical display range on screen, in scanlines
+    display_range_y: (u32, u32),
+    display_mode: DisplayMode,
+    /// Whether GP0 E1h may set the texture disable bit (GP1 09h)
+    allow_texture_disable: bool,
+    //#endregion
+    /// The value returned by reads from GPUREAD
+    gpuread: u32,
+    /// The in-progress VRAM-to-CPU transfer, if any
+    image_store: Option<VramTransfer>,
+    /// The interrupt flag set by GP0 1Fh
+    irq: bool,
+    /// Whether the interrupt flag went high since the last check
+    irq_pending: bool,
+}
 
 impl Gpu {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Gpu {
-        Gpu {}
+        let mut gpu = Gpu {
+            timing: VideoTiming::new(),
+            vram: Vram::new(),
+            gp0_buffer: Vec::with_capacity(16),
+            gp0_command_length: 0,
+            gp0_mode: Gp0Mode::Command,
+            draw_mode: DrawMode::from(0),
+            texture_window: 0,
+            draw_area_top_left: 0,
+            draw_area_bottom_right: 0,
+            draw_offset: 0,
+            set_mask_bit: false,
+            check_mask_bit: false,
+            display_disabled: true,
+            dma_direction: GpuDmaDirection::Off,
+            display_start: (0, 0),
+            display_range_x: (0, 0),
+            display_range_y: (0, 0),
+            display_mode: DisplayMode::from(0),
+            allow_texture_disable: false,
+            gpuread: 0,
+            image_store: None,
+            irq: false,
+            irq_pending: false,
+        };
+        gpu.gp1_reset();
+        gpu
+    }
+
+    /// Advance the video beam by the given number of CPU cycles
+    pub fn tick(&mut self, cycles: u32) -> VideoSignals {
+        self.timing.tick(cycles)
+    }
+
+    pub fn vram(&self) -> &Vram {
+        &self.vram
+    }
+
+    /// Read the currently displayed area of VRAM
+    pub fn get_display_frame(&self) -> Frame {
+        let width = self.display_mode.get_horizontal_res();
+        let (y1, y2) = self.display_range_y;
+        let mut height = y2.saturating_sub(y1);
+        if self.display_mode.is_interlaced() && self.display_mode.is_vres_480() {
+            height *= 2;
+        }
+        let height = height.clamp(1, 512);
+        return display::read_display_area(
+            &self.vram,
+            self.display_start,
+            width,
+            height,
+            self.display_mode.is_24bit(),
+        );
+    }
+
+    /// Return, and clear, whether a GPU interrupt should be raised
+    pub fn take_irq(&mut self) -> bool {
+        let irq = self.irq_pending;
+        self.irq_pending = false;
+        irq
+    }
+
+    /// Return the current value of GPUSTAT
+    pub fn get_status(&self) -> u32 {
+        let mut status = *self.draw_mode & DRAW_MODE_STATUS_BITS;
+        if self.draw_mode.is_texture_disabled() {
+            status |= GPUSTAT_TEXTURE_DISABLE;
+        }
+        if self.set_mask_bit {
+            status |= GPUSTAT_SET_MASK;
+        }
+        if self.check_mask_bit {
+            status |= GPUSTAT_CHECK_MASK;
+        }
+        if !self.display_mode.is_interlaced() || self.timing.is_odd_field() {
+            status |= GPUSTAT_INTERLACE_FIELD;
+        }
+        status |= self.display_mode.get_status_bits();
+        if self.display_disabled {
+            status |= GPUSTAT_DISPLAY_DISABLE;
+        }
+        if self.irq {
+            status |= GPUSTAT_IRQ;
+        }
+
+        let ready_for_command = self.gp0_mode == Gp0Mode::Command && self.gp0_buffer.is_empty();
+        let ready_for_vram_send = self.image_store.is_some();
+        // commands run instantly, so there's always room for more
+        let ready_for_dma_block = true;
+        if ready_for_command {
+            status |= GPUSTAT_READY_FOR_COMMAND;
+        }
+        if ready_for_vram_send {
+            status |= GPUSTAT_READY_FOR_VRAM_SEND;
+        }
+        if ready_for_dma_block {
+            status |= GPUSTAT_READY_FOR_DMA_BLOCK;
+        }
+        let dma_request = match self.dma_direction {
+            GpuDmaDirection::Off => false,
+            GpuDmaDirection::Fifo => true,
+            GpuDmaDirection::CpuToGp0 => ready_for_dma_block,
+            GpuDmaDirection::GpuReadToCpu => ready_for_vram_send,
+        };
+        if dma_request {
+            status |= GPUSTAT_DMA_REQUEST;
+        }
+        status |= (self.dma_direction as u32) << 29;
+        // in 480-line mode this reports the field being drawn, otherwise it
+        // flips with every scanline, and it's always 0 during vblank
+        let is_odd = if self.display_mode.is_interlaced() && self.display_mode.is_vres_480() {
+            self.timing.is_odd_field()
+        } else {
+            self.timing.is_odd_line()
+        };
+        if is_odd && !self.timing.is_in_vblank() {
+            status |= GPUSTAT_DRAWING_ODD_LINE;
+        }
+        return status;
+    }
+
+    //#region GP0
+    fn gp0_write(&mut self, data: u32) {
+        match self.gp0_mode {
+            Gp0Mode::ImageLoad(mut transfer) => {
+                for pixel in [data as u16, (data >> 16) as u16] {
+                    // odd-sized transfers discard the last halfword
+                    if let Some((x, y)) = transfer.next_pixel() {
+                        self.put_pixel(x, y, pixel);
+                    }
+                }
+                self.gp0_mode = if transfer.is_done() {
+                    Gp0Mode::Command
+                } else {
+                    Gp0Mode::ImageLoad(transfer)
+                };
+            }
+            Gp0Mode::Polyline => {
+                let is_shaded = (self.gp0_buffer[0] & 0x1000_0000) != 0;
+                // the terminator is only recognized in place of the first
+                // word of a vertex, which is its color for shaded lines
+                let is_group_start = !is_shaded || self.gp0_buffer.len().is_multiple_of(2);
+                if is_group_start && (data & POLYLINE_TERMINATOR_MASK) == POLYLINE_TERMINATOR {
+                    self.gp0_buffer.clear();
+                    self.gp0_mode = Gp0Mode::Command;
+                    return;
+                }
+                self.gp0_buffer.push(data);
+                if self.gp0_buffer.len() == self.gp0_command_length {
+                    self.draw_line_segment();
+                    self.start_next_polyline_segment();
+                }
+            }
+            Gp0Mode::Command => {
+                if self.gp0_buffer.is_empty() {
+                    self.gp0_command_length = gp0_command_length(data >> 24);
+                }
+                self.gp0_buffer.push(data);
+                if self.gp0_buffer.len() == self.gp0_command_length {
+                    self.gp0_execute();
+                }
+            }
+        }
+    }
+
+    /// Run the command in the GP0 buffer, which has all of its parameters
+    fn gp0_execute(&mut self) {
+        let command = self.gp0_buffer[0];
+        let opcode = command >> 24;
+        match opcode {
+            0x00 | 0x03..=0x1E | 0xE0 | 0xE7..=0xFF => {
+                debug!(target: "gpu", "GP0 NOP 0x{:08X}", command)
+            }
+            0x01 => debug!(target: "gpu", "GP0 clear texture cache"),
+            0x1F => {
+                if !self.irq {
+                    self.irq_pending = true;
+                }
+                self.irq = true;
+            }
+            0x20..=0x3F => self.draw_polygon(),
+            0x02 => {
+                let x = self.gp0_buffer[1] & 0x3F0;
+                let y = (self.gp0_buffer[1] >> 16) & 0x1FF;
+                // the width is rounded up to a multiple of 16
+                let width = ((self.gp0_buffer[2] & 0x3FF) + 0xF) & !0xF;
+                let height = (self.gp0_buffer[2] >> 16) & 0x1FF;
+                rasterizer::fill_rectangle(&mut self.vram, x, y, width, height, command);
+            }
+            0x40..=0x47 | 0x50..=0x57 => self.draw_line_segment(),
+            0x48..=0x4F | 0x58..=0x5F => {
+                self.draw_line_segment();
+                // keep the last vertex around until the terminator arrives
+                self.start_next_polyline_segment();
+                self.gp0_mode = Gp0Mode::Polyline;
+                return;
+            }
+            0x60..=0x7F => self.draw_rectangle(),
+            0x80..=0x9F => self.copy_rectangle(),
+            0xA0..=0xBF => {
+                let (x, y) = get_transfer_position(self.gp0_buffer[1]);
+                let (width, height) = get_transfer_size(self.gp0_buffer[2]);
+                self.gp0_mode = Gp0Mode::ImageLoad(VramTransfer::new(x, y, width, height));
+            }
+            0xC0..=0xDF => {
+                let (x, y) = get_transfer_position(self.gp0_buffer[1]);
+                let (width, height) = get_transfer_size(self.gp0_buffer[2]);
+                self.image_store = Some(VramTransfer::new(x, y, width, height));
+            }
+            0xE1 => {
+                let mut data = command;
+                if !self.allow_texture_disable {
+                    data &= !0x0800;
+                }
+                self.draw_mode = DrawMode::from(data);
+            }
+            0xE2 => self.texture_window = command & 0x000F_FFFF,
+            0xE3 => self.draw_area_top_left = command & 0x000F_FFFF,
+            0xE4 => self.draw_area_bottom_right = command & 0x000F_FFFF,
+            0xE5 => self.draw_offset = command & 0x003F_FFFF,
+            0xE6 => {
+                self.set_mask_bit = (command & 0x1) != 0;
+                self.check_mask_bit = (command & 0x2) != 0;
+            }
+            _ => debug!(target: "gpu", "Unimplemented GP0 command 0x{:08X}", command),
+        }
+        self.gp0_buffer.clear();
+    }
+
+    /// Return the drawing state that applies to new primitives
+    fn draw_context(&self) -> DrawContext {
+        DrawContext {
+            draw_mode: self.draw_mode,
+            texture_window: self.texture_window,
+            clip_left: (self.draw_area_top_left & 0x3FF) as i32,
+            clip_top: ((self.draw_area_top_left >> 10) & 0x3FF) as i32,
+            clip_right: (self.draw_area_bottom_right & 0x3FF) as i32,
+            clip_bottom: ((self.draw_area_bottom_right >> 10) & 0x3FF) as i32,
+            offset_x: sign_extend_11(self.draw_offset),
+            offset_y: sign_extend_11(self.draw_offset >> 11),
+            set_mask_bit: self.set_mask_bit,
+            check_mask_bit: self.check_mask_bit,
+        }
+    }
+
+    /// Apply the texpage attribute of a textured primitive to the draw mode
+    fn set_texpage(&mut self, texpage: u32) {
+        let mut texpage = texpage;
+        if !self.allow_texture_disable {
+            texpage &= !0x0800;
+        }
+        self.draw_mode = self.draw_mode.with_texpage(texpage);
+    }
+
+    /// GP0 20h-3Fh, triangles and quads
+    fn draw_polygon(&mut self) {
+        let opcode = self.gp0_buffer[0] >> 24;
+        let mut attrs = PrimitiveAttributes::from_opcode(opcode);
+        let vertex_count = if (opcode & 0x08) != 0 { 4 } else { 3 };
+        let mut vertices = [Vertex::default(); 4];
+        let mut color = self.gp0_buffer[0];
+        let mut idx = 1;
+        for (i, vertex) in vertices.iter_mut().enumerate().take(vertex_count) {
+            if attrs.is_shaded && i > 0 {
+                color = self.gp0_buffer[idx];
+                idx += 1;
+            }
+            *vertex = Vertex::from_position(self.gp0_buffer[idx]).with_color(color);
+            idx += 1;
+            if attrs.is_textured {
+                let texcoord = self.gp0_buffer[idx];
+                idx += 1;
+                *vertex = vertex.with_texcoord(texcoord);
+                match i {
+                    0 => attrs = attrs.with_clut(texcoord),
+                    1 => self.set_texpage(texcoord >> 16),
+                    _ => {}
+                }
+            }
+        }
+        if self.draw_mode.is_texture_disabled() {
+            attrs.is_textured = false;
+        }
+
+        let ctx = self.draw_context();
+        for vertex in vertices.iter_mut() {
+            vertex.x += ctx.offset_x;
+            vertex.y += ctx.offset_y;
+        }
+        let [v0, v1, v2, v3] = vertices;
+        rasterizer::draw_triangle(&mut self.vram, &ctx, &attrs, [v0, v1, v2]);
+        if vertex_count == 4 {
+            rasterizer::draw_triangle(&mut self.vram, &ctx, &attrs, [v1, v2, v3]);
+        }
+    }
+
+    /// GP0 40h-5Fh, lines, drawn between the two vertices in the buffer
+    fn draw_line_segment(&mut self) {
+        let mut attrs = PrimitiveAttributes::from_opcode(self.gp0_buffer[0] >> 24);
+        attrs.is_textured = false;
+        let (color, position) = if attrs.is_shaded {
+            (self.gp0_buffer[2], self.gp0_buffer[3])
+        } else {
+            (self.gp0_buffer[0], self.gp0_buffer[2])
+        };
+        let ctx = self.draw_context();
+        let mut v0 = Vertex::from_position(self.gp0_buffer[1]).with_color(self.gp0_buffer[0]);
+        let mut v1 = Vertex::from_position(position).with_color(color);
+        for vertex in [&mut v0, &mut v1] {
+            vertex.x += ctx.offset_x;
+            vertex.y += ctx.offset_y;
+        }
+        rasterizer::draw_line(&mut self.vram, &ctx, &attrs, v0, v1);
+    }
+
+    /// Make the end of the last polyline segment the start of the next one
+    fn start_next_polyline_segment(&mut self) {
+        let command = self.gp0_buffer[0];
+        let is_shaded = (command & 0x1000_0000) != 0;
+        let last_vertex = if is_shaded {
+            [
+                (command & 0xFF00_0000) | (self.gp0_buffer[2] & 0x00FF_FFFF),
+                self.gp0_buffer[3],
+            ]
+        } else {
+            [command, self.gp0_buffer[2]]
+        };
+        self.gp0_buffer.clear();
+        self.gp0_buffer.extend_from_slice(&last_vertex);
+    }
+
+    /// GP0 60h-7Fh, rectangles and sprites
+    fn draw_rectangle(&mut self) {
+        let opcode = self.gp0_buffer[0] >> 24;
+        let mut attrs = PrimitiveAttributes::from_opcode(opcode);
+        // bits 3-4 are the size, rather than the shading
+        attrs.is_shaded = false;
+        let ctx = self.draw_context();
+        let mut origin = Vertex::from_position(self.gp0_buffer[1]).with_color(self.gp0_buffer[0]);
+        origin.x += ctx.offset_x;
+        origin.y += ctx.offset_y;
+        let mut idx = 2;
+        if attrs.is_textured {
+            origin = origin.with_texcoord(self.gp0_buffer[idx]);
+            attrs = attrs.with_clut(self.gp0_buffer[idx]);
+            idx += 1;
+        }
+        if self.draw_mode.is_texture_disabled() {
+            attrs.is_textured = false;
+        }
+        let (width, height) = match (opcode >> 3) & 0x3 {
+            0 => {
+                let size = self.gp0_buffer[idx];
+                ((size & 0x3FF) as i32, ((size >> 16) & 0x1FF) as i32)
+            }
+            1 => (1, 1),
+            2 => (8, 8),
+            3 => (16, 16),
+            _ => unreachable!(),
+        };
+        rasterizer::draw_rectangle(&mut self.vram, &ctx, &attrs, origin, width, height);
+    }
+
+    /// Write a pixel to VRAM, applying the mask bit settings from GP0 E6h
+    fn put_pixel(&mut self, x: u32, y: u32, data: u16) {
+        if self.check_mask_bit && (self.vram.get_pixel(x, y) & MASK_BIT) != 0 {
+            return;
+        }
+        let mask = if self.set_mask_bit { MASK_BIT } else { 0 };
+        self.vram.set_pixel(x, y, data | mask);
     }
+
+    /// GP0 80h, VRAM-to-VRAM copy
+    fn copy_rectangle(&mut self) {
+        let (src_x, src_y) = get_transfer_position(self.gp0_buffer[1]);
+        let (dst_x, dst_y) = get_transfer_position(self.gp0_buffer[2]);
+        let (width, height) = get_transfer_size(self.gp0_buffer[3]);
+        for row in 0..height {
+            for col in 0..width {
+                let pixel = self.vram.get_pixel(src_x + col, src_y + row);
+                self.put_pixel(dst_x + col, dst_y + row, pixel);
+            }
+        }
+    }
+
+    /// Read the next two pixels of a VRAM-to-CPU transfer into GPUREAD
+    fn read_image_store(&mut self) {
+        let mut transfer = match self.image_store {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        let mut data = 0u32;
+        for shift in [0, 16] {
+            if let Some((x, y)) = transfer.next_pixel() {
+                data |= (self.vram.get_pixel(x, y) as u32) << shift;
+            }
+        }
+        self.gpuread = data;
+        self.image_store = if transfer.is_done() {
+            None
+        } else {
+            Some(transfer)
+        };
+    }
+    //#endregion
+
+    //#region GP1
+    fn gp1_write(&mut self, data: u32) {
+        let opcode = (data >> 24) & 0x3F;
+        match opcode {
+            0x00 => self.gp1_reset(),
+            0x01 => {
+                self.gp0_buffer.clear();
+                self.gp0_mode = Gp0Mode::Command;
+                self.image_store = None;
+            }
+            0x02 => self.irq = false,
+            0x03 => self.display_disabled = (data & 0x1) != 0,
+            0x04 => self.dma_direction = GpuDmaDirection::from(data),
+            0x05 => self.display_start = (data & 0x3FE, (data >> 10) & 0x1FF),
+            0x06 => self.display_range_x = (data & 0xFFF, (data >> 12) & 0xFFF),
+            0x07 => self.display_range_y = (data & 0x3FF, (data >> 10) & 0x3FF),
+            0x08 => {
+                self.display_mode = DisplayMode::from(data);
+                self.timing.set_display_mode(&self.display_mode);
+            }
+            0x09 => self.allow_texture_disable = (data & 0x1) != 0,
+            0x10..=0x1F => self.gp1_get_info(data),
+            _ => debug!(target: "gpu", "Unimplemented GP1 command 0x{:08X}", data),
+        }
+    }
+
+    fn gp1_reset(&mut self) {
+        self.gp1_write(0x0100_0000);
+        self.gp1_write(0x0200_0000);
+        self.gp1_write(0x0300_0001);
+        self.gp1_write(0x0400_0000);
+        self.gp1_write(0x0500_0000);
+        self.gp1_write(0x06C0_0200);
+        self.gp1_write(0x0704_0010);
+        self.gp1_write(0x0800_0000);
+        for command in 0xE1..=0xE6 {
+            self.gp0_write(command << 24);
+        }
+    }
+
+    /// Latch an internal register into GPUREAD
+    fn gp1_get_info(&mut self, data: u32) {
+        self.gpuread = match data & 0xF {
+            0x2 => self.texture_window,
+            0x3 => self.draw_area_top_left,
+            0x4 => self.draw_area_bottom_right,
+            0x5 => self.draw_offset,
+            // GPU version (the 160-pin GPU reports 2)
+            0x7 => 0x0000_0002,
+            0x8 => 0,
+            // everything else leaves GPUREAD unchanged
+            _ => self.gpuread,
+        };
+    }
+    //#endregion
+}
+
+/// Return the number of words (including the command) in a GP0 command
+///
+/// Polylines return the length of their first two vertices, and image loads
+/// return the length of their header.
+fn gp0_command_length(opcode: u32) -> usize {
+    return match opcode {
+        0x02 => 3,
+        0x20..=0x3F => {
+            let vertices = if (opcode & 0x08) != 0 { 4 } else { 3 };
+            let is_textured = (opcode & 0x04) != 0;
+            let is_shaded = (opcode & 0x10) != 0;
+            let vertex_words = if is_textured { 2 } else { 1 };
+            let color_words = if is_shaded { vertices - 1 } else { 0 };
+            1 + vertices * vertex_words + color_words
+        }
+        0x40..=0x5F => {
+            let is_shaded = (opcode & 0x10) != 0;
+            if is_shaded {
+                4
+            } else {
+                3
+            }
+        }
+        0x60..=0x7F => {
+            let is_textured = (opcode & 0x04) != 0;
+            let is_variable_size = (opcode & 0x18) == 0;
+            2 + is_textured as usize + is_variable_size as usize
+        }
+        0x80..=0x9F => 4,
+        0xA0..=0xDF => 3,
+        _ => 1,
+    };
+}
+
+/// Decode the VRAM position of a transfer
+fn get_transfer_position(data: u32) -> (u32, u32) {
+    return (data & 0x3FF, (data >> 16) & 0x1FF);
+}
+
+/// Decode the size of a VRAM transfer, where 0 means the maximum
+fn get_transfer_size(data: u32) -> (u32, u32) {
+    let width = ((data & 0xFFFF).wrapping_sub(1) & 0x3FF) + 1;
+    let height = ((data >> 16).wrapping_sub(1) & 0x1FF) + 1;
+    return (width, height);
 }
 
 impl BusDevice for Gpu {
-    fn read<T: crate::devices::bus::SizedData>(&mut self, addr: u32) -> T {
-        debug!(target: "gpu", "Read from GP{}", addr / 4);
-        // mock the DMAREADY flag
-        T::from_u32(0x1000_0000)
+    fn read<T: SizedData>(&mut self, addr: u32) -> T {
+        T::from_u32(match addr {
+            GP0_PORT => {
+                self.read_image_store();
+                self.gpuread
+            }
+            GP1_PORT => self.get_status(),
+            _ => unreachable!(),
+        })
+    }
+
+    fn peek<T: SizedData>(&self, addr: u32) -> Option<T> {
+        Some(T::from_u32(match addr {
+            GP0_PORT => self.gpuread,
+            GP1_PORT => self.get_status(),
+            _ => return None,
+        }))
+    }
+
+    fn write<T: SizedData>(&mut self, addr: u32, data: T) {
+        match addr {
+            GP0_PORT => self.gp0_write(data.to_u32()),
+            GP1_PORT => self.gp1_write(data.to_u32()),
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn is_ready_for_command(gpu: &mut Gpu) -> bool {
+        (gpu.read::<u32>(GP1_PORT) & GPUSTAT_READY_FOR_COMMAND) != 0
+    }
+
+    #[test]
+    fn reports_reset_status() {
+        let mut gpu = Gpu::new();
+        gpu.write(GP1_PORT, 0u32);
+        assert_eq!(gpu.read::<u32>(GP1_PORT), 0x1480_2000);
+    }
+
+    #[test]
+    fn waits_for_all_polygon_parameters() {
+        let mut gpu = Gpu::new();
+        // shaded textured quad: command + 4 * (vertex, uv) + 3 colors
+        gpu.write(GP0_PORT, 0x3C00_0000u32);
+        for _ in 0..10 {
+            assert!(!is_ready_for_command(&mut gpu));
+            gpu.write(GP0_PORT, 0u32);
+        }
+        assert!(!is_ready_for_command(&mut gpu));
+        gpu.write(GP0_PORT, 0u32);
+        assert!(is_ready_for_command(&mut gpu));
+    }
+
+    #[test]
+    fn waits_for_polyline_terminator() {
+        let mut gpu = Gpu::new();
+        gpu.write(GP0_PORT, 0x4800_0000u32);
+        for _ in 0..5 {
+            gpu.write(GP0_PORT, 0x0010_0010u32);
+        }
+        assert!(!is_ready_for_command(&mut gpu));
+        gpu.write(GP0_PORT, 0x5555_5555u32);
+        assert!(is_ready_for_command(&mut gpu));
+    }
+
+    fn draw_everywhere(gpu: &mut Gpu) {
+        gpu.write(GP0_PORT, 0xE300_0000u32);
+        gpu.write(GP0_PORT, 0xE407_FFFFu32);
     }
-    fn peek<T: crate::devices::bus::SizedData>(&self, addr: u32) -> Option<T> {
-        debug!(target: "gpu", "Peek from GP{}", addr / 4);
-        Some(T::from_u32(0))
+
+    #[test]
+    fn draws_shaded_polylines() {
+        let mut gpu = Gpu::new();
+        draw_everywhere(&mut gpu);
+        gpu.write(GP0_PORT, 0x5800_00FFu32);
+        gpu.write(GP0_PORT, 0x0000_0000u32);
+        gpu.write(GP0_PORT, 0x0000_00FFu32);
+        gpu.write(GP0_PORT, 0x0000_0004u32);
+        gpu.write(GP0_PORT, 0x00FF_0000u32);
+        gpu.write(GP0_PORT, 0x0004_0004u32);
+        gpu.write(GP0_PORT, 0x5000_5000u32);
+        assert!(is_ready_for_command(&mut gpu));
+        assert_eq!(gpu.vram.get_pixel(0, 0), 0x001F);
+        assert_eq!(gpu.vram.get_pixel(4, 0), 0x001F);
+        assert_eq!(gpu.vram.get_pixel(4, 4), 0x7C00);
+        assert_eq!(gpu.vram.get_pixel(0, 1), 0);
+    }
+
+    #[test]
+    fn draws_fixed_and_variable_size_rectangles() {
+        let mut gpu = Gpu::new();
+        draw_everywhere(&mut gpu);
+        // an 8x8 flat rectangle at (8, 8)
+        gpu.write(GP0_PORT, 0x7000_00FFu32);
+        gpu.write(GP0_PORT, 0x0008_0008u32);
+        assert_eq!(gpu.vram.get_pixel(8, 8), 0x001F);
+        assert_eq!(gpu.vram.get_pixel(15, 15), 0x001F);
+        assert_eq!(gpu.vram.get_pixel(16, 16), 0);
+        // a 3x2 rectangle at (100, 100)
+        gpu.write(GP0_PORT, 0x6000_FF00u32);
+        gpu.write(GP0_PORT, 0x0064_0064u32);
+        gpu.write(GP0_PORT, 0x0002_0003u32);
+        assert_eq!(gpu.vram.get_pixel(102, 101), 0x03E0);
+        assert_eq!(gpu.vram.get_pixel(103, 101), 0);
+        assert_eq!(gpu.vram.get_pixel(102, 102), 0);
     }
-    fn write<T: crate::devices::bus::SizedData>(&mut self, addr: u32, data: T) {
-        debug!(target: "gpu", "Write to GP{} = 0x{:08X}", addr / 4, data);
+
+    #[test]
+    fn fills_rectangles_ignoring_drawing_area() {
+        let mut gpu = Gpu::new();
+        gpu.write(GP0_PORT, 0xE300_0000u32);
+        gpu.write(GP0_PORT, 0xE400_0000u32);
+        gpu.write(GP0_PORT, 0x02FF_0000u32);
+        gpu.write(GP0_PORT, 0x0010_0013u32);
+        // 17 pixels wide rounds up to 32
+        gpu.write(GP0_PORT, 0x0002_0011u32);
+        assert_eq!(gpu.vram.get_pixel(0x10, 0x10), 0x7C00);
+        assert_eq!(gpu.vram.get_pixel(0x2F, 0x11), 0x7C00);
+        assert_eq!(gpu.vram.get_pixel(0x30, 0x10), 0);
+        assert_eq!(gpu.vram.get_pixel(0x10, 0x12), 0);
+    }
+
+    #[test]
+    fn consumes_image_load_data() {
+        let mut gpu = Gpu::new();
+        gpu.write(GP0_PORT, 0xA000_0000u32);
+        gpu.write(GP0_PORT, 0u32);
+        // a 3x3 image is 9 halfwords, which rounds up to 5 words
+        gpu.write(GP0_PORT, 0x0003_0003u32);
+        for _ in 0..5 {
+            assert!(!is_ready_for_command(&mut gpu));
+            gpu.write(GP0_PORT, 0xFFFF_FFFFu32);
+        }
+        assert!(is_ready_for_command(&mut gpu));
+    }
+
+    /// Upload a rectangle of pixels to VRAM with GP0 A0h
+    fn load_image(gpu: &mut Gpu, x: u32, y: u32, width: u32, pixels: &[u16]) {
+        let height = pixels.len() as u32 / width;
+        gpu.write(GP0_PORT, 0xA000_0000u32);
+        gpu.write(GP0_PORT, (y << 16) | x);
+        gpu.write(GP0_PORT, (height << 16) | width);
+        for pair in pixels.chunks(2) {
+            let hi = pair.get(1).copied().unwrap_or(0) as u32;
+            gpu.write(GP0_PORT, (hi << 16) | pair[0] as u32);
+        }
+    }
+
+    #[test]
+    fn loads_images_into_vram() {
+        let mut gpu = Gpu::new();
+        load_image(&mut gpu, 10, 20, 3, &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(gpu.vram.get_pixel(10, 20), 1);
+        assert_eq!(gpu.vram.get_pixel(12, 20), 3);
+        assert_eq!(gpu.vram.get_pixel(10, 21), 4);
+        assert_eq!(gpu.vram.get_pixel(12, 21), 6);
+        assert_eq!(gpu.vram.get_pixel(13, 20), 0);
+    }
+
+    #[test]
+    fn stores_images_from_vram() {
+        let mut gpu = Gpu::new();
+        load_image(&mut gpu, 1020, 0, 3, &[1, 2, 3, 4, 5, 6]);
+        gpu.write(GP0_PORT, 0xC000_0000u32);
+        gpu.write(GP0_PORT, 0x0000_03FCu32);
+        gpu.write(GP0_PORT, 0x0002_0003u32);
+        assert_ne!(gpu.read::<u32>(GP1_PORT) & GPUSTAT_READY_FOR_VRAM_SEND, 0);
+        assert_eq!(gpu.read::<u32>(GP0_PORT), 0x0002_0001);
+        assert_eq!(gpu.read::<u32>(GP0_PORT), 0x0004_0003);
+        assert_eq!(gpu.read::<u32>(GP0_PORT), 0x0006_0005);
+        assert_eq!(gpu.read::<u32>(GP1_PORT) & GPUSTAT_READY_FOR_VRAM_SEND, 0);
+    }
+
+    #[test]
+    fn wraps_transfers_around_vram() {
+        let mut gpu = Gpu::new();
+        load_image(&mut gpu, 1023, 511, 2, &[1, 2, 3, 4]);
+        assert_eq!(gpu.vram.get_pixel(1023, 511), 1);
+        assert_eq!(gpu.vram.get_pixel(0, 511), 2);
+        assert_eq!(gpu.vram.get_pixel(1023, 0), 3);
+        assert_eq!(gpu.vram.get_pixel(0, 0), 4);
+    }
+
+    #[test]
+    fn copies_within_vram() {
+        let mut gpu = Gpu::new();
+        load_image(&mut gpu, 0, 0, 2, &[1, 2, 3, 4]);
+        gpu.write(GP0_PORT, 0x8000_0000u32);
+        gpu.write(GP0_PORT, 0u32);
+        gpu.write(GP0_PORT, 0x0010_0100u32);
+        gpu.write(GP0_PORT, 0x0002_0002u32);
+        assert_eq!(gpu.vram.get_pixel(0x100, 0x10), 1);
+        assert_eq!(gpu.vram.get_pixel(0x101, 0x11), 4);
+    }
+
+    #[test]
+    fn applies_mask_bit_settings() {
+        let mut gpu = Gpu::new();
+        // set the mask bit on everything drawn
+        gpu.write(GP0_PORT, 0xE600_0001u32);
+        load_image(&mut gpu, 0, 0, 2, &[0x0001, 0x0002]);
+        assert_eq!(gpu.vram.get_pixel(0, 0), 0x8001);
+        // now protect masked pixels
+        gpu.write(GP0_PORT, 0xE600_0002u32);
+        load_image(&mut gpu, 1, 0, 2, &[0x0003, 0x0004]);
+        assert_eq!(gpu.vram.get_pixel(1, 0), 0x8002);
+        assert_eq!(gpu.vram.get_pixel(2, 0), 0x0004);
+    }
+
+    #[test]
+    fn draws_polygons_with_offset_and_clipping() {
+        let mut gpu = Gpu::new();
+        gpu.write(GP0_PORT, 0xE300_0000u32);
+        // clip to (0, 0)-(99, 99), and offset everything by (10, 10)
+        gpu.write(GP0_PORT, 0xE401_8C63u32);
+        gpu.write(GP0_PORT, 0xE500_500Au32);
+        // a flat white quad from (-10, -10) to (200, 200)
+        gpu.write(GP0_PORT, 0x28FF_FFFFu32);
+        gpu.write(GP0_PORT, 0xFFF6_FFF6u32);
+        gpu.write(GP0_PORT, 0xFFF6_00C8u32);
+        gpu.write(GP0_PORT, 0x00C8_FFF6u32);
+        gpu.write(GP0_PORT, 0x00C8_00C8u32);
+        assert_eq!(gpu.vram.get_pixel(0, 0), 0x7FFF);
+        assert_eq!(gpu.vram.get_pixel(99, 99), 0x7FFF);
+        assert_eq!(gpu.vram.get_pixel(100, 0), 0);
+        assert_eq!(gpu.vram.get_pixel(0, 100), 0);
+    }
+
+    #[test]
+    fn updates_texpage_from_textured_polygons() {
+        let mut gpu = Gpu::new();
+        gpu.write(GP0_PORT, 0xE300_0000u32);
+        gpu.write(GP0_PORT, 0xE407_FFFFu32);
+        load_image(&mut gpu, 128, 256, 1, &[0x001F]);
+        // a raw-textured triangle using a 15-bit page at (128, 256)
+        gpu.write(GP0_PORT, 0x2500_0000u32);
+        gpu.write(GP0_PORT, 0x0000_0000u32);
+        gpu.write(GP0_PORT, 0x0000_0000u32);
+        gpu.write(GP0_PORT, 0x0000_0001u32);
+        gpu.write(GP0_PORT, 0x0112_0000u32);
+        gpu.write(GP0_PORT, 0x0001_0000u32);
+        gpu.write(GP0_PORT, 0x0000_0000u32);
+        assert_eq!(gpu.read::<u32>(GP1_PORT) & 0x1FF, 0x112);
+        assert_eq!(gpu.vram.get_pixel(0, 0), 0x001F);
+    }
+
+    #[test]
+    fn reports_draw_state_through_gpuread() {
+        let mut gpu = Gpu::new();
+        gpu.write(GP0_PORT, 0xE300_2805u32);
+        gpu.write(GP0_PORT, 0xE600_0003u32);
+        gpu.write(GP1_PORT, 0x1000_0003u32);
+        assert_eq!(gpu.read::<u32>(GP0_PORT), 0x0000_2805);
+        gpu.write(GP1_PORT, 0x1000_0007u32);
+        assert_eq!(gpu.read::<u32>(GP0_PORT), 0x0000_0002);
+        assert_eq!(gpu.read::<u32>(GP1_PORT) & 0x1800, 0x1800);
+    }
+
+    #[test]
+    fn raises_and_acknowledges_irq() {
+        let mut gpu = Gpu::new();
+        gpu.write(GP0_PORT, 0x1F00_0000u32);
+        assert!(gpu.take_irq());
+        assert_ne!(gpu.read::<u32>(GP1_PORT) & GPUSTAT_IRQ, 0);
+        gpu.write(GP1_PORT, 0x0200_0000u32);
+        assert_eq!(gpu.read::<u32>(GP1_PORT) & GPUSTAT_IRQ, 0);
     }
 }
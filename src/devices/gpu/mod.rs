@@ -1,3 +1,13 @@
+mod display;
+#[allow(clippy::module_inception)]
 mod gpu;
+mod rasterizer;
+mod structs;
+mod timing;
+mod vram;
 
+pub use self::display::Frame;
 pub use self::gpu::{Gpu, WithGpu};
+pub use self::structs::*;
+pub use self::timing::VideoSignals;
+pub use self::vram::{Vram, VRAM_HEIGHT, VRAM_WIDTH};
@@ -0,0 +1,754 @@
+//! The software rasterizer behind the GP0 drawing commands
+//!
+//! Polygons are set up the way the hardware does it, in fixed point, so the
+//! output matches it pixel for pixel:
+//!
+//! - Triangles are drawn a scanline at a time, between edges that step in
+//!   32.32 fixed point. Pixels exactly on a top or left edge are drawn,
+//!   while those on a bottom or right edge are not.
+//! - Color and texture coordinates are interpolated with per-pixel gradients
+//!   that are computed to 12 fractional bits and kept in 8.24 fixed point,
+//!   starting from the leftmost vertex. The integer part wraps, rather than
+//!   saturating, if a gradient overshoots.
+
+use super::structs::{DrawMode, SemiTransparency, TextureDepth};
+use super::vram::{Vram, MASK_BIT};
+
+/// The 4x4 ordered dither matrix, applied to 8-bit color before truncation
+const DITHER_MATRIX: [[i32; 4]; 4] = [
+    [-4, 0, -3, 1],
+    [2, -2, 3, -1],
+    [-3, 1, -4, 0],
+    [3, -1, 2, -2],
+];
+
+/// Polygons larger than this (in either dimension) are silently dropped
+const MAX_PRIMITIVE_WIDTH: i32 = 1024;
+const MAX_PRIMITIVE_HEIGHT: i32 = 512;
+
+/// A vertex of a primitive, after the drawing offset has been applied
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct Vertex {
+    pub x: i32,
+    pub y: i32,
+    /// 24-bit color, as [r, g, b]
+    pub color: [u8; 3],
+    pub u: u8,
+    pub v: u8,
+}
+
+impl Vertex {
+    /// Decode a GP0 vertex word, whose coordinates are signed 11-bit values
+    pub fn from_position(data: u32) -> Vertex {
+        Vertex {
+            x: sign_extend_11(data),
+            y: sign_extend_11(data >> 16),
+            ..Vertex::default()
+        }
+    }
+
+    /// Set the color from a GP0 color word
+    pub fn with_color(self, data: u32) -> Vertex {
+        Vertex {
+            color: [data as u8, (data >> 8) as u8, (data >> 16) as u8],
+            ..self
+        }
+    }
+
+    /// Set the texture coordinates from a GP0 texcoord word
+    pub fn with_texcoord(self, data: u32) -> Vertex {
+        Vertex {
+            u: data as u8,
+            v: (data >> 8) as u8,
+            ..self
+        }
+    }
+}
+
+/// Sign-extend the low 11 bits of a coordinate
+pub fn sign_extend_11(data: u32) -> i32 {
+    return ((data << 21) as i32) >> 21;
+}
+
+/// The drawing state shared by every primitive, from GP0 E1h-E6h
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct DrawContext {
+    pub draw_mode: DrawMode,
+    /// The raw texture window register (GP0 E2h)
+    pub texture_window: u32,
+    /// The drawing area, inclusive on all sides
+    pub clip_left: i32,
+    pub clip_top: i32,
+    pub clip_right: i32,
+    pub clip_bottom: i32,
+    pub offset_x: i32,
+    pub offset_y: i32,
+    pub set_mask_bit: bool,
+    pub check_mask_bit: bool,
+}
+
+/// Flags that vary per-primitive, mostly decoded from the GP0 opcode
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct PrimitiveAttributes {
+    pub is_shaded: bool,
+    pub is_textured: bool,
+    pub is_semi_transparent: bool,
+    /// Textures are drawn without being blended with the vertex color
+    pub is_raw_texture: bool,
+    /// The position of the color lookup table, for 4- and 8-bit textures
+    pub clut_x: u32,
+    pub clut_y: u32,
+}
+
+impl PrimitiveAttributes {
+    pub fn from_opcode(opcode: u32) -> PrimitiveAttributes {
+        PrimitiveAttributes {
+            is_shaded: (opcode & 0x10) != 0,
+            is_textured: (opcode & 0x04) != 0,
+            is_semi_transparent: (opcode & 0x02) != 0,
+            is_raw_texture: (opcode & 0x01) != 0,
+            clut_x: 0,
+            clut_y: 0,
+        }
+    }
+
+    /// Set the CLUT position from the upper half of a texcoord word
+    pub fn with_clut(self, data: u32) -> PrimitiveAttributes {
+        PrimitiveAttributes {
+            clut_x: ((data >> 16) & 0x3F) * 16,
+            clut_y: (data >> 22) & 0x1FF,
+            ..self
+        }
+    }
+}
+
+/// Rasterize a triangle into VRAM
+pub fn draw_triangle(
+    vram: &mut Vram,
+    ctx: &DrawContext,
+    attrs: &PrimitiveAttributes,
+    vertices: [Vertex; 3],
+) {
+    let flat_color = vertices[0].color;
+    let mut v = vertices;
+    // sort the vertices from top to bottom
+    if v[2].y < v[1].y {
+        v.swap(1, 2);
+    }
+    if v[1].y < v[0].y {
+        v.swap(0, 1);
+    }
+    if v[2].y < v[1].y {
+        v.swap(1, 2);
+    }
+    if v[0].y == v[2].y {
+        return;
+    }
+
+    let min_x = v[0].x.min(v[1].x).min(v[2].x);
+    let max_x = v[0].x.max(v[1].x).max(v[2].x);
+    if max_x - min_x >= MAX_PRIMITIVE_WIDTH || v[2].y - v[0].y >= MAX_PRIMITIVE_HEIGHT {
+        return;
+    }
+    let gradients = match Gradients::new(&v[0], &v[1], &v[2]) {
+        Some(gradients) => gradients,
+        None => return,
+    };
+    let origin = gradients.origin(&v[core_vertex(&v)]);
+
+    // each half of the triangle is drawn between the long edge, running from
+    // the top vertex to the bottom one, and one of the two short edges
+    let long_step = edge_step(v[2].x - v[0].x, v[2].y - v[0].y);
+    let (upper_step, is_long_edge_left) = if v[1].y == v[0].y {
+        (0, v[1].x > v[0].x)
+    } else {
+        let step = edge_step(v[1].x - v[0].x, v[1].y - v[0].y);
+        (step, step > long_step)
+    };
+    let lower_step = if v[2].y == v[1].y {
+        0
+    } else {
+        edge_step(v[2].x - v[1].x, v[2].y - v[1].y)
+    };
+    let halves = [
+        (v[0].y, v[1].y, edge_origin(v[0].x), upper_step),
+        (v[1].y, v[2].y, edge_origin(v[1].x), lower_step),
+    ];
+
+    let dither = ctx.draw_mode.is_dithered()
+        && (attrs.is_shaded || (attrs.is_textured && !attrs.is_raw_texture));
+    let mut long_x = edge_origin(v[0].x);
+    for &(top, bottom, mut short_x, short_step) in halves.iter() {
+        for y in top..bottom {
+            let (left, right) = if is_long_edge_left {
+                (long_x, short_x)
+            } else {
+                (short_x, long_x)
+            };
+            long_x += long_step;
+            short_x += short_step;
+            if y < ctx.clip_top || y > ctx.clip_bottom {
+                continue;
+            }
+            let left = ((left >> 32) as i32).max(ctx.clip_left);
+            let right = ((right >> 32) as i32 - 1).min(ctx.clip_right);
+            let row = gradients.step(origin, 0, y);
+            for x in left..=right {
+                let [r, g, b, u, v] = gradients.step(row, x, 0).map(|attr| attr >> 24);
+                let color = if attrs.is_shaded {
+                    [r as i32, g as i32, b as i32]
+                } else {
+                    [
+                        flat_color[0] as i32,
+                        flat_color[1] as i32,
+                        flat_color[2] as i32,
+                    ]
+                };
+                let texcoord = if attrs.is_textured {
+                    Some((u as u8, v as u8))
+                } else {
+                    None
+                };
+                draw_pixel(vram, ctx, attrs, x, y, color, texcoord, dither);
+            }
+        }
+    }
+}
+
+/// The red, green, blue, U and V values at a pixel, in 8.24 fixed point
+type Attributes = [u32; 5];
+
+/// How much each attribute changes per pixel across and down a triangle
+struct Gradients {
+    dx: Attributes,
+    dy: Attributes,
+}
+
+impl Gradients {
+    /// Work out the gradients of the plane through three vertices, or None if
+    /// they're in a line
+    fn new(a: &Vertex, b: &Vertex, c: &Vertex) -> Option<Gradients> {
+        let (ab_x, ab_y) = ((b.x - a.x) as i64, (b.y - a.y) as i64);
+        let (bc_x, bc_y) = ((c.x - b.x) as i64, (c.y - b.y) as i64);
+        let denom = ab_x * bc_y - bc_x * ab_y;
+        if denom == 0 {
+            return None;
+        }
+        // the gradients get 12 fractional bits, through a 32-bit reciprocal
+        let reciprocal = (1i64 << 44) / denom;
+        let (a, b, c) = (attributes(a), attributes(b), attributes(c));
+        let mut gradients = Gradients {
+            dx: [0; 5],
+            dy: [0; 5],
+        };
+        for i in 0..5 {
+            let (ab, bc) = (b[i] - a[i], c[i] - b[i]);
+            let dx = reciprocal.wrapping_mul(ab * bc_y - bc * ab_y) >> 32;
+            let dy = reciprocal.wrapping_mul(ab_x * bc - bc_x * ab) >> 32;
+            gradients.dx[i] = (dx as u32) << 12;
+            gradients.dy[i] = (dy as u32) << 12;
+        }
+        return Some(gradients);
+    }
+
+    /// The attributes at (0, 0), working back from the given vertex, whose
+    /// own attributes are rounded to the middle of a step
+    fn origin(&self, vertex: &Vertex) -> Attributes {
+        let attrs = attributes(vertex).map(|attr| ((attr as u32) << 24) + (1 << 23));
+        return self.step(attrs, -vertex.x, -vertex.y);
+    }
+
+    /// Step attributes across and down by the given number of pixels
+    fn step(&self, attrs: Attributes, x: i32, y: i32) -> Attributes {
+        let mut stepped = attrs;
+        for (i, attr) in stepped.iter_mut().enumerate() {
+            *attr = attr
+                .wrapping_add(self.dx[i].wrapping_mul(x as u32))
+                .wrapping_add(self.dy[i].wrapping_mul(y as u32));
+        }
+        return stepped;
+    }
+}
+
+fn attributes(vertex: &Vertex) -> [i64; 5] {
+    let [r, g, b] = vertex.color;
+    return [
+        r as i64,
+        g as i64,
+        b as i64,
+        vertex.u as i64,
+        vertex.v as i64,
+    ];
+}
+
+/// Pick the vertex the attributes are set up from: the leftmost one, with
+/// ties going the way the hardware breaks them
+fn core_vertex(v: &[Vertex; 3]) -> usize {
+    if v[1].x <= v[0].x {
+        return if v[2].x <= v[1].x { 2 } else { 1 };
+    }
+    return if v[2].x < v[0].x { 2 } else { 0 };
+}
+
+/// An edge's starting X in 32.32 fixed point. The bias makes the integer part
+/// the first pixel at or right of the edge.
+fn edge_origin(x: i32) -> i64 {
+    return ((x as i64) << 32) + (1 << 32) - (1 << 11);
+}
+
+/// How far an edge moves along X per scanline, in 32.32 fixed point, rounded
+/// away from zero
+fn edge_step(dx: i32, dy: i32) -> i64 {
+    let dy = dy as i64;
+    let mut dx = (dx as i64) << 32;
+    if dx < 0 {
+        dx -= dy - 1;
+    } else if dx > 0 {
+        dx += dy - 1;
+    }
+    return dx / dy;
+}
+
+/// Draw a line between two vertices, including both endpoints
+pub fn draw_line(
+    vram: &mut Vram,
+    ctx: &DrawContext,
+    attrs: &PrimitiveAttributes,
+    v0: Vertex,
+    v1: Vertex,
+) {
+    let dx = (v1.x - v0.x) as i64;
+    let dy = (v1.y - v0.y) as i64;
+    if dx.abs() >= MAX_PRIMITIVE_WIDTH as i64 || dy.abs() >= MAX_PRIMITIVE_HEIGHT as i64 {
+        return;
+    }
+    let steps = dx.abs().max(dy.abs());
+    let dither = ctx.draw_mode.is_dithered() && attrs.is_shaded;
+    // step along the major axis, rounding the position on the minor axis
+    let lerp = |a: i64, b: i64, i: i64| -> i64 {
+        if steps == 0 {
+            return a;
+        }
+        return a + ((b - a) * i * 2 + steps).div_euclid(steps * 2);
+    };
+
+    for i in 0..=steps {
+        let x = lerp(v0.x as i64, v1.x as i64, i) as i32;
+        let y = lerp(v0.y as i64, v1.y as i64, i) as i32;
+        if x < ctx.clip_left || x > ctx.clip_right || y < ctx.clip_top || y > ctx.clip_bottom {
+            continue;
+        }
+        let color = if attrs.is_shaded {
+            [
+                lerp(v0.color[0] as i64, v1.color[0] as i64, i) as i32,
+                lerp(v0.color[1] as i64, v1.color[1] as i64, i) as i32,
+                lerp(v0.color[2] as i64, v1.color[2] as i64, i) as i32,
+            ]
+        } else {
+            [v0.color[0] as i32, v0.color[1] as i32, v0.color[2] as i32]
+        };
+        draw_pixel(vram, ctx, attrs, x, y, color, None, dither);
+    }
+}
+
+/// Draw an axis-aligned rectangle, optionally textured
+///
+/// Texture coordinates advance one texel per pixel from the origin vertex,
+/// and run backwards if the draw mode flips rectangles on that axis.
+pub fn draw_rectangle(
+    vram: &mut Vram,
+    ctx: &DrawContext,
+    attrs: &PrimitiveAttributes,
+    origin: Vertex,
+    width: i32,
+    height: i32,
+) {
+    let color = [
+        origin.color[0] as i32,
+        origin.color[1] as i32,
+        origin.color[2] as i32,
+    ];
+    let step_u: i32 = if ctx.draw_mode.is_flipped_x() { -1 } else { 1 };
+    let step_v: i32 = if ctx.draw_mode.is_flipped_y() { -1 } else { 1 };
+    let top = origin.y.max(ctx.clip_top);
+    let bottom = (origin.y + height - 1).min(ctx.clip_bottom);
+    let left = origin.x.max(ctx.clip_left);
+    let right = (origin.x + width - 1).min(ctx.clip_right);
+    for y in top..=bottom {
+        let v = (origin.v as i32 + (y - origin.y) * step_v) as u8;
+        for x in left..=right {
+            let texcoord = if attrs.is_textured {
+                let u = (origin.u as i32 + (x - origin.x) * step_u) as u8;
+                Some((u, v))
+            } else {
+                None
+            };
+            // rectangles are never dithered
+            draw_pixel(vram, ctx, attrs, x, y, color, texcoord, false);
+        }
+    }
+}
+
+/// Fill a rectangle in VRAM with a solid color
+///
+/// Unlike other primitives, this ignores the drawing area, the drawing
+/// offset and the mask settings, and wraps around the edges of VRAM.
+pub fn fill_rectangle(vram: &mut Vram, x: u32, y: u32, width: u32, height: u32, color: u32) {
+    let r = (color & 0xFF) >> 3;
+    let g = ((color >> 8) & 0xFF) >> 3;
+    let b = ((color >> 16) & 0xFF) >> 3;
+    let pixel = (r | (g << 5) | (b << 10)) as u16;
+    for row in 0..height {
+        for col in 0..width {
+            vram.set_pixel(x + col, y + row, pixel);
+        }
+    }
+}
+
+/// Shade a single pixel and write it to VRAM
+///
+/// `color` is the 24-bit vertex color at this pixel, and `texcoord` is the
+/// texture coordinate for textured primitives.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_pixel(
+    vram: &mut Vram,
+    ctx: &DrawContext,
+    attrs: &PrimitiveAttributes,
+    x: i32,
+    y: i32,
+    color: [i32; 3],
+    texcoord: Option<(u8, u8)>,
+    dither: bool,
+) {
+    let (x, y) = (x as u32, y as u32);
+    let background = vram.get_pixel(x, y);
+    if ctx.check_mask_bit && (background & MASK_BIT) != 0 {
+        return;
+    }
+
+    let dither_offset = if dither {
+        DITHER_MATRIX[(y & 3) as usize][(x & 3) as usize]
+    } else {
+        0
+    };
+    let to_5bit = |c: i32| -> u16 { ((c + dither_offset).clamp(0, 255) >> 3) as u16 };
+
+    let (rgb, mask, is_semi_transparent) = match texcoord {
+        Some((u, v)) => {
+            let texel = fetch_texel(vram, ctx, attrs, u, v);
+            // fully black texels are transparent
+            if texel == 0 {
+                return;
+            }
+            let texel_rgb = [texel & 0x1F, (texel >> 5) & 0x1F, (texel >> 10) & 0x1F];
+            let rgb = if attrs.is_raw_texture {
+                texel_rgb
+            } else {
+                // 0x80 is "full brightness" for texture blending
+                let blend = |t: u16, c: i32| to_5bit((((t as i32) << 3) * c) >> 7);
+                [
+                    blend(texel_rgb[0], color[0]),
+                    blend(texel_rgb[1], color[1]),
+                    blend(texel_rgb[2], color[2]),
+                ]
+            };
+            let mask = texel & MASK_BIT;
+            // only texels with their top bit set are semi-transparent
+            (rgb, mask, attrs.is_semi_transparent && mask != 0)
+        }
+        None => (
+            [to_5bit(color[0]), to_5bit(color[1]), to_5bit(color[2])],
+            0,
+            attrs.is_semi_transparent,
+        ),
+    };
+
+    let rgb = if is_semi_transparent {
+        let mode = ctx.draw_mode.get_semi_transparency();
+        let b = [
+            background & 0x1F,
+            (background >> 5) & 0x1F,
+            (background >> 10) & 0x1F,
+        ];
+        [
+            blend_semi_transparent(mode, b[0], rgb[0]),
+            blend_semi_transparent(mode, b[1], rgb[1]),
+            blend_semi_transparent(mode, b[2], rgb[2]),
+        ]
+    } else {
+        rgb
+    };
+
+    let set_mask = if ctx.set_mask_bit { MASK_BIT } else { 0 };
+    vram.set_pixel(
+        x,
+        y,
+        rgb[0] | (rgb[1] << 5) | (rgb[2] << 10) | mask | set_mask,
+    );
+}
+
+/// Blend a 5-bit foreground channel onto a 5-bit background channel
+fn blend_semi_transparent(mode: SemiTransparency, back: u16, front: u16) -> u16 {
+    let (back, front) = (back as i32, front as i32);
+    let result = match mode {
+        SemiTransparency::Average => (back + front) / 2,
+        SemiTransparency::Add => back + front,
+        SemiTransparency::Subtract => back - front,
+        SemiTransparency::AddQuarter => back + front / 4,
+    };
+    return result.clamp(0, 31) as u16;
+}
+
+/// Look up a texel in the current texture page, through the CLUT if needed
+fn fetch_texel(vram: &Vram, ctx: &DrawContext, attrs: &PrimitiveAttributes, u: u8, v: u8) -> u16 {
+    let window = ctx.texture_window;
+    let (mask_x, mask_y) = (window & 0x1F, (window >> 5) & 0x1F);
+    let (offset_x, offset_y) = ((window >> 10) & 0x1F, (window >> 15) & 0x1F);
+    let u = (u as u32 & !(mask_x * 8)) | ((offset_x & mask_x) * 8);
+    let v = (v as u32 & !(mask_y * 8)) | ((offset_y & mask_y) * 8);
+
+    let page_x = ctx.draw_mode.get_page_x();
+    let page_y = ctx.draw_mode.get_page_y();
+    return match ctx.draw_mode.get_texture_depth() {
+        TextureDepth::Clut4 => {
+            let word = vram.get_pixel(page_x + u / 4, page_y + v);
+            let index = (word >> ((u % 4) * 4)) & 0xF;
+            vram.get_pixel(attrs.clut_x + index as u32, attrs.clut_y)
+        }
+        TextureDepth::Clut8 => {
+            let word = vram.get_pixel(page_x + u / 2, page_y + v);
+            let index = (word >> ((u % 2) * 8)) & 0xFF;
+            vram.get_pixel(attrs.clut_x + index as u32, attrs.clut_y)
+        }
+        TextureDepth::Direct15 => vram.get_pixel(page_x + u, page_y + v),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn context() -> DrawContext {
+        DrawContext {
+            draw_mode: DrawMode::from(0),
+            texture_window: 0,
+            clip_left: 0,
+            clip_top: 0,
+            clip_right: 1023,
+            clip_bottom: 511,
+            offset_x: 0,
+            offset_y: 0,
+            set_mask_bit: false,
+            check_mask_bit: false,
+        }
+    }
+
+    fn vertex(x: i32, y: i32) -> Vertex {
+        Vertex {
+            x,
+            y,
+            color: [0xFF, 0xFF, 0xFF],
+            ..Vertex::default()
+        }
+    }
+
+    fn count_drawn(vram: &Vram) -> usize {
+        vram.as_slice().iter().filter(|&&p| p != 0).count()
+    }
+
+    #[test]
+    fn follows_top_left_fill_rule() {
+        let mut vram = Vram::new();
+        let attrs = PrimitiveAttributes::from_opcode(0x20);
+        let verts = [vertex(0, 0), vertex(4, 0), vertex(0, 4)];
+        draw_triangle(&mut vram, &context(), &attrs, verts);
+        // rows of 4, 3, 2 and 1 pixels; the hypotenuse and the bottom
+        // vertex are excluded
+        assert_eq!(count_drawn(&vram), 10);
+        assert_ne!(vram.get_pixel(0, 0), 0);
+        assert_ne!(vram.get_pixel(3, 0), 0);
+        assert_eq!(vram.get_pixel(4, 0), 0);
+        assert_eq!(vram.get_pixel(0, 4), 0);
+    }
+
+    #[test]
+    fn does_not_overdraw_shared_edges() {
+        let mut vram = Vram::new();
+        let mut ctx = context();
+        // additive blending would make any overdraw visible
+        ctx.draw_mode = DrawMode::from(0x20);
+        let attrs = PrimitiveAttributes::from_opcode(0x22);
+        let color = [0x08, 0, 0];
+        let v = |x, y| Vertex {
+            color,
+            ..vertex(x, y)
+        };
+        draw_triangle(&mut vram, &ctx, &attrs, [v(0, 0), v(8, 0), v(0, 8)]);
+        draw_triangle(&mut vram, &ctx, &attrs, [v(8, 0), v(0, 8), v(8, 8)]);
+        assert_eq!(count_drawn(&vram), 64);
+        assert!(vram.as_slice().iter().all(|&p| p == 0 || p == 0x0001));
+    }
+
+    #[test]
+    fn clips_to_drawing_area() {
+        let mut vram = Vram::new();
+        let mut ctx = context();
+        ctx.clip_left = 2;
+        ctx.clip_top = 2;
+        ctx.clip_right = 3;
+        ctx.clip_bottom = 3;
+        let attrs = PrimitiveAttributes::from_opcode(0x20);
+        draw_triangle(
+            &mut vram,
+            &ctx,
+            &attrs,
+            [vertex(0, 0), vertex(10, 0), vertex(0, 10)],
+        );
+        assert_eq!(count_drawn(&vram), 4);
+    }
+
+    #[test]
+    fn interpolates_gouraud_shading() {
+        let mut vram = Vram::new();
+        let attrs = PrimitiveAttributes::from_opcode(0x30);
+        let verts = [
+            Vertex {
+                color: [0, 0, 0],
+                ..vertex(0, 0)
+            },
+            Vertex {
+                color: [0xF8, 0, 0],
+                ..vertex(32, 0)
+            },
+            Vertex {
+                color: [0, 0, 0],
+                ..vertex(0, 32)
+            },
+        ];
+        draw_triangle(&mut vram, &context(), &attrs, verts);
+        let red = |x| vram.get_pixel(x, 0) & 0x1F;
+        assert_eq!(red(0), 0);
+        assert_eq!(red(16), 15);
+        assert_eq!(red(31), 30);
+    }
+
+    #[test]
+    fn rounds_interpolated_texcoords_from_the_leftmost_vertex() {
+        let mut vram = Vram::new();
+        // a 15-bit texture page at (64, 0), where each texel is its U plus 1
+        for u in 0..32 {
+            vram.set_pixel(64 + u, 0, u as u16 + 1);
+        }
+        let mut ctx = context();
+        ctx.draw_mode = DrawMode::from(0x0101);
+        let attrs = PrimitiveAttributes::from_opcode(0x25);
+        let tex = |x, y, u| Vertex { u, ..vertex(x, y) };
+        let verts = [tex(0, 10, 0), tex(6, 10, 20), tex(0, 16, 0)];
+        draw_triangle(&mut vram, &ctx, &attrs, verts);
+        // U steps by 20/6 per pixel, from 0.5 at the leftmost vertex
+        let row: Vec<u16> = (0..6).map(|x| vram.get_pixel(x, 10) - 1).collect();
+        assert_eq!(row, vec![0, 3, 7, 10, 13, 17]);
+    }
+
+    #[test]
+    fn samples_4bit_textures_through_clut() {
+        let mut vram = Vram::new();
+        // a texture page at (64, 0), with a CLUT at (0, 256)
+        vram.set_pixel(64, 0, 0x0021);
+        vram.set_pixel(1, 256, 0x7C00);
+        vram.set_pixel(2, 256, 0x03E0);
+        let mut ctx = context();
+        ctx.draw_mode = DrawMode::from(0x0001);
+        let attrs = PrimitiveAttributes::from_opcode(0x25).with_clut(256 << 22);
+        let tex = |x, y, u, v| Vertex {
+            u,
+            v,
+            ..vertex(x, y)
+        };
+        let verts = [tex(0, 10, 0, 0), tex(2, 10, 2, 0), tex(0, 12, 0, 2)];
+        draw_triangle(&mut vram, &ctx, &attrs, verts);
+        assert_eq!(vram.get_pixel(0, 10), 0x7C00);
+        assert_eq!(vram.get_pixel(1, 10), 0x03E0);
+        // index 0 maps to a black (transparent) CLUT entry
+        assert_eq!(vram.get_pixel(0, 11), 0);
+    }
+
+    #[test]
+    fn blends_textures_with_vertex_color() {
+        let mut vram = Vram::new();
+        vram.set_pixel(64, 0, 0x001F);
+        let mut ctx = context();
+        ctx.draw_mode = DrawMode::from(0x0101);
+        let attrs = PrimitiveAttributes::from_opcode(0x24);
+        let tex = |x, y| Vertex {
+            color: [0x40, 0x80, 0x80],
+            ..vertex(x, y)
+        };
+        draw_triangle(&mut vram, &ctx, &attrs, [tex(0, 0), tex(1, 0), tex(0, 1)]);
+        // half brightness on red
+        assert_eq!(vram.get_pixel(0, 0), 0x000F);
+    }
+
+    #[test]
+    fn applies_semi_transparency_modes() {
+        assert_eq!(
+            blend_semi_transparent(SemiTransparency::Average, 10, 20),
+            15
+        );
+        assert_eq!(blend_semi_transparent(SemiTransparency::Add, 20, 20), 31);
+        assert_eq!(
+            blend_semi_transparent(SemiTransparency::Subtract, 10, 20),
+            0
+        );
+        assert_eq!(
+            blend_semi_transparent(SemiTransparency::AddQuarter, 10, 8),
+            12
+        );
+    }
+
+    #[test]
+    fn draws_lines_with_both_endpoints() {
+        let mut vram = Vram::new();
+        let attrs = PrimitiveAttributes::from_opcode(0x40);
+        draw_line(&mut vram, &context(), &attrs, vertex(2, 2), vertex(9, 5));
+        assert_eq!(count_drawn(&vram), 8);
+        assert_ne!(vram.get_pixel(2, 2), 0);
+        assert_ne!(vram.get_pixel(9, 5), 0);
+    }
+
+    #[test]
+    fn flips_textured_rectangles() {
+        let mut vram = Vram::new();
+        vram.set_pixel(64, 0, 0x0001);
+        vram.set_pixel(65, 0, 0x0002);
+        vram.set_pixel(66, 0, 0x0003);
+        let mut ctx = context();
+        // 15-bit page at (64, 0), flipped horizontally
+        ctx.draw_mode = DrawMode::from(0x1101);
+        let attrs = PrimitiveAttributes::from_opcode(0x65);
+        let origin = Vertex {
+            u: 2,
+            ..vertex(0, 0)
+        };
+        draw_rectangle(&mut vram, &ctx, &attrs, origin, 3, 1);
+        assert_eq!(vram.get_pixel(0, 0), 0x0003);
+        assert_eq!(vram.get_pixel(1, 0), 0x0002);
+        assert_eq!(vram.get_pixel(2, 0), 0x0001);
+    }
+
+    #[test]
+    fn dithers_shaded_polygons() {
+        let mut vram = Vram::new();
+        let mut ctx = context();
+        ctx.draw_mode = DrawMode::from(0x0200);
+        let attrs = PrimitiveAttributes::from_opcode(0x30);
+        let v = |x, y| Vertex {
+            color: [0x07, 0, 0],
+            ..vertex(x, y)
+        };
+        draw_triangle(&mut vram, &ctx, &attrs, [v(0, 0), v(8, 0), v(0, 8)]);
+        // the matrix is -4 at (0, 0) and +2 at (0, 1)
+        assert_eq!(vram.get_pixel(0, 0) & 0x1F, 0, "0x07 - 4 should be 0");
+        assert_eq!(vram.get_pixel(0, 1) & 0x1F, 1, "0x07 + 2 should be 1");
+    }
+}
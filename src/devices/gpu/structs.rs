@@ -0,0 +1,241 @@
+use std::ops::Deref;
+
+//#region Draw mode (GP0 E1h)
+/// How semi-transparent pixels are blended with the background
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum SemiTransparency {
+    /// B/2 + F/2
+    Average,
+    /// B + F
+    Add,
+    /// B - F
+    Subtract,
+    /// B + F/4
+    AddQuarter,
+}
+
+/// The color depth of a texture page
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum TextureDepth {
+    /// 4-bit CLUT indices
+    Clut4,
+    /// 8-bit CLUT indices
+    Clut8,
+    /// 15-bit direct color
+    Direct15,
+}
+
+const DRAW_MODE_USED: u32 = 0x0000_3FFF;
+const DRAW_MODE_PAGE_X: u32 = 0x0000_000F;
+const DRAW_MODE_PAGE_Y: u32 = 0x0000_0010;
+const DRAW_MODE_SEMI_TRANSPARENCY: u32 = 0x0000_0060;
+const DRAW_MODE_TEXTURE_DEPTH: u32 = 0x0000_0180;
+const DRAW_MODE_DITHER: u32 = 0x0000_0200;
+const DRAW_MODE_DRAW_TO_DISPLAY: u32 = 0x0000_0400;
+const DRAW_MODE_TEXTURE_DISABLE: u32 = 0x0000_0800;
+const DRAW_MODE_FLIP_X: u32 = 0x0000_1000;
+const DRAW_MODE_FLIP_Y: u32 = 0x0000_2000;
+/// The draw mode bits that are mirrored in GPUSTAT bits 0-10
+pub const DRAW_MODE_STATUS_BITS: u32 = 0x0000_07FF;
+
+/// The texture page and drawing settings set by GP0 E1h
+///
+/// Textured polygons also overwrite the low 9 bits of this from their texpage
+/// attribute.
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
+pub struct DrawMode(u32);
+
+impl DrawMode {
+    /// The X base of the texture page, in halfwords
+    pub fn get_page_x(&self) -> u32 {
+        return (**self & DRAW_MODE_PAGE_X) * 64;
+    }
+
+    /// The Y base of the texture page, in lines
+    pub fn get_page_y(&self) -> u32 {
+        return ((**self & DRAW_MODE_PAGE_Y) >> 4) * 256;
+    }
+
+    pub fn get_semi_transparency(&self) -> SemiTransparency {
+        return match (**self & DRAW_MODE_SEMI_TRANSPARENCY) >> 5 {
+            0 => SemiTransparency::Average,
+            1 => SemiTransparency::Add,
+            2 => SemiTransparency::Subtract,
+            3 => SemiTransparency::AddQuarter,
+            _ => unreachable!(),
+        };
+    }
+
+    pub fn get_texture_depth(&self) -> TextureDepth {
+        return match (**self & DRAW_MODE_TEXTURE_DEPTH) >> 7 {
+            0 => TextureDepth::Clut4,
+            1 => TextureDepth::Clut8,
+            // 3 is reserved, and behaves like 15-bit
+            _ => TextureDepth::Direct15,
+        };
+    }
+
+    pub fn is_dithered(&self) -> bool {
+        return (**self & DRAW_MODE_DITHER) != 0;
+    }
+
+    pub fn is_draw_to_display_allowed(&self) -> bool {
+        return (**self & DRAW_MODE_DRAW_TO_DISPLAY) != 0;
+    }
+
+    pub fn is_texture_disabled(&self) -> bool {
+        return (**self & DRAW_MODE_TEXTURE_DISABLE) != 0;
+    }
+
+    /// Whether textured rectangles are mirrored horizontally
+    pub fn is_flipped_x(&self) -> bool {
+        return (**self & DRAW_MODE_FLIP_X) != 0;
+    }
+
+    /// Whether textured rectangles are mirrored vertically
+    pub fn is_flipped_y(&self) -> bool {
+        return (**self & DRAW_MODE_FLIP_Y) != 0;
+    }
+
+    /// Replace the texture page bits with those from a polygon's texpage
+    /// attribute
+    pub fn with_texpage(&self, texpage: u32) -> DrawMode {
+        // the texture disable bit is only honored if GP1 09h allows it, so
+        // the caller is expected to mask it accordingly
+        const TEXPAGE_BITS: u32 = 0x0000_09FF;
+        return DrawMode((**self & !TEXPAGE_BITS) | (texpage & TEXPAGE_BITS));
+    }
+}
+
+impl From<u32> for DrawMode {
+    fn from(data: u32) -> Self {
+        DrawMode(data & DRAW_MODE_USED)
+    }
+}
+
+impl Deref for DrawMode {
+    type Target = u32;
+
+    fn deref(&self) -> &u32 {
+        return &self.0;
+    }
+}
+//#endregion
+
+//#region Display mode (GP1 08h)
+/// The video standard the GPU generates
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum VideoMode {
+    Ntsc,
+    Pal,
+}
+
+const DISPLAY_MODE_USED: u32 = 0x0000_00FF;
+const DISPLAY_MODE_HRES_1: u32 = 0x0000_0003;
+const DISPLAY_MODE_VRES: u32 = 0x0000_0004;
+const DISPLAY_MODE_VIDEO_MODE: u32 = 0x0000_0008;
+const DISPLAY_MODE_COLOR_DEPTH: u32 = 0x0000_0010;
+const DISPLAY_MODE_INTERLACE: u32 = 0x0000_0020;
+const DISPLAY_MODE_HRES_2: u32 = 0x0000_0040;
+const DISPLAY_MODE_REVERSE: u32 = 0x0000_0080;
+
+/// The display settings set by GP1 08h
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
+pub struct DisplayMode(u32);
+
+impl DisplayMode {
+    /// The horizontal resolution, in dots
+    pub fn get_horizontal_res(&self) -> u32 {
+        if (**self & DISPLAY_MODE_HRES_2) != 0 {
+            return 368;
+        }
+        return match **self & DISPLAY_MODE_HRES_1 {
+            0 => 256,
+            1 => 320,
+            2 => 512,
+            3 => 640,
+            _ => unreachable!(),
+        };
+    }
+
+    /// The number of video clock cycles per dot
+    pub fn get_dot_clock_divider(&self) -> u32 {
+        if (**self & DISPLAY_MODE_HRES_2) != 0 {
+            return 7;
+        }
+        return match **self & DISPLAY_MODE_HRES_1 {
+            0 => 10,
+            1 => 8,
+            2 => 5,
+            3 => 4,
+            _ => unreachable!(),
+        };
+    }
+
+    /// Whether 480-line mode is selected (only takes effect when interlaced)
+    pub fn is_vres_480(&self) -> bool {
+        return (**self & DISPLAY_MODE_VRES) != 0;
+    }
+
+    pub fn get_video_mode(&self) -> VideoMode {
+        return match **self & DISPLAY_MODE_VIDEO_MODE {
+            0 => VideoMode::Ntsc,
+            _ => VideoMode::Pal,
+        };
+    }
+
+    /// Whether the display area is output as 24-bit color
+    pub fn is_24bit(&self) -> bool {
+        return (**self & DISPLAY_MODE_COLOR_DEPTH) != 0;
+    }
+
+    pub fn is_interlaced(&self) -> bool {
+        return (**self & DISPLAY_MODE_INTERLACE) != 0;
+    }
+
+    /// Return the bits of this register as they appear in GPUSTAT
+    pub fn get_status_bits(&self) -> u32 {
+        let hres_2 = (**self & DISPLAY_MODE_HRES_2) >> 6;
+        let hres_1 = **self & DISPLAY_MODE_HRES_1;
+        let rest = (**self & 0x3C) >> 2;
+        let reverse = (**self & DISPLAY_MODE_REVERSE) >> 7;
+        return (hres_2 << 16) | (hres_1 << 17) | (rest << 19) | (reverse << 14);
+    }
+}
+
+impl From<u32> for DisplayMode {
+    fn from(data: u32) -> Self {
+        DisplayMode(data & DISPLAY_MODE_USED)
+    }
+}
+
+impl Deref for DisplayMode {
+    type Target = u32;
+
+    fn deref(&self) -> &u32 {
+        return &self.0;
+    }
+}
+//#endregion
+
+/// The direction of GPU DMA transfers, set by GP1 04h
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum GpuDmaDirection {
+    Off,
+    /// Reports whether the command FIFO has room, without a DMA request
+    Fifo,
+    CpuToGp0,
+    GpuReadToCpu,
+}
+
+impl From<u32> for GpuDmaDirection {
+    fn from(data: u32) -> Self {
+        match data & 0x3 {
+            0 => GpuDmaDirection::Off,
+            1 => GpuDmaDirection::Fifo,
+            2 => GpuDmaDirection::CpuToGp0,
+            3 => GpuDmaDirection::GpuReadToCpu,
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! Video beam timing
+//!
+//! The GPU runs off its own video clock (11/7ths of the CPU clock), and the
+//! position of the beam on that clock determines when blanking happens. The
+//! dot clock used by timer 0 is the video clock divided by a factor that
+//! depends on the horizontal resolution.
+
+use super::structs::{DisplayMode, VideoMode};
+
+/// Video clock cycles per scanline
+const NTSC_CYCLES_PER_LINE: u32 = 3413;
+const PAL_CYCLES_PER_LINE: u32 = 3406;
+/// Scanlines per (progressive) frame
+const NTSC_LINES_PER_FRAME: u32 = 263;
+const PAL_LINES_PER_FRAME: u32 = 314;
+/// Scanlines per even and odd interlaced field
+const NTSC_LINES_PER_FIELD: (u32, u32) = (263, 262);
+const PAL_LINES_PER_FIELD: (u32, u32) = (313, 312);
+/// The visible scanlines; everything else is vertical blanking
+const NTSC_VERTICAL_ACTIVE: (u32, u32) = (16, 256);
+const PAL_VERTICAL_ACTIVE: (u32, u32) = (20, 308);
+/// The visible part of each scanline, in video clock cycles
+const HORIZONTAL_ACTIVE: (u32, u32) = (0x260, 0xC60);
+
+/// The state of the video signals after advancing the beam
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct VideoSignals {
+    /// Number of dot clock ticks that elapsed
+    pub dots: u32,
+    pub in_hblank: bool,
+    pub in_vblank: bool,
+    /// Whether the beam entered vertical blanking, finishing a frame
+    pub frame_completed: bool,
+}
+
+pub struct VideoTiming {
+    video_mode: VideoMode,
+    /// Video cycles per dot, for the current horizontal resolution
+    dot_clock_divider: u32,
+    is_interlaced: bool,
+    /// CPU cycles (times 11) that haven't yet made up a whole video cycle
+    cycle_remainder: u32,
+    /// Video cycles that haven't yet made up a whole dot
+    dot_remainder: u32,
+    /// Video cycles into the current scanline
+    line_cycle: u32,
+    /// Current scanline
+    scanline: u32,
+    /// Whether the current interlaced field is the odd one
+    is_odd_field: bool,
+}
+
+impl VideoTiming {
+    pub fn new() -> VideoTiming {
+        VideoTiming {
+            video_mode: VideoMode::Ntsc,
+            dot_clock_divider: 10,
+            is_interlaced: false,
+            cycle_remainder: 0,
+            dot_remainder: 0,
+            line_cycle: 0,
+            scanline: 0,
+            is_odd_field: false,
+        }
+    }
+
+    /// Update the timings from the display mode set by GP1 08h
+    pub fn set_display_mode(&mut self, mode: &DisplayMode) {
+        self.video_mode = mode.get_video_mode();
+        self.dot_clock_divider = mode.get_dot_clock_divider();
+        self.is_interlaced = mode.is_interlaced();
+    }
+
+    fn cycles_per_line(&self) -> u32 {
+        return match self.video_mode {
+            VideoMode::Ntsc => NTSC_CYCLES_PER_LINE,
+            VideoMode::Pal => PAL_CYCLES_PER_LINE,
+        };
+    }
+
+    fn lines_per_frame(&self) -> u32 {
+        if self.is_interlaced {
+            // a whole interlaced frame is an odd number of lines (525 or
+            // 625), so every other field is one line shorter
+            let (even, odd) = match self.video_mode {
+                VideoMode::Ntsc => NTSC_LINES_PER_FIELD,
+                VideoMode::Pal => PAL_LINES_PER_FIELD,
+            };
+            return if self.is_odd_field { odd } else { even };
+        }
+        return match self.video_mode {
+            VideoMode::Ntsc => NTSC_LINES_PER_FRAME,
+            VideoMode::Pal => PAL_LINES_PER_FRAME,
+        };
+    }
+
+    fn vertical_active(&self) -> (u32, u32) {
+        return match self.video_mode {
+            VideoMode::Ntsc => NTSC_VERTICAL_ACTIVE,
+            VideoMode::Pal => PAL_VERTICAL_ACTIVE,
+        };
+    }
+
+    pub fn is_in_vblank(&self) -> bool {
+        let (start, end) = self.vertical_active();
+        return !(start..end).contains(&self.scanline);
+    }
+
+    /// Whether the current interlaced field is the odd one
+    pub fn is_odd_field(&self) -> bool {
+        return self.is_odd_field;
+    }
+
+    /// Whether the beam is on an odd scanline
+    pub fn is_odd_line(&self) -> bool {
+        return (self.scanline & 1) != 0;
+    }
+
+    /// Advance the beam by the given number of CPU cycles
+    pub fn tick(&mut self, cycles: u32) -> VideoSignals {
+        self.cycle_remainder += cycles * 11;
+        let video_cycles = self.cycle_remainder / 7;
+        self.cycle_remainder %= 7;
+
+        self.dot_remainder += video_cycles;
+        let dots = self.dot_remainder / self.dot_clock_divider;
+        self.dot_remainder %= self.dot_clock_divider;
+
+        let mut frame_completed = false;
+        self.line_cycle += video_cycles;
+        while self.line_cycle >= self.cycles_per_line() {
+            self.line_cycle -= self.cycles_per_line();
+            self.scanline += 1;
+            if self.scanline == self.vertical_active().1 {
+                frame_completed = true;
+            }
+            if self.scanline >= self.lines_per_frame() {
+                self.scanline = 0;
+                self.is_odd_field = !self.is_odd_field;
+            }
+        }
+
+        let (h_start, h_end) = HORIZONTAL_ACTIVE;
+        return VideoSignals {
+            dots,
+            in_hblank: !(h_start..h_end).contains(&self.line_cycle),
+            in_vblank: self.is_in_vblank(),
+            frame_completed,
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Count the CPU cycles until the next frame completes
+    fn cycles_to_next_frame(timing: &mut VideoTiming) -> u32 {
+        let mut cycles = 1;
+        while !timing.tick(1).frame_completed {
+            cycles += 1;
+        }
+        cycles
+    }
+
+    /// Count the CPU cycles between two frame completions
+    fn measure_frame(timing: &mut VideoTiming) -> u32 {
+        cycles_to_next_frame(timing);
+        cycles_to_next_frame(timing)
+    }
+
+    #[test]
+    fn runs_ntsc_frames_at_60hz() {
+        let mut timing = VideoTiming::new();
+        let cycles = measure_frame(&mut timing);
+        // 263 lines of 3413 video cycles, converted to CPU cycles
+        assert!((cycles as i32 - 263 * 3413 * 7 / 11).abs() <= 1);
+    }
+
+    #[test]
+    fn runs_pal_frames_at_50hz() {
+        let mut timing = VideoTiming::new();
+        timing.set_display_mode(&DisplayMode::from(0x08));
+        let cycles = measure_frame(&mut timing);
+        assert!((cycles as i32 - 314 * 3406 * 7 / 11).abs() <= 1);
+    }
+
+    #[test]
+    fn divides_dot_clock_by_resolution() {
+        let mut timing = VideoTiming::new();
+        // 640 wide is a divider of 4
+        timing.set_display_mode(&DisplayMode::from(0x03));
+        assert_eq!(timing.tick(7 * 40).dots, 11 * 10);
+        // 368 wide is a divider of 7
+        timing.set_display_mode(&DisplayMode::from(0x40));
+        assert_eq!(timing.tick(7 * 7).dots, 11);
+    }
+
+    #[test]
+    fn alternates_interlaced_fields() {
+        let mut timing = VideoTiming::new();
+        timing.set_display_mode(&DisplayMode::from(0x24));
+        let first = timing.is_odd_field();
+        measure_frame(&mut timing);
+        assert_ne!(timing.is_odd_field(), first);
+        let first_field = cycles_to_next_frame(&mut timing);
+        let second_field = cycles_to_next_frame(&mut timing);
+        let difference = (first_field as i32 - second_field as i32).abs();
+        // one scanline, give or take the rounding at either end
+        assert!((difference - 3413 * 7 / 11).abs() <= 2);
+    }
+
+    /// Count the scanlines in the next two interlaced fields
+    fn lines_per_field_pair(timing: &mut VideoTiming) -> u32 {
+        let start_field = timing.is_odd_field();
+        while timing.is_odd_field() == start_field {
+            timing.tick(1);
+        }
+        let mut lines = 0;
+        let mut scanline = timing.scanline;
+        let mut fields = 0;
+        while fields < 2 {
+            let field = timing.is_odd_field();
+            timing.tick(1);
+            if timing.scanline != scanline {
+                lines += 1;
+                scanline = timing.scanline;
+            }
+            if timing.is_odd_field() != field {
+                fields += 1;
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn runs_525_line_ntsc_interlaced_frames() {
+        let mut timing = VideoTiming::new();
+        timing.set_display_mode(&DisplayMode::from(0x24));
+        assert_eq!(lines_per_field_pair(&mut timing), 525);
+    }
+
+    #[test]
+    fn runs_625_line_pal_interlaced_frames() {
+        let mut timing = VideoTiming::new();
+        timing.set_display_mode(&DisplayMode::from(0x2C));
+        assert_eq!(lines_per_field_pair(&mut timing), 625);
+    }
+}
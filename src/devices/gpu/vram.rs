@@ -0,0 +1,87 @@
+//! The GPU's video memory
+
+/// VRAM width, in 16-bit pixels
+pub const VRAM_WIDTH: u32 = 1024;
+/// VRAM height, in lines
+pub const VRAM_HEIGHT: u32 = 512;
+
+/// The bit of a 15-bit pixel used as the mask bit
+pub const MASK_BIT: u16 = 0x8000;
+
+/// 1MiB of video memory, laid out as a 1024x512 buffer of 16-bit pixels
+///
+/// All coordinates wrap around the edges of VRAM, as they do on hardware.
+pub struct Vram {
+    data: Vec<u16>,
+}
+
+impl Vram {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Vram {
+        Vram {
+            data: vec![0u16; (VRAM_WIDTH * VRAM_HEIGHT) as usize],
+        }
+    }
+
+    fn index(x: u32, y: u32) -> usize {
+        return ((y & (VRAM_HEIGHT - 1)) * VRAM_WIDTH + (x & (VRAM_WIDTH - 1))) as usize;
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> u16 {
+        return self.data[Vram::index(x, y)];
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, data: u16) {
+        self.data[Vram::index(x, y)] = data;
+    }
+
+    /// The raw contents of VRAM, line by line
+    pub fn as_slice(&self) -> &[u16] {
+        return &self.data;
+    }
+}
+
+/// The position of an in-progress transfer between VRAM and the CPU
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct VramTransfer {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    /// The current offset into the transfer rectangle
+    col: u32,
+    row: u32,
+}
+
+impl VramTransfer {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> VramTransfer {
+        VramTransfer {
+            x,
+            y,
+            width,
+            height,
+            col: 0,
+            row: 0,
+        }
+    }
+
+    /// Return the coordinates of the next pixel, and advance the transfer
+    ///
+    /// Returns None once every pixel has been transferred.
+    pub fn next_pixel(&mut self) -> Option<(u32, u32)> {
+        if self.is_done() {
+            return None;
+        }
+        let pos = (self.x + self.col, self.y + self.row);
+        self.col += 1;
+        if self.col == self.width {
+            self.col = 0;
+            self.row += 1;
+        }
+        return Some(pos);
+    }
+
+    pub fn is_done(&self) -> bool {
+        return self.row >= self.height;
+    }
+}
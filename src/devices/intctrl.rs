@@ -0,0 +1,144 @@
+use super::bus::{BusDevice, SizedData};
+use log::debug;
+
+const I_STAT_PORT: u32 = 0x0;
+const I_MASK_PORT: u32 = 0x4;
+
+/// Only the low 11 bits of I_STAT and I_MASK are connected to anything
+const IRQ_LINES_MASK: u32 = 0x07FF;
+
+/// The hardware interrupt lines wired into the interrupt controller
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Irq {
+    /// Raised by the GPU at the start of vertical blanking
+    VBlank = 0,
+    /// Raised by the GPU via GP0 1Fh
+    Gpu = 1,
+    CdRom = 2,
+    Dma = 3,
+    Timer0 = 4,
+    Timer1 = 5,
+    Timer2 = 6,
+    /// Controllers and memory cards (SIO0)
+    Controller = 7,
+    /// The serial port (SIO1)
+    Sio = 8,
+    Spu = 9,
+    /// Secondary controller IRQ, used by lightguns
+    Lightpen = 10,
+}
+
+/// The interrupt controller at 0x1F801070
+///
+/// Devices latch interrupt requests into I_STAT, and software acknowledges
+/// them by writing 0 to the corresponding bits. Whenever any bit in I_STAT is
+/// also set in I_MASK, the controller drives the CPU's hardware interrupt line
+/// (cop0 CAUSE bit 10).
+pub struct InterruptController {
+    /// Interrupt status register
+    status: u32,
+    /// Interrupt mask register
+    mask: u32,
+}
+
+impl InterruptController {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> InterruptController {
+        InterruptController { status: 0, mask: 0 }
+    }
+
+    /// Latch an interrupt request into I_STAT
+    pub fn request(&mut self, irq: Irq) {
+        debug!(target: "intctrl", "IRQ raised: {:?}", irq);
+        self.status |= 1 << (irq as u32);
+    }
+
+    /// Return whether the CPU interrupt line is asserted
+    pub fn is_pending(&self) -> bool {
+        (self.status & self.mask) != 0
+    }
+
+    fn read_reg(&self, addr: u32) -> u32 {
+        match addr & !0x3 {
+            I_STAT_PORT => self.status,
+            I_MASK_PORT => self.mask,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl BusDevice for InterruptController {
+    fn read<T: SizedData>(&mut self, addr: u32) -> T {
+        T::from_u32(self.read_reg(addr) >> ((addr & 0x3) * 8))
+    }
+
+    fn peek<T: SizedData>(&self, addr: u32) -> Option<T> {
+        Some(T::from_u32(self.read_reg(addr) >> ((addr & 0x3) * 8)))
+    }
+
+    fn write<T: SizedData>(&mut self, addr: u32, data: T) {
+        let shift = (addr & 0x3) * 8;
+        // narrower writes only touch the byte lanes they address
+        let lanes = if T::width() == 4 {
+            0xFFFF_FFFF
+        } else {
+            ((1u32 << (T::width() * 8)) - 1) << shift
+        };
+        let data = (data.to_u32() << shift) & lanes;
+        match addr & !0x3 {
+            // writing 0 to a status bit acknowledges that interrupt, writing 1
+            // leaves it unchanged
+            I_STAT_PORT => self.status &= (data | !lanes) & IRQ_LINES_MASK,
+            I_MASK_PORT => {
+                self.mask = ((self.mask & !lanes) | data) & IRQ_LINES_MASK;
+                debug!(target: "intctrl", "I_MASK = 0x{:04X}", self.mask);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latches_masked_interrupts() {
+        let mut intctrl = InterruptController::new();
+        intctrl.request(Irq::VBlank);
+        assert!(!intctrl.is_pending(), "Unmasked IRQ should not be pending");
+        intctrl.write(I_MASK_PORT, 0x0001u32);
+        assert!(intctrl.is_pending());
+        assert_eq!(intctrl.read::<u32>(I_STAT_PORT), 0x0001);
+    }
+
+    #[test]
+    fn acknowledges_interrupts() {
+        let mut intctrl = InterruptController::new();
+        intctrl.write(I_MASK_PORT, 0xFFFFu16);
+        intctrl.request(Irq::VBlank);
+        intctrl.request(Irq::Dma);
+        intctrl.write(I_STAT_PORT, !(1u32 << (Irq::VBlank as u32)));
+        assert_eq!(intctrl.read::<u16>(I_STAT_PORT), 1 << (Irq::Dma as u32));
+        intctrl.write(I_STAT_PORT, 0u32);
+        assert!(!intctrl.is_pending());
+    }
+
+    #[test]
+    fn applies_narrow_writes_to_their_lanes() {
+        let mut intctrl = InterruptController::new();
+        intctrl.write(I_MASK_PORT, 0x01u8);
+        intctrl.write(I_MASK_PORT + 1, 0x02u8);
+        assert_eq!(intctrl.read::<u32>(I_MASK_PORT), 0x0201);
+        intctrl.request(Irq::VBlank);
+        intctrl.request(Irq::Sio);
+        intctrl.request(Irq::Spu);
+        // acknowledge the SIO IRQ (bit 8) only, leaving the low byte alone
+        intctrl.write(I_STAT_PORT + 1, 0xFEu8);
+        assert_eq!(intctrl.read::<u32>(I_STAT_PORT), 0x0201);
+        intctrl.write(I_STAT_PORT + 2, 0x0000u16);
+        assert_eq!(intctrl.read::<u32>(I_STAT_PORT), 0x0201);
+        intctrl.write(I_STAT_PORT, 0xFEu8);
+        assert_eq!(intctrl.read::<u32>(I_STAT_PORT), 0x0200);
+    }
+}
@@ -20,6 +20,7 @@ const COM_DELAY_PORT: u32 = 0x20;
 pub struct MemoryController {}
 
 impl MemoryController {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> MemoryController {
         MemoryController {}
     }
@@ -1,8 +1,14 @@
 pub mod bus;
+pub mod cdrom;
 pub mod cpu;
 pub mod dma;
 pub mod gpu;
+pub mod intctrl;
 pub mod memctrl;
 pub mod motherboard;
 pub mod ram;
+pub mod region;
 pub mod rom;
+pub mod sio;
+pub mod spu;
+pub mod timers;
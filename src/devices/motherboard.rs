@@ -1,12 +1,30 @@
 use crate::devices::bus::{BusDevice, SizedData};
+use crate::devices::cdrom::disc::DiscImage;
+use crate::devices::cdrom::CdRom;
 use crate::devices::cpu;
+use crate::devices::cpu::structs::{MagicAddress, RegisterIndex};
 use crate::devices::dma;
 use crate::devices::gpu;
+use crate::devices::intctrl::{InterruptController, Irq};
 use crate::devices::memctrl::MemoryController;
 use crate::devices::ram::Ram;
+use crate::devices::region::Region;
 use crate::devices::rom::Rom;
+use crate::devices::sio::{DigitalPad, InputState, Peripheral, Sio0};
+use crate::devices::spu::{AudioSink, NullSink, Spu};
+use crate::devices::timers::Timers;
+use crate::utils::exe::PsxExe;
 use crate::utils::memorymap::{map_device, Device};
-use log::{debug, warn};
+use log::{debug, error, info};
+use std::io;
+
+/// Where SIO1, the serial port, starts in the peripheral I/O range. It isn't
+/// emulated, since nothing ships with the link cable plugged in.
+const SIO1_OFFSET: u32 = 0x10;
+
+/// How often memory cards are saved, about once a second. Cards only write
+/// their image if it's changed.
+const FRAMES_PER_FLUSH: u32 = 60;
 
 /// This represents the system motherboard.
 ///
@@ -18,21 +36,168 @@ pub struct Motherboard {
     dma: dma::DmaController,
     cpu: cpu::CpuR3000,
     gpu: gpu::Gpu,
+    cdrom: CdRom,
+    spu: Spu,
+    /// Where the SPU's output goes
+    audio_sink: Box<dyn AudioSink>,
+    intctrl: InterruptController,
+    timers: Timers,
+    sio0: Sio0,
+    /// Frames since memory cards were last saved
+    frames_since_flush: u32,
+    /// Whether a frame has completed since the last check
+    frame_completed: bool,
+    /// An EXE to load once the BIOS reaches the shell
+    pending_exe: Option<PsxExe>,
 }
 
 impl Motherboard {
     pub fn tick(&mut self) {
+        let signals = self.gpu.tick(1);
+        if self.gpu.take_irq() {
+            self.intctrl.request(Irq::Gpu);
+        }
+        self.timers.tick(1, &mut self.intctrl);
+        self.timers.tick_dotclock(signals.dots, &mut self.intctrl);
+        self.timers.set_hblank(signals.in_hblank, &mut self.intctrl);
+        self.timers.set_vblank(signals.in_vblank);
+        self.cdrom.tick(1, &mut self.intctrl);
+        self.spu.push_cd_audio(self.cdrom.drain_audio());
+        self.spu.tick(1, self.audio_sink.as_mut());
+        self.sio0.tick(1, &mut self.intctrl);
+        if self.spu.take_irq() {
+            self.intctrl.request(Irq::Spu);
+        }
+        if signals.frame_completed {
+            self.intctrl.request(Irq::VBlank);
+            self.frame_completed = true;
+            self.frames_since_flush += 1;
+            if self.frames_since_flush >= FRAMES_PER_FLUSH {
+                self.frames_since_flush = 0;
+                if let Err(err) = self.sio0.flush() {
+                    error!(target: "mb", "Failed to save memory cards: {}", err);
+                }
+            }
+        }
+
+        let irq_pending = self.intctrl.is_pending();
+        self.cpu.cop0.set_interrupt_line(irq_pending);
+        if self.cpu.state.next_instruction.1 == MagicAddress::ShellEntry as u32 {
+            if let Some(exe) = self.pending_exe.take() {
+                self.load_exe(exe);
+            }
+        }
         cpu::exec(self);
     }
 
+    /// Copy an EXE into RAM, set up its registers, and jump to it
+    fn load_exe(&mut self, exe: PsxExe) {
+        info!(target: "mb", "Loading EXE at ${:08X}, entry ${:08X}", exe.text_address, exe.pc);
+        let (_, _, text_address) = map_device(exe.text_address);
+        self.ram.load(text_address, &exe.text);
+        if exe.bss_size > 0 {
+            let (_, _, bss_address) = map_device(exe.bss_address);
+            self.ram
+                .load(bss_address, &vec![0u8; exe.bss_size as usize]);
+        }
+        let registers = &mut self.cpu.state.registers;
+        registers[RegisterIndex::GP as usize] = exe.gp;
+        if let Some(stack) = exe.stack {
+            registers[RegisterIndex::SP as usize] = stack;
+            registers[RegisterIndex::FP as usize] = stack;
+        }
+        cpu::jump(self, exe.pc);
+    }
+
+    /// Run until the start of the next vertical blanking period
+    pub fn run_frame(&mut self) {
+        while !self.take_frame_completed() {
+            self.tick();
+        }
+    }
+
+    /// Return, and clear, whether a frame has completed
+    ///
+    /// Frontends can poll this after each tick to know when to present the
+    /// display area.
+    pub fn take_frame_completed(&mut self) -> bool {
+        let completed = self.frame_completed;
+        self.frame_completed = false;
+        completed
+    }
+
+    /// Put a disc in the CD-ROM drive
+    pub fn insert_disc(&mut self, disc: Box<dyn DiscImage>) {
+        self.cdrom.insert_disc(disc);
+    }
+
+    /// Set the console's region, which decides the discs it'll boot
+    ///
+    /// With no region set, any licensed disc is accepted.
+    pub fn set_region(&mut self, region: Option<Region>) {
+        self.cdrom.set_region(region);
+    }
+
+    /// Send the SPU's output to a different sink
+    ///
+    /// The default sink discards everything.
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink>) {
+        self.audio_sink = sink;
+    }
+
+    pub fn audio_sink_mut(&mut self) -> &mut dyn AudioSink {
+        return self.audio_sink.as_mut();
+    }
+
+    /// Plug a controller into a port (0 or 1), or unplug it with `None`
+    ///
+    /// A digital pad starts out plugged into the first port.
+    pub fn set_controller(&mut self, port: usize, controller: Option<Box<dyn Peripheral>>) {
+        self.sio0.set_controller(port, controller);
+    }
+
+    /// Put a memory card in a slot (0 or 1), or take it out with `None`
+    pub fn set_memory_card(&mut self, port: usize, card: Option<Box<dyn Peripheral>>) {
+        self.sio0.set_memory_card(port, card);
+    }
+
+    /// Save memory cards now, rather than waiting for the next periodic save
+    pub fn flush_memory_cards(&mut self) -> io::Result<()> {
+        return self.sio0.flush();
+    }
+
+    /// Run an EXE instead of whatever the BIOS would boot
+    ///
+    /// The BIOS still runs first to set up the kernel, and the EXE is loaded
+    /// when it would otherwise start the shell.
+    pub fn sideload_exe(&mut self, exe: PsxExe) {
+        self.pending_exe = Some(exe);
+    }
+
+    /// Update what's being pressed on the controller in a port
+    pub fn set_controller_input(&mut self, port: usize, input: &InputState) {
+        self.sio0.set_input(port, input);
+    }
+
     pub fn new(bios: Vec<u8>) -> Motherboard {
+        let mut sio0 = Sio0::new();
+        sio0.set_controller(0, Some(Box::new(DigitalPad::new())));
         return Motherboard {
             bios: Rom::from_buf(bios),
             ram: Ram::with_size(2 * 1024 * 1024),
             cpu: cpu::CpuR3000::new(),
             gpu: gpu::Gpu::new(),
+            cdrom: CdRom::new(),
+            spu: Spu::new(),
+            audio_sink: Box::new(NullSink),
             dma: dma::DmaController::new(),
             memctrl: MemoryController::new(),
+            intctrl: InterruptController::new(),
+            timers: Timers::new(),
+            sio0,
+            frames_since_flush: 0,
+            frame_completed: false,
+            pending_exe: None,
         };
     }
 }
@@ -54,23 +219,24 @@ impl BusDevice for Motherboard {
             }
             // Device::Scratch => {}
             Device::MemCtrl => self.memctrl.read::<T>(local_addr),
-            Device::SPU => {
-                debug!(target: "cpu", "Attempt to read from SPU, ignoring for now");
+            Device::IOPeripheral if local_addr < SIO1_OFFSET => self.sio0.read::<T>(local_addr),
+            Device::IOPeripheral => {
+                debug!(target: "mb", "Attempt to read from SIO1, ignoring");
                 T::from_u32(0)
             }
+            Device::SPU => self.spu.read::<T>(local_addr),
             // Device::Expansion2 => {}
             // Device::Expansion3 => {}
+            Device::CdRom => self.cdrom.read::<T>(local_addr),
             Device::GPU => self.gpu.read::<T>(local_addr),
             Device::BIOS => self.bios.read::<T>(local_addr),
-            Device::IntCtrl => {
-                debug!(target: "mb", "Attempt to read from interrupt controller, ignoring for now");
-                T::from_u32(0)
-            }
+            Device::IntCtrl => self.intctrl.read::<T>(local_addr),
             Device::RamCtrl => {
                 debug!(target: "mb", "Attempt to read from RAM memory controller, ignoring for now");
                 T::from_u32(0)
             }
             Device::DMA => self.dma.read::<T>(local_addr),
+            Device::Timers => self.timers.read::<T>(local_addr),
             _ => panic!("Unmapped memory read from dev {:?}: ${:08X}", dev, addr),
             // Device::IOCacheControl => {}
             // Device::None => {}
@@ -88,14 +254,15 @@ impl BusDevice for Motherboard {
             // Device::Expansion1 => {}
             // Device::Scratch => {}
             Device::MemCtrl => self.memctrl.peek::<T>(local_addr),
-            Device::SPU => {
-                debug!("Attempt to peek from SPU, ignoring for now");
-                Some(T::from_u32(0))
-            }
+            Device::IOPeripheral if local_addr < SIO1_OFFSET => self.sio0.peek::<T>(local_addr),
+            Device::SPU => self.spu.peek::<T>(local_addr),
             // Device::Expansion2 => {}
             // Device::Expansion3 => {}
+            Device::CdRom => self.cdrom.peek::<T>(local_addr),
             Device::GPU => self.gpu.peek::<T>(local_addr),
             Device::BIOS => self.bios.peek::<T>(local_addr),
+            Device::IntCtrl => self.intctrl.peek::<T>(local_addr),
+            Device::Timers => self.timers.peek::<T>(local_addr),
             _ => None,
             // Device::IOCacheControl => {}
             // Device::None => {}
@@ -113,13 +280,21 @@ impl BusDevice for Motherboard {
             // Device::Expansion1 => {}
             // Device::Scratch => {}
             Device::MemCtrl => self.memctrl.write(local_addr, data),
+            Device::IOPeripheral if local_addr < SIO1_OFFSET => self.sio0.write(local_addr, data),
+            Device::IOPeripheral => {
+                debug!(target: "mb", "Attempt to write to SIO1: ${:08X} = 0x{:08X}", addr, data);
+            }
             Device::SPU => {
-                debug!(target: "mb", "Attempt to write to SPU, but SPU is unimplemented: ${:08X} = 0x{:08X}", addr, data)
+                self.spu.write(local_addr, data);
+                if self.spu.take_irq() {
+                    self.intctrl.request(Irq::Spu);
+                }
             }
             Device::Expansion2 => {
                 debug!(target: "cpu", "Attempt to write to Expansion2: ${:08X} = 0x{:08X}", addr, data);
             }
             // Device::Expansion3 => {}
+            Device::CdRom => self.cdrom.write(local_addr, data),
             Device::GPU => self.gpu.write(local_addr, data),
             Device::BIOS => panic!(
                 "Attempt to write 0x{:08X} to read-only BIOS at ${:08}",
@@ -132,20 +307,18 @@ impl BusDevice for Motherboard {
                     addr, data
                 );
             }
-            Device::IntCtrl => {
-                if data != T::from_u32(0x0) {
-                    warn!(target: "mb", "Enabling write to I_MASK, this program is expecting interrupts");
-                    return;
-                }
-                debug!(target: "mb", "Disabling write to I_MASK");
-            }
+            Device::IntCtrl => self.intctrl.write::<T>(local_addr, data),
             Device::RamCtrl => {
                 debug!(target: "mb", "Attempt to write to RAM memory controller, ignoring for now");
             }
-            Device::Timers => {
-                debug!(target: "mb", "Attempt to write to timer controller, ignoring for now");
+            Device::Timers => self.timers.write::<T>(local_addr, data),
+            Device::DMA => {
+                self.dma.write::<T>(local_addr, data);
+                dma::exec(self);
+                if self.dma.take_irq() {
+                    self.intctrl.request(Irq::Dma);
+                }
             }
-            Device::DMA => self.dma.write::<T>(local_addr, data),
             _ => panic!("Unmapped memory write to dev {:?}: ${:08X}", dev, addr),
             // Device::None => {}
             // Device::VMemException => {}
@@ -171,3 +344,109 @@ impl gpu::WithGpu for Motherboard {
         &mut self.gpu
     }
 }
+
+impl dma::WithDma for Motherboard {
+    fn dma(&self) -> &dma::DmaController {
+        &self.dma
+    }
+
+    fn dma_mut(&mut self) -> &mut dma::DmaController {
+        &mut self.dma
+    }
+
+    fn dma_read(&mut self, port: dma::DmaPort) -> u32 {
+        match port {
+            // GPUREAD
+            dma::DmaPort::Gpu => self.gpu.read::<u32>(0),
+            dma::DmaPort::CdRom => self.cdrom.read_data_word(),
+            dma::DmaPort::Spu => self.spu.read_dma_word(),
+            _ => {
+                debug!(target: "mb", "DMA read from unimplemented port {:?}", port);
+                0
+            }
+        }
+    }
+
+    fn dma_write(&mut self, port: dma::DmaPort, data: u32) {
+        match port {
+            // GP0
+            dma::DmaPort::Gpu => self.gpu.write::<u32>(0, data),
+            dma::DmaPort::Spu => self.spu.write_dma_word(data),
+            _ => {
+                debug!(target: "mb", "DMA write to unimplemented port {:?}: 0x{:08X}", port, data);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::gpu::WithGpu;
+
+    const GP0: u32 = 0x1F80_1810;
+    const DPCR: u32 = 0x1F80_10F0;
+    const GPU_MADR: u32 = 0x1F80_10A0;
+    const GPU_BCR: u32 = 0x1F80_10A4;
+    const GPU_CHCR: u32 = 0x1F80_10A8;
+
+    #[test]
+    fn transfers_vram_through_gpu_dma() {
+        let mut mb = Motherboard::new(vec![0u8; 512 * 1024]);
+        mb.write(DPCR, 0x0765_4B21u32);
+        for i in 0..4u32 {
+            mb.write(0x1000 + i * 4, 0x0001_0001u32 * (i + 1));
+        }
+
+        // upload a 4x2 image in two blocks of two words
+        mb.write(GP0, 0xA000_0000u32);
+        mb.write(GP0, 0x0008_0010u32);
+        mb.write(GP0, 0x0002_0004u32);
+        mb.write(GPU_MADR, 0x1000u32);
+        mb.write(GPU_BCR, 0x0002_0002u32);
+        mb.write(GPU_CHCR, 0x0100_0201u32);
+        assert_eq!(mb.gpu().vram().get_pixel(0x10, 0x08), 1);
+        assert_eq!(mb.gpu().vram().get_pixel(0x13, 0x09), 4);
+
+        // and read it back somewhere else in RAM
+        mb.write(GP0, 0xC000_0000u32);
+        mb.write(GP0, 0x0008_0010u32);
+        mb.write(GP0, 0x0002_0004u32);
+        mb.write(GPU_MADR, 0x2000u32);
+        mb.write(GPU_BCR, 0x0002_0002u32);
+        mb.write(GPU_CHCR, 0x0100_0200u32);
+        for i in 0..4u32 {
+            assert_eq!(mb.read::<u32>(0x2000 + i * 4), 0x0001_0001 * (i + 1));
+        }
+    }
+
+    #[test]
+    fn sideloads_exe_at_shell_entry() {
+        let mut mb = Motherboard::new(vec![0u8; 512 * 1024]);
+        mb.write(0x0001_0800, 0xFFFF_FFFFu32);
+        let mut text = vec![0u8; 0x800];
+        // ori $v0, $zero, 0x1234
+        text[..4].copy_from_slice(&0x3402_1234u32.to_le_bytes());
+        mb.sideload_exe(PsxExe {
+            pc: 0x8001_0000,
+            gp: 0x8004_0000,
+            text_address: 0x8001_0000,
+            text,
+            bss_address: 0x8001_0800,
+            bss_size: 0x100,
+            stack: Some(0x801F_FFF0),
+            region: String::new(),
+        });
+
+        mb.tick();
+        assert_eq!(mb.cpu.state.registers[2], 0);
+        cpu::jump(&mut mb, MagicAddress::ShellEntry as u32);
+        mb.tick();
+        let registers = &mb.cpu.state.registers;
+        assert_eq!(registers[2], 0x1234);
+        assert_eq!(registers[RegisterIndex::GP as usize], 0x8004_0000);
+        assert_eq!(registers[RegisterIndex::SP as usize], 0x801F_FFF0);
+        assert_eq!(mb.read::<u32>(0x0001_0800), 0);
+        assert!(mb.pending_exe.is_none());
+    }
+}
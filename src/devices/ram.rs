@@ -11,6 +11,17 @@ impl Ram {
         };
     }
 
+    /// Copy a block of bytes in, starting at a local address
+    ///
+    /// This is for loading programs, so it wraps around like the RAM mirrors
+    /// do instead of panicking at the end.
+    pub fn load(&mut self, addr: u32, bytes: &[u8]) {
+        let size = self.data.len();
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.data[(addr as usize + i) % size] = byte;
+        }
+    }
+
     fn read_buf<T: SizedData>(&self, addr: usize) -> T {
         return T::from_le_byteslice(&self.data[addr..(addr + T::width())]);
     }
@@ -0,0 +1,65 @@
+//! Console regions
+//!
+//! A console's region decides which discs its CD-ROM drive accepts, by
+//! matching the "SCEx" string licensed discs carry, and which video standard
+//! its BIOS sets up.
+
+use crate::devices::gpu::VideoMode;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Region {
+    /// NTSC-J, for discs licensed by Sony Computer Entertainment Inc.
+    Japan,
+    /// NTSC-U, for discs licensed by Sony Computer Entertainment America
+    NorthAmerica,
+    /// PAL, for discs licensed by Sony Computer Entertainment Europe
+    Europe,
+}
+
+impl Region {
+    /// The last letter of the SCEx string on discs from this region
+    pub fn get_license_letter(&self) -> u8 {
+        return match self {
+            Region::Japan => b'I',
+            Region::NorthAmerica => b'A',
+            Region::Europe => b'E',
+        };
+    }
+
+    pub fn get_video_mode(&self) -> VideoMode {
+        return match self {
+            Region::Japan | Region::NorthAmerica => VideoMode::Ntsc,
+            Region::Europe => VideoMode::Pal,
+        };
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    /// Parse a region from a name like "usa" or a standard like "ntsc-u"
+    fn from_str(name: &str) -> Result<Region, String> {
+        return match name.to_ascii_lowercase().as_str() {
+            "japan" | "jp" | "ntsc-j" => Ok(Region::Japan),
+            "usa" | "us" | "ntsc-u" => Ok(Region::NorthAmerica),
+            "europe" | "eu" | "pal" => Ok(Region::Europe),
+            _ => Err(format!(
+                "Not a region: {} (expected japan, usa or europe)",
+                name
+            )),
+        };
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Region::Japan => "NTSC-J",
+            Region::NorthAmerica => "NTSC-U",
+            Region::Europe => "PAL",
+        };
+        return write!(f, "{}", name);
+    }
+}
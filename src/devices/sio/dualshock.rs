@@ -0,0 +1,343 @@
+//! The SCPH-1200 DualShock analog controller
+//!
+//! The DualShock starts out in digital mode, answering exactly like a digital
+//! pad. In analog mode (with the LED lit) reads also report both sticks. Games
+//! switch modes, and set up rumble, through a config mode entered with command
+//! 0x43:
+//!
+//! | Command | Mode    | Effect                                           |
+//! |---------|---------|--------------------------------------------------|
+//! | 0x42    | any     | Read buttons and sticks, and drive the motors    |
+//! | 0x43    | any     | Enter (0x01) or leave (0x00) config mode         |
+//! | 0x44    | config  | Pick digital (0x00) or analog (0x01), and lock   |
+//! | 0x45    | config  | Report the controller type and LED state         |
+//! | 0x46    | config  | Constant tables                                  |
+//! | 0x47    | config  | Constant tables                                  |
+//! | 0x4C    | config  | Constant tables                                  |
+//! | 0x4D    | config  | Map read parameters to the motors                |
+//!
+//! Every command is followed by 0x5A then 2 or 6 bytes, which carry the
+//! command's parameters one way and its reply the other.
+
+use super::pad::{CONTROLLER_ADDRESS, REPLY_READY};
+use super::peripheral::{InputState, Peripheral};
+
+//#region Controller IDs
+const DIGITAL_ID: u8 = 0x41;
+const ANALOG_ID: u8 = 0x73;
+const CONFIG_ID: u8 = 0xF3;
+//#endregion
+
+//#region Commands
+const READ: u8 = 0x42;
+const CONFIG_MODE: u8 = 0x43;
+const SET_MODE: u8 = 0x44;
+const GET_STATUS: u8 = 0x45;
+const GET_CONSTANT_46: u8 = 0x46;
+const GET_CONSTANT_47: u8 = 0x47;
+const GET_CONSTANT_4C: u8 = 0x4C;
+const SET_RUMBLE: u8 = 0x4D;
+//#endregion
+
+/// The number of parameters 0x4D can map to a motor
+const RUMBLE_CONFIG_SIZE: usize = 6;
+/// Rumble config entries for each motor, with anything else unmapped
+const SMALL_MOTOR: u8 = 0x00;
+const LARGE_MOTOR: u8 = 0x01;
+/// Mode lock value for 0x44, which stops the Analog button changing modes
+const MODE_LOCKED: u8 = 0x03;
+
+/// What the console is asking the controller to show the player
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct Feedback {
+    /// The small motor, which is either off (0x00) or on (0xFF)
+    pub small_motor: u8,
+    /// The large motor's strength, from off (0x00) to full (0xFF)
+    pub large_motor: u8,
+    /// The red LED, lit in analog mode
+    pub is_led_on: bool,
+}
+
+pub struct DualShock {
+    input: InputState,
+    is_analog: bool,
+    /// Whether the Analog button is ignored
+    is_mode_locked: bool,
+    is_config_mode: bool,
+    /// Which read parameter drives which motor
+    rumble_config: [u8; RUMBLE_CONFIG_SIZE],
+    feedback: Feedback,
+    on_feedback: Option<Box<dyn FnMut(Feedback)>>,
+    /// The byte of the exchange coming next, or None if the controller isn't
+    /// being talked to
+    position: Option<usize>,
+    command: u8,
+    /// The ID replied at the start of the current command
+    id: u8,
+    params: [u8; RUMBLE_CONFIG_SIZE],
+}
+
+impl DualShock {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> DualShock {
+        return DualShock {
+            input: InputState::new(),
+            is_analog: false,
+            is_mode_locked: false,
+            is_config_mode: false,
+            rumble_config: [0xFF; RUMBLE_CONFIG_SIZE],
+            feedback: Feedback::default(),
+            on_feedback: None,
+            position: Some(0),
+            command: READ,
+            id: DIGITAL_ID,
+            params: [0; RUMBLE_CONFIG_SIZE],
+        };
+    }
+
+    /// Call `callback` whenever the motors or LED change, so a frontend can
+    /// forward rumble to a real controller
+    pub fn set_feedback_callback(&mut self, callback: Box<dyn FnMut(Feedback)>) {
+        self.on_feedback = Some(callback);
+    }
+
+    pub fn get_feedback(&self) -> Feedback {
+        return self.feedback;
+    }
+
+    fn get_id(&self) -> u8 {
+        return if self.is_config_mode {
+            CONFIG_ID
+        } else if self.is_analog {
+            ANALOG_ID
+        } else {
+            DIGITAL_ID
+        };
+    }
+
+    /// The number of bytes after 0x5A, given by the low nibble of the ID in
+    /// halfwords
+    fn get_reply_length(&self) -> usize {
+        return ((self.id & 0x0F) as usize) * 2;
+    }
+
+    /// The reply for a byte after 0x5A, given the parameters received so far
+    fn get_reply(&self, index: usize) -> u8 {
+        let buttons = self.input.get_button_report();
+        let [lx, ly] = self.input.get_left_stick();
+        let [rx, ry] = self.input.get_right_stick();
+        let param = self.params[0];
+        let reply: [u8; 6] = match self.command {
+            READ | CONFIG_MODE if self.id != CONFIG_ID || self.command == READ => {
+                [buttons as u8, (buttons >> 8) as u8, rx, ry, lx, ly]
+            }
+            GET_STATUS => [0x01, 0x02, self.is_analog as u8, 0x02, 0x01, 0x00],
+            GET_CONSTANT_46 if param == 0 => [0x00, 0x00, 0x01, 0x02, 0x00, 0x0A],
+            GET_CONSTANT_46 if param == 1 => [0x00, 0x00, 0x01, 0x01, 0x01, 0x14],
+            GET_CONSTANT_47 => [0x00, 0x00, 0x02, 0x00, 0x01, 0x00],
+            GET_CONSTANT_4C if param == 0 => [0x00, 0x00, 0x00, 0x04, 0x00, 0x00],
+            GET_CONSTANT_4C if param == 1 => [0x00, 0x00, 0x00, 0x07, 0x00, 0x00],
+            SET_RUMBLE => self.rumble_config,
+            _ => [0x00; 6],
+        };
+        return reply[index];
+    }
+
+    /// Act on a parameter byte as it arrives
+    fn receive_param(&mut self, index: usize, data: u8) {
+        self.params[index] = data;
+        match self.command {
+            READ => {
+                let mut feedback = self.feedback;
+                match self.rumble_config[index] {
+                    SMALL_MOTOR => feedback.small_motor = if (data & 1) != 0 { 0xFF } else { 0 },
+                    LARGE_MOTOR => feedback.large_motor = data,
+                    _ => (),
+                }
+                self.set_feedback(feedback);
+            }
+            SET_RUMBLE if self.id == CONFIG_ID => self.rumble_config[index] = data,
+            _ => (),
+        }
+    }
+
+    /// Apply the effects of a command once its last byte has gone
+    fn finish_command(&mut self) {
+        match self.command {
+            CONFIG_MODE => self.is_config_mode = self.params[0] == 0x01,
+            SET_MODE if self.id == CONFIG_ID => {
+                match self.params[0] {
+                    0x00 => self.is_analog = false,
+                    0x01 => self.is_analog = true,
+                    _ => (),
+                }
+                self.is_mode_locked = self.params[1] == MODE_LOCKED;
+                self.set_feedback(Feedback {
+                    small_motor: 0,
+                    large_motor: 0,
+                    is_led_on: self.is_analog,
+                });
+            }
+            _ => (),
+        }
+    }
+
+    fn set_feedback(&mut self, feedback: Feedback) {
+        if feedback == self.feedback {
+            return;
+        }
+        self.feedback = feedback;
+        if let Some(callback) = &mut self.on_feedback {
+            callback(feedback);
+        }
+    }
+}
+
+impl Peripheral for DualShock {
+    fn transfer(&mut self, data: u8) -> (u8, bool) {
+        let position = match self.position {
+            Some(position) => position,
+            None => return (0xFF, false),
+        };
+        let reply = match position {
+            0 if data == CONTROLLER_ADDRESS => (0xFF, true),
+            0 => (0xFF, false),
+            1 => {
+                // only reads and config mode work outside of config mode
+                self.command = if self.is_config_mode || data == CONFIG_MODE {
+                    data
+                } else {
+                    READ
+                };
+                self.id = self.get_id();
+                self.params = [0; RUMBLE_CONFIG_SIZE];
+                (self.id, true)
+            }
+            2 => (REPLY_READY, true),
+            _ => {
+                let index = position - 3;
+                let reply = self.get_reply(index);
+                self.receive_param(index, data);
+                let is_last = index + 1 == self.get_reply_length();
+                if is_last {
+                    self.finish_command();
+                }
+                (reply, !is_last)
+            }
+        };
+        self.position = if reply.1 { Some(position + 1) } else { None };
+        return reply;
+    }
+
+    fn deselect(&mut self) {
+        self.position = Some(0);
+    }
+
+    fn set_input(&mut self, input: &InputState) {
+        let was_pressed = self.input.is_analog_button_pressed();
+        self.input = *input;
+        if input.is_analog_button_pressed() && !was_pressed && !self.is_mode_locked {
+            self.is_analog = !self.is_analog;
+            let feedback = Feedback {
+                is_led_on: self.is_analog,
+                ..self.feedback
+            };
+            self.set_feedback(feedback);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::sio::Button;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Run a whole command, returning the replies after the ID and 0x5A
+    fn command(pad: &mut DualShock, command: u8, params: &[u8]) -> (u8, Vec<u8>) {
+        assert_eq!(pad.transfer(0x01), (0xFF, true));
+        let (id, _) = pad.transfer(command);
+        assert_eq!(pad.transfer(0x00), (REPLY_READY, true));
+        let mut replies = vec![];
+        loop {
+            let param = params.get(replies.len()).copied().unwrap_or(0);
+            let (reply, ack) = pad.transfer(param);
+            replies.push(reply);
+            if !ack {
+                break;
+            }
+        }
+        pad.deselect();
+        return (id, replies);
+    }
+
+    #[test]
+    fn reports_sticks_in_analog_mode() {
+        let mut pad = DualShock::new();
+        let mut input = InputState::new();
+        input.set_button(Button::L3, true);
+        input.set_left_stick(0x00, 0xFF);
+        pad.set_input(&input);
+        assert_eq!(command(&mut pad, READ, &[]), (DIGITAL_ID, vec![0xFD, 0xFF]));
+
+        // the Analog button switches modes
+        input.set_analog_button(true);
+        pad.set_input(&input);
+        assert!(pad.get_feedback().is_led_on);
+        assert_eq!(
+            command(&mut pad, READ, &[]),
+            (ANALOG_ID, vec![0xFD, 0xFF, 0x80, 0x80, 0x00, 0xFF])
+        );
+    }
+
+    #[test]
+    fn locks_mode_from_config() {
+        let mut pad = DualShock::new();
+        assert_eq!(command(&mut pad, CONFIG_MODE, &[0x01]).0, DIGITAL_ID);
+        assert_eq!(
+            command(&mut pad, SET_MODE, &[0x01, MODE_LOCKED]).0,
+            CONFIG_ID
+        );
+        assert_eq!(
+            command(&mut pad, GET_STATUS, &[]),
+            (CONFIG_ID, vec![0x01, 0x02, 0x01, 0x02, 0x01, 0x00])
+        );
+        assert_eq!(
+            command(&mut pad, GET_CONSTANT_46, &[0x01]).1,
+            vec![0x00, 0x00, 0x01, 0x01, 0x01, 0x14]
+        );
+        assert_eq!(command(&mut pad, CONFIG_MODE, &[0x00]).0, CONFIG_ID);
+
+        // the Analog button does nothing while the mode is locked
+        let mut input = InputState::new();
+        input.set_analog_button(true);
+        pad.set_input(&input);
+        assert_eq!(command(&mut pad, READ, &[]).0, ANALOG_ID);
+    }
+
+    #[test]
+    fn drives_motors() {
+        let mut pad = DualShock::new();
+        let events = Rc::new(RefCell::new(vec![]));
+        let sink = events.clone();
+        pad.set_feedback_callback(Box::new(move |feedback| sink.borrow_mut().push(feedback)));
+
+        command(&mut pad, CONFIG_MODE, &[0x01]);
+        command(&mut pad, SET_MODE, &[0x01, 0x00]);
+        let (_, old) = command(
+            &mut pad,
+            SET_RUMBLE,
+            &[SMALL_MOTOR, LARGE_MOTOR, 0xFF, 0xFF, 0xFF, 0xFF],
+        );
+        assert_eq!(old, vec![0xFF; 6]);
+        command(&mut pad, CONFIG_MODE, &[0x00]);
+        command(&mut pad, READ, &[0x01, 0x80]);
+
+        let feedback = pad.get_feedback();
+        assert_eq!((feedback.small_motor, feedback.large_motor), (0xFF, 0x80));
+        let events = events.borrow();
+        assert_eq!(events.len(), 3);
+        assert!(events[0].is_led_on);
+    }
+}
@@ -0,0 +1,393 @@
+//! Memory cards
+//!
+//! A card holds 128KiB as 1024 sectors ("frames") of 128 bytes, and is
+//! addressed with 0x81 on the same port as a controller. It understands three
+//! commands:
+//!
+//! | Byte | Read (0x52)   | Write (0x57)    | ID (0x53) |
+//! |------|---------------|-----------------|-----------|
+//! | 1    | FLAG          | FLAG            | FLAG      |
+//! | 2    | 0x5A          | 0x5A            | 0x5A      |
+//! | 3    | 0x5D          | 0x5D            | 0x5D      |
+//! | 4    | (sector MSB)  | (sector MSB)    | 0x5C      |
+//! | 5    | (sector LSB)  | (sector LSB)    | 0x5D      |
+//! | 6    | 0x5C          | (128 data)      | 0x04      |
+//! | 7    | 0x5D          | (checksum)      | 0x00      |
+//! | 8    | sector MSB    | 0x5C            | 0x00      |
+//! | 9    | sector LSB    | 0x5D            | 0x80      |
+//! | 10   | 128 data      | end status      |           |
+//! | 11   | checksum      |                 |           |
+//! | 12   | end status    |                 |           |
+//!
+//! While a sector number or data is being sent, the card echoes back the
+//! previous byte it received. The checksum is the XOR of the sector number's
+//! bytes and the data. The FLAG byte says whether the card has been written
+//! since it was inserted, which is how games notice a card being swapped.
+//!
+//! Cards are saved to raw 128KiB images (.mcr, or ePSXe's .mcd). Changes are
+//! written to a temporary file that's then renamed over the image, so a crash
+//! leaves either the old or the new image but never half of each.
+
+use super::peripheral::Peripheral;
+use crate::utils::memcard::write_atomically;
+use log::error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const MEMORY_CARD_SIZE: usize = 128 * 1024;
+pub const FRAME_SIZE: usize = 128;
+const FRAME_COUNT: u16 = (MEMORY_CARD_SIZE / FRAME_SIZE) as u16;
+
+/// The first byte of an exchange with a memory card
+const MEMORY_CARD_ADDRESS: u8 = 0x81;
+
+//#region Commands
+const READ: u8 = 0x52;
+const GET_ID: u8 = 0x53;
+const WRITE: u8 = 0x57;
+//#endregion
+
+//#region Replies
+const ID1: u8 = 0x5A;
+const ID2: u8 = 0x5D;
+const COMMAND_ACK1: u8 = 0x5C;
+const COMMAND_ACK2: u8 = 0x5D;
+const END_GOOD: u8 = 0x47;
+const END_BAD_CHECKSUM: u8 = 0x4E;
+const END_BAD_SECTOR: u8 = 0xFF;
+/// The card's size and frame size, as sent after the ID command
+const CARD_ID: [u8; 4] = [0x04, 0x00, 0x00, 0x80];
+//#endregion
+
+//#region FLAG bits
+const FLAG_WRITE_ERROR: u8 = 0x04;
+/// Set until the first write after the card is inserted
+const FLAG_NOT_WRITTEN: u8 = 0x08;
+//#endregion
+
+/// Where the card is in an exchange
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum State {
+    /// Waiting for 0x81
+    Idle,
+    Command,
+    /// Sending the bytes after the FLAG, counting from 0
+    Reply(u8, usize),
+    /// Not being talked to until the next deselect
+    Ignored,
+}
+
+pub struct MemoryCard {
+    data: Vec<u8>,
+    /// Where the image is saved, if anywhere
+    path: Option<PathBuf>,
+    /// Whether there are changes that haven't been saved
+    is_dirty: bool,
+    flag: u8,
+    state: State,
+    sector: u16,
+    /// The last byte received, echoed back during sector numbers and data
+    last_received: u8,
+    checksum: u8,
+    /// Whether the checksum sent with a write matched the data
+    is_checksum_ok: bool,
+    /// The data of a sector being written
+    buffer: [u8; FRAME_SIZE],
+}
+
+impl MemoryCard {
+    /// A freshly formatted card that's never saved
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> MemoryCard {
+        let mut data = vec![0u8; MEMORY_CARD_SIZE];
+        format_card(&mut data);
+        return MemoryCard::with_data(data, None);
+    }
+
+    /// Load a card from a raw image, or create a formatted one there if it
+    /// doesn't exist yet
+    pub fn open(path: &Path) -> io::Result<MemoryCard> {
+        if !path.exists() {
+            let mut card = MemoryCard::new();
+            card.path = Some(path.to_path_buf());
+            card.is_dirty = true;
+            card.flush()?;
+            return Ok(card);
+        }
+        let data = fs::read(path)?;
+        if data.len() != MEMORY_CARD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is {} bytes, but raw memory card images are {} bytes",
+                    path.display(),
+                    data.len(),
+                    MEMORY_CARD_SIZE
+                ),
+            ));
+        }
+        return Ok(MemoryCard::with_data(data, Some(path.to_path_buf())));
+    }
+
+    fn with_data(data: Vec<u8>, path: Option<PathBuf>) -> MemoryCard {
+        return MemoryCard {
+            data,
+            path,
+            is_dirty: false,
+            flag: FLAG_NOT_WRITTEN,
+            state: State::Idle,
+            sector: 0,
+            last_received: 0,
+            checksum: 0,
+            is_checksum_ok: false,
+            buffer: [0; FRAME_SIZE],
+        };
+    }
+
+    /// The raw card image
+    pub fn data(&self) -> &[u8] {
+        return &self.data;
+    }
+
+    fn get_frame(&self, sector: u16) -> &[u8] {
+        let start = sector as usize * FRAME_SIZE;
+        return &self.data[start..start + FRAME_SIZE];
+    }
+
+    /// Work out the reply to a byte of a command, returning it and whether the
+    /// command goes on
+    fn reply(&mut self, command: u8, index: usize, data: u8) -> (u8, bool) {
+        let echo = self.last_received;
+        self.last_received = data;
+        return match (command, index) {
+            (_, 0) => (ID1, true),
+            (_, 1) => (ID2, true),
+            (GET_ID, 2) => (COMMAND_ACK1, true),
+            (GET_ID, 3) => (COMMAND_ACK2, true),
+            (GET_ID, 4..=7) => (CARD_ID[index - 4], index < 7),
+            (READ, 2) | (WRITE, 2) => {
+                self.sector = (data as u16) << 8;
+                (0x00, true)
+            }
+            (READ, 3) | (WRITE, 3) => {
+                self.sector |= data as u16;
+                self.checksum = (self.sector >> 8) as u8 ^ self.sector as u8;
+                (echo, true)
+            }
+            (READ, 4) => (COMMAND_ACK1, true),
+            (READ, 5) => (COMMAND_ACK2, true),
+            (READ, 6) | (READ, 7) if self.sector >= FRAME_COUNT => (0xFF, false),
+            (READ, 6) => ((self.sector >> 8) as u8, true),
+            (READ, 7) => (self.sector as u8, true),
+            (READ, 8..=135) => {
+                let byte = self.get_frame(self.sector)[index - 8];
+                self.checksum ^= byte;
+                (byte, true)
+            }
+            (READ, 136) => (self.checksum, true),
+            (READ, 137) => (END_GOOD, false),
+            (WRITE, 4..=131) => {
+                self.buffer[index - 4] = data;
+                self.checksum ^= data;
+                (echo, true)
+            }
+            (WRITE, 132) => {
+                self.is_checksum_ok = data == self.checksum;
+                (echo, true)
+            }
+            (WRITE, 133) => (COMMAND_ACK1, true),
+            (WRITE, 134) => (COMMAND_ACK2, true),
+            (WRITE, 135) => (self.finish_write(), false),
+            _ => (0xFF, false),
+        };
+    }
+
+    /// Store the sector that was sent, returning the end status
+    fn finish_write(&mut self) -> u8 {
+        if self.sector >= FRAME_COUNT {
+            self.flag |= FLAG_WRITE_ERROR;
+            return END_BAD_SECTOR;
+        }
+        if !self.is_checksum_ok {
+            self.flag |= FLAG_WRITE_ERROR;
+            return END_BAD_CHECKSUM;
+        }
+        let start = self.sector as usize * FRAME_SIZE;
+        self.data[start..start + FRAME_SIZE].copy_from_slice(&self.buffer);
+        self.flag &= !(FLAG_NOT_WRITTEN | FLAG_WRITE_ERROR);
+        self.is_dirty = true;
+        return END_GOOD;
+    }
+}
+
+impl Peripheral for MemoryCard {
+    fn transfer(&mut self, data: u8) -> (u8, bool) {
+        let (reply, next) = match self.state {
+            State::Idle if data == MEMORY_CARD_ADDRESS => (0xFF, State::Command),
+            State::Command => match data {
+                READ | WRITE | GET_ID => (self.flag, State::Reply(data, 0)),
+                // unknown commands get the FLAG, but aren't acknowledged
+                _ => (self.flag, State::Ignored),
+            },
+            State::Reply(command, index) => match self.reply(command, index, data) {
+                (reply, true) => (reply, State::Reply(command, index + 1)),
+                (reply, false) => (reply, State::Ignored),
+            },
+            State::Idle | State::Ignored => (0xFF, State::Ignored),
+        };
+        let ack = next != State::Ignored;
+        self.state = next;
+        return (reply, ack);
+    }
+
+    fn deselect(&mut self) {
+        self.state = State::Idle;
+    }
+
+    /// Save the card, if it's been written to since the last save
+    fn flush(&mut self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) if self.is_dirty => path,
+            _ => return Ok(()),
+        };
+        write_atomically(path, &self.data)?;
+        self.is_dirty = false;
+        return Ok(());
+    }
+}
+
+impl Drop for MemoryCard {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            error!(target: "sio", "Failed to save memory card: {}", err);
+        }
+    }
+}
+
+/// XOR together the first 127 bytes of a frame, as stored in its last byte
+pub fn frame_checksum(frame: &[u8]) -> u8 {
+    return frame[..FRAME_SIZE - 1]
+        .iter()
+        .fold(0, |acc, &byte| acc ^ byte);
+}
+
+/// Erase a card image, leaving an empty directory
+///
+/// Frame 0 is the header, frames 1-15 the directory, and frames 16-35 the
+/// list of broken sectors, which is empty. Frame 63 is used by the BIOS to
+/// test writes and holds a copy of the header.
+pub fn format_card(data: &mut [u8]) {
+    for byte in data.iter_mut() {
+        *byte = 0;
+    }
+    let mut set_frame = |index: usize, prefix: &[u8], link: u16| {
+        let frame = &mut data[index * FRAME_SIZE..(index + 1) * FRAME_SIZE];
+        frame[..prefix.len()].copy_from_slice(prefix);
+        frame[8..10].copy_from_slice(&link.to_le_bytes());
+        frame[FRAME_SIZE - 1] = frame_checksum(frame);
+    };
+    set_frame(0, b"MC", 0);
+    for index in 1..16 {
+        // a free block, not linked to another
+        set_frame(index, &[0xA0, 0x00, 0x00, 0x00], 0xFFFF);
+    }
+    for index in 16..36 {
+        // no broken sector
+        set_frame(index, &[0xFF, 0xFF, 0xFF, 0xFF], 0xFFFF);
+    }
+    set_frame(63, b"MC", 0);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run a whole command, returning every reply after the FLAG
+    fn command(card: &mut MemoryCard, command: u8, params: &[u8]) -> (u8, Vec<u8>) {
+        assert_eq!(card.transfer(MEMORY_CARD_ADDRESS), (0xFF, true));
+        let (flag, _) = card.transfer(command);
+        let mut replies = vec![];
+        loop {
+            let (reply, ack) = card.transfer(params.get(replies.len()).copied().unwrap_or(0));
+            replies.push(reply);
+            if !ack {
+                break;
+            }
+        }
+        card.deselect();
+        return (flag, replies);
+    }
+
+    fn write_params(sector: u16, data: &[u8; FRAME_SIZE], checksum_error: u8) -> Vec<u8> {
+        let mut params = vec![0, 0, (sector >> 8) as u8, sector as u8];
+        params.extend_from_slice(data);
+        let checksum = params[2..].iter().fold(0, |acc, &byte| acc ^ byte);
+        params.push(checksum ^ checksum_error);
+        return params;
+    }
+
+    #[test]
+    fn reports_id() {
+        let mut card = MemoryCard::new();
+        assert_eq!(
+            command(&mut card, GET_ID, &[]),
+            (0x08, vec![0x5A, 0x5D, 0x5C, 0x5D, 0x04, 0x00, 0x00, 0x80])
+        );
+    }
+
+    #[test]
+    fn reads_and_writes_sectors() {
+        let mut card = MemoryCard::new();
+        let mut data = [0u8; FRAME_SIZE];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        // a bad checksum is refused
+        let (flag, replies) = command(&mut card, WRITE, &write_params(0x123, &data, 1));
+        assert_eq!(flag, FLAG_NOT_WRITTEN);
+        assert_eq!(replies.last(), Some(&END_BAD_CHECKSUM));
+        let (_, replies) = command(&mut card, WRITE, &write_params(0x123, &data, 0));
+        // the card echoes the previous byte while data is sent
+        assert_eq!(replies[..6], [0x5A, 0x5D, 0x00, 0x01, 0x23, 0x00]);
+        assert_eq!(replies.len(), 136);
+        assert_eq!(replies[replies.len() - 3..], [0x5C, 0x5D, END_GOOD]);
+
+        let (flag, replies) = command(&mut card, READ, &[0, 0, 0x01, 0x23]);
+        assert_eq!(flag, 0x00);
+        assert_eq!(
+            replies[..8],
+            [0x5A, 0x5D, 0x00, 0x01, 0x5C, 0x5D, 0x01, 0x23]
+        );
+        assert_eq!(replies[8..136], data[..]);
+        assert_eq!(replies[136], write_params(0x123, &data, 0)[132]);
+        assert_eq!(replies[137], END_GOOD);
+
+        // sectors past the end of the card stop the read early
+        let (_, replies) = command(&mut card, READ, &[0, 0, 0x04, 0x00]);
+        assert_eq!(replies.len(), 7);
+        assert_eq!(replies[6], 0xFF);
+    }
+
+    #[test]
+    fn saves_atomically() {
+        let dir = std::env::temp_dir().join(format!("psx-memcard-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("card.mcr");
+        let _ = fs::remove_file(&path);
+        {
+            let mut card = MemoryCard::open(&path).unwrap();
+            assert_eq!(fs::read(&path).unwrap(), card.data());
+            let data = [0xAB; FRAME_SIZE];
+            command(&mut card, WRITE, &write_params(0x40, &data, 0));
+            card.flush().unwrap();
+            assert!(!dir.join("card.mcr.tmp").exists());
+        }
+        let card = MemoryCard::open(&path).unwrap();
+        assert_eq!(&card.data()[..2], b"MC");
+        assert_eq!(card.get_frame(0x40), &[0xAB; FRAME_SIZE][..]);
+        assert_eq!(frame_checksum(card.get_frame(1)), card.get_frame(1)[127]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
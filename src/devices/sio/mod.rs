@@ -0,0 +1,13 @@
+mod dualshock;
+mod memcard;
+mod pad;
+mod peripheral;
+#[allow(clippy::module_inception)]
+mod sio;
+mod structs;
+
+pub use self::dualshock::{DualShock, Feedback};
+pub use self::memcard::{format_card, frame_checksum, MemoryCard, FRAME_SIZE, MEMORY_CARD_SIZE};
+pub use self::pad::DigitalPad;
+pub use self::peripheral::{Button, InputState, Peripheral, STICK_CENTER};
+pub use self::sio::{Sio0, PORT_COUNT};
@@ -0,0 +1,106 @@
+//! The SCPH-1080 digital pad
+//!
+//! The pad only understands the read command. An exchange goes:
+//!
+//! | Byte | Console | Pad                  |
+//! |------|---------|----------------------|
+//! | 0    | 0x01    | hi-z (0xFF)          |
+//! | 1    | 0x42    | 0x41, the pad's ID   |
+//! | 2    | 0x00    | 0x5A                 |
+//! | 3    | 0x00    | buttons, low byte    |
+//! | 4    | 0x00    | buttons, high byte   |
+//!
+//! Every byte but the last is acknowledged.
+
+use super::peripheral::{InputState, Peripheral};
+
+/// The first byte of an exchange with a controller
+pub const CONTROLLER_ADDRESS: u8 = 0x01;
+/// The low byte of the pad's ID: a digital pad, followed by 1 halfword
+const DIGITAL_PAD_ID: u8 = 0x41;
+/// Sent after the ID by every controller
+pub const REPLY_READY: u8 = 0x5A;
+
+pub struct DigitalPad {
+    input: InputState,
+    /// The byte of the exchange coming next, or None if the pad isn't being
+    /// talked to
+    position: Option<usize>,
+}
+
+impl DigitalPad {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> DigitalPad {
+        return DigitalPad {
+            input: InputState::new(),
+            position: Some(0),
+        };
+    }
+}
+
+impl Peripheral for DigitalPad {
+    fn transfer(&mut self, data: u8) -> (u8, bool) {
+        let position = match self.position {
+            Some(position) => position,
+            None => return (0xFF, false),
+        };
+        let buttons = self.input.get_button_report();
+        let reply = match position {
+            0 if data == CONTROLLER_ADDRESS => (0xFF, true),
+            1 => (DIGITAL_PAD_ID, true),
+            2 => (REPLY_READY, true),
+            3 => (buttons as u8, true),
+            4 => ((buttons >> 8) as u8, false),
+            _ => (0xFF, false),
+        };
+        self.position = if reply.1 { Some(position + 1) } else { None };
+        return reply;
+    }
+
+    fn deselect(&mut self) {
+        self.position = Some(0);
+    }
+
+    fn set_input(&mut self, input: &InputState) {
+        self.input = *input;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::sio::Button;
+
+    #[test]
+    fn reports_buttons() {
+        let mut pad = DigitalPad::new();
+        let mut input = InputState::new();
+        input.set_button(Button::Start, true);
+        input.set_button(Button::Cross, true);
+        pad.set_input(&input);
+        let replies: Vec<_> = [0x01, 0x42, 0x00, 0x00, 0x00]
+            .iter()
+            .map(|&byte| pad.transfer(byte))
+            .collect();
+        assert_eq!(
+            replies,
+            vec![
+                (0xFF, true),
+                (0x41, true),
+                (0x5A, true),
+                (0xF7, true),
+                (0xBF, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_other_devices() {
+        let mut pad = DigitalPad::new();
+        // a memory card access
+        assert_eq!(pad.transfer(0x81), (0xFF, false));
+        assert_eq!(pad.transfer(0x52), (0xFF, false));
+        pad.deselect();
+        assert_eq!(pad.transfer(0x01), (0xFF, true));
+    }
+}
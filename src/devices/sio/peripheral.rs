@@ -0,0 +1,125 @@
+use std::io;
+
+/// A device plugged into a controller port, like a pad or memory card
+///
+/// Bytes are exchanged one at a time while the port is selected: the console
+/// shifts a byte out as the device shifts its reply back. After each byte a
+/// device that wants to continue pulses /ACK, and the console gives up on the
+/// exchange as soon as one isn't acknowledged.
+pub trait Peripheral {
+    /// Exchange a byte, returning the reply and whether to pulse /ACK
+    ///
+    /// Devices that aren't being addressed by the first byte should reply
+    /// 0xFF without acknowledging, and stay quiet until deselected.
+    fn transfer(&mut self, data: u8) -> (u8, bool);
+
+    /// The port's select line went high, ending the exchange
+    fn deselect(&mut self);
+
+    /// Update the buttons and sticks a controller reports
+    ///
+    /// Devices without any input, like memory cards, ignore this.
+    fn set_input(&mut self, _input: &InputState) {}
+
+    /// Save anything the device stores, like a memory card's contents
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+/// A controller button, numbered by its bit in a pad's button report
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum Button {
+    Select = 0,
+    /// The left stick's button, only on analog controllers
+    L3 = 1,
+    /// The right stick's button, only on analog controllers
+    R3 = 2,
+    Start = 3,
+    Up = 4,
+    Right = 5,
+    Down = 6,
+    Left = 7,
+    L2 = 8,
+    R2 = 9,
+    L1 = 10,
+    R1 = 11,
+    Triangle = 12,
+    Circle = 13,
+    Cross = 14,
+    Square = 15,
+}
+
+/// The reading of a centred analog stick axis
+pub const STICK_CENTER: u8 = 0x80;
+
+/// What's being pressed on a controller, set by frontends and tests
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct InputState {
+    /// A bit per button, set while it's held
+    buttons: u16,
+    /// The X and Y axes of the left stick, from 0x00 (left or up) to 0xFF
+    left_stick: [u8; 2],
+    right_stick: [u8; 2],
+    /// The Analog button between the sticks, which isn't in button reports
+    analog_button: bool,
+}
+
+impl InputState {
+    pub fn new() -> InputState {
+        return InputState {
+            buttons: 0,
+            left_stick: [STICK_CENTER; 2],
+            right_stick: [STICK_CENTER; 2],
+            analog_button: false,
+        };
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let bit = 1 << (button as u16);
+        if pressed {
+            self.buttons |= bit;
+        } else {
+            self.buttons &= !bit;
+        }
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        return (self.buttons & (1 << (button as u16))) != 0;
+    }
+
+    /// The buttons as a pad reports them, where a cleared bit is held
+    pub fn get_button_report(&self) -> u16 {
+        return !self.buttons;
+    }
+
+    pub fn set_left_stick(&mut self, x: u8, y: u8) {
+        self.left_stick = [x, y];
+    }
+
+    pub fn get_left_stick(&self) -> [u8; 2] {
+        return self.left_stick;
+    }
+
+    pub fn set_right_stick(&mut self, x: u8, y: u8) {
+        self.right_stick = [x, y];
+    }
+
+    pub fn get_right_stick(&self) -> [u8; 2] {
+        return self.right_stick;
+    }
+
+    pub fn set_analog_button(&mut self, pressed: bool) {
+        self.analog_button = pressed;
+    }
+
+    pub fn is_analog_button_pressed(&self) -> bool {
+        return self.analog_button;
+    }
+}
+
+impl Default for InputState {
+    fn default() -> Self {
+        return InputState::new();
+    }
+}
@@ -0,0 +1,472 @@
+//! SIO0, the serial interface behind the controller and memory card ports
+//!
+//! Each port is shared by a controller and a memory card, which both see every
+//! byte while the port is selected; the first byte of an exchange says which
+//! one should answer. Bytes take 8 baud periods to shift out, after which the
+//! device that answered pulls /ACK low for a moment if it wants another byte.
+//! The BIOS waits for /ACK with IRQ7, so getting that timing roughly right
+//! matters more than the exact baud rate.
+
+use super::peripheral::{InputState, Peripheral};
+use super::structs::{JoyCtrl, JoyStat};
+use crate::devices::bus::{BusDevice, SizedData};
+use crate::devices::intctrl::{InterruptController, Irq};
+use log::debug;
+use std::collections::VecDeque;
+use std::io;
+
+/// The number of controller ports
+pub const PORT_COUNT: usize = 2;
+
+//#region Registers
+const JOY_DATA: u32 = 0x0;
+const JOY_STAT: u32 = 0x4;
+const JOY_MODE: u32 = 0x8;
+const JOY_CTRL: u32 = 0xA;
+const JOY_BAUD: u32 = 0xE;
+//#endregion
+
+const RX_FIFO_SIZE: usize = 8;
+/// The cycles between the end of a byte and the device pulling /ACK low
+const ACK_DELAY: u32 = 338;
+/// The cycles /ACK stays low for
+const ACK_LENGTH: u32 = 100;
+
+/// Which device on a port is answering the current exchange
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum Listener {
+    Controller,
+    MemoryCard,
+    /// Neither device recognised the first byte
+    Nobody,
+}
+
+struct Port {
+    controller: Option<Box<dyn Peripheral>>,
+    memory_card: Option<Box<dyn Peripheral>>,
+    /// The device answering, once the first byte has been sent
+    listener: Option<Listener>,
+}
+
+impl Port {
+    fn new() -> Port {
+        return Port {
+            controller: None,
+            memory_card: None,
+            listener: None,
+        };
+    }
+
+    fn transfer(&mut self, data: u8) -> (u8, bool) {
+        let device = match self.listener {
+            Some(Listener::Controller) => self.controller.as_mut(),
+            Some(Listener::MemoryCard) => self.memory_card.as_mut(),
+            Some(Listener::Nobody) => None,
+            None => return self.address(data),
+        };
+        return match device {
+            Some(device) => device.transfer(data),
+            None => (0xFF, false),
+        };
+    }
+
+    /// Offer the first byte of an exchange to each device, to find the one
+    /// it's for
+    fn address(&mut self, data: u8) -> (u8, bool) {
+        self.listener = Some(Listener::Nobody);
+        for (listener, device) in [
+            (Listener::Controller, &mut self.controller),
+            (Listener::MemoryCard, &mut self.memory_card),
+        ] {
+            if let Some(device) = device {
+                let (reply, ack) = device.transfer(data);
+                if ack {
+                    self.listener = Some(listener);
+                    return (reply, ack);
+                }
+            }
+        }
+        return (0xFF, false);
+    }
+
+    fn deselect(&mut self) {
+        for device in self
+            .controller
+            .iter_mut()
+            .chain(self.memory_card.iter_mut())
+        {
+            device.deselect();
+        }
+        self.listener = None;
+    }
+}
+
+/// A byte being shifted out to the selected port
+#[derive(Debug, Copy, Clone)]
+struct Transfer {
+    cycles_left: u32,
+    reply: u8,
+    ack: bool,
+}
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum AckState {
+    Idle,
+    /// The device will pull /ACK low after this many cycles
+    Pending(u32),
+    /// /ACK is low for this many more cycles
+    Low(u32),
+}
+
+pub struct Sio0 {
+    stat: JoyStat,
+    mode: u16,
+    ctrl: JoyCtrl,
+    baud: u16,
+    rx_fifo: VecDeque<u8>,
+    /// A byte written while another was being sent
+    tx_pending: Option<u8>,
+    transfer: Option<Transfer>,
+    ack: AckState,
+    ports: [Port; PORT_COUNT],
+}
+
+impl Sio0 {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Sio0 {
+        return Sio0 {
+            stat: JoyStat::default(),
+            mode: 0,
+            ctrl: JoyCtrl::default(),
+            baud: 0,
+            rx_fifo: VecDeque::with_capacity(RX_FIFO_SIZE),
+            tx_pending: None,
+            transfer: None,
+            ack: AckState::Idle,
+            ports: [Port::new(), Port::new()],
+        };
+    }
+
+    /// Plug a controller into a port, replacing whatever was there
+    pub fn set_controller(&mut self, port: usize, controller: Option<Box<dyn Peripheral>>) {
+        self.ports[port].controller = controller;
+        self.ports[port].listener = None;
+    }
+
+    /// Plug a memory card into a port, replacing whatever was there
+    pub fn set_memory_card(&mut self, port: usize, card: Option<Box<dyn Peripheral>>) {
+        self.ports[port].memory_card = card;
+        self.ports[port].listener = None;
+    }
+
+    /// Save anything the devices in either port store
+    pub fn flush(&mut self) -> io::Result<()> {
+        for port in self.ports.iter_mut() {
+            for device in port
+                .controller
+                .iter_mut()
+                .chain(port.memory_card.iter_mut())
+            {
+                device.flush()?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Update what's being pressed on the controller in a port
+    pub fn set_input(&mut self, port: usize, input: &InputState) {
+        if let Some(controller) = &mut self.ports[port].controller {
+            controller.set_input(input);
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32, intctrl: &mut InterruptController) {
+        if let Some(mut transfer) = self.transfer {
+            if transfer.cycles_left > cycles {
+                transfer.cycles_left -= cycles;
+                self.transfer = Some(transfer);
+            } else {
+                self.transfer = None;
+                self.finish_transfer(transfer, intctrl);
+            }
+        }
+        self.ack = match self.ack {
+            AckState::Idle => AckState::Idle,
+            AckState::Pending(delay) if delay > cycles => AckState::Pending(delay - cycles),
+            AckState::Pending(_) => {
+                self.stat.set_ack_low(true);
+                if self.ctrl.is_ack_irq_enabled() {
+                    self.raise_irq(intctrl);
+                }
+                AckState::Low(ACK_LENGTH)
+            }
+            AckState::Low(length) if length > cycles => AckState::Low(length - cycles),
+            AckState::Low(_) => {
+                self.stat.set_ack_low(false);
+                AckState::Idle
+            }
+        };
+    }
+
+    fn finish_transfer(&mut self, transfer: Transfer, intctrl: &mut InterruptController) {
+        if self.ctrl.is_selected() || self.ctrl.is_rx_forced() {
+            if self.rx_fifo.len() == RX_FIFO_SIZE {
+                // an overrun replaces the newest byte
+                self.rx_fifo.pop_back();
+            }
+            self.rx_fifo.push_back(transfer.reply);
+            if self.ctrl.is_rx_irq_enabled() && self.rx_fifo.len() >= self.ctrl.get_rx_irq_count() {
+                self.raise_irq(intctrl);
+            }
+        }
+        if transfer.ack {
+            self.ack = AckState::Pending(ACK_DELAY);
+        }
+        if self.ctrl.is_tx_irq_enabled() {
+            self.raise_irq(intctrl);
+        }
+        self.start_pending();
+    }
+
+    fn raise_irq(&mut self, intctrl: &mut InterruptController) {
+        if !self.stat.is_irq_requested() {
+            self.stat.set_irq();
+            intctrl.request(Irq::Controller);
+        }
+    }
+
+    fn send(&mut self, data: u8) {
+        if self.tx_pending.is_some() {
+            debug!(target: "sio", "TX FIFO overrun, dropping 0x{:02X}", data);
+            return;
+        }
+        self.tx_pending = Some(data);
+        self.start_pending();
+    }
+
+    /// Start sending the byte waiting in the TX FIFO, if it can be sent
+    fn start_pending(&mut self) {
+        if self.transfer.is_some() || !self.ctrl.is_tx_enabled() {
+            return;
+        }
+        let data = match self.tx_pending.take() {
+            Some(data) => data,
+            None => return,
+        };
+        let (reply, ack) = if self.ctrl.is_selected() {
+            self.ports[self.ctrl.get_port()].transfer(data)
+        } else {
+            (0xFF, false)
+        };
+        self.transfer = Some(Transfer {
+            cycles_left: self.get_byte_cycles(),
+            reply,
+            ack,
+        });
+    }
+
+    /// The cycles it takes to shift out a byte at the current baud rate
+    fn get_byte_cycles(&self) -> u32 {
+        let factor = match self.mode & 0x3 {
+            2 => 16,
+            3 => 64,
+            _ => 1,
+        };
+        return (self.baud as u32 * factor * 8).max(1);
+    }
+
+    fn write_ctrl(&mut self, value: u16) {
+        let ctrl = JoyCtrl::from(value);
+        let was_selected = self.ctrl.is_selected();
+        let old_port = self.ctrl.get_port();
+        if ctrl.is_reset() {
+            self.reset();
+        } else {
+            self.ctrl = ctrl.stored();
+        }
+        if ctrl.is_acknowledge() {
+            self.stat.acknowledge();
+        }
+        if was_selected && (!self.ctrl.is_selected() || self.ctrl.get_port() != old_port) {
+            self.ports[old_port].deselect();
+        }
+        self.start_pending();
+    }
+
+    fn reset(&mut self) {
+        self.stat = JoyStat::default();
+        self.mode = 0;
+        self.ctrl = JoyCtrl::default();
+        self.baud = 0;
+        self.rx_fifo.clear();
+        self.tx_pending = None;
+        self.transfer = None;
+        self.ack = AckState::Idle;
+    }
+
+    fn get_stat(&self) -> JoyStat {
+        let mut stat = self.stat;
+        stat.set_tx_ready(self.tx_pending.is_none());
+        stat.set_tx_finished(self.tx_pending.is_none() && self.transfer.is_none());
+        stat.set_rx_not_empty(!self.rx_fifo.is_empty());
+        return stat;
+    }
+
+    /// The word at an aligned register address
+    fn read_word(&self, addr: u32) -> Option<u32> {
+        return match addr {
+            JOY_DATA => Some(*self.rx_fifo.front().unwrap_or(&0xFF) as u32),
+            JOY_STAT => Some(*self.get_stat()),
+            JOY_MODE => Some(self.mode as u32 | ((*self.ctrl as u32) << 16)),
+            0xC => Some((self.baud as u32) << 16),
+            _ => None,
+        };
+    }
+}
+
+impl BusDevice for Sio0 {
+    fn read<T: SizedData>(&mut self, addr: u32) -> T {
+        if (addr & !0x3) == JOY_DATA {
+            let data = self.rx_fifo.pop_front().unwrap_or(0xFF);
+            return T::from_u32(data as u32);
+        }
+        return self.peek(addr).unwrap_or_else(|| {
+            debug!(target: "sio", "Read from unmapped SIO0 register ${:02X}", addr);
+            T::from_u32(0)
+        });
+    }
+
+    fn peek<T: SizedData>(&self, addr: u32) -> Option<T> {
+        let word = self.read_word(addr & !0x3)?;
+        return Some(T::from_u32(word >> ((addr & 0x3) * 8)));
+    }
+
+    fn write<T: SizedData>(&mut self, addr: u32, data: T) {
+        let data = data.to_u32();
+        match addr {
+            JOY_DATA => self.send(data as u8),
+            JOY_MODE => {
+                self.mode = data as u16;
+                if T::width() == 4 {
+                    self.write_ctrl((data >> 16) as u16);
+                }
+            }
+            JOY_CTRL => self.write_ctrl(data as u16),
+            JOY_BAUD => self.baud = data as u16,
+            _ => {
+                debug!(target: "sio", "Write to read-only SIO0 register ${:02X} = 0x{:08X}", addr, data);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::sio::{Button, DigitalPad, MemoryCard};
+
+    /// TX enabled, port 1 selected, and /ACK interrupts on
+    const SELECT_PORT1: u16 = 0x1003;
+
+    /// Send a byte, wait for it to finish, and return the reply and whether
+    /// it raised IRQ7
+    fn exchange(sio: &mut Sio0, intctrl: &mut InterruptController, data: u8) -> (u8, bool) {
+        sio.write(JOY_DATA, data);
+        for _ in 0..2000 {
+            sio.tick(1, intctrl);
+        }
+        let irq = (intctrl.read::<u32>(0) & (1 << (Irq::Controller as u32))) != 0;
+        intctrl.write(0, 0u32);
+        sio.write(JOY_CTRL, SELECT_PORT1 | 0x10);
+        return (sio.read::<u8>(JOY_DATA), irq);
+    }
+
+    fn setup() -> (Sio0, InterruptController) {
+        let mut sio = Sio0::new();
+        sio.write(JOY_CTRL, 0x40u16);
+        sio.write(JOY_MODE, 0x000Du16);
+        sio.write(JOY_BAUD, 0x0088u16);
+        sio.write(JOY_CTRL, SELECT_PORT1);
+        return (sio, InterruptController::new());
+    }
+
+    #[test]
+    fn reads_digital_pad() {
+        let (mut sio, mut intctrl) = setup();
+        sio.set_controller(0, Some(Box::new(DigitalPad::new())));
+        let mut input = InputState::new();
+        input.set_button(Button::Circle, true);
+        sio.set_input(0, &input);
+
+        let replies: Vec<_> = [0x01, 0x42, 0x00, 0x00, 0x00]
+            .iter()
+            .map(|&byte| exchange(&mut sio, &mut intctrl, byte))
+            .collect();
+        assert_eq!(
+            replies,
+            vec![
+                (0xFF, true),
+                (0x41, true),
+                (0x5A, true),
+                (0xFF, true),
+                (0xDF, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn times_ack_interrupt() {
+        let (mut sio, mut intctrl) = setup();
+        sio.set_controller(0, Some(Box::new(DigitalPad::new())));
+        sio.write(JOY_DATA, 0x01u8);
+        assert_eq!(sio.read::<u32>(JOY_STAT) & 0x5, 0x1);
+        // the byte takes 0x88 * 8 cycles, then /ACK follows a little later
+        for _ in 0..0x88 * 8 {
+            sio.tick(1, &mut intctrl);
+        }
+        assert_eq!(sio.read::<u32>(JOY_STAT) & 0x287, 0x007);
+        for _ in 0..ACK_DELAY {
+            sio.tick(1, &mut intctrl);
+        }
+        assert_eq!(sio.read::<u32>(JOY_STAT) & 0x287, 0x287);
+        assert_eq!(intctrl.read::<u32>(0), 1 << (Irq::Controller as u32));
+        for _ in 0..ACK_LENGTH {
+            sio.tick(1, &mut intctrl);
+        }
+        assert_eq!(sio.read::<u32>(JOY_STAT) & 0x280, 0x200);
+        sio.write(JOY_CTRL, SELECT_PORT1 | 0x10);
+        assert_eq!(sio.read::<u32>(JOY_STAT) & 0x200, 0);
+    }
+
+    #[test]
+    fn empty_port_never_acks() {
+        let (mut sio, mut intctrl) = setup();
+        assert_eq!(exchange(&mut sio, &mut intctrl, 0x01), (0xFF, false));
+        // port 2 is just as empty
+        sio.set_controller(0, Some(Box::new(DigitalPad::new())));
+        sio.write(JOY_CTRL, 0u16);
+        sio.write(JOY_CTRL, SELECT_PORT1 | 0x2000);
+        sio.write(JOY_DATA, 0x01u8);
+        for _ in 0..2000 {
+            sio.tick(1, &mut intctrl);
+        }
+        assert_eq!(sio.read::<u8>(JOY_DATA), 0xFF);
+        assert_eq!(sio.read::<u32>(JOY_STAT) & 0x200, 0);
+    }
+
+    #[test]
+    fn shares_port_with_memory_card() {
+        let (mut sio, mut intctrl) = setup();
+        sio.set_controller(0, Some(Box::new(DigitalPad::new())));
+        sio.set_memory_card(0, Some(Box::new(MemoryCard::new())));
+        // the pad stays quiet while the card answers with its FLAG
+        assert_eq!(exchange(&mut sio, &mut intctrl, 0x81), (0xFF, true));
+        assert_eq!(exchange(&mut sio, &mut intctrl, 0x53), (0x08, true));
+        assert_eq!(exchange(&mut sio, &mut intctrl, 0x00), (0x5A, true));
+
+        sio.write(JOY_CTRL, 0u16);
+        sio.write(JOY_CTRL, SELECT_PORT1);
+        assert_eq!(exchange(&mut sio, &mut intctrl, 0x01), (0xFF, true));
+        assert_eq!(exchange(&mut sio, &mut intctrl, 0x42), (0x41, true));
+    }
+}
@@ -0,0 +1,152 @@
+use std::ops::Deref;
+
+//#region JOY_STAT bits
+const JOY_STAT_TX_READY: u32 = 0x0000_0001;
+const JOY_STAT_RX_NOT_EMPTY: u32 = 0x0000_0002;
+const JOY_STAT_TX_FINISHED: u32 = 0x0000_0004;
+const JOY_STAT_RX_PARITY_ERROR: u32 = 0x0000_0008;
+/// The level of the /ACK input, set while a device is pulling it low
+const JOY_STAT_ACK_LOW: u32 = 0x0000_0080;
+const JOY_STAT_IRQ: u32 = 0x0000_0200;
+//#endregion
+
+/// The SIO0 status register, JOY_STAT
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct JoyStat(u32);
+
+impl JoyStat {
+    pub fn is_irq_requested(&self) -> bool {
+        return (**self & JOY_STAT_IRQ) != 0;
+    }
+
+    pub fn set_tx_ready(&mut self, ready: bool) {
+        self.set(JOY_STAT_TX_READY, ready);
+    }
+
+    pub fn set_tx_finished(&mut self, finished: bool) {
+        self.set(JOY_STAT_TX_FINISHED, finished);
+    }
+
+    pub fn set_rx_not_empty(&mut self, not_empty: bool) {
+        self.set(JOY_STAT_RX_NOT_EMPTY, not_empty);
+    }
+
+    pub fn set_ack_low(&mut self, low: bool) {
+        self.set(JOY_STAT_ACK_LOW, low);
+    }
+
+    pub fn set_irq(&mut self) {
+        self.0 |= JOY_STAT_IRQ;
+    }
+
+    /// Clear the interrupt and parity error flags, as JOY_CTRL.4 does
+    pub fn acknowledge(&mut self) {
+        self.0 &= !(JOY_STAT_IRQ | JOY_STAT_RX_PARITY_ERROR);
+    }
+
+    fn set(&mut self, bit: u32, value: bool) {
+        if value {
+            self.0 |= bit;
+        } else {
+            self.0 &= !bit;
+        }
+    }
+}
+
+impl Deref for JoyStat {
+    type Target = u32;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<u32> for JoyStat {
+    fn from(value: u32) -> Self {
+        JoyStat(value)
+    }
+}
+
+//#region JOY_CTRL bits
+const JOY_CTRL_TX_ENABLE: u16 = 0x0001;
+/// Drives the selected port's /JOYn line low, selecting its devices
+const JOY_CTRL_SELECT: u16 = 0x0002;
+const JOY_CTRL_RX_ENABLE: u16 = 0x0004;
+const JOY_CTRL_ACKNOWLEDGE: u16 = 0x0010;
+const JOY_CTRL_RESET: u16 = 0x0040;
+const JOY_CTRL_RX_IRQ_MODE: u16 = 0x0300;
+const JOY_CTRL_TX_IRQ_ENABLE: u16 = 0x0400;
+const JOY_CTRL_RX_IRQ_ENABLE: u16 = 0x0800;
+const JOY_CTRL_ACK_IRQ_ENABLE: u16 = 0x1000;
+const JOY_CTRL_PORT: u16 = 0x2000;
+/// The acknowledge and reset bits don't stick
+const JOY_CTRL_WRITE_ONLY: u16 = JOY_CTRL_ACKNOWLEDGE | JOY_CTRL_RESET;
+//#endregion
+
+/// The SIO0 control register, JOY_CTRL
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct JoyCtrl(u16);
+
+impl JoyCtrl {
+    pub fn is_tx_enabled(&self) -> bool {
+        return (**self & JOY_CTRL_TX_ENABLE) != 0;
+    }
+
+    pub fn is_selected(&self) -> bool {
+        return (**self & JOY_CTRL_SELECT) != 0;
+    }
+
+    /// Whether bytes are received even while no port is selected
+    pub fn is_rx_forced(&self) -> bool {
+        return (**self & JOY_CTRL_RX_ENABLE) != 0;
+    }
+
+    pub fn is_acknowledge(&self) -> bool {
+        return (**self & JOY_CTRL_ACKNOWLEDGE) != 0;
+    }
+
+    pub fn is_reset(&self) -> bool {
+        return (**self & JOY_CTRL_RESET) != 0;
+    }
+
+    /// How many bytes the RX FIFO needs before an RX interrupt
+    pub fn get_rx_irq_count(&self) -> usize {
+        return 1 << ((**self & JOY_CTRL_RX_IRQ_MODE) >> 8);
+    }
+
+    pub fn is_tx_irq_enabled(&self) -> bool {
+        return (**self & JOY_CTRL_TX_IRQ_ENABLE) != 0;
+    }
+
+    pub fn is_rx_irq_enabled(&self) -> bool {
+        return (**self & JOY_CTRL_RX_IRQ_ENABLE) != 0;
+    }
+
+    pub fn is_ack_irq_enabled(&self) -> bool {
+        return (**self & JOY_CTRL_ACK_IRQ_ENABLE) != 0;
+    }
+
+    /// The port being talked to, 0 for /JOY1 or 1 for /JOY2
+    pub fn get_port(&self) -> usize {
+        return ((**self & JOY_CTRL_PORT) >> 13) as usize;
+    }
+
+    /// The value as stored, without the bits that only act on write
+    pub fn stored(&self) -> JoyCtrl {
+        return JoyCtrl(self.0 & !JOY_CTRL_WRITE_ONLY);
+    }
+}
+
+impl Deref for JoyCtrl {
+    type Target = u16;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<u16> for JoyCtrl {
+    fn from(value: u16) -> Self {
+        JoyCtrl(value)
+    }
+}
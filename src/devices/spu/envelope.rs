@@ -0,0 +1,372 @@
+//! ADSR volume envelopes
+//!
+//! Each voice's output is scaled by an envelope that rises during attack,
+//! falls to the sustain level during decay, holds (or slowly changes) during
+//! sustain, then falls to silence on release. Every phase steps the level by
+//! a rate given as a shift and a step; small shifts step by large amounts
+//! every sample, while large shifts wait several samples between steps.
+//!
+//! Volume registers can sweep their volume in the same way, instead of holding
+//! a fixed volume.
+
+use std::ops::Deref;
+
+pub const ENVELOPE_MAX: i16 = 0x7FFF;
+
+/// The part of the envelope a voice is in
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum AdsrPhase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    /// The envelope has finished releasing
+    Off,
+}
+
+//#region ADSR configuration bits
+// The low halfword (voice register +8)
+const ADSR_SUSTAIN_LEVEL: u32 = 0x0000_000F;
+const ADSR_DECAY_SHIFT: u32 = 0x0000_00F0;
+const ADSR_ATTACK_STEP: u32 = 0x0000_0300;
+const ADSR_ATTACK_SHIFT: u32 = 0x0000_7C00;
+const ADSR_ATTACK_EXPONENTIAL: u32 = 0x0000_8000;
+// The high halfword (voice register +A)
+const ADSR_RELEASE_SHIFT: u32 = 0x001F_0000;
+const ADSR_RELEASE_EXPONENTIAL: u32 = 0x0020_0000;
+const ADSR_SUSTAIN_STEP: u32 = 0x00C0_0000;
+const ADSR_SUSTAIN_SHIFT: u32 = 0x1F00_0000;
+const ADSR_SUSTAIN_DECREASE: u32 = 0x4000_0000;
+const ADSR_SUSTAIN_EXPONENTIAL: u32 = 0x8000_0000;
+//#endregion
+
+//#region Volume sweep bits
+const VOLUME_SWEEP: u16 = 0x8000;
+const VOLUME_SWEEP_EXPONENTIAL: u16 = 0x4000;
+const VOLUME_SWEEP_DECREASE: u16 = 0x2000;
+/// Sweep the volume below zero, inverting the output
+const VOLUME_SWEEP_NEGATIVE: u16 = 0x1000;
+const VOLUME_SWEEP_SHIFT: u16 = 0x007C;
+const VOLUME_SWEEP_STEP: u16 = 0x0003;
+//#endregion
+
+/// The 32-bit ADSR configuration of a voice
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone)]
+pub struct AdsrConfig(u32);
+
+impl AdsrConfig {
+    pub fn get_sustain_level(&self) -> i16 {
+        let level = (**self & ADSR_SUSTAIN_LEVEL) + 1;
+        return (level * 0x800).min(ENVELOPE_MAX as u32) as i16;
+    }
+
+    pub fn get_decay_shift(&self) -> u8 {
+        return ((**self & ADSR_DECAY_SHIFT) >> 4) as u8;
+    }
+
+    pub fn get_attack_step(&self) -> u8 {
+        return ((**self & ADSR_ATTACK_STEP) >> 8) as u8;
+    }
+
+    pub fn get_attack_shift(&self) -> u8 {
+        return ((**self & ADSR_ATTACK_SHIFT) >> 10) as u8;
+    }
+
+    pub fn is_attack_exponential(&self) -> bool {
+        return (**self & ADSR_ATTACK_EXPONENTIAL) != 0;
+    }
+
+    pub fn get_release_shift(&self) -> u8 {
+        return ((**self & ADSR_RELEASE_SHIFT) >> 16) as u8;
+    }
+
+    pub fn is_release_exponential(&self) -> bool {
+        return (**self & ADSR_RELEASE_EXPONENTIAL) != 0;
+    }
+
+    pub fn get_sustain_step(&self) -> u8 {
+        return ((**self & ADSR_SUSTAIN_STEP) >> 22) as u8;
+    }
+
+    pub fn get_sustain_shift(&self) -> u8 {
+        return ((**self & ADSR_SUSTAIN_SHIFT) >> 24) as u8;
+    }
+
+    pub fn is_sustain_decreasing(&self) -> bool {
+        return (**self & ADSR_SUSTAIN_DECREASE) != 0;
+    }
+
+    pub fn is_sustain_exponential(&self) -> bool {
+        return (**self & ADSR_SUSTAIN_EXPONENTIAL) != 0;
+    }
+
+    pub fn set_low(&mut self, value: u16) {
+        self.0 = (self.0 & 0xFFFF_0000) | value as u32;
+    }
+
+    pub fn set_high(&mut self, value: u16) {
+        self.0 = (self.0 & 0x0000_FFFF) | ((value as u32) << 16);
+    }
+}
+
+impl Deref for AdsrConfig {
+    type Target = u32;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<u32> for AdsrConfig {
+    fn from(value: u32) -> Self {
+        AdsrConfig(value)
+    }
+}
+
+/// How the level changes in one phase of the envelope
+struct Rate {
+    exponential: bool,
+    decreasing: bool,
+    shift: u8,
+    /// The raw 2-bit step value
+    step: u8,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Envelope {
+    pub phase: AdsrPhase,
+    pub level: i16,
+    /// Samples waited since the last step
+    counter: u32,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        return Envelope {
+            phase: AdsrPhase::Off,
+            level: 0,
+            counter: 0,
+        };
+    }
+
+    pub fn key_on(&mut self) {
+        self.phase = AdsrPhase::Attack;
+        self.level = 0;
+        self.counter = 0;
+    }
+
+    pub fn key_off(&mut self) {
+        if self.phase != AdsrPhase::Off {
+            self.phase = AdsrPhase::Release;
+            self.counter = 0;
+        }
+    }
+
+    /// Silence the envelope immediately, as happens when a voice reaches the
+    /// end of a sample that doesn't loop
+    pub fn mute(&mut self) {
+        self.phase = AdsrPhase::Release;
+        self.level = 0;
+    }
+
+    /// Advance the envelope by one 44.1kHz sample
+    pub fn tick(&mut self, config: AdsrConfig) {
+        let rate = match self.phase {
+            AdsrPhase::Off => return,
+            AdsrPhase::Attack => Rate {
+                exponential: config.is_attack_exponential(),
+                decreasing: false,
+                shift: config.get_attack_shift(),
+                step: config.get_attack_step(),
+            },
+            AdsrPhase::Decay => Rate {
+                exponential: true,
+                decreasing: true,
+                shift: config.get_decay_shift(),
+                step: 0,
+            },
+            AdsrPhase::Sustain => Rate {
+                exponential: config.is_sustain_exponential(),
+                decreasing: config.is_sustain_decreasing(),
+                shift: config.get_sustain_shift(),
+                step: config.get_sustain_step(),
+            },
+            AdsrPhase::Release => Rate {
+                exponential: config.is_release_exponential(),
+                decreasing: true,
+                shift: config.get_release_shift(),
+                step: 0,
+            },
+        };
+
+        if !step_level(&mut self.level, &mut self.counter, &rate) {
+            return;
+        }
+        match self.phase {
+            AdsrPhase::Attack if self.level == ENVELOPE_MAX => self.phase = AdsrPhase::Decay,
+            AdsrPhase::Decay if self.level <= config.get_sustain_level() => {
+                self.phase = AdsrPhase::Sustain
+            }
+            AdsrPhase::Release if self.level == 0 => self.phase = AdsrPhase::Off,
+            _ => (),
+        }
+    }
+}
+
+/// Wait out the rate's delay, then step the level, returning whether it was
+/// stepped
+fn step_level(level: &mut i16, counter: &mut u32, rate: &Rate) -> bool {
+    let mut cycles = 1u32 << rate.shift.saturating_sub(11);
+    let mut step = if rate.decreasing {
+        -8 + rate.step as i32
+    } else {
+        7 - rate.step as i32
+    } << 11u8.saturating_sub(rate.shift);
+    let current = *level as i32;
+    if rate.exponential && !rate.decreasing && current > 0x6000 {
+        cycles *= 4;
+    }
+    if rate.exponential && rate.decreasing {
+        step = (step * current) >> 15;
+    }
+
+    *counter += 1;
+    if *counter < cycles {
+        return false;
+    }
+    *counter = 0;
+    *level = (current + step).clamp(0, ENVELOPE_MAX as i32) as i16;
+    return true;
+}
+
+/// A volume register, which either holds a fixed volume or sweeps it up or
+/// down over time at a rate, like an envelope phase
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Volume {
+    /// The raw register
+    pub register: u16,
+    /// The current volume, where 0x7FFF is unity gain
+    pub level: i16,
+    /// The magnitude of a sweeping volume
+    sweep_level: i16,
+    /// Samples waited since the last sweep step
+    counter: u32,
+}
+
+impl Volume {
+    pub fn set(&mut self, value: u16) {
+        self.register = value;
+        self.counter = 0;
+        if (value & VOLUME_SWEEP) == 0 {
+            self.level = (value << 1) as i16;
+        } else {
+            // sweeps start from the current volume
+            self.sweep_level = self.level.saturating_abs();
+        }
+    }
+
+    /// Advance a sweep by one 44.1kHz sample
+    pub fn tick(&mut self) {
+        let value = self.register;
+        if (value & VOLUME_SWEEP) == 0 {
+            return;
+        }
+        let rate = Rate {
+            exponential: (value & VOLUME_SWEEP_EXPONENTIAL) != 0,
+            decreasing: (value & VOLUME_SWEEP_DECREASE) != 0,
+            shift: ((value & VOLUME_SWEEP_SHIFT) >> 2) as u8,
+            step: (value & VOLUME_SWEEP_STEP) as u8,
+        };
+        step_level(&mut self.sweep_level, &mut self.counter, &rate);
+        self.level = if (value & VOLUME_SWEEP_NEGATIVE) != 0 {
+            -self.sweep_level
+        } else {
+            self.sweep_level
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runs_through_phases() {
+        // fastest linear attack, fastest decay to a sustain level of 0x4000,
+        // the slowest sustain, and fastest linear release
+        let config = AdsrConfig::from(0x0000_0007 | (0x7F << 24));
+        let mut envelope = Envelope::new();
+        envelope.key_on();
+        envelope.tick(config);
+        assert_eq!(envelope.phase, AdsrPhase::Attack);
+        while envelope.phase == AdsrPhase::Attack {
+            envelope.tick(config);
+        }
+        assert_eq!(envelope.phase, AdsrPhase::Decay);
+        assert_eq!(envelope.level, ENVELOPE_MAX);
+        for _ in 0..64 {
+            envelope.tick(config);
+        }
+        assert_eq!(envelope.phase, AdsrPhase::Sustain);
+        assert!(envelope.level <= 0x4000);
+
+        envelope.key_off();
+        for _ in 0..64 {
+            envelope.tick(config);
+        }
+        assert_eq!(envelope.phase, AdsrPhase::Off);
+        assert_eq!(envelope.level, 0);
+    }
+
+    #[test]
+    fn finishes_slow_phases() {
+        // the slowest decay down to a sustain level of 0x800
+        let config = AdsrConfig::from(0x0000_00F0 | (0x1F << 16));
+        let mut envelope = Envelope::new();
+        envelope.phase = AdsrPhase::Decay;
+        envelope.level = ENVELOPE_MAX;
+        for _ in 0..0x10_0000 {
+            if envelope.phase != AdsrPhase::Decay {
+                break;
+            }
+            envelope.tick(config);
+        }
+        assert_eq!(envelope.phase, AdsrPhase::Sustain);
+        assert!(envelope.level <= 0x800);
+
+        // the slowest linear release waits 2^20 samples for each step
+        envelope.level = 8;
+        envelope.key_off();
+        for _ in 0..(1 << 20) - 1 {
+            envelope.tick(config);
+        }
+        assert_eq!(envelope.level, 8);
+        envelope.tick(config);
+        assert_eq!(envelope.phase, AdsrPhase::Off);
+        assert_eq!(envelope.level, 0);
+    }
+
+    #[test]
+    fn sweeps_volumes() {
+        let mut volume = Volume::default();
+        volume.set(0x2000);
+        assert_eq!(volume.level, 0x4000);
+
+        // the fastest linear increase
+        volume.set(0x8000);
+        volume.tick();
+        assert_eq!(volume.level, 0x4000 + (7 << 11));
+        while volume.level < ENVELOPE_MAX {
+            volume.tick();
+        }
+
+        // a fast exponential decrease in the negative phase
+        volume.set(0x8000 | 0x4000 | 0x2000 | 0x1000 | (8 << 2));
+        volume.tick();
+        assert!(volume.level < 0 && volume.level > -ENVELOPE_MAX);
+        for _ in 0..0x10000 {
+            volume.tick();
+        }
+        assert_eq!(volume.level, 0);
+    }
+}
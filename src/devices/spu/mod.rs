@@ -0,0 +1,9 @@
+mod envelope;
+mod reverb;
+mod sink;
+#[allow(clippy::module_inception)]
+mod spu;
+mod voice;
+
+pub use self::sink::{AudioSink, NullSink, SAMPLE_RATE};
+pub use self::spu::{Spu, CYCLES_PER_SAMPLE, SPU_RAM_SIZE, VOICE_COUNT};
@@ -0,0 +1,537 @@
+//! The reverb unit
+//!
+//! Reverb runs at 22.05kHz, half the output rate, using a work area at the
+//! end of sound RAM as a set of delay lines. Each step mixes the input into
+//! "same side" and "different side" reflections, sums four comb filters, and
+//! runs the result through two all-pass filters. The input is downsampled and
+//! the output upsampled with a 39-tap half-band filter.
+
+/// The number of reverb configuration registers, at 0x1F801DC0
+pub const REVERB_REG_COUNT: usize = 32;
+
+/// The end of the work area, which runs from the base address to the end of
+/// sound RAM
+const WORK_AREA_END: u32 = 0x8_0000;
+
+/// The half-band filter used to resample to and from 22.05kHz, which sums
+/// to unity gain
+const HALF_BAND_FILTER: [i32; 39] = [
+    -0x0001, 0x0000, 0x0002, 0x0000, -0x000A, 0x0000, 0x0023, 0x0000, -0x0067, 0x0000, 0x010A,
+    0x0000, -0x0268, 0x0000, 0x0534, 0x0000, -0x0B90, 0x0000, 0x2806, 0x4000, 0x2806, 0x0000,
+    -0x0B90, 0x0000, 0x0534, 0x0000, -0x0268, 0x0000, 0x010A, 0x0000, -0x0067, 0x0000, 0x0023,
+    0x0000, -0x000A, 0x0000, 0x0002, 0x0000, -0x0001,
+];
+
+//#region Configuration registers
+// Addresses (prefixed d and m) are in 8-byte units, relative to the current
+// position in the work area, and volumes (prefixed v) are signed. Registers
+// for the left and right channels come in pairs, left first.
+const DAPF1: usize = 0;
+const DAPF2: usize = 1;
+const VIIR: usize = 2;
+const VCOMB1: usize = 3;
+const VCOMB2: usize = 4;
+const VCOMB3: usize = 5;
+const VCOMB4: usize = 6;
+const VWALL: usize = 7;
+const VAPF1: usize = 8;
+const VAPF2: usize = 9;
+const MSAME: usize = 10;
+const MCOMB1: usize = 12;
+const MCOMB2: usize = 14;
+const DSAME: usize = 16;
+const MDIFF: usize = 18;
+const MCOMB3: usize = 20;
+const MCOMB4: usize = 22;
+const DDIFF: usize = 24;
+const MAPF1: usize = 26;
+const MAPF2: usize = 28;
+const VIN: usize = 30;
+//#endregion
+
+fn multiply(sample: i32, volume: i16) -> i32 {
+    return (sample * volume as i32) >> 15;
+}
+
+fn clamp_sample(sample: i32) -> i32 {
+    return sample.clamp(i16::MIN as i32, i16::MAX as i32);
+}
+
+/// Run the half-band filter over a history of samples, oldest first
+fn filter(history: &[i32; 39]) -> i32 {
+    let sum: i32 = history
+        .iter()
+        .zip(HALF_BAND_FILTER.iter())
+        .map(|(&sample, &tap)| sample * tap)
+        .sum();
+    return sum >> 15;
+}
+
+fn push_history(history: &mut [i32; 39], sample: i32) {
+    history.copy_within(1.., 0);
+    history[38] = sample;
+}
+
+pub struct Reverb {
+    regs: [u16; REVERB_REG_COUNT],
+    /// The left and right output volume
+    pub output_volume: [i16; 2],
+    /// The start of the work area, in bytes
+    base: u32,
+    /// The current position in the work area, in bytes
+    address: u32,
+    /// Input samples at 44.1kHz, for downsampling
+    input_history: [[i32; 39]; 2],
+    /// Output samples at 44.1kHz, with every other one zero, for upsampling
+    output_history: [[i32; 39]; 2],
+    /// Whether the next output sample runs a reverb step
+    is_step_due: bool,
+}
+
+impl Reverb {
+    pub fn new() -> Reverb {
+        return Reverb {
+            regs: [0; REVERB_REG_COUNT],
+            output_volume: [0; 2],
+            base: 0,
+            address: 0,
+            input_history: [[0; 39]; 2],
+            output_history: [[0; 39]; 2],
+            is_step_due: false,
+        };
+    }
+
+    pub fn write_reg(&mut self, index: usize, value: u16) {
+        self.regs[index] = value;
+    }
+
+    /// Set the start of the work area, in 8-byte units
+    pub fn set_base(&mut self, base: u16) {
+        self.base = (base as u32) << 3;
+        self.address = self.base;
+    }
+
+    /// Feed in a 44.1kHz sample of the voices sent to reverb, and return the
+    /// reverb's output
+    ///
+    /// The work area is only written if `write_enabled` is set, though the
+    /// output is still produced from whatever is in it.
+    pub fn process(&mut self, ram: &mut [u8], input: [i32; 2], write_enabled: bool) -> [i32; 2] {
+        for (history, &sample) in self.input_history.iter_mut().zip(input.iter()) {
+            push_history(history, clamp_sample(sample));
+        }
+        let mut output = [0; 2];
+        if self.is_step_due {
+            let input = [
+                filter(&self.input_history[0]),
+                filter(&self.input_history[1]),
+            ];
+            output = self.step(ram, input, write_enabled);
+        }
+        self.is_step_due = !self.is_step_due;
+
+        let mut result = [0; 2];
+        for channel in 0..2 {
+            // zero-stuffing halves the level, so make it up here
+            let history = &mut self.output_history[channel];
+            push_history(history, output[channel] * 2);
+            result[channel] = multiply(clamp_sample(filter(history)), self.output_volume[channel]);
+        }
+        return result;
+    }
+
+    /// Run one 22.05kHz step of the reverb for both channels
+    fn step(&mut self, ram: &mut [u8], input: [i32; 2], write_enabled: bool) -> [i32; 2] {
+        let mut output = [0; 2];
+        for channel in 0..2 {
+            let other = 1 - channel;
+            let input = multiply(input[channel], self.volume(VIN + channel));
+            let iir = self.volume(VIIR);
+            let wall = self.volume(VWALL);
+
+            // reflections off the same side, and off the other side
+            let same = self.offset(MSAME + channel);
+            let same_prev = self.read(ram, same, -2);
+            let same_wall = multiply(self.read(ram, self.offset(DSAME + channel), 0), wall);
+            let same_value = multiply(input + same_wall - same_prev, iir) + same_prev;
+            self.write(ram, same, same_value, write_enabled);
+
+            let diff = self.offset(MDIFF + channel);
+            let diff_prev = self.read(ram, diff, -2);
+            let diff_wall = multiply(self.read(ram, self.offset(DDIFF + other), 0), wall);
+            let diff_value = multiply(input + diff_wall - diff_prev, iir) + diff_prev;
+            self.write(ram, diff, diff_value, write_enabled);
+
+            let mut out = 0;
+            for &(volume, address) in &[
+                (VCOMB1, MCOMB1),
+                (VCOMB2, MCOMB2),
+                (VCOMB3, MCOMB3),
+                (VCOMB4, MCOMB4),
+            ] {
+                let sample = self.read(ram, self.offset(address + channel), 0);
+                out += multiply(sample, self.volume(volume));
+            }
+
+            // two all-pass filters in series
+            for &(volume, address, delay) in &[(VAPF1, MAPF1, DAPF1), (VAPF2, MAPF2, DAPF2)] {
+                let volume = self.volume(volume);
+                let address = self.offset(address + channel);
+                let delayed = self.read(ram, address, -(self.offset(delay) as i32));
+                out = clamp_sample(out - multiply(delayed, volume));
+                self.write(ram, address, out, write_enabled);
+                out = clamp_sample(multiply(out, volume) + delayed);
+            }
+            output[channel] = out;
+        }
+        self.address = (self.address + 2) & (WORK_AREA_END - 2);
+        self.address = self.address.max(self.base);
+        return output;
+    }
+
+    fn volume(&self, index: usize) -> i16 {
+        return self.regs[index] as i16;
+    }
+
+    fn offset(&self, index: usize) -> u32 {
+        return (self.regs[index] as u32) << 3;
+    }
+
+    /// Find an address in the work area, relative to the current position
+    fn resolve(&self, offset: u32, adjust: i32) -> usize {
+        let size = (WORK_AREA_END - self.base) as i64;
+        let relative = (self.address - self.base) as i64 + offset as i64 + adjust as i64;
+        return (self.base as i64 + relative.rem_euclid(size)) as usize;
+    }
+
+    fn read(&self, ram: &[u8], offset: u32, adjust: i32) -> i32 {
+        let address = self.resolve(offset, adjust);
+        return i16::from_le_bytes([ram[address], ram[address + 1]]) as i32;
+    }
+
+    fn write(&self, ram: &mut [u8], offset: u32, value: i32, write_enabled: bool) {
+        if !write_enabled {
+            return;
+        }
+        let address = self.resolve(offset, 0);
+        let value = clamp_sample(value) as i16;
+        ram[address..address + 2].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// One of the standard reverb settings games pick from, as set by the
+    /// SDK's sound library, with the size of the work area it needs in bytes
+    struct Preset {
+        name: &'static str,
+        size: u32,
+        regs: [u16; REVERB_REG_COUNT],
+    }
+
+    #[rustfmt::skip]
+    const PRESETS: [Preset; 10] = [
+        Preset {
+            name: "Room",
+            size: 0x26C0,
+            regs: [
+                0x007D, 0x005B, 0x6D80, 0x54B8, 0xBED0, 0x0000, 0x0000, 0xBA80,
+                0x5800, 0x5300, 0x04D6, 0x0333, 0x03F0, 0x0227, 0x0374, 0x01EF,
+                0x0334, 0x01B5, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000,
+                0x0000, 0x0000, 0x01B4, 0x0136, 0x00B8, 0x005C, 0x8000, 0x8000,
+            ],
+        },
+        Preset {
+            name: "Studio Small",
+            size: 0x1F40,
+            regs: [
+                0x0033, 0x0025, 0x70F0, 0x4FA8, 0xBCE0, 0x4410, 0xC0F0, 0x9C00,
+                0x5280, 0x4EC0, 0x03E4, 0x031B, 0x03A4, 0x02AF, 0x0372, 0x0266,
+                0x031C, 0x025D, 0x025C, 0x018E, 0x022F, 0x0135, 0x01D2, 0x00B7,
+                0x018F, 0x00B5, 0x00B4, 0x0080, 0x004C, 0x0026, 0x8000, 0x8000,
+            ],
+        },
+        Preset {
+            name: "Studio Medium",
+            size: 0x4840,
+            regs: [
+                0x00B1, 0x007F, 0x70F0, 0x4FA8, 0xBCE0, 0x4510, 0xBEF0, 0xB4C0,
+                0x5280, 0x4EC0, 0x0904, 0x076B, 0x0824, 0x065F, 0x07A2, 0x0616,
+                0x076C, 0x05ED, 0x05EC, 0x042E, 0x050F, 0x0305, 0x0462, 0x02B7,
+                0x042F, 0x0265, 0x0264, 0x01B2, 0x0100, 0x0080, 0x8000, 0x8000,
+            ],
+        },
+        Preset {
+            name: "Studio Large",
+            size: 0x6FE0,
+            regs: [
+                0x00E3, 0x00A9, 0x6F60, 0x4FA8, 0xBCE0, 0x4510, 0xBEF0, 0xA680,
+                0x5680, 0x52C0, 0x0DFB, 0x0B58, 0x0D09, 0x0A3C, 0x0BD9, 0x0973,
+                0x0B59, 0x08DA, 0x08D9, 0x05E9, 0x07EC, 0x04B0, 0x06EF, 0x03D2,
+                0x05EA, 0x031D, 0x031C, 0x0238, 0x0154, 0x00AA, 0x8000, 0x8000,
+            ],
+        },
+        Preset {
+            name: "Hall",
+            size: 0xADE0,
+            regs: [
+                0x01A5, 0x0139, 0x6000, 0x5000, 0x4C00, 0xB800, 0xBC00, 0xC000,
+                0x6000, 0x5C00, 0x15BA, 0x11BB, 0x14C2, 0x10BD, 0x11BC, 0x0DC1,
+                0x11C0, 0x0DC3, 0x0DC0, 0x09C1, 0x0BC4, 0x07C1, 0x0A00, 0x06CD,
+                0x09C2, 0x05C1, 0x05C0, 0x041A, 0x0274, 0x013A, 0x8000, 0x8000,
+            ],
+        },
+        Preset {
+            name: "Half Echo",
+            size: 0x3C00,
+            regs: [
+                0x0017, 0x0013, 0x70F0, 0x4FA8, 0xBCE0, 0x4510, 0xBEF0, 0x8500,
+                0x5F80, 0x54C0, 0x0371, 0x02AF, 0x02E5, 0x01DF, 0x02B0, 0x01D7,
+                0x0358, 0x026A, 0x01D6, 0x011E, 0x012D, 0x00B1, 0x011F, 0x0059,
+                0x01A0, 0x00E3, 0x0058, 0x0040, 0x0028, 0x0014, 0x8000, 0x8000,
+            ],
+        },
+        Preset {
+            name: "Space Echo",
+            size: 0xF6C0,
+            regs: [
+                0x033D, 0x0231, 0x7E00, 0x5000, 0xB400, 0xB000, 0x4C00, 0xB000,
+                0x6000, 0x5400, 0x1ED6, 0x1A31, 0x1D14, 0x183B, 0x1BC2, 0x16B2,
+                0x1A32, 0x15EF, 0x15EE, 0x1055, 0x1334, 0x0F2D, 0x11F6, 0x0C5D,
+                0x1056, 0x0AE1, 0x0AE0, 0x07A2, 0x0464, 0x0232, 0x8000, 0x8000,
+            ],
+        },
+        Preset {
+            name: "Chaos Echo",
+            size: 0x18040,
+            regs: [
+                0x0001, 0x0001, 0x7FFF, 0x7FFF, 0x0000, 0x0000, 0x0000, 0x8100,
+                0x0000, 0x0000, 0x1FFF, 0x0FFF, 0x1005, 0x0005, 0x0000, 0x0000,
+                0x1005, 0x0005, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000,
+                0x0000, 0x0000, 0x1004, 0x1002, 0x0004, 0x0002, 0x8000, 0x8000,
+            ],
+        },
+        Preset {
+            name: "Delay",
+            size: 0x18040,
+            regs: [
+                0x0001, 0x0001, 0x7FFF, 0x7FFF, 0x0000, 0x0000, 0x0000, 0x0000,
+                0x0000, 0x0000, 0x1FFF, 0x0FFF, 0x1005, 0x0005, 0x0000, 0x0000,
+                0x1005, 0x0005, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000,
+                0x0000, 0x0000, 0x1004, 0x1002, 0x0004, 0x0002, 0x8000, 0x8000,
+            ],
+        },
+        Preset {
+            name: "Off",
+            size: 0x10,
+            regs: [
+                0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000,
+                0x0000, 0x0000, 0x0001, 0x0001, 0x0001, 0x0001, 0x0001, 0x0001,
+                0x0000, 0x0000, 0x0001, 0x0001, 0x0001, 0x0001, 0x0001, 0x0001,
+                0x0000, 0x0000, 0x0001, 0x0001, 0x0001, 0x0001, 0x0000, 0x0000,
+            ],
+        },
+    ];
+
+    /// A reverb set up with a preset, with its work area at the end of sound
+    /// RAM
+    fn preset(preset: &Preset) -> Reverb {
+        let mut reverb = Reverb::new();
+        reverb.set_base(((WORK_AREA_END - preset.size) >> 3) as u16);
+        reverb.output_volume = [0x7FFF, 0x7FFF];
+        for (index, &value) in preset.regs.iter().enumerate() {
+            reverb.write_reg(index, value);
+        }
+        reverb
+    }
+
+    /// A reverb with a single echo: input is written straight to the same
+    /// side reflection, read back by the first comb filter, then passed
+    /// through both all-pass filters as plain delays
+    ///
+    /// The address registers are in 8-byte units, or 4 steps, so the echo
+    /// arrives `4 * (comb_delay + 2)` steps later.
+    fn echo(comb_delay: u16) -> Reverb {
+        let mut reverb = Reverb::new();
+        reverb.set_base(0x7000);
+        reverb.output_volume = [0x7FFF, 0x7FFF];
+        reverb.write_reg(VIIR, 0x7FFF);
+        reverb.write_reg(VCOMB1, 0x7FFF);
+        reverb.write_reg(DAPF1, 1);
+        reverb.write_reg(DAPF2, 1);
+        for channel in 0..2 {
+            let area = 0x100 * (channel as u16 + 1);
+            reverb.write_reg(VIN + channel, 0x7FFF);
+            reverb.write_reg(MSAME + channel, area);
+            reverb.write_reg(MCOMB1 + channel, area - comb_delay);
+            reverb.write_reg(MAPF1 + channel, area + 0x40);
+            reverb.write_reg(MAPF2 + channel, area + 0x80);
+        }
+        reverb
+    }
+
+    #[test]
+    fn half_band_filter_has_unity_gain() {
+        let history = [0x4000; 39];
+        assert!((filter(&history) - 0x4000).abs() <= 1);
+    }
+
+    #[test]
+    fn echoes_input() {
+        let mut ram = vec![0u8; 0x8_0000];
+        let mut reverb = echo(14);
+        let mut output = vec![];
+        for i in 0..400 {
+            let input = if i < 8 { 0x4000 } else { 0 };
+            output.push(reverb.process(&mut ram, [input, -input], true));
+        }
+        // the echo arrives 64 steps, or 128 output samples, later
+        assert!(output[..100].iter().all(|&sample| sample == [0, 0]));
+        let peak = output.iter().map(|sample| sample[0]).max().unwrap();
+        let peak_at = output.iter().position(|sample| sample[0] == peak).unwrap();
+        assert!(peak > 0x1000, "{:X}", peak);
+        assert!((120..200).contains(&peak_at), "{}", peak_at);
+        assert!(output[peak_at][1] < 0);
+    }
+
+    #[test]
+    fn only_writes_when_enabled() {
+        let mut ram = vec![0u8; 0x8_0000];
+        let mut reverb = echo(14);
+        for _ in 0..400 {
+            reverb.process(&mut ram, [0x4000, 0x4000], false);
+        }
+        assert!(ram.iter().all(|&byte| byte == 0));
+        for _ in 0..400 {
+            reverb.process(&mut ram, [0x4000, 0x4000], true);
+        }
+        // the work area is at the end of sound RAM
+        assert!(ram[..0x7000 << 3].iter().all(|&byte| byte == 0));
+        assert!(ram[0x7000 << 3..].iter().any(|&byte| byte != 0));
+    }
+
+    #[test]
+    fn presets_fit_their_work_areas() {
+        let addresses = [DAPF1, DAPF2]
+            .iter()
+            .copied()
+            .chain(MSAME..VIN)
+            .collect::<Vec<_>>();
+        for preset in PRESETS.iter() {
+            for &index in addresses.iter() {
+                let offset = (preset.regs[index] as u32) << 3;
+                assert!(offset < preset.size, "{}: register {}", preset.name, index);
+            }
+        }
+    }
+
+    /// Reverb registers for `runs_one_step_by_the_formulas`, with taps laid
+    /// out so none of them overlap. The right channel's taps are 0x10 past the
+    /// left channel's.
+    fn stepped() -> Reverb {
+        let mut reverb = Reverb::new();
+        reverb.set_base(0x7000);
+        let volumes = [
+            (VIIR, 0x6000),
+            (VWALL, 0xC000),
+            (VCOMB1, 0x4000),
+            (VCOMB2, 0xE000),
+            (VAPF1, 0x2000),
+            (VAPF2, 0xE000),
+            (VIN, 0x4000),
+            (VIN + 1, 0x4000),
+        ];
+        for &(index, value) in volumes.iter() {
+            reverb.write_reg(index, value);
+        }
+        reverb.write_reg(DAPF1, 0x08);
+        reverb.write_reg(DAPF2, 0x04);
+        for channel in 0..2 {
+            let taps = [MSAME, DSAME, MDIFF, DDIFF, MCOMB1, MCOMB2, MAPF1, MAPF2];
+            for (i, &index) in taps.iter().enumerate() {
+                let area = 0x10 + 0x20 * i as u16 + 0x10 * channel as u16;
+                reverb.write_reg(index + channel, area);
+            }
+        }
+        reverb
+    }
+
+    #[test]
+    fn runs_one_step_by_the_formulas() {
+        let mut ram = vec![0u8; 0x8_0000];
+        let mut reverb = stepped();
+        let base = 0x7000 << 3;
+        let mut poke = |index: usize, adjust: i32, value: i16| {
+            let address = (base + reverb.offset(index) as i32 + adjust) as usize;
+            ram[address..address + 2].copy_from_slice(&value.to_le_bytes());
+        };
+        // [dSAME], [mSAME-2], [dDIFF], [mDIFF-2], [mCOMB1], [mCOMB2],
+        // [mAPF1-dAPF1] and [mAPF2-dAPF2], for each side
+        for &(channel, values) in &[
+            (0, [1000, 200, 700, -300, 6000, 2000, 1200, -400]),
+            (1, [-1500, 100, -800, 250, -4000, 3000, -900, 600]),
+        ] {
+            poke(DSAME + channel, 0, values[0]);
+            poke(MSAME + channel, -2, values[1]);
+            poke(DDIFF + channel, 0, values[2]);
+            poke(MDIFF + channel, -2, values[3]);
+            poke(MCOMB1 + channel, 0, values[4]);
+            poke(MCOMB2 + channel, 0, values[5]);
+            poke(MAPF1 + channel, -0x40, values[6]);
+            poke(MAPF2 + channel, -0x20, values[7]);
+        }
+
+        // Left, with values scaled by 0x8000:
+        //   Lin     = 0x2000 * 0.5                                = 4096
+        //   [mSAME] = (4096 + 1000 * -0.5 - 200) * 0.75 + 200     = 2747
+        //   [mDIFF] = (4096 + -800 * -0.5 - -300) * 0.75 + -300   = 3297
+        //   comb    = 6000 * 0.5 + 2000 * -0.25                   = 2500
+        //   [mAPF1] = 2500 - 1200 * 0.25                          = 2200
+        //   apf1    = 2200 * 0.25 + 1200                          = 1750
+        //   [mAPF2] = 1750 - -400 * -0.25                         = 1650
+        //   out     = 1650 * -0.25 + -400                         = -813
+        // Right works the same way, reflecting the left [dDIFF] of 700.
+        let output = reverb.step(&mut ram, [0x2000, -0x2000], true);
+        assert_eq!(output, [-813, 945]);
+        let peek = |index: usize| {
+            let address = (base + reverb.offset(index) as i32) as usize;
+            i16::from_le_bytes([ram[address], ram[address + 1]])
+        };
+        assert_eq!(
+            [peek(MSAME), peek(MDIFF), peek(MAPF1), peek(MAPF2)],
+            [2747, 3297, 2200, 1650]
+        );
+        assert_eq!(
+            [
+                peek(MSAME + 1),
+                peek(MDIFF + 1),
+                peek(MAPF1 + 1),
+                peek(MAPF2 + 1)
+            ],
+            [-2485, -3272, -2525, -1382]
+        );
+    }
+
+    #[test]
+    fn presets_echo_within_their_work_areas() {
+        for preset in PRESETS.iter() {
+            let mut ram = vec![0u8; 0x8_0000];
+            let mut reverb = self::preset(preset);
+            let mut peak = 0;
+            for i in 0..0x1_0000 {
+                let input = if i < 8 { 0x4000 } else { 0 };
+                let output = reverb.process(&mut ram, [input, input], true);
+                peak = peak.max(output[0].abs()).max(output[1].abs());
+            }
+            let base = (WORK_AREA_END - preset.size) as usize;
+            assert!(ram[..base].iter().all(|&byte| byte == 0), "{}", preset.name);
+            if preset.name == "Off" {
+                assert_eq!(peak, 0);
+            } else {
+                assert!(peak > 0x100, "{}: {:X}", preset.name, peak);
+            }
+        }
+    }
+}
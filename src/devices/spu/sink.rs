@@ -0,0 +1,32 @@
+use std::io;
+
+/// The rate the SPU produces samples at
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Somewhere for the SPU's output to go
+///
+/// The SPU pushes one stereo sample (left, then right) at a time, at a
+/// steady 44.1kHz of emulated time. Sinks shouldn't block, since they're
+/// called from the middle of emulation.
+pub trait AudioSink {
+    fn push_sample(&mut self, sample: [i16; 2]);
+
+    /// Write out anything that's been buffered
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+/// A sink that throws samples away, for when nothing is listening
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn push_sample(&mut self, _sample: [i16; 2]) {}
+}
+
+/// Collect samples in memory, as tests do
+impl AudioSink for Vec<[i16; 2]> {
+    fn push_sample(&mut self, sample: [i16; 2]) {
+        self.push(sample);
+    }
+}
@@ -0,0 +1,647 @@
+//! The Sound Processing Unit
+//!
+//! The SPU mixes 24 voices playing ADPCM samples out of 512KiB of its own
+//! RAM, at a fixed 44.1kHz. Its registers sit at 0x1F801C00: 16 bytes for
+//! each voice, then the global volume, key and mode registers, and the
+//! transfer registers used to fill sound RAM from the CPU or DMA.
+
+use super::envelope::Volume;
+use super::reverb::{Reverb, REVERB_REG_COUNT};
+use super::sink::AudioSink;
+use super::voice::Voice;
+use crate::devices::bus::{BusDevice, SizedData};
+use log::debug;
+use std::collections::VecDeque;
+
+/// The CPU clock divided by the 44.1kHz output rate
+pub const CYCLES_PER_SAMPLE: u32 = 768;
+pub const VOICE_COUNT: usize = 24;
+pub const SPU_RAM_SIZE: usize = 512 * 1024;
+/// How much CD audio is buffered before the oldest is dropped
+const CD_INPUT_BUFFER_SIZE: usize = 44100 / 2;
+/// The transfer FIFO holds 32 halfwords
+const TRANSFER_FIFO_SIZE: usize = 32;
+
+//#region Register offsets
+const VOICE_REGS_END: u32 = 0x180;
+const VOICE_VOLUME_LEFT: u32 = 0x0;
+const VOICE_VOLUME_RIGHT: u32 = 0x2;
+const VOICE_PITCH: u32 = 0x4;
+const VOICE_START_ADDRESS: u32 = 0x6;
+const VOICE_ADSR_LOW: u32 = 0x8;
+const VOICE_ADSR_HIGH: u32 = 0xA;
+const VOICE_ADSR_LEVEL: u32 = 0xC;
+const VOICE_REPEAT_ADDRESS: u32 = 0xE;
+const MAIN_VOLUME_LEFT: u32 = 0x180;
+const MAIN_VOLUME_RIGHT: u32 = 0x182;
+const REVERB_VOLUME_LEFT: u32 = 0x184;
+const REVERB_VOLUME_RIGHT: u32 = 0x186;
+const KEY_ON: u32 = 0x188;
+const KEY_OFF: u32 = 0x18C;
+const PITCH_MOD_ENABLE: u32 = 0x190;
+const NOISE_ENABLE: u32 = 0x194;
+const REVERB_ENABLE: u32 = 0x198;
+const VOICE_END_FLAGS: u32 = 0x19C;
+const REVERB_BASE: u32 = 0x1A2;
+const IRQ_ADDRESS: u32 = 0x1A4;
+const TRANSFER_ADDRESS: u32 = 0x1A6;
+const TRANSFER_FIFO: u32 = 0x1A8;
+const SPUCNT: u32 = 0x1AA;
+const SPUSTAT: u32 = 0x1AE;
+const CD_VOLUME_LEFT: u32 = 0x1B0;
+const CD_VOLUME_RIGHT: u32 = 0x1B2;
+const CURRENT_MAIN_VOLUME: u32 = 0x1B8;
+const REVERB_CONFIG: u32 = 0x1C0;
+const VOICE_CURRENT_VOLUME: u32 = 0x200;
+const VOICE_CURRENT_VOLUME_END: u32 = 0x260;
+//#endregion
+
+//#region SPUCNT bits
+const SPUCNT_STAT_MIRROR: u16 = 0x003F;
+const SPUCNT_CD_AUDIO_ENABLE: u16 = 0x0001;
+const SPUCNT_CD_REVERB_ENABLE: u16 = 0x0004;
+const SPUCNT_TRANSFER_MODE: u16 = 0x0030;
+const SPUCNT_IRQ_ENABLE: u16 = 0x0040;
+/// Allows the reverb unit to write to its work area
+const SPUCNT_REVERB_ENABLE: u16 = 0x0080;
+const SPUCNT_NOISE_STEP: u16 = 0x0300;
+const SPUCNT_NOISE_SHIFT: u16 = 0x3C00;
+const SPUCNT_UNMUTE: u16 = 0x4000;
+const SPUCNT_ENABLE: u16 = 0x8000;
+//#endregion
+
+//#region SPUSTAT bits
+const SPUSTAT_IRQ: u16 = 0x0040;
+const SPUSTAT_DMA_REQUEST: u16 = 0x0080;
+const SPUSTAT_DMA_WRITE_REQUEST: u16 = 0x0100;
+const SPUSTAT_DMA_READ_REQUEST: u16 = 0x0200;
+//#endregion
+
+/// How sound RAM transfers are carried out, from SPUCNT bits 4-5
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum TransferMode {
+    Stop,
+    ManualWrite,
+    DmaWrite,
+    DmaRead,
+}
+
+impl From<u16> for TransferMode {
+    fn from(spucnt: u16) -> Self {
+        match (spucnt & SPUCNT_TRANSFER_MODE) >> 4 {
+            0 => TransferMode::Stop,
+            1 => TransferMode::ManualWrite,
+            2 => TransferMode::DmaWrite,
+            _ => TransferMode::DmaRead,
+        }
+    }
+}
+
+/// Scale a sample by a volume, where 0x7FFF is unity gain
+fn apply_volume(sample: i32, volume: i16) -> i32 {
+    return (sample * volume as i32) >> 15;
+}
+
+fn clamp_sample(sample: i32) -> i16 {
+    return sample.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+}
+
+pub struct Spu {
+    ram: Vec<u8>,
+    voices: Vec<Voice>,
+    reverb: Reverb,
+    /// The raw registers, for those that read back what was written
+    regs: Vec<u16>,
+    main_volume: [Volume; 2],
+    control: u16,
+    irq_flag: bool,
+    /// Whether an IRQ was raised since the last check
+    irq_requested: bool,
+    /// The address voices and transfers raise an IRQ at, in bytes
+    irq_address: u32,
+    /// The current transfer address, in bytes
+    transfer_address: u32,
+    transfer_fifo: VecDeque<u16>,
+    pitch_mod_enable: u32,
+    noise_enable: u32,
+    reverb_enable: u32,
+    /// Audio from the CD-ROM controller, at 44.1kHz
+    cd_input: VecDeque<[i16; 2]>,
+    cd_volume: [i16; 2],
+    end_flags: u32,
+    noise_level: i16,
+    noise_timer: i32,
+    /// CPU cycles since the last output sample
+    cycles: u32,
+}
+
+impl Spu {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Spu {
+        return Spu {
+            ram: vec![0; SPU_RAM_SIZE],
+            voices: (0..VOICE_COUNT).map(|_| Voice::new()).collect(),
+            reverb: Reverb::new(),
+            regs: vec![0; 0x140],
+            main_volume: [Volume::default(); 2],
+            control: 0,
+            irq_flag: false,
+            irq_requested: false,
+            irq_address: 0,
+            transfer_address: 0,
+            transfer_fifo: VecDeque::with_capacity(TRANSFER_FIFO_SIZE),
+            pitch_mod_enable: 0,
+            noise_enable: 0,
+            reverb_enable: 0,
+            cd_input: VecDeque::new(),
+            cd_volume: [0; 2],
+            end_flags: 0,
+            noise_level: 1,
+            noise_timer: 0,
+            cycles: 0,
+        };
+    }
+
+    /// Advance the SPU, pushing a stereo sample to the sink every 768 CPU
+    /// cycles
+    pub fn tick(&mut self, cycles: u32, sink: &mut dyn AudioSink) {
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_SAMPLE {
+            self.cycles -= CYCLES_PER_SAMPLE;
+            let sample = self.generate_sample();
+            sink.push_sample(sample);
+        }
+    }
+
+    /// Return, and clear, whether the SPU has raised an interrupt
+    pub fn take_irq(&mut self) -> bool {
+        let irq = self.irq_requested;
+        self.irq_requested = false;
+        return irq;
+    }
+
+    /// Read a word for DMA, from the current transfer address
+    pub fn read_dma_word(&mut self) -> u32 {
+        let low = self.read_transfer_halfword() as u32;
+        let high = self.read_transfer_halfword() as u32;
+        return low | (high << 16);
+    }
+
+    /// Queue up audio from the CD-ROM controller, to be mixed in at the
+    /// output rate
+    pub fn push_cd_audio<I: Iterator<Item = [i16; 2]>>(&mut self, samples: I) {
+        self.cd_input.extend(samples);
+        let excess = self.cd_input.len().saturating_sub(CD_INPUT_BUFFER_SIZE);
+        self.cd_input.drain(..excess);
+    }
+
+    /// Write a word from DMA to the current transfer address
+    pub fn write_dma_word(&mut self, data: u32) {
+        self.write_transfer_halfword(data as u16);
+        self.write_transfer_halfword((data >> 16) as u16);
+    }
+
+    fn generate_sample(&mut self) -> [i16; 2] {
+        self.tick_noise();
+        let mut mix = [0i32; 2];
+        let mut reverb_input = [0i32; 2];
+        let mut previous_output = 0i16;
+        for n in 0..VOICE_COUNT {
+            let voice = &mut self.voices[n];
+            let raw = if (self.noise_enable & (1 << n)) != 0 {
+                self.noise_level
+            } else {
+                voice.sample()
+            };
+            let output = apply_volume(raw as i32, voice.envelope.level);
+            voice.last_output = output as i16;
+            let is_reverb_enabled = (self.reverb_enable & (1 << n)) != 0;
+            for channel in 0..2 {
+                voice.volume[channel].tick();
+                let level = apply_volume(output, voice.volume[channel].level);
+                mix[channel] += level;
+                if is_reverb_enabled {
+                    reverb_input[channel] += level;
+                }
+            }
+
+            let mut step = voice.pitch as u32;
+            if n > 0 && (self.pitch_mod_enable & (1 << n)) != 0 {
+                let factor = previous_output as i32 + 0x8000;
+                step = ((step as i32 * factor) >> 15) as u32 & 0xFFFF;
+            }
+            previous_output = voice.last_output;
+            voice.envelope.tick(voice.adsr);
+            if let Some(address) = voice.advance(step.min(0x4000), &self.ram) {
+                if voice.reached_end {
+                    voice.reached_end = false;
+                    self.end_flags |= 1 << n;
+                }
+                self.check_irq(address, 16);
+            }
+        }
+
+        let cd = self.cd_input.pop_front().unwrap_or([0, 0]);
+        let mut cd_mix = [0i32; 2];
+        if (self.control & SPUCNT_CD_AUDIO_ENABLE) != 0 {
+            for channel in 0..2 {
+                let level = apply_volume(cd[channel] as i32, self.cd_volume[channel]);
+                cd_mix[channel] = level;
+                if (self.control & SPUCNT_CD_REVERB_ENABLE) != 0 {
+                    reverb_input[channel] += level;
+                }
+            }
+        }
+
+        let write_enabled = (self.control & SPUCNT_REVERB_ENABLE) != 0;
+        let reverb = self
+            .reverb
+            .process(&mut self.ram, reverb_input, write_enabled);
+        mix[0] += reverb[0];
+        mix[1] += reverb[1];
+        self.main_volume[0].tick();
+        self.main_volume[1].tick();
+
+        // muting or disabling the SPU silences the voices and reverb, but CD
+        // audio only answers to its own enable bit and volume
+        if (self.control & SPUCNT_ENABLE) == 0 || (self.control & SPUCNT_UNMUTE) == 0 {
+            return [clamp_sample(cd_mix[0]), clamp_sample(cd_mix[1])];
+        }
+        return [
+            clamp_sample(apply_volume(mix[0], self.main_volume[0].level) + cd_mix[0]),
+            clamp_sample(apply_volume(mix[1], self.main_volume[1].level) + cd_mix[1]),
+        ];
+    }
+
+    /// Step the pseudo-random noise generator shared by all voices
+    fn tick_noise(&mut self) {
+        let step = 4 + ((self.control & SPUCNT_NOISE_STEP) >> 8) as i32;
+        let shift = (self.control & SPUCNT_NOISE_SHIFT) >> 10;
+        let level = self.noise_level as u16;
+        let parity = ((level >> 15) ^ (level >> 12) ^ (level >> 11) ^ (level >> 10) ^ 1) & 1;
+        self.noise_timer -= step;
+        if self.noise_timer < 0 {
+            self.noise_level = ((level << 1) | parity) as i16;
+            let period = 0x20000 >> shift;
+            self.noise_timer += period;
+            if self.noise_timer < 0 {
+                self.noise_timer += period;
+            }
+        }
+    }
+
+    /// Raise an IRQ if the given range of sound RAM covers the IRQ address
+    fn check_irq(&mut self, address: u32, length: u32) {
+        if (self.control & SPUCNT_IRQ_ENABLE) == 0 || self.irq_flag {
+            return;
+        }
+        if self.irq_address >= address && self.irq_address < address + length {
+            self.irq_flag = true;
+            self.irq_requested = true;
+        }
+    }
+
+    fn write_transfer_halfword(&mut self, data: u16) {
+        let address = self.transfer_address as usize;
+        self.ram[address..address + 2].copy_from_slice(&data.to_le_bytes());
+        self.check_irq(self.transfer_address, 2);
+        self.transfer_address = (self.transfer_address + 2) % SPU_RAM_SIZE as u32;
+    }
+
+    fn read_transfer_halfword(&mut self) -> u16 {
+        let address = self.transfer_address as usize;
+        let data = u16::from_le_bytes([self.ram[address], self.ram[address + 1]]);
+        self.check_irq(self.transfer_address, 2);
+        self.transfer_address = (self.transfer_address + 2) % SPU_RAM_SIZE as u32;
+        return data;
+    }
+
+    fn get_status(&self) -> u16 {
+        let mut status = self.control & SPUCNT_STAT_MIRROR;
+        if self.irq_flag {
+            status |= SPUSTAT_IRQ;
+        }
+        match TransferMode::from(self.control) {
+            TransferMode::DmaWrite => status |= SPUSTAT_DMA_REQUEST | SPUSTAT_DMA_WRITE_REQUEST,
+            TransferMode::DmaRead => status |= SPUSTAT_DMA_REQUEST | SPUSTAT_DMA_READ_REQUEST,
+            _ => (),
+        }
+        return status;
+    }
+
+    fn read_reg(&self, addr: u32) -> u16 {
+        let addr = addr & 0x3FE;
+        if addr < VOICE_REGS_END {
+            let voice = &self.voices[(addr >> 4) as usize];
+            return match addr & 0xF {
+                VOICE_ADSR_LEVEL => voice.envelope.level as u16,
+                VOICE_REPEAT_ADDRESS => voice.repeat_address,
+                _ => self.regs[(addr >> 1) as usize],
+            };
+        }
+        if (VOICE_CURRENT_VOLUME..VOICE_CURRENT_VOLUME_END).contains(&addr) {
+            let voice = &self.voices[((addr - VOICE_CURRENT_VOLUME) >> 2) as usize];
+            return voice.volume[((addr >> 1) & 1) as usize].level as u16;
+        }
+        return match addr {
+            VOICE_END_FLAGS => self.end_flags as u16,
+            0x19E => (self.end_flags >> 16) as u16,
+            SPUSTAT => self.get_status(),
+            CURRENT_MAIN_VOLUME => self.main_volume[0].level as u16,
+            0x1BA => self.main_volume[1].level as u16,
+            _ if ((addr >> 1) as usize) < self.regs.len() => self.regs[(addr >> 1) as usize],
+            _ => {
+                debug!(target: "spu", "Read from unmapped SPU register ${:03X}", addr);
+                0
+            }
+        };
+    }
+
+    fn write_reg(&mut self, addr: u32, value: u16) {
+        let addr = addr & 0x3FE;
+        if let Some(reg) = self.regs.get_mut((addr >> 1) as usize) {
+            *reg = value;
+        }
+        if addr < VOICE_REGS_END {
+            self.write_voice_reg((addr >> 4) as usize, addr & 0xF, value);
+            return;
+        }
+        // the key and mode registers are split into two halves, with voices
+        // 16-23 in the high half
+        let (half_shift, half_mask) = if (addr & 2) != 0 {
+            (16, 0x0000_FFFF)
+        } else {
+            (0, 0xFFFF_0000)
+        };
+        let bits = (value as u32) << half_shift;
+        match addr {
+            MAIN_VOLUME_LEFT | MAIN_VOLUME_RIGHT => {
+                self.main_volume[((addr >> 1) & 1) as usize].set(value);
+            }
+            _ if (addr & !2) == KEY_ON => {
+                for n in (0..VOICE_COUNT).filter(|n| (bits & (1 << n)) != 0) {
+                    let address = self.voices[n].key_on(&self.ram);
+                    self.end_flags &= !(1 << n);
+                    self.check_irq(address, 16);
+                }
+            }
+            _ if (addr & !2) == KEY_OFF => {
+                for n in (0..VOICE_COUNT).filter(|n| (bits & (1 << n)) != 0) {
+                    self.voices[n].key_off();
+                }
+            }
+            _ if (addr & !2) == PITCH_MOD_ENABLE => {
+                self.pitch_mod_enable = (self.pitch_mod_enable & half_mask) | bits;
+            }
+            _ if (addr & !2) == NOISE_ENABLE => {
+                self.noise_enable = (self.noise_enable & half_mask) | bits
+            }
+            _ if (addr & !2) == REVERB_ENABLE => {
+                self.reverb_enable = (self.reverb_enable & half_mask) | bits
+            }
+            REVERB_VOLUME_LEFT | REVERB_VOLUME_RIGHT => {
+                // the reverb output volume can't sweep, and is signed
+                self.reverb.output_volume[((addr >> 1) & 1) as usize] = value as i16;
+            }
+            REVERB_BASE => self.reverb.set_base(value),
+            CD_VOLUME_LEFT | CD_VOLUME_RIGHT => {
+                self.cd_volume[((addr >> 1) & 1) as usize] = value as i16;
+            }
+            _ if (REVERB_CONFIG..REVERB_CONFIG + 2 * REVERB_REG_COUNT as u32).contains(&addr) => {
+                self.reverb
+                    .write_reg(((addr - REVERB_CONFIG) >> 1) as usize, value);
+            }
+            IRQ_ADDRESS => self.irq_address = (value as u32) << 3,
+            TRANSFER_ADDRESS => self.transfer_address = (value as u32) << 3,
+            TRANSFER_FIFO if self.transfer_fifo.len() < TRANSFER_FIFO_SIZE => {
+                self.transfer_fifo.push_back(value);
+            }
+            SPUCNT => {
+                self.control = value;
+                if (value & SPUCNT_IRQ_ENABLE) == 0 {
+                    self.irq_flag = false;
+                }
+                if TransferMode::from(value) == TransferMode::ManualWrite {
+                    while let Some(data) = self.transfer_fifo.pop_front() {
+                        self.write_transfer_halfword(data);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn write_voice_reg(&mut self, n: usize, reg: u32, value: u16) {
+        let voice = &mut self.voices[n];
+        match reg {
+            VOICE_VOLUME_LEFT | VOICE_VOLUME_RIGHT => {
+                let channel = (reg >> 1) as usize;
+                voice.volume[channel].set(value);
+            }
+            VOICE_PITCH => voice.pitch = value,
+            VOICE_START_ADDRESS => voice.start_address = value,
+            VOICE_ADSR_LOW => voice.adsr.set_low(value),
+            VOICE_ADSR_HIGH => voice.adsr.set_high(value),
+            VOICE_ADSR_LEVEL => voice.envelope.level = value as i16,
+            VOICE_REPEAT_ADDRESS => voice.repeat_address = value,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl BusDevice for Spu {
+    fn read<T: SizedData>(&mut self, addr: u32) -> T {
+        return self.peek(addr).unwrap();
+    }
+
+    fn peek<T: SizedData>(&self, addr: u32) -> Option<T> {
+        // the registers are 16 bits wide, so word accesses read two of them
+        let mut data = self.read_reg(addr) as u32;
+        if T::width() == 4 {
+            data |= (self.read_reg(addr + 2) as u32) << 16;
+        } else if (addr & 1) != 0 {
+            data >>= 8;
+        }
+        return Some(T::from_u32(data));
+    }
+
+    fn write<T: SizedData>(&mut self, addr: u32, data: T) {
+        let data = data.to_u32();
+        self.write_reg(addr, data as u16);
+        if T::width() == 4 {
+            self.write_reg(addr + 2, (data >> 16) as u16);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::utils::audio::{read_wav, WavWriter};
+    use std::path::{Path, PathBuf};
+
+    /// Golden output is rendered by this SPU and checked in, to catch changes
+    /// to what it plays. Set UPDATE_GOLDEN to write it again after a change
+    /// that's meant to be heard.
+    fn golden_path(name: &str) -> PathBuf {
+        return Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/devices/spu/testdata")
+            .join(name);
+    }
+
+    /// Upload data to sound RAM through the transfer FIFO
+    fn upload(spu: &mut Spu, address: u32, data: &[u8]) {
+        spu.write(TRANSFER_ADDRESS, (address >> 3) as u16);
+        for chunk in data.chunks(64) {
+            for halfword in chunk.chunks(2) {
+                let value = u16::from_le_bytes([halfword[0], halfword[1]]);
+                spu.write(TRANSFER_FIFO, value);
+            }
+            spu.write(SPUCNT, SPUCNT_ENABLE | 0x0010);
+            spu.write(SPUCNT, SPUCNT_ENABLE);
+        }
+    }
+
+    #[test]
+    fn transfers_sound_ram() {
+        let mut spu = Spu::new();
+        let data: Vec<u8> = (0..128u32).map(|i| i as u8).collect();
+        upload(&mut spu, 0x1000, &data);
+        assert_eq!(spu.ram[0x1000..0x1080], data[..]);
+
+        // and read it back with DMA
+        spu.write(TRANSFER_ADDRESS, 0x1000u16 >> 3);
+        spu.write(SPUCNT, SPUCNT_ENABLE | 0x0030);
+        assert_eq!(spu.peek::<u16>(SPUSTAT), Some(0x0030 | 0x0280));
+        assert_eq!(spu.read_dma_word(), 0x0302_0100);
+    }
+
+    #[test]
+    fn plays_voices() {
+        let mut spu = Spu::new();
+        // a block of constant samples that ends without looping
+        let mut block = [0x77u8; 16];
+        block[0] = 0x00;
+        block[1] = 0x01;
+        upload(&mut spu, 0x2000, &block);
+
+        spu.write(SPUCNT, SPUCNT_ENABLE | SPUCNT_UNMUTE);
+        spu.write(MAIN_VOLUME_LEFT, 0x3FFFu16);
+        spu.write(MAIN_VOLUME_RIGHT, 0x3FFFu16);
+        spu.write(VOICE_VOLUME_LEFT, 0x3FFFu16);
+        spu.write(VOICE_PITCH, 0x1000u16);
+        spu.write(VOICE_START_ADDRESS, 0x2000u16 >> 3);
+        spu.write(VOICE_ADSR_LOW, 0x000Fu16);
+        spu.write(KEY_ON, 1u16);
+
+        let mut samples = vec![];
+        spu.tick(CYCLES_PER_SAMPLE * 20, &mut samples);
+        assert_eq!(samples.len(), 20);
+        assert!(samples[19][0] > 0);
+        assert_eq!(samples[19][1], 0);
+        assert_eq!(spu.peek::<u16>(VOICE_END_FLAGS), Some(0));
+
+        // the block ends without looping, so the voice is muted
+        spu.tick(CYCLES_PER_SAMPLE * 10, &mut samples);
+        assert_eq!(spu.peek::<u16>(VOICE_END_FLAGS), Some(1));
+        assert_eq!(spu.peek::<u16>(VOICE_ADSR_LEVEL), Some(0));
+    }
+
+    #[test]
+    fn raises_irq_at_address() {
+        let mut spu = Spu::new();
+        spu.write(IRQ_ADDRESS, 0x3000u16 >> 3);
+        spu.write(SPUCNT, SPUCNT_ENABLE | SPUCNT_IRQ_ENABLE);
+        spu.write(VOICE_START_ADDRESS, 0x3000u16 >> 3);
+        spu.write(KEY_ON, 1u16);
+        assert!(spu.take_irq());
+        assert_eq!(spu.peek::<u16>(SPUSTAT).unwrap() & SPUSTAT_IRQ, SPUSTAT_IRQ);
+
+        // clearing the enable bit acknowledges it
+        spu.write(SPUCNT, SPUCNT_ENABLE);
+        assert_eq!(spu.peek::<u16>(SPUSTAT).unwrap() & SPUSTAT_IRQ, 0);
+    }
+
+    #[test]
+    fn plays_cd_audio_while_muted() {
+        let mut spu = Spu::new();
+        // the SPU is disabled and muted, with only CD audio enabled
+        spu.write(SPUCNT, SPUCNT_CD_AUDIO_ENABLE);
+        spu.write(CD_VOLUME_LEFT, 0x4000u16);
+        spu.write(CD_VOLUME_RIGHT, 0x4000u16);
+        spu.push_cd_audio(std::iter::repeat_n([0x1000, -0x1000], 8));
+
+        let mut samples = vec![];
+        spu.tick(CYCLES_PER_SAMPLE * 4, &mut samples);
+        assert_eq!(samples, vec![[0x0800, -0x0800]; 4]);
+
+        spu.write(SPUCNT, 0u16);
+        spu.tick(CYCLES_PER_SAMPLE * 4, &mut samples);
+        assert_eq!(samples[4..], [[0, 0]; 4]);
+    }
+
+    #[test]
+    fn generates_noise() {
+        let mut spu = Spu::new();
+        spu.write(SPUCNT, SPUCNT_ENABLE | SPUCNT_UNMUTE | 0x3F00);
+        let levels: Vec<_> = (0..8)
+            .map(|_| {
+                spu.tick_noise();
+                spu.noise_level
+            })
+            .collect();
+        assert!(levels.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn matches_golden_output() {
+        // four blocks of a triangle wave, one for each of the first four
+        // filters, looping back to the first
+        let mut sample = vec![];
+        for filter in 0..4u8 {
+            sample.push((filter << 4) | (4 + filter));
+            sample.push(match filter {
+                0 => 0x04,
+                3 => 0x03,
+                _ => 0x00,
+            });
+            for i in 0..14u8 {
+                let low = [1, 3, 5, 7, 5, 3, 1, 0xF, 0xD, 0xB, 0x9, 0xB, 0xD, 0xF][i as usize];
+                let high = [2, 4, 6, 6, 4, 2, 0, 0xE, 0xC, 0xA, 0xA, 0xC, 0xE, 0][i as usize];
+                sample.push(low | (high << 4));
+            }
+        }
+        let mut spu = Spu::new();
+        upload(&mut spu, 0x2000, &sample);
+
+        spu.write(SPUCNT, SPUCNT_ENABLE | SPUCNT_UNMUTE);
+        spu.write(MAIN_VOLUME_LEFT, 0x3FFFu16);
+        spu.write(MAIN_VOLUME_RIGHT, 0x3FFFu16);
+        // the left volume is fixed, and the right sweeps up from silence
+        spu.write(VOICE_VOLUME_LEFT, 0x3FFFu16);
+        spu.write(VOICE_VOLUME_RIGHT, 0x8020u16);
+        spu.write(VOICE_PITCH, 0x0D00u16);
+        spu.write(VOICE_START_ADDRESS, 0x2000u16 >> 3);
+        spu.write(VOICE_ADSR_LOW, 0x1848u16);
+        spu.write(VOICE_ADSR_HIGH, 0x0008u16);
+        spu.write(KEY_ON, 1u16);
+
+        let mut samples = vec![];
+        spu.tick(CYCLES_PER_SAMPLE * 1536, &mut samples);
+        spu.write(KEY_OFF, 1u16);
+        spu.tick(CYCLES_PER_SAMPLE * 512, &mut samples);
+
+        let path = golden_path("adpcm_triangle.wav");
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            let mut writer = WavWriter::create(&path).unwrap();
+            for &sample in samples.iter() {
+                writer.push_sample(sample);
+            }
+            writer.flush().unwrap();
+        }
+        let golden = read_wav(&path).unwrap();
+        assert_eq!(samples.len(), golden.len());
+        let mismatch = samples.iter().zip(golden.iter()).position(|(a, b)| a != b);
+        assert_eq!(mismatch, None, "output differs from {:?}", path);
+    }
+}
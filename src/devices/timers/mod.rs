@@ -0,0 +1,6 @@
+mod structs;
+#[allow(clippy::module_inception)]
+mod timers;
+
+pub use self::structs::ClockSource;
+pub use self::timers::Timers;
@@ -0,0 +1,121 @@
+use std::ops::Deref;
+
+/// The clock a root counter increments on
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ClockSource {
+    /// The CPU clock (~33.87MHz)
+    SystemClock,
+    /// The GPU dot clock, which depends on the horizontal resolution
+    DotClock,
+    /// Once per scanline, at the start of horizontal blanking
+    HBlank,
+    /// The CPU clock divided by 8
+    SystemClockDiv8,
+}
+
+// Bits 13-15 are always zero, and bits 16-31 are garbage
+const COUNTER_MODE_UNUSED: u32 = 0xFFFF_E000;
+const COUNTER_MODE_SYNC_ENABLE: u32 = 0x0000_0001;
+const COUNTER_MODE_SYNC_MODE: u32 = 0x0000_0006;
+const COUNTER_MODE_RESET_ON_TARGET: u32 = 0x0000_0008;
+const COUNTER_MODE_IRQ_ON_TARGET: u32 = 0x0000_0010;
+const COUNTER_MODE_IRQ_ON_OVERFLOW: u32 = 0x0000_0020;
+const COUNTER_MODE_IRQ_REPEAT: u32 = 0x0000_0040;
+const COUNTER_MODE_IRQ_TOGGLE: u32 = 0x0000_0080;
+const COUNTER_MODE_CLOCK_SOURCE: u32 = 0x0000_0300;
+/// Active-low interrupt request flag
+const COUNTER_MODE_IRQ_FLAG: u32 = 0x0000_0400;
+const COUNTER_MODE_REACHED_TARGET: u32 = 0x0000_0800;
+const COUNTER_MODE_REACHED_OVERFLOW: u32 = 0x0000_1000;
+/// The bits software can actually write to
+pub const COUNTER_MODE_WRITABLE: u32 = 0x0000_03FF;
+/// The bits that are cleared after the mode register is read
+pub const COUNTER_MODE_READ_CLEARED: u32 =
+    COUNTER_MODE_REACHED_TARGET | COUNTER_MODE_REACHED_OVERFLOW;
+
+/// A root counter mode register
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
+pub struct CounterMode(u32);
+
+impl CounterMode {
+    pub fn is_sync_enabled(&self) -> bool {
+        return (**self & COUNTER_MODE_SYNC_ENABLE) != 0;
+    }
+
+    /// The raw synchronization mode, whose meaning depends on the counter
+    pub fn get_sync_mode(&self) -> u8 {
+        return ((**self & COUNTER_MODE_SYNC_MODE) >> 1) as u8;
+    }
+
+    pub fn is_reset_on_target(&self) -> bool {
+        return (**self & COUNTER_MODE_RESET_ON_TARGET) != 0;
+    }
+
+    pub fn is_irq_on_target(&self) -> bool {
+        return (**self & COUNTER_MODE_IRQ_ON_TARGET) != 0;
+    }
+
+    pub fn is_irq_on_overflow(&self) -> bool {
+        return (**self & COUNTER_MODE_IRQ_ON_OVERFLOW) != 0;
+    }
+
+    /// If false, the IRQ only fires once after each mode write
+    pub fn is_irq_repeat(&self) -> bool {
+        return (**self & COUNTER_MODE_IRQ_REPEAT) != 0;
+    }
+
+    /// If false, the IRQ flag is pulsed instead of toggled
+    pub fn is_irq_toggle(&self) -> bool {
+        return (**self & COUNTER_MODE_IRQ_TOGGLE) != 0;
+    }
+
+    /// Return the clock source for the counter at the given index
+    pub fn get_clock_source(&self, counter: usize) -> ClockSource {
+        let source = (**self & COUNTER_MODE_CLOCK_SOURCE) >> 8;
+        return match (counter, source) {
+            (0, 1) | (0, 3) => ClockSource::DotClock,
+            (1, 1) | (1, 3) => ClockSource::HBlank,
+            (2, 2) | (2, 3) => ClockSource::SystemClockDiv8,
+            _ => ClockSource::SystemClock,
+        };
+    }
+
+    /// Whether an interrupt has been requested (the flag is active-low)
+    pub fn is_irq_requested(&self) -> bool {
+        return (**self & COUNTER_MODE_IRQ_FLAG) == 0;
+    }
+
+    pub fn set_irq_flag(&mut self, requested: bool) {
+        if requested {
+            self.0 &= !COUNTER_MODE_IRQ_FLAG;
+        } else {
+            self.0 |= COUNTER_MODE_IRQ_FLAG;
+        }
+    }
+
+    pub fn set_reached_target(&mut self) {
+        self.0 |= COUNTER_MODE_REACHED_TARGET;
+    }
+
+    pub fn set_reached_overflow(&mut self) {
+        self.0 |= COUNTER_MODE_REACHED_OVERFLOW;
+    }
+
+    pub fn clear_reached_flags(&mut self) {
+        self.0 &= !COUNTER_MODE_READ_CLEARED;
+    }
+}
+
+impl From<u32> for CounterMode {
+    fn from(data: u32) -> Self {
+        CounterMode(data & !COUNTER_MODE_UNUSED)
+    }
+}
+
+impl Deref for CounterMode {
+    type Target = u32;
+
+    fn deref(&self) -> &u32 {
+        return &self.0;
+    }
+}
@@ -0,0 +1,421 @@
+//! The three PSX root counters
+
+use super::structs::{ClockSource, CounterMode, COUNTER_MODE_READ_CLEARED, COUNTER_MODE_WRITABLE};
+use crate::devices::bus::{BusDevice, SizedData};
+use crate::devices::intctrl::{InterruptController, Irq};
+use log::debug;
+
+const COUNTER_VALUE_PORT: u32 = 0x0;
+const COUNTER_MODE_PORT: u32 = 0x4;
+const COUNTER_TARGET_PORT: u32 = 0x8;
+
+const TIMER_IRQS: [Irq; 3] = [Irq::Timer0, Irq::Timer1, Irq::Timer2];
+
+struct RootCounter {
+    /// Current counter value
+    value: u16,
+    /// Counter mode register
+    mode: CounterMode,
+    /// Counter target value
+    target: u16,
+    /// Whether the IRQ has fired since the last mode write (for one-shot mode)
+    has_fired: bool,
+    /// Whether a blank has released the counter from sync mode 3 since the
+    /// last mode write
+    sync_released: bool,
+}
+
+impl RootCounter {
+    fn new() -> RootCounter {
+        RootCounter {
+            value: 0,
+            mode: CounterMode::from(0x0400),
+            target: 0,
+            has_fired: false,
+            sync_released: false,
+        }
+    }
+
+    fn write_mode(&mut self, data: u32) {
+        let preserved = *self.mode & COUNTER_MODE_READ_CLEARED;
+        self.mode = CounterMode::from((data & COUNTER_MODE_WRITABLE) | preserved);
+        // writing the mode resets the counter and clears any pending IRQ
+        self.mode.set_irq_flag(false);
+        self.value = 0;
+        self.has_fired = false;
+        self.sync_released = false;
+    }
+
+    /// Advance the counter, returning whether an IRQ should be raised
+    fn increment(&mut self, ticks: u32) -> bool {
+        let mut irq = false;
+        for _ in 0..ticks {
+            let value = self.value as u32 + 1;
+            let mut event = false;
+            if value == self.target as u32 {
+                self.mode.set_reached_target();
+                event |= self.mode.is_irq_on_target();
+            }
+            // overflow happens as the counter wraps from FFFFh back to 0
+            if value == 0x1_0000 {
+                self.mode.set_reached_overflow();
+                event |= self.mode.is_irq_on_overflow();
+            }
+            // the hardware resets _as_ the counter reaches the target, so
+            // the counter never actually holds the target value
+            self.value = if self.mode.is_reset_on_target() && value >= self.target as u32 {
+                0
+            } else {
+                (value & 0xFFFF) as u16
+            };
+            if event {
+                irq |= self.trigger_irq();
+            }
+        }
+        irq
+    }
+
+    fn trigger_irq(&mut self) -> bool {
+        if self.has_fired && !self.mode.is_irq_repeat() {
+            return false;
+        }
+        self.has_fired = true;
+        if self.mode.is_irq_toggle() {
+            let requested = !self.mode.is_irq_requested();
+            self.mode.set_irq_flag(requested);
+            requested
+        } else {
+            // in pulse mode the flag only drops for a few cycles, so software
+            // never sees it change
+            true
+        }
+    }
+}
+
+/// The root counter controller
+///
+/// Counters 0 and 1 can be synchronized to the GPU's horizontal and vertical
+/// blanking, respectively, and counter 2 can be stopped entirely.
+pub struct Timers {
+    counters: [RootCounter; 3],
+    /// Whether the GPU is currently in horizontal blanking
+    in_hblank: bool,
+    /// Whether the GPU is currently in vertical blanking
+    in_vblank: bool,
+    /// System clock cycles that haven't been counted by the div-8 source yet
+    div8_remainder: u32,
+}
+
+impl Timers {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Timers {
+        Timers {
+            counters: [RootCounter::new(), RootCounter::new(), RootCounter::new()],
+            in_hblank: false,
+            in_vblank: false,
+            div8_remainder: 0,
+        }
+    }
+
+    /// Advance the counters clocked by the system clock
+    pub fn tick(&mut self, cycles: u32, intctrl: &mut InterruptController) {
+        self.div8_remainder += cycles;
+        let div8_ticks = self.div8_remainder / 8;
+        self.div8_remainder %= 8;
+        for idx in 0..3 {
+            let ticks = match self.counters[idx].mode.get_clock_source(idx) {
+                ClockSource::SystemClock => cycles,
+                ClockSource::SystemClockDiv8 => div8_ticks,
+                _ => continue,
+            };
+            self.advance(idx, ticks, intctrl);
+        }
+    }
+
+    /// Advance counter 0 if it is clocked by the GPU dot clock
+    pub fn tick_dotclock(&mut self, dots: u32, intctrl: &mut InterruptController) {
+        if self.counters[0].mode.get_clock_source(0) == ClockSource::DotClock {
+            self.advance(0, dots, intctrl);
+        }
+    }
+
+    /// Update the horizontal blanking state from the GPU
+    pub fn set_hblank(&mut self, active: bool, intctrl: &mut InterruptController) {
+        if active == self.in_hblank {
+            return;
+        }
+        self.in_hblank = active;
+        if active {
+            self.blank_started(0);
+            if self.counters[1].mode.get_clock_source(1) == ClockSource::HBlank {
+                self.advance(1, 1, intctrl);
+            }
+        }
+    }
+
+    /// Update the vertical blanking state from the GPU
+    pub fn set_vblank(&mut self, active: bool) {
+        if active == self.in_vblank {
+            return;
+        }
+        self.in_vblank = active;
+        if active {
+            self.blank_started(1);
+        }
+    }
+
+    fn advance(&mut self, idx: usize, ticks: u32, intctrl: &mut InterruptController) {
+        if ticks == 0 || self.is_paused(idx) {
+            return;
+        }
+        if self.counters[idx].increment(ticks) {
+            intctrl.request(TIMER_IRQS[idx]);
+        }
+    }
+
+    /// Return whether the given counter is paused by its sync mode
+    fn is_paused(&self, idx: usize) -> bool {
+        let counter = &self.counters[idx];
+        let mode = &counter.mode;
+        if !mode.is_sync_enabled() || counter.sync_released {
+            return false;
+        }
+        let in_blank = match idx {
+            0 => self.in_hblank,
+            1 => self.in_vblank,
+            // counter 2 sync modes 0 and 3 stop the counter entirely
+            _ => return mode.get_sync_mode() == 0 || mode.get_sync_mode() == 3,
+        };
+        match mode.get_sync_mode() {
+            // pause during blanking
+            0 => in_blank,
+            // reset at blanking, never pause
+            1 => false,
+            // reset at blanking, pause outside of blanking
+            2 => !in_blank,
+            // pause until the first blank, then switch to free-run
+            _ => true,
+        }
+    }
+
+    /// Apply the sync mode effects of a blanking period starting
+    fn blank_started(&mut self, idx: usize) {
+        let counter = &mut self.counters[idx];
+        if !counter.mode.is_sync_enabled() {
+            return;
+        }
+        match counter.mode.get_sync_mode() {
+            1 | 2 => counter.value = 0,
+            // the mode register keeps its sync bits, the counter just stops
+            // honoring them until the next mode write
+            3 => counter.sync_released = true,
+            _ => {}
+        }
+    }
+
+    fn read_reg(&mut self, addr: u32, is_peek: bool) -> u32 {
+        let idx = (addr >> 4) as usize;
+        if idx > 2 {
+            debug!(target: "timers", "Read from unmapped timer register ${:02X}", addr);
+            return 0;
+        }
+        let counter = &mut self.counters[idx];
+        match addr & 0xC {
+            COUNTER_VALUE_PORT => counter.value as u32,
+            COUNTER_MODE_PORT => {
+                let mode = *counter.mode;
+                if !is_peek {
+                    counter.mode.clear_reached_flags();
+                }
+                mode
+            }
+            COUNTER_TARGET_PORT => counter.target as u32,
+            _ => {
+                debug!(target: "timers", "Read from unused timer register ${:02X}", addr);
+                0
+            }
+        }
+    }
+}
+
+impl BusDevice for Timers {
+    fn read<T: SizedData>(&mut self, addr: u32) -> T {
+        T::from_u32(self.read_reg(addr & !0x3, false) >> ((addr & 0x3) * 8))
+    }
+
+    fn peek<T: SizedData>(&self, addr: u32) -> Option<T> {
+        let idx = (addr >> 4) as usize;
+        if idx > 2 {
+            return None;
+        }
+        let counter = &self.counters[idx];
+        let data = match addr & 0xC {
+            COUNTER_VALUE_PORT => counter.value as u32,
+            COUNTER_MODE_PORT => *counter.mode,
+            COUNTER_TARGET_PORT => counter.target as u32,
+            _ => return None,
+        };
+        Some(T::from_u32(data >> ((addr & 0x3) * 8)))
+    }
+
+    fn write<T: SizedData>(&mut self, addr: u32, data: T) {
+        let idx = (addr >> 4) as usize;
+        if idx > 2 {
+            debug!(target: "timers", "Write to unmapped timer register ${:02X}", addr);
+            return;
+        }
+        let data = data.to_u32();
+        let counter = &mut self.counters[idx];
+        match addr & 0xC {
+            COUNTER_VALUE_PORT => counter.value = data as u16,
+            COUNTER_MODE_PORT => counter.write_mode(data),
+            COUNTER_TARGET_PORT => counter.target = data as u16,
+            _ => {
+                debug!(target: "timers", "Write to unused timer register ${:02X}", addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const IRQ_ON_TARGET: u32 = 0x0010;
+    const RESET_ON_TARGET: u32 = 0x0008;
+    const IRQ_ON_OVERFLOW: u32 = 0x0020;
+    const IRQ_REPEAT: u32 = 0x0040;
+    const REACHED_OVERFLOW: u16 = 0x1000;
+
+    fn timer2_irq_raised(intctrl: &mut InterruptController) -> bool {
+        let raised = intctrl.read::<u32>(0) & (1 << (Irq::Timer2 as u32)) != 0;
+        intctrl.write(0, 0u32);
+        raised
+    }
+
+    #[test]
+    fn counts_system_clock() {
+        let mut timers = Timers::new();
+        let mut intctrl = InterruptController::new();
+        timers.tick(100, &mut intctrl);
+        assert_eq!(timers.read::<u16>(0x20), 100);
+    }
+
+    #[test]
+    fn counts_system_clock_div8() {
+        let mut timers = Timers::new();
+        let mut intctrl = InterruptController::new();
+        timers.write(0x24, 0x0200u32);
+        timers.tick(100, &mut intctrl);
+        assert_eq!(timers.read::<u16>(0x20), 12);
+    }
+
+    #[test]
+    fn fires_one_shot_irq_on_target() {
+        let mut timers = Timers::new();
+        let mut intctrl = InterruptController::new();
+        timers.write(0x28, 10u32);
+        timers.write(0x24, IRQ_ON_TARGET | RESET_ON_TARGET);
+        timers.tick(9, &mut intctrl);
+        assert!(!timer2_irq_raised(&mut intctrl));
+        timers.tick(1, &mut intctrl);
+        assert!(timer2_irq_raised(&mut intctrl));
+        assert_eq!(
+            timers.read::<u16>(0x20),
+            0,
+            "Counter should reset on target"
+        );
+        timers.tick(10, &mut intctrl);
+        assert!(!timer2_irq_raised(&mut intctrl), "One-shot IRQ fired twice");
+    }
+
+    #[test]
+    fn fires_repeating_irq_on_target() {
+        let mut timers = Timers::new();
+        let mut intctrl = InterruptController::new();
+        timers.write(0x28, 10u32);
+        timers.write(0x24, IRQ_ON_TARGET | RESET_ON_TARGET | IRQ_REPEAT);
+        timers.tick(10, &mut intctrl);
+        assert!(timer2_irq_raised(&mut intctrl));
+        timers.tick(10, &mut intctrl);
+        assert!(timer2_irq_raised(&mut intctrl));
+    }
+
+    #[test]
+    fn clears_reached_flags_on_read() {
+        let mut timers = Timers::new();
+        let mut intctrl = InterruptController::new();
+        timers.write(0x28, 5u32);
+        timers.write(0x24, 0u32);
+        timers.tick(5, &mut intctrl);
+        assert_eq!(timers.read::<u32>(0x24) & 0x0800, 0x0800);
+        assert_eq!(timers.read::<u32>(0x24) & 0x0800, 0);
+    }
+
+    #[test]
+    fn resets_on_hblank_in_sync_mode_1() {
+        let mut timers = Timers::new();
+        let mut intctrl = InterruptController::new();
+        // sync enabled, mode 1 (reset counter at hblank)
+        timers.write(0x04, 0x0003u32);
+        timers.tick(50, &mut intctrl);
+        assert_eq!(timers.read::<u16>(0x00), 50);
+        timers.set_hblank(true, &mut intctrl);
+        assert_eq!(timers.read::<u16>(0x00), 0);
+    }
+
+    #[test]
+    fn free_runs_after_first_hblank_in_sync_mode_3() {
+        let mut timers = Timers::new();
+        let mut intctrl = InterruptController::new();
+        // sync enabled, mode 3 (pause until hblank, then free-run)
+        timers.write(0x04, 0x0007u32);
+        timers.tick(50, &mut intctrl);
+        assert_eq!(timers.read::<u16>(0x00), 0);
+        timers.set_hblank(true, &mut intctrl);
+        timers.set_hblank(false, &mut intctrl);
+        timers.tick(50, &mut intctrl);
+        assert_eq!(timers.read::<u16>(0x00), 50);
+        assert_eq!(
+            timers.read::<u16>(0x04) & 0x0007,
+            0x0007,
+            "Sync bits should read back as written"
+        );
+        // rewriting the mode arms the sync again
+        timers.write(0x04, 0x0007u32);
+        timers.tick(50, &mut intctrl);
+        assert_eq!(timers.read::<u16>(0x00), 0);
+    }
+
+    #[test]
+    fn counts_hblanks() {
+        let mut timers = Timers::new();
+        let mut intctrl = InterruptController::new();
+        timers.write(0x14, 0x0100u32);
+        for _ in 0..3 {
+            timers.set_hblank(true, &mut intctrl);
+            timers.set_hblank(false, &mut intctrl);
+        }
+        timers.tick(100, &mut intctrl);
+        assert_eq!(timers.read::<u16>(0x10), 3);
+    }
+
+    #[test]
+    fn fires_overflow_irq_on_wrap() {
+        let mut timers = Timers::new();
+        let mut intctrl = InterruptController::new();
+        timers.write(0x24, IRQ_ON_OVERFLOW);
+        timers.write(0x20, 0xFFFEu32);
+        timers.tick(1, &mut intctrl);
+        assert_eq!(timers.read::<u16>(0x20), 0xFFFF);
+        assert!(
+            !timer2_irq_raised(&mut intctrl),
+            "IRQ fired before the wrap"
+        );
+        assert_eq!(timers.read::<u16>(0x24) & REACHED_OVERFLOW, 0);
+        timers.tick(1, &mut intctrl);
+        assert_eq!(timers.read::<u16>(0x20), 0);
+        assert!(timer2_irq_raised(&mut intctrl));
+        assert_ne!(timers.read::<u16>(0x24) & REACHED_OVERFLOW, 0);
+    }
+}
@@ -1,42 +1,172 @@
+// The codebase prefers explicit returns
+#![allow(clippy::needless_return)]
+
 extern crate log;
 extern crate pretty_env_logger;
 
+mod config;
 pub mod devices;
+mod memcard_cli;
 pub mod utils;
+mod window;
 
+use crate::config::{HeadlessOptions, Limits, Mode, Options};
+use crate::devices::cdrom::disc;
+use crate::devices::gpu::{VideoMode, WithGpu};
 use crate::devices::motherboard::Motherboard;
-use log::info;
-use std::fs::File;
-use std::io::prelude::*;
+use crate::devices::sio::MemoryCard;
+use crate::utils::audio::WavWriter;
+use crate::utils::bios::{self, BiosId};
+use crate::utils::exe::PsxExe;
+use crate::utils::frame_dump;
+use log::{info, warn, LevelFilter};
 use std::io::Result;
 use std::path::Path;
 
+/// The size of every retail BIOS, 512KiB
+const BIOS_SIZE: usize = 512 * 1024;
+
 fn main() {
-    pretty_env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("memcard") {
+        init_logger(None);
+        if let Err(msg) = memcard_cli::run(&args[1..]) {
+            eprintln!("{}", msg);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", config::USAGE);
+        return;
+    }
+
+    let opts = match config::parse_args(args.into_iter()) {
+        Ok(opts) => opts,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            eprintln!("{}", config::USAGE);
+            std::process::exit(2);
+        }
+    };
+    init_logger(opts.log.as_deref());
+
+    if let Err(msg) = run(&opts) {
+        eprintln!("{}", msg);
+        std::process::exit(1);
+    }
+}
 
-    let bios = read_bios().expect("Could not read BIOS");
+/// Log warnings and errors, and anything else RUST_LOG or the options ask for
+fn init_logger(filters: Option<&str>) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    builder.filter_level(LevelFilter::Warn);
+    if let Ok(env_filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&env_filters);
+    }
+    if let Some(filters) = filters {
+        builder.parse_filters(filters);
+    }
+    builder.init();
+}
 
+/// Set up the console from the options, and run it
+fn run(opts: &Options) -> std::result::Result<(), String> {
+    let bios = read_bios(&opts.bios).map_err(open_error("BIOS", &opts.bios))?;
+    let bios_id = bios::identify(&bios);
+    match &bios_id {
+        BiosId::Known(info) => info!(target: "main", "BIOS is {}", info),
+        BiosId::Patched(info) => {
+            warn!(target: "main", "BIOS looks like a modified {}, it may not work", info)
+        }
+        BiosId::Unknown(Some(version)) => {
+            warn!(target: "main", "BIOS is an unknown dump of version {}", version)
+        }
+        BiosId::Unknown(None) => warn!(target: "main", "BIOS is not a known dump"),
+    }
+    // the region on the command line wins, so region-locked discs can be
+    // tested against any BIOS
+    let region = opts.region.or_else(|| bios_id.get_region());
     let mut psx = Motherboard::new(bios);
+    psx.set_region(region);
+
+    if let Some(path) = &opts.disc {
+        info!(target: "main", "Loading disc from {:?}", path);
+        let disc = disc::open_disc(path).map_err(open_error("disc image", path))?;
+        psx.insert_disc(disc);
+    }
+
+    if let Some(path) = &opts.exe {
+        info!(target: "main", "Loading EXE from {:?}", path);
+        let exe = PsxExe::open(path).map_err(open_error("EXE", path))?;
+        psx.sideload_exe(exe);
+    }
+
+    for (port, path) in opts.memory_cards.iter().enumerate() {
+        info!(target: "main", "Loading memory card {} from {:?}", port + 1, path);
+        let card = MemoryCard::open(path).map_err(open_error("memory card", path))?;
+        psx.set_memory_card(port, Some(Box::new(card)));
+    }
 
     info!(target: "main", "Starting emulation...");
 
-    loop {
-        psx.tick();
+    match &opts.mode {
+        Mode::Headless(headless) => run_headless(&mut psx, headless, &opts.limits)
+            .map_err(|err| format!("Could not write output: {}", err))?,
+        Mode::Windowed => {
+            let video_mode = region.map_or(VideoMode::Ntsc, |region| region.get_video_mode());
+            window::run_windowed(&mut psx, &opts.limits, video_mode)?
+        }
     }
+    return psx
+        .flush_memory_cards()
+        .map_err(|err| format!("Could not save memory cards: {}", err));
 }
 
-fn read_bios() -> Result<Vec<u8>> {
-    const BIOS_PATH: &str = "./bios/SCPH1001.bin";
-    info!(target: "main", "Loading bios from pwd: {:?}", BIOS_PATH);
-
-    let path = Path::new(&BIOS_PATH);
-    let mut file =
-        File::open(path).expect("BIOS not found in working directory: ./bios/SCPH1001.bin");
-    let mut buf = vec![0u8; 524_288]; // 524,288 = number of bytes in 512kib
+/// Describe an error opening one of the files the options name
+fn open_error(what: &'static str, path: &Path) -> impl FnOnce(std::io::Error) -> String {
+    let path = path.display().to_string();
+    return move |err| format!("Could not open {} {}: {}", what, path, err);
+}
 
-    file.read_exact(&mut buf[..])?;
+/// Run until a limit is reached, writing each frame to disk
+fn run_headless(psx: &mut Motherboard, opts: &HeadlessOptions, limits: &Limits) -> Result<()> {
+    std::fs::create_dir_all(&opts.out_dir)?;
+    if let Some(path) = &opts.wav {
+        psx.set_audio_sink(Box::new(WavWriter::create(path)?));
+    }
+    let mut frames = 0;
+    let mut cycles = 0;
+    while !limits.is_reached(frames, cycles) {
+        psx.tick();
+        cycles += 1;
+        if !psx.take_frame_completed() {
+            continue;
+        }
+        frames += 1;
+        let frame = psx.gpu().get_display_frame();
+        let name = format!("frame_{:05}.{}", frames, opts.format.extension());
+        frame_dump::write_frame(&opts.out_dir.join(name), &frame, opts.format)?;
+        if opts.dump_vram {
+            let name = format!("vram_{:05}.bin", frames);
+            frame_dump::write_vram_dump(&opts.out_dir.join(name), psx.gpu().vram())?;
+        }
+    }
+    psx.audio_sink_mut().flush()?;
+    info!(target: "main", "Wrote {} frames to {:?}", frames, opts.out_dir);
+    return Ok(());
+}
 
+fn read_bios(path: &Path) -> Result<Vec<u8>> {
+    info!(target: "main", "Loading BIOS from {:?}", path);
+    let buf = std::fs::read(path)?;
+    if buf.len() != BIOS_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("BIOS images are 512KiB, but this is {} bytes", buf.len()),
+        ));
+    }
     info!(target: "main", "BIOS loaded");
-
-    Result::Ok(buf)
+    return Ok(buf);
 }
@@ -0,0 +1,99 @@
+//! The `psx memcard` subcommand, for managing memory card images offline
+
+use crate::utils::memcard::{MemoryCardImage, SaveFile, SaveFormat, SAVE_BLOCK_COUNT};
+use std::path::Path;
+
+pub const USAGE: &str = "\
+Usage: psx memcard list CARD
+       psx memcard export CARD SAVE FILE
+       psx memcard import CARD FILE
+       psx memcard delete CARD SAVE
+       psx memcard format CARD
+
+SAVE is either a save's name or the block it starts at (1-15), with names
+matched first. Single saves are read and written as .mcs, .psv, or raw blocks,
+depending on the extension.";
+
+/// Run a memcard command, given the arguments after "memcard"
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    return match args.as_slice() {
+        ["list", card] => list(Path::new(card)),
+        ["export", card, save, file] => export(Path::new(card), save, Path::new(file)),
+        ["import", card, file] => import(Path::new(card), Path::new(file)),
+        ["delete", card, save] => delete(Path::new(card), save),
+        ["format", card] => MemoryCardImage::new()
+            .save(Path::new(card))
+            .map_err(|err| format!("Could not write {}: {}", card, err)),
+        _ => Err(USAGE.to_string()),
+    };
+}
+
+fn list(path: &Path) -> Result<(), String> {
+    let card = open(path)?;
+    let saves = card.saves().map_err(|err| err.to_string())?;
+    println!("Block  Size  Region   Name                  Title");
+    for save in saves {
+        println!(
+            "{:>5}  {:>4}  {:<7}  {:<20}  {}",
+            save.block,
+            save.blocks.len(),
+            save.get_region(),
+            save.name,
+            save.title
+        );
+    }
+    println!("{} of {} blocks free", card.free_blocks(), SAVE_BLOCK_COUNT);
+    return Ok(());
+}
+
+fn export(path: &Path, save: &str, file: &Path) -> Result<(), String> {
+    let card = open(path)?;
+    let block = find_save(&card, save)?;
+    let save = card.export(block).map_err(|err| err.to_string())?;
+    std::fs::write(file, save.encode(SaveFormat::from_path(file)))
+        .map_err(|err| format!("Could not write {}: {}", file.display(), err))?;
+    println!("Exported {} to {}", save.name, file.display());
+    return Ok(());
+}
+
+fn import(path: &Path, file: &Path) -> Result<(), String> {
+    let mut card = open(path)?;
+    let save = SaveFile::open(file)
+        .map_err(|err| format!("Could not read {}: {}", file.display(), err))?;
+    let block = card.import(&save).map_err(|err| err.to_string())?;
+    write(&card, path)?;
+    println!("Imported {} at block {}", save.name, block);
+    return Ok(());
+}
+
+fn delete(path: &Path, save: &str) -> Result<(), String> {
+    let mut card = open(path)?;
+    let block = find_save(&card, save)?;
+    card.delete(block).map_err(|err| err.to_string())?;
+    write(&card, path)?;
+    println!("Deleted the save at block {}", block);
+    return Ok(());
+}
+
+fn open(path: &Path) -> Result<MemoryCardImage, String> {
+    return MemoryCardImage::open(path)
+        .map_err(|err| format!("Could not read {}: {}", path.display(), err));
+}
+
+fn write(card: &MemoryCardImage, path: &Path) -> Result<(), String> {
+    return card
+        .save(path)
+        .map_err(|err| format!("Could not write {}: {}", path.display(), err));
+}
+
+/// Find a save's first block, given the save's name or the block
+///
+/// Names are tried first, since a save's name may be all digits.
+fn find_save(card: &MemoryCardImage, save: &str) -> Result<usize, String> {
+    let saves = card.saves().map_err(|err| err.to_string())?;
+    if let Some(info) = saves.iter().find(|info| info.name == save) {
+        return Ok(info.block);
+    }
+    return save.parse().map_err(|_| format!("No save named {}", save));
+}
@@ -0,0 +1,286 @@
+//! Audio sinks for the SPU's output
+//!
+//! Headless runs can write audio to a WAV file, and frontends with a sound
+//! device can drain a ring buffer from their audio callback. Either can be
+//! handed to the motherboard with `set_audio_sink`.
+
+use crate::devices::spu::{AudioSink, SAMPLE_RATE};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// The size of the RIFF and format headers, up to the start of the samples
+const WAV_HEADER_SIZE: u32 = 44;
+
+/// Writes 16-bit stereo samples to a WAV file
+///
+/// The sizes in the header are filled in on each flush, and when the writer
+/// is dropped, so a file cut short by a crash is still mostly readable.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    /// The number of bytes of samples written
+    data_size: u32,
+    /// The first error hit while writing, reported on the next flush
+    error: Option<io::Error>,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create(path: &Path) -> io::Result<WavWriter<BufWriter<File>>> {
+        return WavWriter::new(BufWriter::new(File::create(path)?));
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W) -> io::Result<WavWriter<W>> {
+        write_wav_header(&mut out, 0)?;
+        return Ok(WavWriter {
+            out,
+            data_size: 0,
+            error: None,
+        });
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavWriter<W> {
+    fn push_sample(&mut self, sample: [i16; 2]) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut bytes = [0u8; 4];
+        bytes[..2].copy_from_slice(&sample[0].to_le_bytes());
+        bytes[2..].copy_from_slice(&sample[1].to_le_bytes());
+        match self.out.write_all(&bytes) {
+            Ok(()) => self.data_size += 4,
+            Err(err) => self.error = Some(err),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.out.seek(SeekFrom::Start(0))?;
+        write_wav_header(&mut self.out, self.data_size)?;
+        self.out.seek(SeekFrom::End(0))?;
+        return self.out.flush();
+    }
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+    fn drop(&mut self) {
+        let _ = AudioSink::flush(self);
+    }
+}
+
+fn write_wav_header<W: Write>(out: &mut W, data_size: u32) -> io::Result<()> {
+    const CHANNELS: u16 = 2;
+    const BITS_PER_SAMPLE: u16 = 16;
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // uncompressed PCM
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&CHANNELS.to_le_bytes())?;
+    out.write_all(&SAMPLE_RATE.to_le_bytes())?;
+    out.write_all(&(SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_size.to_le_bytes())?;
+    return Ok(());
+}
+
+/// Read the samples from a 16-bit stereo WAV file, such as a golden file to
+/// compare output against
+pub fn read_wav(path: &Path) -> io::Result<Vec<[i16; 2]>> {
+    return decode_wav(&std::fs::read(path)?);
+}
+
+fn decode_wav(bytes: &[u8]) -> io::Result<Vec<[i16; 2]>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(invalid("Not a WAV file"));
+    }
+    let mut offset = 12;
+    let mut is_format_supported = false;
+    while offset + 8 <= bytes.len() {
+        let id = &bytes[offset..offset + 4];
+        let size = &bytes[offset + 4..offset + 8];
+        let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        let body = bytes
+            .get(offset + 8..offset + 8 + size)
+            .ok_or_else(|| invalid("Truncated WAV chunk"))?;
+        match id {
+            b"fmt " if body.len() >= 16 => {
+                let field = |at: usize| u16::from_le_bytes([body[at], body[at + 1]]);
+                // PCM, 2 channels, 16 bits per sample
+                is_format_supported = field(0) == 1 && field(2) == 2 && field(14) == 16;
+            }
+            b"data" if is_format_supported => {
+                return Ok(body
+                    .chunks_exact(4)
+                    .map(|sample| {
+                        [
+                            i16::from_le_bytes([sample[0], sample[1]]),
+                            i16::from_le_bytes([sample[2], sample[3]]),
+                        ]
+                    })
+                    .collect());
+            }
+            b"data" => return Err(invalid("Only 16-bit stereo PCM is supported")),
+            _ => (),
+        }
+        // chunks are padded to an even size
+        offset += 8 + size + (size & 1);
+    }
+    return Err(invalid("WAV file has no data"));
+}
+
+/// The most the ring buffer will stretch or squeeze audio by, as a fraction
+/// of the output rate, to keep itself half full
+const MAX_RATE_DELTA: f64 = 0.005;
+
+/// A buffer that resamples the SPU's output to a frontend's output rate
+///
+/// Emulation and the sound device never run at quite the same speed, so the
+/// buffer would slowly fill up or run dry. To avoid that, the resampling rate
+/// is nudged by up to half a percent depending on how full the buffer is,
+/// which is too little to hear as a change in pitch.
+///
+/// Clones share the same buffer, so a frontend can keep one to drain from
+/// its audio callback while the motherboard pushes to another.
+#[derive(Clone)]
+pub struct AudioRingBuffer {
+    state: Arc<Mutex<RingState>>,
+}
+
+struct RingState {
+    samples: VecDeque<[i16; 2]>,
+    capacity: usize,
+    /// SPU samples per output sample, before any adjustment
+    ratio: f64,
+    /// Where the next output sample falls between the last two SPU samples
+    phase: f64,
+    previous: [i16; 2],
+}
+
+impl AudioRingBuffer {
+    /// Create a buffer for the given output rate, holding up to `capacity`
+    /// samples at that rate
+    pub fn new(output_rate: u32, capacity: usize) -> AudioRingBuffer {
+        let state = RingState {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+            ratio: SAMPLE_RATE as f64 / output_rate as f64,
+            phase: 0.0,
+            previous: [0; 2],
+        };
+        return AudioRingBuffer {
+            state: Arc::new(Mutex::new(state)),
+        };
+    }
+
+    /// Move samples into `out`, returning how many there were
+    ///
+    /// If the buffer runs dry, the rest of `out` is left untouched.
+    pub fn drain(&self, out: &mut [[i16; 2]]) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let count = out.len().min(state.samples.len());
+        for (slot, sample) in out.iter_mut().zip(state.samples.drain(..count)) {
+            *slot = sample;
+        }
+        return count;
+    }
+
+    /// The number of samples waiting to be drained
+    pub fn len(&self) -> usize {
+        return self.state.lock().unwrap().samples.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+}
+
+impl AudioSink for AudioRingBuffer {
+    fn push_sample(&mut self, sample: [i16; 2]) {
+        let mut state = self.state.lock().unwrap();
+        let fill = state.samples.len() as f64 / state.capacity as f64;
+        // produce more samples when running low, and fewer when nearly full
+        let step = state.ratio / (1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill));
+        while state.phase < 1.0 {
+            let phase = state.phase;
+            let previous = state.previous;
+            let lerp = |channel: usize| {
+                let from = previous[channel] as f64;
+                (from + (sample[channel] as f64 - from) * phase).round() as i16
+            };
+            let out = [lerp(0), lerp(1)];
+            if state.samples.len() == state.capacity {
+                state.samples.pop_front();
+            }
+            state.samples.push_back(out);
+            state.phase += step;
+        }
+        state.phase -= 1.0;
+        state.previous = sample;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn writes_wav_files() {
+        let mut buf = Cursor::new(vec![]);
+        {
+            let mut writer = WavWriter::new(&mut buf).unwrap();
+            writer.push_sample([1, -1]);
+            writer.push_sample([0x1234, -0x1234]);
+            writer.flush().unwrap();
+        }
+        let bytes = buf.into_inner();
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(bytes[4..8], 44u32.to_le_bytes());
+        assert_eq!(bytes[40..44], 8u32.to_le_bytes());
+        let samples = decode_wav(&bytes).unwrap();
+        assert_eq!(samples, vec![[1, -1], [0x1234, -0x1234]]);
+    }
+
+    #[test]
+    fn ring_buffer_resamples() {
+        let mut ring = AudioRingBuffer::new(22050, 4096);
+        let drain = ring.clone();
+        for i in 0..2000 {
+            ring.push_sample([i as i16, 0]);
+        }
+        // about half as many samples come out, a bit more while nearly empty
+        let count = drain.len();
+        assert!((1000..1010).contains(&count), "{}", count);
+        let mut out = vec![[0, 0]; 4];
+        assert_eq!(drain.drain(&mut out), 4);
+        // a ramp going in comes out twice as steep
+        assert_eq!(out[3][0] - out[2][0], 2);
+    }
+
+    #[test]
+    fn ring_buffer_slows_down_when_full() {
+        let mut ring = AudioRingBuffer::new(SAMPLE_RATE, 10000);
+        for _ in 0..9000 {
+            ring.push_sample([0, 0]);
+        }
+        let before = ring.len();
+        for _ in 0..1000 {
+            ring.push_sample([0, 0]);
+        }
+        // past half full, fewer samples come out than go in
+        let produced = ring.len() - before;
+        assert!((990..1000).contains(&produced), "{}", produced);
+    }
+}
@@ -0,0 +1,181 @@
+//! Identifying BIOS dumps
+//!
+//! Known dumps are matched by their CRC32. Anything else is probably a bad or
+//! patched dump, like the fast-boot patches some tools apply, so the ROM's
+//! version string is used to work out what it was and where it's from.
+
+use crate::devices::region::Region;
+use std::fmt;
+
+/// Where the version string is, near the end of the ROM, like
+/// "System ROM Version 4.1 12/16/97 A". The first BIOS doesn't have one.
+const VERSION_SEARCH_START: usize = 0x7FF00;
+const VERSION_PREFIX: &[u8] = b"System ROM Version ";
+
+/// A known BIOS revision
+#[derive(Debug, Eq, PartialEq)]
+pub struct BiosInfo {
+    /// The first console model this BIOS shipped in
+    pub model: &'static str,
+    /// The version, date and region, as in the ROM's version string
+    pub version: &'static str,
+    pub region: Region,
+    /// The CRC32 of a good dump
+    pub crc32: u32,
+}
+
+impl fmt::Display for BiosInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "{} (v{}, {})", self.model, self.version, self.region);
+    }
+}
+
+macro_rules! bios {
+    ($model: expr, $version: expr, $region: ident, $crc32: expr) => {
+        BiosInfo {
+            model: $model,
+            version: $version,
+            region: Region::$region,
+            crc32: $crc32,
+        }
+    };
+}
+
+pub static KNOWN_BIOSES: [BiosInfo; 22] = [
+    bios!("SCPH-1000", "1.0 09/22/94 J", Japan, 0x3B60_1FC8),
+    bios!("SCPH-3000", "1.1 01/22/95 J", Japan, 0x3539_DEF6),
+    bios!("SCPH-1001", "2.0 05/07/95 A", NorthAmerica, 0x5584_7D8C),
+    bios!("SCPH-1002", "2.0 05/10/95 E", Europe, 0x9BB8_7C4B),
+    bios!("SCPH-3500", "2.1 07/17/95 J", Japan, 0xBC19_0209),
+    bios!("SCPH-1001", "2.1 07/17/95 A", NorthAmerica, 0xAFF0_0F2F),
+    bios!("SCPH-1002", "2.1 07/17/95 E", Europe, 0x86C3_0531),
+    bios!("SCPH-5000", "2.2 12/04/95 J", Japan, 0x24FC_7E17),
+    bios!("SCPH-1001", "2.2 12/04/95 A", NorthAmerica, 0x3715_7331),
+    bios!("SCPH-1002", "2.2 12/04/95 E", Europe, 0x1E26_792F),
+    bios!("SCPH-5500", "3.0 09/09/96 J", Japan, 0xFF3E_EB8C),
+    bios!("SCPH-5501", "3.0 11/18/96 A", NorthAmerica, 0x8D8C_B7E4),
+    bios!("SCPH-5502", "3.0 01/06/97 E", Europe, 0xD786_F0B9),
+    bios!("SCPH-7000", "4.0 08/18/97 J", Japan, 0xEC54_1CD0),
+    // a Japanese model, despite the "A" in its version string
+    bios!("SCPH-7000W", "4.1 11/14/97 A", Japan, 0xB7C4_3DAD),
+    bios!("SCPH-7001", "4.1 12/16/97 A", NorthAmerica, 0x5022_24B6),
+    bios!("SCPH-7002", "4.1 12/16/97 E", Europe, 0x3181_78BF),
+    bios!("SCPH-100", "4.3 03/11/00 J", Japan, 0xF2AF_798B),
+    bios!("SCPH-101", "4.4 03/24/00 A", NorthAmerica, 0x6A0E_22A0),
+    bios!("SCPH-102", "4.4 03/24/00 E", Europe, 0x0BAD_7EA9),
+    bios!("SCPH-101", "4.5 05/25/00 A", NorthAmerica, 0x171B_DCEC),
+    bios!("SCPH-102", "4.5 05/25/00 E", Europe, 0x76B8_80E5),
+];
+
+/// What a BIOS image was identified as
+#[derive(Debug, Eq, PartialEq)]
+pub enum BiosId {
+    /// A good dump of a known BIOS
+    Known(&'static BiosInfo),
+    /// A known BIOS, going by its version string, that's been modified
+    Patched(&'static BiosInfo),
+    /// A BIOS that isn't in the table, with the version string if it has one
+    Unknown(Option<String>),
+}
+
+impl BiosId {
+    /// The region the BIOS is for, if it could be worked out
+    pub fn get_region(&self) -> Option<Region> {
+        return match self {
+            BiosId::Known(info) | BiosId::Patched(info) => Some(info.region),
+            BiosId::Unknown(version) => match version.as_deref()?.chars().last()? {
+                'J' => Some(Region::Japan),
+                'A' => Some(Region::NorthAmerica),
+                'E' => Some(Region::Europe),
+                _ => None,
+            },
+        };
+    }
+}
+
+pub fn identify(bios: &[u8]) -> BiosId {
+    let crc32 = crc32fast::hash(bios);
+    if let Some(info) = KNOWN_BIOSES.iter().find(|info| info.crc32 == crc32) {
+        return BiosId::Known(info);
+    }
+    let version = read_version(bios);
+    let known = version
+        .as_deref()
+        .and_then(|version| KNOWN_BIOSES.iter().find(|info| info.version == version));
+    return match known {
+        Some(info) => BiosId::Patched(info),
+        None => BiosId::Unknown(version),
+    };
+}
+
+/// Find the version string, without the "System ROM Version" prefix
+fn read_version(bios: &[u8]) -> Option<String> {
+    let tail = bios.get(VERSION_SEARCH_START..)?;
+    let start = tail
+        .windows(VERSION_PREFIX.len())
+        .position(|window| window == VERSION_PREFIX)?
+        + VERSION_PREFIX.len();
+    let length = tail[start..].iter().position(|&byte| byte == 0)?;
+    return Some(String::from_utf8_lossy(&tail[start..start + length]).into_owned());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BIOS_SIZE: usize = 512 * 1024;
+
+    #[test]
+    fn identifies_patched_and_unknown_dumps() {
+        let mut bios = vec![0u8; BIOS_SIZE];
+        assert_eq!(identify(&bios), BiosId::Unknown(None));
+        assert_eq!(identify(&bios).get_region(), None);
+
+        let version = b"System ROM Version 4.1 12/16/97 A\0";
+        bios[0x7FF32..0x7FF32 + version.len()].copy_from_slice(version);
+        let id = identify(&bios);
+        let info = KNOWN_BIOSES.iter().find(|info| info.crc32 == 0x5022_24B6);
+        assert_eq!(id, BiosId::Patched(info.unwrap()));
+        assert_eq!(id.get_region(), Some(Region::NorthAmerica));
+
+        let version = b"System ROM Version 9.9 01/01/01 E\0";
+        bios[0x7FF32..0x7FF32 + version.len()].copy_from_slice(version);
+        let id = identify(&bios);
+        assert_eq!(id, BiosId::Unknown(Some("9.9 01/01/01 E".to_string())));
+        assert_eq!(id.get_region(), Some(Region::Europe));
+    }
+
+    #[test]
+    fn lists_the_readme_bios() {
+        let info = KNOWN_BIOSES.iter().find(|info| info.crc32 == 0x3715_7331);
+        assert_eq!(info.map(|info| info.model), Some("SCPH-1001"));
+        assert_eq!(
+            info.unwrap().to_string(),
+            "SCPH-1001 (v2.2 12/04/95 A, NTSC-U)"
+        );
+    }
+
+    #[test]
+    fn lists_each_revision_once() {
+        for (i, info) in KNOWN_BIOSES.iter().enumerate() {
+            for other in KNOWN_BIOSES[i + 1..].iter() {
+                assert_ne!(info.crc32, other.crc32, "{}", info);
+                assert_ne!(info.version, other.version, "{}", info);
+            }
+        }
+        // later revisions of a model are told apart by their dumps
+        let versions_of = |model: &str| {
+            return KNOWN_BIOSES
+                .iter()
+                .filter(|info| info.model == model)
+                .map(|info| info.version)
+                .collect::<Vec<_>>();
+        };
+        assert_eq!(versions_of("SCPH-1001").len(), 3);
+        assert_eq!(versions_of("SCPH-7000W"), vec!["4.1 11/14/97 A"]);
+        assert_eq!(
+            versions_of("SCPH-102"),
+            vec!["4.4 03/24/00 E", "4.5 05/25/00 E"]
+        );
+    }
+}
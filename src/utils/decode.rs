@@ -166,9 +166,14 @@ fn decode_regimm_instruction(instr: Instruction) -> Mnemonic {
 }
 
 fn decode_copz_instruction(instr: Instruction) -> Mnemonic {
+    // COPz commands set bit 25, and the remaining rs bits belong to the
+    // command itself (this matters for the GTE)
+    if instr.rs() & 0b10000 != 0 {
+        return Mnemonic::COPz;
+    }
     return match instr.rs() {
-        0b10000 => Mnemonic::COPz,
         0b00010 => Mnemonic::CFCz,
+        0b00110 => Mnemonic::CTCz,
         0b00000 => Mnemonic::MFCz,
         0b00100 => Mnemonic::MTCz,
         _ => panic!("Invalid COPz instruction: 0x{:08X}", *instr),
@@ -207,6 +212,16 @@ mod test {
         assert_eq!(mnemonic, Mnemonic::BLTZ);
     }
 
+    #[test]
+    fn decodes_gte_instrs() {
+        const RTPT_INSTR: u32 = 0x4A28_0030;
+        let (mnemonic, _instr) = decode_instruction(RTPT_INSTR);
+        assert_eq!(mnemonic, Mnemonic::COPz);
+        const CTC2_INSTR: u32 = 0x48C8_0000;
+        let (mnemonic, _instr) = decode_instruction(CTC2_INSTR);
+        assert_eq!(mnemonic, Mnemonic::CTCz);
+    }
+
     #[test]
     fn decodes_mtc0_instr() {
         const MTC0_INSTR: u32 = 0x408C_6000;
@@ -0,0 +1,189 @@
+//! PS-X EXE executables, for sideloading homebrew and test programs
+//!
+//! An EXE is a 2KiB header followed by the program's text, which is copied
+//! to RAM as-is:
+//!
+//! | Offset | Contents                                          |
+//! |--------|---------------------------------------------------|
+//! | 0x000  | "PS-X EXE"                                        |
+//! | 0x010  | The initial PC                                    |
+//! | 0x014  | The initial GP (R28)                              |
+//! | 0x018  | Where the text is loaded                          |
+//! | 0x01C  | The size of the text, a multiple of 2KiB          |
+//! | 0x028  | The start of the BSS, which is zero-filled        |
+//! | 0x02C  | The size of the BSS                               |
+//! | 0x030  | The initial SP and FP, if non-zero                |
+//! | 0x034  | An offset added to the initial SP and FP          |
+//! | 0x04C  | The region, like "Sony Computer Entertainment..." |
+
+use std::io;
+use std::path::Path;
+
+const HEADER_SIZE: usize = 0x800;
+const MAGIC: &[u8] = b"PS-X EXE";
+
+//#region Header fields
+const PC_OFFSET: usize = 0x10;
+const GP_OFFSET: usize = 0x14;
+const TEXT_ADDRESS_OFFSET: usize = 0x18;
+const TEXT_SIZE_OFFSET: usize = 0x1C;
+const BSS_ADDRESS_OFFSET: usize = 0x28;
+const BSS_SIZE_OFFSET: usize = 0x2C;
+const STACK_BASE_OFFSET: usize = 0x30;
+const STACK_OFFSET_OFFSET: usize = 0x34;
+const REGION_OFFSET: usize = 0x4C;
+//#endregion
+
+/// The size of main RAM, which the text and BSS must fit in
+const RAM_SIZE: u64 = 2 * 1024 * 1024;
+/// Where main RAM starts in KUSEG, KSEG0 and KSEG1
+const RAM_BASES: [u32; 3] = [0x0000_0000, 0x8000_0000, 0xA000_0000];
+
+#[derive(Debug, Clone)]
+pub struct PsxExe {
+    pub pc: u32,
+    pub gp: u32,
+    pub text_address: u32,
+    pub text: Vec<u8>,
+    pub bss_address: u32,
+    pub bss_size: u32,
+    /// The initial stack and frame pointer, or None to keep the BIOS's
+    pub stack: Option<u32>,
+    /// The region marker, which the BIOS doesn't check
+    pub region: String,
+}
+
+impl PsxExe {
+    pub fn open(path: &Path) -> io::Result<PsxExe> {
+        return PsxExe::parse(&std::fs::read(path)?);
+    }
+
+    pub fn parse(bytes: &[u8]) -> io::Result<PsxExe> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if bytes.len() < HEADER_SIZE || !bytes.starts_with(MAGIC) {
+            return Err(invalid("Not a PS-X EXE".to_string()));
+        }
+        let field = |offset: usize| {
+            u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        let text_size = field(TEXT_SIZE_OFFSET) as usize;
+        let text = bytes
+            .get(HEADER_SIZE..HEADER_SIZE + text_size)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "The EXE's text is {} bytes, but the file only has {}",
+                    text_size,
+                    bytes.len() - HEADER_SIZE
+                ))
+            })?;
+        let stack_base = field(STACK_BASE_OFFSET);
+        let stack = if stack_base != 0 {
+            Some(stack_base.wrapping_add(field(STACK_OFFSET_OFFSET)))
+        } else {
+            None
+        };
+        let text_address = field(TEXT_ADDRESS_OFFSET);
+        if !is_in_ram(text_address, text_size as u64) {
+            return Err(invalid(format!(
+                "The EXE's text at ${:08X} doesn't fit in RAM",
+                text_address
+            )));
+        }
+        let bss_address = field(BSS_ADDRESS_OFFSET);
+        let bss_size = field(BSS_SIZE_OFFSET);
+        if bss_size > 0 && !is_in_ram(bss_address, bss_size as u64) {
+            return Err(invalid(format!(
+                "The EXE's BSS at ${:08X} doesn't fit in RAM",
+                bss_address
+            )));
+        }
+        let region = &bytes[REGION_OFFSET..HEADER_SIZE];
+        let region_end = region
+            .iter()
+            .position(|&byte| byte == 0)
+            .unwrap_or(region.len());
+        return Ok(PsxExe {
+            pc: field(PC_OFFSET),
+            gp: field(GP_OFFSET),
+            text_address,
+            text: text.to_vec(),
+            bss_address,
+            bss_size,
+            stack,
+            region: String::from_utf8_lossy(&region[..region_end]).into_owned(),
+        });
+    }
+}
+
+/// Whether a range of memory is all in main RAM, through one of its mirrors
+fn is_in_ram(address: u32, size: u64) -> bool {
+    return RAM_BASES
+        .iter()
+        .any(|&base| address >= base && (address - base) as u64 + size <= RAM_SIZE);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_header() {
+        let mut bytes = vec![0u8; HEADER_SIZE + 0x800];
+        bytes[..8].copy_from_slice(MAGIC);
+        let mut set = |offset: usize, value: u32| {
+            bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes())
+        };
+        set(PC_OFFSET, 0x8001_0000);
+        set(GP_OFFSET, 0x8004_0000);
+        set(TEXT_ADDRESS_OFFSET, 0x8001_0000);
+        set(TEXT_SIZE_OFFSET, 0x800);
+        set(BSS_ADDRESS_OFFSET, 0x8001_0800);
+        set(BSS_SIZE_OFFSET, 0x100);
+        set(STACK_BASE_OFFSET, 0x801F_FF00);
+        set(STACK_OFFSET_OFFSET, 0xF0);
+        bytes[REGION_OFFSET..REGION_OFFSET + 4].copy_from_slice(b"Sony");
+        bytes[HEADER_SIZE] = 0x42;
+
+        let exe = PsxExe::parse(&bytes).unwrap();
+        assert_eq!((exe.pc, exe.gp), (0x8001_0000, 0x8004_0000));
+        assert_eq!(exe.text.len(), 0x800);
+        assert_eq!(exe.text[0], 0x42);
+        assert_eq!((exe.bss_address, exe.bss_size), (0x8001_0800, 0x100));
+        assert_eq!(exe.stack, Some(0x801F_FFF0));
+        assert_eq!(exe.region, "Sony");
+
+        // a region marker that fills the header isn't terminated
+        bytes[REGION_OFFSET..HEADER_SIZE].fill(b'S');
+        let exe = PsxExe::parse(&bytes).unwrap();
+        assert_eq!(exe.region.len(), HEADER_SIZE - REGION_OFFSET);
+
+        bytes.truncate(HEADER_SIZE + 0x400);
+        assert!(PsxExe::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_addresses_outside_ram() {
+        let parse = |fields: &[(usize, u32)]| {
+            let mut bytes = vec![0u8; HEADER_SIZE + 0x800];
+            bytes[..8].copy_from_slice(MAGIC);
+            for &(offset, value) in [(TEXT_SIZE_OFFSET, 0x800)].iter().chain(fields) {
+                bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            }
+            return PsxExe::parse(&bytes);
+        };
+        assert!(parse(&[(TEXT_ADDRESS_OFFSET, 0xA01F_F800)]).is_ok());
+        assert!(parse(&[(TEXT_ADDRESS_OFFSET, 0x801F_FC00)]).is_err());
+        assert!(parse(&[(TEXT_ADDRESS_OFFSET, 0x1F00_0000)]).is_err());
+        assert!(parse(&[(TEXT_ADDRESS_OFFSET, 0xBFC0_0000)]).is_err());
+        assert!(parse(&[(BSS_SIZE_OFFSET, 0x0030_0000)]).is_err());
+        // an empty BSS can be anywhere
+        assert!(parse(&[(BSS_ADDRESS_OFFSET, 0xFFFF_FF00)]).is_ok());
+        let bss = [(BSS_ADDRESS_OFFSET, 0xFFFF_FF00), (BSS_SIZE_OFFSET, 0x100)];
+        assert!(parse(&bss).is_err());
+    }
+}
@@ -0,0 +1,78 @@
+//! Writing frames and VRAM out to files, for headless runs
+
+use crate::devices::gpu::{Frame, Vram};
+use std::fs::File;
+use std::io::{BufWriter, Result, Write};
+use std::path::Path;
+
+/// The image formats frames can be written in
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum ImageFormat {
+    Png,
+    /// Binary PPM (P6), which needs no encoder and diffs easily
+    Ppm,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        return match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Ppm => "ppm",
+        };
+    }
+}
+
+/// Write a frame to the given path in the given format
+pub fn write_frame(path: &Path, frame: &Frame, format: ImageFormat) -> Result<()> {
+    let file = BufWriter::new(File::create(path)?);
+    return match format {
+        ImageFormat::Png => encode_png(file, frame),
+        ImageFormat::Ppm => encode_ppm(file, frame),
+    };
+}
+
+fn encode_png<W: Write>(out: W, frame: &Frame) -> Result<()> {
+    let mut encoder = png::Encoder::new(out, frame.width, frame.height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(std::io::Error::other)?;
+    writer
+        .write_image_data(&frame.pixels)
+        .map_err(std::io::Error::other)?;
+    return Ok(());
+}
+
+fn encode_ppm<W: Write>(mut out: W, frame: &Frame) -> Result<()> {
+    write!(out, "P6\n{} {}\n255\n", frame.width, frame.height)?;
+    out.write_all(&frame.pixels)?;
+    return out.flush();
+}
+
+/// Write the whole of VRAM as raw little-endian 16-bit pixels
+///
+/// The result is 1024x512 halfwords with no header, which most image tools
+/// can import as raw BGR555 data.
+pub fn write_vram_dump(path: &Path, vram: &Vram) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for pixel in vram.as_slice() {
+        out.write_all(&pixel.to_le_bytes())?;
+    }
+    return out.flush();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encodes_ppm_header() {
+        let frame = Frame {
+            width: 2,
+            height: 1,
+            pixels: vec![1, 2, 3, 4, 5, 6],
+        };
+        let mut buf = vec![];
+        encode_ppm(&mut buf, &frame).unwrap();
+        assert_eq!(buf, b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06");
+    }
+}
@@ -0,0 +1,66 @@
+//! Save icons, decoded from the frames after a save's title frame
+
+use crate::devices::sio::FRAME_SIZE;
+
+/// The width and height of a save's icon, in pixels
+pub const ICON_SIZE: usize = 16;
+/// The size of a 4bpp icon bitmap
+const ICON_BITMAP_SIZE: usize = ICON_SIZE * ICON_SIZE / 2;
+
+/// A save's icon, which can animate through up to three frames
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Icon {
+    /// 15-bit colours, where 0x0000 is transparent
+    palette: [u16; 16],
+    /// 4bpp bitmaps, with the left pixel of each pair in the low nibble
+    frames: Vec<[u8; ICON_BITMAP_SIZE]>,
+}
+
+impl Icon {
+    /// Read the icon from the start of a save's first block, where the title
+    /// frame is followed by one frame per bitmap
+    pub fn from_block(block: &[u8]) -> Option<Icon> {
+        let frame_count = match block[2] {
+            0x11 => 1,
+            0x12 => 2,
+            0x13 => 3,
+            _ => return None,
+        };
+        let mut palette = [0u16; 16];
+        for (i, color) in palette.iter_mut().enumerate() {
+            let offset = 0x60 + i * 2;
+            *color = u16::from_le_bytes([block[offset], block[offset + 1]]);
+        }
+        let frames = (1..=frame_count)
+            .map(|frame| {
+                let mut bitmap = [0u8; ICON_BITMAP_SIZE];
+                bitmap.copy_from_slice(&block[frame * FRAME_SIZE..(frame + 1) * FRAME_SIZE]);
+                bitmap
+            })
+            .collect();
+        return Some(Icon { palette, frames });
+    }
+
+    pub fn frame_count(&self) -> usize {
+        return self.frames.len();
+    }
+
+    /// Convert a frame to 8-bit RGBA, row by row
+    pub fn to_rgba(&self, frame: usize) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(ICON_SIZE * ICON_SIZE * 4);
+        for &pair in self.frames[frame].iter() {
+            for &index in &[pair & 0xF, pair >> 4] {
+                let color = self.palette[index as usize];
+                let expand = |value: u16| {
+                    let value = (value & 0x1F) as u8;
+                    (value << 3) | (value >> 2)
+                };
+                rgba.push(expand(color));
+                rgba.push(expand(color >> 5));
+                rgba.push(expand(color >> 10));
+                rgba.push(if color == 0 { 0x00 } else { 0xFF });
+            }
+        }
+        return rgba;
+    }
+}
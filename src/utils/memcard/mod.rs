@@ -0,0 +1,365 @@
+//! Memory card image management, for working with cards offline
+//!
+//! A card's first 8KiB block holds the directory: after the "MC" header frame,
+//! frames 1-15 each describe one of the 15 save blocks. A save takes one or
+//! more blocks, linked together by their directory frames:
+//!
+//! | Offset | Size | Contents                                             |
+//! |--------|------|------------------------------------------------------|
+//! | 0x00   | 4    | State: 0x51 first, 0x52 middle, 0x53 last, 0xA0 free |
+//! | 0x04   | 4    | The save's size in bytes, in its first frame         |
+//! | 0x08   | 2    | The next block, less one, or 0xFFFF at the end       |
+//! | 0x0A   | 21   | The save's name, like "BASLUS-00067DRAX00"           |
+//! | 0x7F   | 1    | The XOR of the frame's other bytes                   |
+//!
+//! Deleting a save just marks its blocks 0xA1-0xA3, so it can be undeleted
+//! until something else is written there. The first block of a save starts
+//! with a title frame: "SC", the icon's frame count, the title in Shift-JIS,
+//! and the icon's palette, followed by the icon's bitmaps.
+//!
+//! Cards whose broken sector list (frames 16-35) is in use aren't supported,
+//! since no card made in this century has needed it.
+
+mod icon;
+mod save_file;
+mod title;
+
+pub use self::icon::{Icon, ICON_SIZE};
+pub use self::save_file::{SaveFile, SaveFormat};
+
+use self::save_file::{invalid, read_name};
+use self::title::decode_title;
+use crate::devices::sio::{format_card, frame_checksum, FRAME_SIZE, MEMORY_CARD_SIZE};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+pub const BLOCK_SIZE: usize = 8 * 1024;
+/// Save blocks are numbered 1 to 15, after the directory block
+pub const SAVE_BLOCK_COUNT: usize = 15;
+
+//#region Directory frames
+const DIR_FIRST: u32 = 0x51;
+const DIR_MIDDLE: u32 = 0x52;
+const DIR_LAST: u32 = 0x53;
+const DIR_FREE: u32 = 0xA0;
+/// Added to the state of each block of a deleted save
+const DIR_DELETED: u32 = 0x50;
+const SIZE_OFFSET: usize = 0x04;
+const NEXT_OFFSET: usize = 0x08;
+const NAME_OFFSET: usize = 0x0A;
+const NAME_LENGTH: usize = 20;
+const NO_NEXT_BLOCK: u16 = 0xFFFF;
+//#endregion
+
+/// DexDrive images (.gme) have a 3904-byte header before the raw image
+const GME_HEADER_SIZE: usize = 0xF40;
+const GME_MAGIC: &[u8] = b"123-456-STD";
+
+/// A save found in a card's directory
+#[derive(Debug, Clone)]
+pub struct SaveInfo {
+    /// The save's first block, from 1 to 15
+    pub block: usize,
+    pub name: String,
+    /// The title shown in the BIOS's memory card manager
+    pub title: String,
+    /// Every block the save takes up, in order
+    pub blocks: Vec<usize>,
+    pub icon: Option<Icon>,
+}
+
+impl SaveInfo {
+    /// The region of the game the save is from, from its name
+    pub fn get_region(&self) -> &'static str {
+        return match self.name.get(..2) {
+            Some("BA") => "NTSC-U",
+            Some("BI") => "NTSC-J",
+            Some("BE") => "PAL",
+            _ => "Unknown",
+        };
+    }
+}
+
+/// A raw memory card image
+pub struct MemoryCardImage {
+    data: Vec<u8>,
+}
+
+impl MemoryCardImage {
+    /// A freshly formatted card
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> MemoryCardImage {
+        let mut data = vec![0u8; MEMORY_CARD_SIZE];
+        format_card(&mut data);
+        return MemoryCardImage { data };
+    }
+
+    /// Read a raw card image (.mcr, .mcd), or a DexDrive image (.gme)
+    pub fn open(path: &Path) -> io::Result<MemoryCardImage> {
+        let mut data = fs::read(path)?;
+        if data.len() == GME_HEADER_SIZE + MEMORY_CARD_SIZE && data.starts_with(GME_MAGIC) {
+            data.drain(..GME_HEADER_SIZE);
+        }
+        if data.len() != MEMORY_CARD_SIZE || &data[..2] != b"MC" {
+            return Err(invalid(&format!(
+                "{} isn't a memory card image",
+                path.display()
+            )));
+        }
+        return Ok(MemoryCardImage { data });
+    }
+
+    /// Write the card out as a raw image
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        return write_atomically(path, &self.data);
+    }
+
+    pub fn data(&self) -> &[u8] {
+        return &self.data;
+    }
+
+    /// Erase every save
+    pub fn format(&mut self) {
+        format_card(&mut self.data);
+    }
+
+    /// List the saves on the card, in block order
+    pub fn saves(&self) -> io::Result<Vec<SaveInfo>> {
+        let mut saves = vec![];
+        for block in 1..=SAVE_BLOCK_COUNT {
+            if self.get_state(block) != DIR_FIRST {
+                continue;
+            }
+            let first = self.get_block(block);
+            let (title, icon) = if &first[..2] == b"SC" {
+                (decode_title(&first[4..0x44]), Icon::from_block(first))
+            } else {
+                (String::new(), None)
+            };
+            saves.push(SaveInfo {
+                block,
+                name: read_name(&self.get_frame(block)[NAME_OFFSET..]),
+                title,
+                blocks: self.get_chain(block)?,
+                icon,
+            });
+        }
+        return Ok(saves);
+    }
+
+    /// The number of blocks not used by any save
+    pub fn free_blocks(&self) -> usize {
+        return (1..=SAVE_BLOCK_COUNT)
+            .filter(|&block| self.is_free(block))
+            .count();
+    }
+
+    /// Copy out the save starting at a block
+    pub fn export(&self, block: usize) -> io::Result<SaveFile> {
+        self.check_first_block(block)?;
+        let mut data = vec![];
+        for block in self.get_chain(block)? {
+            data.extend_from_slice(self.get_block(block));
+        }
+        return Ok(SaveFile {
+            name: read_name(&self.get_frame(block)[NAME_OFFSET..]),
+            data,
+        });
+    }
+
+    /// Copy a save onto the card, returning the block it starts at
+    pub fn import(&mut self, save: &SaveFile) -> io::Result<usize> {
+        if self.saves()?.iter().any(|info| info.name == save.name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("A save named {} is already on the card", save.name),
+            ));
+        }
+        let free: Vec<usize> = (1..=SAVE_BLOCK_COUNT)
+            .filter(|&block| self.is_free(block))
+            .collect();
+        let count = save.block_count();
+        if free.len() < count {
+            return Err(io::Error::other(format!(
+                "{} needs {} blocks, but only {} are free",
+                save.name,
+                count,
+                free.len()
+            )));
+        }
+        let blocks = &free[..count];
+        for (i, &block) in blocks.iter().enumerate() {
+            let next = blocks.get(i + 1).copied();
+            let frame = if i == 0 {
+                directory_frame(DIR_FIRST, save.data.len() as u32, next, &save.name)
+            } else if next.is_some() {
+                directory_frame(DIR_MIDDLE, 0, next, "")
+            } else {
+                directory_frame(DIR_LAST, 0, None, "")
+            };
+            self.get_frame_mut(block).copy_from_slice(&frame);
+            let data = &save.data[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE];
+            self.data[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE].copy_from_slice(data);
+        }
+        return Ok(blocks[0]);
+    }
+
+    /// Delete the save starting at a block, the same way the BIOS does
+    pub fn delete(&mut self, block: usize) -> io::Result<()> {
+        self.check_first_block(block)?;
+        for block in self.get_chain(block)? {
+            let state = self.get_state(block) + DIR_DELETED;
+            let frame = self.get_frame_mut(block);
+            frame[..4].copy_from_slice(&state.to_le_bytes());
+            frame[FRAME_SIZE - 1] = frame_checksum(frame);
+        }
+        return Ok(());
+    }
+
+    fn check_first_block(&self, block: usize) -> io::Result<()> {
+        if !(1..=SAVE_BLOCK_COUNT).contains(&block) || self.get_state(block) != DIR_FIRST {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No save starts at block {}", block),
+            ));
+        }
+        return Ok(());
+    }
+
+    /// Follow the links from a save's first block
+    fn get_chain(&self, first: usize) -> io::Result<Vec<usize>> {
+        let mut blocks = vec![first];
+        let mut block = first;
+        loop {
+            let frame = self.get_frame(block);
+            let next = u16::from_le_bytes([frame[NEXT_OFFSET], frame[NEXT_OFFSET + 1]]);
+            if next == NO_NEXT_BLOCK {
+                return Ok(blocks);
+            }
+            block = next as usize + 1;
+            if block > SAVE_BLOCK_COUNT || blocks.contains(&block) {
+                return Err(invalid(&format!(
+                    "The save at block {} has a broken link to block {}",
+                    first, block
+                )));
+            }
+            blocks.push(block);
+        }
+    }
+
+    fn is_free(&self, block: usize) -> bool {
+        return (self.get_state(block) & 0xF0) == DIR_FREE;
+    }
+
+    fn get_state(&self, block: usize) -> u32 {
+        let frame = self.get_frame(block);
+        return u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]);
+    }
+
+    /// The directory frame for a block
+    fn get_frame(&self, block: usize) -> &[u8] {
+        return &self.data[block * FRAME_SIZE..(block + 1) * FRAME_SIZE];
+    }
+
+    fn get_frame_mut(&mut self, block: usize) -> &mut [u8] {
+        return &mut self.data[block * FRAME_SIZE..(block + 1) * FRAME_SIZE];
+    }
+
+    fn get_block(&self, block: usize) -> &[u8] {
+        return &self.data[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE];
+    }
+}
+
+/// Build a directory frame
+fn directory_frame(state: u32, size: u32, next: Option<usize>, name: &str) -> [u8; FRAME_SIZE] {
+    let mut frame = [0u8; FRAME_SIZE];
+    frame[..4].copy_from_slice(&state.to_le_bytes());
+    frame[SIZE_OFFSET..SIZE_OFFSET + 4].copy_from_slice(&size.to_le_bytes());
+    let next = next.map(|block| block as u16 - 1).unwrap_or(NO_NEXT_BLOCK);
+    frame[NEXT_OFFSET..NEXT_OFFSET + 2].copy_from_slice(&next.to_le_bytes());
+    let name = name.as_bytes();
+    let length = name.len().min(NAME_LENGTH);
+    frame[NAME_OFFSET..NAME_OFFSET + length].copy_from_slice(&name[..length]);
+    frame[FRAME_SIZE - 1] = frame_checksum(&frame);
+    return frame;
+}
+
+/// Replace a file's contents by writing a temporary file and renaming it over
+/// the original, so a crash never leaves a half-written file behind
+pub fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp = path.to_path_buf().into_os_string();
+    temp.push(".tmp");
+    let mut file = File::create(&temp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    return fs::rename(&temp, path);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A save with a title frame, a one-frame icon and distinct blocks
+    fn test_save(name: &str, blocks: usize) -> SaveFile {
+        let mut data = vec![0u8; blocks * BLOCK_SIZE];
+        data[..4].copy_from_slice(&[b'S', b'C', 0x11, blocks as u8]);
+        // "ＡＢ"
+        data[4..8].copy_from_slice(&[0x82, 0x60, 0x82, 0x61]);
+        // palette entry 1 is pure red, and the first pixel uses it
+        data[0x62..0x64].copy_from_slice(&0x001Fu16.to_le_bytes());
+        data[0x80] = 0x01;
+        for block in 1..blocks {
+            data[block * BLOCK_SIZE] = block as u8;
+        }
+        return SaveFile {
+            name: name.to_string(),
+            data,
+        };
+    }
+
+    #[test]
+    fn lists_saves() {
+        let mut card = MemoryCardImage::new();
+        assert_eq!(card.free_blocks(), 15);
+        card.import(&test_save("BASLUS-00001ONE", 1)).unwrap();
+        card.import(&test_save("BISLPS-00002TWO", 3)).unwrap();
+        let saves = card.saves().unwrap();
+        assert_eq!(saves.len(), 2);
+        assert_eq!(saves[1].block, 2);
+        assert_eq!(saves[1].blocks, vec![2, 3, 4]);
+        assert_eq!(saves[1].title, "AB");
+        assert_eq!(saves[1].get_region(), "NTSC-J");
+        let icon = saves[0].icon.as_ref().unwrap();
+        assert_eq!(icon.frame_count(), 1);
+        assert_eq!(icon.to_rgba(0)[..8], [0xFF, 0, 0, 0xFF, 0, 0, 0, 0]);
+        assert_eq!(card.free_blocks(), 11);
+        for block in 1..=4 {
+            let frame = card.get_frame(block);
+            assert_eq!(frame_checksum(frame), frame[127]);
+        }
+    }
+
+    #[test]
+    fn exports_and_deletes_saves() {
+        let mut card = MemoryCardImage::new();
+        let first = test_save("BESLES-00001FIRST", 2);
+        let second = test_save("BESLES-00002SECOND", 2);
+        card.import(&first).unwrap();
+        card.import(&second).unwrap();
+        assert!(card.import(&first).is_err());
+        assert_eq!(card.export(3).unwrap(), second);
+        assert!(card.export(2).is_err());
+
+        // deleted blocks are reused, and saves can be split around others
+        card.delete(1).unwrap();
+        assert_eq!(card.free_blocks(), 13);
+        let third = test_save("BESLES-00003THIRD", 3);
+        assert_eq!(card.import(&third).unwrap(), 1);
+        assert_eq!(card.saves().unwrap()[0].blocks, vec![1, 2, 5]);
+        assert_eq!(card.export(1).unwrap(), third);
+
+        card.format();
+        assert!(card.saves().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,195 @@
+//! Single saves, as exported from or imported to a card
+//!
+//! A save on its own is just its name and its blocks. Tools wrap it in a few
+//! ways:
+//!
+//! - Raw files hold only the blocks, with the save's name as the file name.
+//! - .mcs files (from PSXGameEdit and others) prefix the blocks with the
+//!   save's 128-byte directory frame.
+//! - .psv files are what a PS3 exports. They start with a 0x84-byte header
+//!   holding a signature, the save's size and name.
+
+use super::{directory_frame, BLOCK_SIZE, DIR_FIRST, NAME_LENGTH, NAME_OFFSET};
+use std::io;
+use std::path::Path;
+
+/// The size of a .psv header, which the blocks follow
+const PSV_HEADER_SIZE: usize = 0x84;
+const PSV_MAGIC: &[u8; 4] = b"\0VSP";
+const PSV_SIZE_OFFSET: usize = 0x40;
+const PSV_DATA_OFFSET: usize = 0x44;
+const PSV_NAME_OFFSET: usize = 0x64;
+/// Header fields that are the same in every PS1 save a PS3 exports, as
+/// (offset, value) pairs
+const PSV_CONSTANTS: [(usize, u32); 5] = [
+    (0x38, 0x14),
+    (0x3C, 0x01),
+    (0x48, 0x200),
+    (0x5C, 0x2000),
+    (0x60, 0x9003),
+];
+
+/// The ways a single save can be stored in a file
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum SaveFormat {
+    Raw,
+    Mcs,
+    Psv,
+}
+
+impl SaveFormat {
+    /// Pick the format from a file's extension, assuming raw if it's not one
+    /// of the others
+    pub fn from_path(path: &Path) -> SaveFormat {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        return match extension.as_deref() {
+            Some("mcs") => SaveFormat::Mcs,
+            Some("psv") => SaveFormat::Psv,
+            _ => SaveFormat::Raw,
+        };
+    }
+}
+
+/// A save taken off a card
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SaveFile {
+    /// The name in the directory, like "BASLUS-00067DRAX00"
+    pub name: String,
+    /// The save's blocks, in order
+    pub data: Vec<u8>,
+}
+
+impl SaveFile {
+    /// Read a save from a file
+    ///
+    /// Raw saves don't hold their name, so it's taken from the file name.
+    pub fn open(path: &Path) -> io::Result<SaveFile> {
+        let bytes = std::fs::read(path)?;
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        return SaveFile::decode(&bytes, SaveFormat::from_path(path), &stem);
+    }
+
+    /// Read a save from the contents of a file, using `raw_name` as the name
+    /// of raw saves
+    pub fn decode(bytes: &[u8], format: SaveFormat, raw_name: &str) -> io::Result<SaveFile> {
+        let (name, data) = match format {
+            SaveFormat::Raw => (raw_name.to_string(), bytes),
+            SaveFormat::Mcs => {
+                if bytes.len() < BLOCK_SIZE || bytes[0] != DIR_FIRST as u8 {
+                    return Err(invalid("Not an .mcs save"));
+                }
+                (read_name(&bytes[NAME_OFFSET..]), &bytes[0x80..])
+            }
+            SaveFormat::Psv => {
+                if bytes.len() < PSV_HEADER_SIZE || &bytes[..4] != PSV_MAGIC {
+                    return Err(invalid("Not a .psv save"));
+                }
+                let size = read_u32(bytes, PSV_SIZE_OFFSET) as usize;
+                let start = read_u32(bytes, PSV_DATA_OFFSET) as usize;
+                let data = bytes
+                    .get(start..start + size)
+                    .ok_or_else(|| invalid("Truncated .psv save"))?;
+                (read_name(&bytes[PSV_NAME_OFFSET..]), data)
+            }
+        };
+        if data.is_empty() || (data.len() % BLOCK_SIZE) != 0 {
+            return Err(invalid(&format!(
+                "Saves are a whole number of 8KiB blocks, not {} bytes",
+                data.len()
+            )));
+        }
+        if name.is_empty() || name.len() > NAME_LENGTH {
+            return Err(invalid(&format!("Not a valid save name: {:?}", name)));
+        }
+        return Ok(SaveFile {
+            name,
+            data: data.to_vec(),
+        });
+    }
+
+    /// The number of blocks the save takes up on a card
+    pub fn block_count(&self) -> usize {
+        return self.data.len() / BLOCK_SIZE;
+    }
+
+    /// Write the save out in a given format
+    ///
+    /// .psv files are written without a signature, which emulators and card
+    /// tools ignore but which a PS3 checks before importing them.
+    pub fn encode(&self, format: SaveFormat) -> Vec<u8> {
+        let mut bytes = match format {
+            SaveFormat::Raw => vec![],
+            SaveFormat::Mcs => {
+                directory_frame(DIR_FIRST, self.data.len() as u32, None, &self.name).to_vec()
+            }
+            SaveFormat::Psv => {
+                let mut header = vec![0u8; PSV_HEADER_SIZE];
+                header[..4].copy_from_slice(PSV_MAGIC);
+                let mut fields = PSV_CONSTANTS.to_vec();
+                fields.push((PSV_SIZE_OFFSET, self.data.len() as u32));
+                fields.push((PSV_DATA_OFFSET, PSV_HEADER_SIZE as u32));
+                for (offset, value) in fields {
+                    header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+                }
+                let name = self.name.as_bytes();
+                let length = name.len().min(NAME_LENGTH);
+                header[PSV_NAME_OFFSET..PSV_NAME_OFFSET + length].copy_from_slice(&name[..length]);
+                header
+            }
+        };
+        bytes.extend_from_slice(&self.data);
+        return bytes;
+    }
+}
+
+/// Read a NUL-terminated ASCII save name
+pub fn read_name(bytes: &[u8]) -> String {
+    let bytes = &bytes[..NAME_LENGTH.min(bytes.len())];
+    let end = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    return String::from_utf8_lossy(&bytes[..end]).into_owned();
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    return u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ]);
+}
+
+pub fn invalid(msg: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trips_formats() {
+        let save = SaveFile {
+            name: "BESLES-01234GAME".to_string(),
+            data: vec![0x5A; BLOCK_SIZE * 2],
+        };
+        for &format in &[SaveFormat::Raw, SaveFormat::Mcs, SaveFormat::Psv] {
+            let bytes = save.encode(format);
+            assert_eq!(SaveFile::decode(&bytes, format, &save.name).unwrap(), save);
+        }
+        assert_eq!(save.encode(SaveFormat::Mcs).len(), 0x80 + BLOCK_SIZE * 2);
+        assert_eq!(
+            SaveFormat::from_path(Path::new("game.PSV")),
+            SaveFormat::Psv
+        );
+        assert!(SaveFile::decode(&[0; 100], SaveFormat::Raw, "NAME").is_err());
+    }
+}
@@ -0,0 +1,40 @@
+//! Save titles, as shown by the BIOS's memory card manager
+
+use encoding_rs::SHIFT_JIS;
+
+/// Decode a save's title, which is NUL-terminated Shift-JIS
+///
+/// Titles are almost always written in full-width characters, so those are
+/// converted to their ASCII equivalents to be readable in a terminal.
+pub fn decode_title(bytes: &[u8]) -> String {
+    let end = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    let (text, _, _) = SHIFT_JIS.decode(&bytes[..end]);
+    let title: String = text.chars().map(to_half_width).collect();
+    return title.trim_end().to_string();
+}
+
+fn to_half_width(c: char) -> char {
+    return match c {
+        '\u{3000}' => ' ',
+        '\u{FF01}'..='\u{FF5E}' => std::char::from_u32(c as u32 - 0xFEE0).unwrap_or(c),
+        _ => c,
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decodes_full_width_titles() {
+        // "ＦＦ７　セーブ" then padding
+        let bytes = [
+            0x82, 0x65, 0x82, 0x65, 0x82, 0x56, 0x81, 0x40, 0x83, 0x5A, 0x81, 0x5B, 0x83, 0x75,
+            0x00, 0x00,
+        ];
+        assert_eq!(decode_title(&bytes), "FF7 セーブ");
+    }
+}
@@ -28,6 +28,8 @@ pub enum Device {
     DMA,
     /// The Timer controller
     Timers,
+    /// The CD-ROM controller
+    CdRom,
     /// The GPU control ports
     GPU,
     /// The Sound Processing Unit
@@ -91,14 +93,15 @@ const RAM_CTRL_RANGE: Range = Range::new(0x0F80_1060, 4);
 const INT_CTRL_RANGE: Range = Range::new(0x0F80_1070, 8);
 const DMA_RANGE: Range = Range::new(0x0F80_1080, 128);
 const TIMER_RANGE: Range = Range::new(0x0F80_1100, 0x30);
+const CDROM_RANGE: Range = Range::new(0x0F80_1800, 4);
 const GPU_RANGE: Range = Range::new(0x0F80_1810, 8);
-const SPU_RANGE: Range = Range::new(0x0F80_1c00, 640);
+const SPU_RANGE: Range = Range::new(0x0F80_1C00, 640);
 const EXP2_RANGE: Range = Range::new(0x0F80_2000, 8 * 1024);
 const EXP3_RANGE: Range = Range::new(0x0FA0_0000, 2048 * 1024);
 const BIOS_RANGE: Range = Range::new(0x0FC0_0000, 512 * 1024);
 const CACHE_CTRL_RANGE: Range = Range::new(0x3FFE_0000, 512);
 
-const RANGES: &'static [(Device, Range)] = &[
+const RANGES: &[(Device, Range)] = &[
     (Device::RAM, RAM_RANGE),
     (Device::Expansion1, EXP1_RANGE),
     (Device::Scratch, SCRATCH_RANGE),
@@ -108,6 +111,7 @@ const RANGES: &'static [(Device, Range)] = &[
     (Device::IntCtrl, INT_CTRL_RANGE),
     (Device::DMA, DMA_RANGE),
     (Device::Timers, TIMER_RANGE),
+    (Device::CdRom, CDROM_RANGE),
     (Device::GPU, GPU_RANGE),
     (Device::SPU, SPU_RANGE),
     (Device::Expansion2, EXP2_RANGE),
@@ -131,7 +135,7 @@ pub fn map_device(addr: u32) -> (Segment, Device, u32) {
     };
     if segment == Segment::KSEG2 {
         let addr = addr - KSEG2_RANGE.start;
-        if addr < CACHE_CTRL_RANGE.start || addr >= CACHE_CTRL_RANGE.end {
+        if !CACHE_CTRL_RANGE.contains(addr) {
             panic!("Invalid KSEG2 address: ${:08X}", addr + KSEG2_RANGE.start);
         }
         return (
@@ -150,10 +154,12 @@ pub fn map_device(addr: u32) -> (Segment, Device, u32) {
         .iter()
         .find(|&(_, range)| range.contains(seg_local_addr))
         .map(|(dev, range)| (dev.to_owned(), range.as_local_addr(seg_local_addr)))
-        .expect(&format!(
-            "Invalid memory location in {:?}: ${:08X} / ${:08X}",
-            segment, addr, seg_local_addr
-        ));
+        .unwrap_or_else(|| {
+            panic!(
+                "Invalid memory location in {:?}: ${:08X} / ${:08X}",
+                segment, addr, seg_local_addr
+            )
+        });
     // TODO: find a better way of handling seg-specific conditions
     if segment == Segment::KSEG1 && device == Device::Scratch {
         panic!(
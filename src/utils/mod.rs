@@ -1,3 +1,8 @@
+pub mod audio;
+pub mod bios;
 pub mod decode;
 pub mod disasm;
+pub mod exe;
+pub mod frame_dump;
+pub mod memcard;
 pub mod memorymap;
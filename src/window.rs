@@ -0,0 +1,93 @@
+//! Running the emulator in a window, with the keyboard as the first controller
+
+use crate::config::Limits;
+use crate::devices::gpu::{Frame, VideoMode, WithGpu};
+use crate::devices::motherboard::Motherboard;
+use crate::devices::sio::{Button, InputState};
+use minifb::{Key, ScaleMode, Window, WindowOptions};
+
+const TITLE: &str = "ps.rs";
+/// The window's starting size, which fits the common 320x240 modes at 2x
+const WINDOW_SIZE: (usize, usize) = (640, 480);
+const NTSC_FPS: usize = 60;
+const PAL_FPS: usize = 50;
+
+/// Keys for each controller button
+const KEY_MAP: [(Key, Button); 14] = [
+    (Key::Up, Button::Up),
+    (Key::Down, Button::Down),
+    (Key::Left, Button::Left),
+    (Key::Right, Button::Right),
+    (Key::X, Button::Cross),
+    (Key::C, Button::Circle),
+    (Key::Z, Button::Square),
+    (Key::S, Button::Triangle),
+    (Key::Q, Button::L1),
+    (Key::W, Button::R1),
+    (Key::A, Button::L2),
+    (Key::E, Button::R2),
+    (Key::Enter, Button::Start),
+    (Key::Backspace, Button::Select),
+];
+
+/// Run until the window is closed, Escape is pressed, or a limit is reached
+///
+/// Frames are shown at the rate of the console's video standard.
+pub fn run_windowed(
+    psx: &mut Motherboard,
+    limits: &Limits,
+    video_mode: VideoMode,
+) -> Result<(), String> {
+    let options = WindowOptions {
+        resize: true,
+        scale_mode: ScaleMode::AspectRatioStretch,
+        ..WindowOptions::default()
+    };
+    let mut window = Window::new(TITLE, WINDOW_SIZE.0, WINDOW_SIZE.1, options)
+        .map_err(|err| format!("Could not open a window ({}), try --headless", err))?;
+    window.set_target_fps(match video_mode {
+        VideoMode::Ntsc => NTSC_FPS,
+        VideoMode::Pal => PAL_FPS,
+    });
+
+    let mut buffer = vec![];
+    let mut frames = 0;
+    let mut cycles = 0;
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        if limits.is_reached(frames, cycles) {
+            break;
+        }
+        psx.tick();
+        cycles += 1;
+        if !psx.take_frame_completed() {
+            continue;
+        }
+        frames += 1;
+        let frame = psx.gpu().get_display_frame();
+        to_window_pixels(&frame, &mut buffer);
+        window
+            .update_with_buffer(&buffer, frame.width as usize, frame.height as usize)
+            .map_err(|err| format!("Could not draw to the window: {}", err))?;
+        psx.set_controller_input(0, &read_input(&window));
+    }
+    return Ok(());
+}
+
+/// Convert packed RGB888 to the 0RGB words minifb takes
+fn to_window_pixels(frame: &Frame, buffer: &mut Vec<u32>) {
+    buffer.clear();
+    buffer.extend(
+        frame
+            .pixels
+            .chunks_exact(3)
+            .map(|rgb| u32::from_be_bytes([0, rgb[0], rgb[1], rgb[2]])),
+    );
+}
+
+fn read_input(window: &Window) -> InputState {
+    let mut input = InputState::new();
+    for &(key, button) in KEY_MAP.iter() {
+        input.set_button(button, window.is_key_down(key));
+    }
+    return input;
+}